@@ -261,7 +261,7 @@ pub fn zero_keyschedule<Protocol: Party>(
     party: &mut Protocol,
     variant: AesVariant,
 ) -> Vec<AesKeyState> {
-    println!("Zero key schedule");
+    tracing::debug!("zero key schedule");
     // aes128_keyschedule(party, vec![RssShare::from(GF8(0), GF8(0)); 16]).unwrap()
     (0..variant.n_rounds()+1)
         .map(|_| AesKeyState::new())
@@ -523,7 +523,7 @@ pub(super) fn add_round_key(states: &mut VectorAesState, round_key: &AesKeyState
     }
 }
 
-fn sbox_layer<Protocol: GF8InvBlackBox>(
+pub(crate) fn sbox_layer<Protocol: GF8InvBlackBox>(
     party: &mut Protocol,
     si: &mut [GF8],
     sii: &mut [GF8],
@@ -540,7 +540,7 @@ fn sbox_layer<Protocol: GF8InvBlackBox>(
     Ok(())
 }
 
-fn inv_sbox_layer<Protocol: GF8InvBlackBox>(
+pub(crate) fn inv_sbox_layer<Protocol: GF8InvBlackBox>(
     party: &mut Protocol,
     si: &mut [GF8],
     sii: &mut [GF8],
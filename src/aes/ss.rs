@@ -1 +1,138 @@
-pub use maestro::aes::ss::*;
\ No newline at end of file
+pub use maestro::aes::ss::*;
+
+use maestro::rep3_core::party::error::MpcResult;
+use maestro::share::gf8::GF8;
+
+use super::{add_round_key, AesKeyState, AesVariant, VectorAesState};
+use crate::encrypt::backend::MpcParty;
+use crate::encrypt::fault::{self, Fault};
+use crate::metrics::{self, Phase};
+
+// maestro only provides the forward AES-128 cipher for the SS protocols. The ciphers here run on
+// any backend (`encrypt::backend`): AES-128, AES-256 and the inverse cipher, with every S-box
+// layer measured as an `aes_sbox` phase. The states are converted to the local copies to reach
+// si and sii.
+
+/// S-box layer of the SS protocols, for their `MpcParty::sbox_layer`
+pub(crate) fn sbox_layer_ss<Protocol: GF8InvBlackBoxSS>(
+    party: &mut Protocol,
+    si: &mut [GF8],
+    sii: &mut [GF8],
+) -> MpcResult<()> {
+    // gf8 inverse, then affine transform
+    let mut state_ss: Vec<GF8> = vec![GF8(0); si.len()];
+    party.gf8_inv_rss_to_ss(&mut state_ss, si, sii)?;
+    let c = party.constant(GF8(0x63));
+    state_ss.iter_mut().for_each(|dst| *dst = dst.aes_sbox_affine_transform() + c);
+    party.gf8_inv_and_rss_output(&mut state_ss, si, sii)
+}
+
+/// Inverse S-box layer of the SS protocols
+pub(crate) fn inv_sbox_layer_ss<Protocol: GF8InvBlackBoxSS>(
+    party: &mut Protocol,
+    si: &mut [GF8],
+    sii: &mut [GF8],
+) -> MpcResult<()> {
+    // first inverse affine transform, then gf8 inverse
+    let c = party.constant_rss(GF8(0x63));
+    for i in 0..si.len() {
+        si[i] = (si[i] + c.si).inv_aes_sbox_affine_transform();
+        sii[i] = (sii[i] + c.sii).inv_aes_sbox_affine_transform();
+    }
+    let mut state_ss: Vec<GF8> = vec![GF8(0); si.len()];
+    party.gf8_inv_rss_to_ss(&mut state_ss, si, sii)?;
+    party.gf8_inv_and_rss_output(&mut state_ss, si, sii)
+}
+
+/// SubBytes on the shared bytes, as an `aes_sbox` phase
+pub(crate) fn sub_bytes(party: &mut dyn MpcParty, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+    let phase: Phase = metrics::start("aes_sbox", party.main_party_mut());
+    party.sbox_layer(si, sii)?;
+    fault::tamper(Fault::SboxOutput, si);
+    phase.end(party.main_party_mut());
+    Ok(())
+}
+
+// InvSubBytes on the shared bytes, as an `aes_sbox` phase
+fn inv_sub_bytes(party: &mut dyn MpcParty, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+    let phase: Phase = metrics::start("aes_sbox", party.main_party_mut());
+    party.inv_sbox_layer(si, sii)?;
+    fault::tamper(Fault::SboxOutput, si);
+    phase.end(party.main_party_mut());
+    Ok(())
+}
+
+fn forward_mal(
+    party: &mut dyn MpcParty,
+    variant: AesVariant,
+    inputs: maestro::aes::VectorAesState,
+    key_schedule: &[maestro::aes::AesKeyState],
+) -> MpcResult<maestro::aes::VectorAesState> {
+    let n_rounds: usize = variant.n_rounds();
+    debug_assert_eq!(key_schedule.len(), n_rounds + 1);
+    let key_schedule: Vec<AesKeyState> = key_schedule.iter()
+        .map(|k| AesKeyState::from_rss_vec(k.to_rss_vec()))
+        .collect();
+    let mut state: VectorAesState = VectorAesState::from_bytes(inputs.to_bytes());
+
+    add_round_key(&mut state, &key_schedule[0]);
+    for round_key in &key_schedule[1..n_rounds] {
+        sub_bytes(party, &mut state.si, &mut state.sii)?;
+        state.shift_rows();
+        state.mix_columns();
+        add_round_key(&mut state, round_key);
+    }
+    sub_bytes(party, &mut state.si, &mut state.sii)?;
+    state.shift_rows();
+    add_round_key(&mut state, &key_schedule[n_rounds]);
+
+    Ok(maestro::aes::VectorAesState::from_bytes(state.to_bytes()))
+}
+
+pub fn aes256_no_keyschedule_mal(
+    party: &mut dyn MpcParty,
+    inputs: maestro::aes::VectorAesState,
+    key_schedule: &[maestro::aes::AesKeyState],
+) -> MpcResult<maestro::aes::VectorAesState> {
+    forward_mal(party, AesVariant::Aes256, inputs, key_schedule)
+}
+
+/// Forward cipher for AES-128 or AES-256, chosen by the length of the key schedule
+pub fn aes_no_keyschedule_mal(
+    party: &mut dyn MpcParty,
+    inputs: maestro::aes::VectorAesState,
+    key_schedule: &[maestro::aes::AesKeyState],
+) -> MpcResult<maestro::aes::VectorAesState> {
+    let variant: AesVariant = match key_schedule.len() == AesVariant::Aes256.n_rounds() + 1 {
+        true => AesVariant::Aes256,
+        false => AesVariant::Aes128,
+    };
+    forward_mal(party, variant, inputs, key_schedule)
+}
+
+/// Inverse cipher for AES-128 or AES-256, chosen by the length of the key schedule
+pub fn aes_inv_no_keyschedule_mal(
+    party: &mut dyn MpcParty,
+    inputs: maestro::aes::VectorAesState,
+    key_schedule: &[maestro::aes::AesKeyState],
+) -> MpcResult<maestro::aes::VectorAesState> {
+    let n_rounds: usize = key_schedule.len() - 1;
+    debug_assert!(n_rounds == AesVariant::Aes128.n_rounds() || n_rounds == AesVariant::Aes256.n_rounds());
+    let key_schedule: Vec<AesKeyState> = key_schedule.iter()
+        .map(|k| AesKeyState::from_rss_vec(k.to_rss_vec()))
+        .collect();
+    let mut state: VectorAesState = VectorAesState::from_bytes(inputs.to_bytes());
+
+    add_round_key(&mut state, &key_schedule[n_rounds]);
+    for round_key in key_schedule[1..n_rounds].iter().rev() {
+        state.inv_shift_rows();
+        inv_sub_bytes(party, &mut state.si, &mut state.sii)?;
+        add_round_key(&mut state, round_key);
+        state.inv_mix_columns();
+    }
+    state.inv_shift_rows();
+    inv_sub_bytes(party, &mut state.si, &mut state.sii)?;
+    add_round_key(&mut state, &key_schedule[0]);
+
+    Ok(maestro::aes::VectorAesState::from_bytes(state.to_bytes()))
+}
@@ -0,0 +1,384 @@
+// TLS certificates for the party links.
+//
+// `certs init` creates a cluster CA and one CA-signed Ed25519 certificate per party and points
+// the cluster file at them. The TLS handshake checks that a peer's certificate was issued by the
+// CA, and each party then pins every peer to the certificates the cluster file names for its slot,
+// so a certificate of one party is not accepted in another's place.
+//
+// `certs rotate` issues new certificates and keeps each old pair as `[p<n>.previous]` until the
+// end of a grace window. During the window a party whose new key is not installed yet starts with
+// its old pair, and its peers accept both because the slot names both; after the window the old
+// pair is refused at startup. The CA key is only needed here and is not deployed to parties.
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+use clap::Subcommand;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose,
+    IsCa, KeyPair, KeyUsagePurpose, SanType, SerialNumber,
+};
+use time::{Date, Month, OffsetDateTime};
+
+use super::{check_key_pair, read_certificate, read_cluster, relative_to, timestamp, write_cluster, ClusterFile, PartyEntry, PreviousCertificate, CLUSTER_FILE};
+use crate::error::{LocksmithError, Result};
+
+pub const DEFAULT_DAYS: u32 = 365;
+
+const CA_NAME: &str = "Locksmith cluster CA";
+
+#[derive(Subcommand)]
+pub enum CertsCommand {
+    /// Create a cluster CA and a certificate per party, and point the cluster file at them
+    Init {
+        /// Cluster file to update, see `config init`
+        #[arg(long, value_name = "FILE", default_value = CLUSTER_FILE)]
+        cluster: PathBuf,
+
+        /// Directory for ca.pem, ca.key and p<n>.pem/p<n>.key, relative to the cluster file
+        /// unless absolute
+        #[arg(long, value_name = "DIR", default_value = "keys")]
+        keys_dir: PathBuf,
+
+        /// Validity of the certificates in days
+        #[arg(long, default_value_t = DEFAULT_DAYS)]
+        days: u32,
+
+        /// Replace an existing CA, which needs a restart of all parties
+        #[arg(long)]
+        force: bool,
+    },
+    /// Issue new certificates, keeping the old ones accepted during a grace window
+    Rotate {
+        /// Cluster file to update
+        #[arg(long, value_name = "FILE", default_value = CLUSTER_FILE)]
+        cluster: PathBuf,
+
+        /// Party to rotate (1, 2 or 3), can be repeated; all parties if omitted
+        #[arg(long = "party", value_name = "N")]
+        parties: Vec<usize>,
+
+        /// How long the old certificates stay usable
+        #[arg(long, default_value_t = 24)]
+        grace_hours: u32,
+
+        /// CA key [default: ca.key next to the CA certificate]
+        #[arg(long, value_name = "FILE")]
+        ca_key: Option<PathBuf>,
+
+        /// Validity of the new certificates in days
+        #[arg(long, default_value_t = DEFAULT_DAYS)]
+        days: u32,
+
+        /// Start a rotation while the grace window of the previous one is still open
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+fn cert_error(e: rcgen::Error) -> LocksmithError {
+    LocksmithError::Config(format!("certificate generation failed: {}", e))
+}
+
+fn validity(params: &mut CertificateParams, days: u32) {
+    let now: OffsetDateTime = OffsetDateTime::now_utc();
+    // A little slack for parties whose clocks are behind
+    params.not_before = now - time::Duration::minutes(5);
+    params.not_after = now + time::Duration::days(days.into());
+    let serial: [u8; 8] = rand::random();
+    params.serial_number = Some(SerialNumber::from_slice(&[&[serial[0] & 0x7f], &serial[1..]].concat()));
+}
+
+// The CA is rebuilt from its key for every rotation, so its name must not change
+fn ca_certificate(key_pair: KeyPair, days: u32) -> Result<Certificate> {
+    let mut params: CertificateParams = CertificateParams::new(Vec::<String>::new());
+    params.alg = &rcgen::PKCS_ED25519;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let mut name: DistinguishedName = DistinguishedName::new();
+    name.push(DnType::CommonName, CA_NAME);
+    params.distinguished_name = name;
+    params.key_pair = Some(key_pair);
+    validity(&mut params, days);
+    Certificate::from_params(params).map_err(cert_error)
+}
+
+/// Certificate and key of `party` (1-based) for `address`, as PEM
+fn issue(ca: &Certificate, party: usize, address: &str, days: u32) -> Result<(String, String)> {
+    let mut params: CertificateParams = CertificateParams::new(Vec::<String>::new());
+    params.alg = &rcgen::PKCS_ED25519;
+    // maestro connects to resolved IP addresses, so host names also get their current addresses
+    params.subject_alt_names = match address.parse::<IpAddr>() {
+        Ok(ip) => vec![SanType::IpAddress(ip)],
+        Err(_) => {
+            let mut names: Vec<SanType> = vec![SanType::DnsName(address.to_string())];
+            for addr in (address, 0).to_socket_addrs().into_iter().flatten() {
+                if !names.contains(&SanType::IpAddress(addr.ip())) {
+                    names.push(SanType::IpAddress(addr.ip()));
+                }
+            }
+            names
+        }
+    };
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth, ExtendedKeyUsagePurpose::ClientAuth];
+    let mut name: DistinguishedName = DistinguishedName::new();
+    name.push(DnType::CommonName, format!("Party {}", party));
+    params.distinguished_name = name;
+    validity(&mut params, days);
+
+    let cert: Certificate = Certificate::from_params(params).map_err(cert_error)?;
+    Ok((cert.serialize_pem_with_signer(ca).map_err(cert_error)?, cert.serialize_private_key_pem()))
+}
+
+/// Writes the CA and a certificate per party to `keys_dir` and updates the cluster file.
+/// Returns the written files.
+pub fn init(cluster_path: &Path, keys_dir: &Path, days: u32, force: bool) -> Result<Vec<PathBuf>> {
+    let mut cluster: ClusterFile = read_cluster(cluster_path)?;
+    let dir: PathBuf = relative_to(cluster_path, keys_dir.to_path_buf());
+    let ca_path: PathBuf = keys_dir.join("ca.pem");
+    if !force && (cluster.ca.is_some() || relative_to(cluster_path, ca_path.clone()).exists()) {
+        return Err(LocksmithError::InvalidInput(format!(
+            "{} already has a CA, use `certs rotate` or --force to replace it", cluster_path.display()
+        )));
+    }
+
+    let key_pair: KeyPair = KeyPair::generate(&rcgen::PKCS_ED25519).map_err(cert_error)?;
+    let ca: Certificate = ca_certificate(key_pair, days)?;
+    let mut files: Vec<(PathBuf, String)> = vec![
+        (dir.join("ca.pem"), ca.serialize_pem().map_err(cert_error)?),
+        (dir.join("ca.key"), ca.serialize_private_key_pem()),
+    ];
+    for (i, entry) in cluster.parties_mut().into_iter().enumerate() {
+        let (cert, key): (String, String) = issue(&ca, i + 1, &entry.address, days)?;
+        entry.certificate = keys_dir.join(format!("p{}.pem", i + 1));
+        entry.private_key = keys_dir.join(format!("p{}.key", i + 1));
+        entry.previous = None;
+        files.push((dir.join(format!("p{}.pem", i + 1)), cert));
+        files.push((dir.join(format!("p{}.key", i + 1)), key));
+    }
+    cluster.ca = Some(ca_path);
+
+    std::fs::create_dir_all(&dir)?;
+    for (path, text) in &files {
+        std::fs::write(path, text)?;
+    }
+    write_cluster(cluster_path, &cluster)?;
+    Ok(files.into_iter().map(|(path, _)| path).chain([cluster_path.to_path_buf()]).collect())
+}
+
+// The cluster's CA, rebuilt from its key, with the path and DER of its certificate
+struct ClusterCa {
+    path: PathBuf,
+    der: Vec<u8>,
+    ca: Certificate,
+}
+
+impl ClusterCa {
+    fn load(cluster_path: &Path, cluster: &ClusterFile, ca_key: Option<&Path>, days: u32) -> Result<Self> {
+        let path: PathBuf = cluster.ca.clone()
+            .map(|ca| relative_to(cluster_path, ca))
+            .ok_or_else(|| LocksmithError::Config(format!("{} has no CA, run `certs init` first", cluster_path.display())))?;
+        let ca_key: PathBuf = ca_key.map_or_else(|| path.with_file_name("ca.key"), Path::to_path_buf);
+        check_key_pair(&path, &ca_key).map_err(LocksmithError::Config)?;
+        let der: Vec<u8> = read_certificate(&path).map_err(LocksmithError::Config)?;
+        let key_pem: String = std::fs::read_to_string(&ca_key).map_err(|e| LocksmithError::Config(format!("{}: {}", ca_key.display(), e)))?;
+        let ca: Certificate = ca_certificate(KeyPair::from_pem(&key_pem).map_err(cert_error)?, days)?;
+        Ok(ClusterCa { path, der, ca })
+    }
+
+    /// Issues a pair for `party` (1-based) at `address`, checked against the CA certificate.
+    /// Returns the certificate and key as PEM and the serial number in hex.
+    fn issue(&self, party: usize, address: &str, days: u32, now: OffsetDateTime) -> Result<(String, String, String)> {
+        let (cert, key): (String, String) = issue(&self.ca, party, address, days)?;
+        let cert_der: Vec<u8> = rustls_pemfile::certs(&mut cert.as_bytes()).next()
+            .and_then(|cert| cert.ok())
+            .map(|cert| cert.to_vec())
+            .ok_or_else(|| LocksmithError::Config("issued certificate does not parse".to_string()))?;
+        // A CA not created by `certs init` has another name, which would break the chain
+        verify_issued_by(&cert_der, &self.der, now)
+            .map_err(|e| LocksmithError::Config(format!("{}: cannot issue certificates for this CA: {}", self.path.display(), e)))?;
+        let serial: String = hex::encode(parse_certificate(&cert_der).map_err(LocksmithError::Config)?.serial);
+        Ok((cert, key, serial))
+    }
+}
+
+/// Issues new certificates for `parties` (1-based, all if empty) next to their current ones and
+/// keeps the current pairs as previous until `grace` from now. Returns the written files.
+pub fn rotate(cluster_path: &Path, parties: &[usize], grace: time::Duration, ca_key: Option<&Path>, days: u32, force: bool) -> Result<Vec<PathBuf>> {
+    let mut cluster: ClusterFile = read_cluster(cluster_path)?;
+    let ca: ClusterCa = ClusterCa::load(cluster_path, &cluster, ca_key, days)?;
+
+    let parties: Vec<usize> = if parties.is_empty() { vec![1, 2, 3] } else { parties.to_vec() };
+    if let Some(party) = parties.iter().find(|party| !(1..=3).contains(*party)) {
+        return Err(LocksmithError::InvalidInput(format!("party must be 1, 2 or 3, not {}", party)));
+    }
+    let now: OffsetDateTime = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap_or(OffsetDateTime::now_utc());
+    let mut files: Vec<(PathBuf, String)> = Vec::new();
+    for (i, entry) in cluster.parties_mut().into_iter().enumerate().filter(|(i, _)| parties.contains(&(i + 1))) {
+        if let Some(previous) = entry.previous.as_ref().filter(|previous| previous.until > now && !force) {
+            return Err(LocksmithError::InvalidInput(format!(
+                "party {} is in the grace window of its last rotation until {}, use --force to rotate anyway", i + 1, timestamp(previous.until)
+            )));
+        }
+
+        let (cert, key, serial): (String, String, String) = ca.issue(i + 1, &entry.address, days, now)?;
+        let certificate: PathBuf = entry.certificate.with_file_name(format!("p{}.{}.pem", i + 1, serial));
+        let private_key: PathBuf = entry.private_key.with_file_name(format!("p{}.{}.key", i + 1, serial));
+        files.push((relative_to(cluster_path, certificate.clone()), cert));
+        files.push((relative_to(cluster_path, private_key.clone()), key));
+        entry.previous = Some(PreviousCertificate {
+            certificate: std::mem::replace(&mut entry.certificate, certificate),
+            private_key: std::mem::replace(&mut entry.private_key, private_key),
+            until: now + grace,
+        });
+    }
+
+    for (path, text) in &files {
+        std::fs::write(path, text)?;
+    }
+    write_cluster(cluster_path, &cluster)?;
+    Ok(files.into_iter().map(|(path, _)| path).chain([cluster_path.to_path_buf()]).collect())
+}
+
+/// Points party `party` (1-based) of the cluster at a new host: sets its address and port and
+/// issues it a certificate. The old pair, and any previous pair of a rotation, is dropped without
+/// a grace window, so peers that load the new cluster file refuse the old host's certificates
+/// although the CA issued them. Returns the written files.
+///
+/// Restart the remaining parties with the new cluster file and run `reshare` so the old host's key
+/// shares go out of date.
+pub fn replace(cluster_path: &Path, party: usize, address: &str, port: Option<u16>, ca_key: Option<&Path>, days: u32) -> Result<Vec<PathBuf>> {
+    let mut cluster: ClusterFile = read_cluster(cluster_path)?;
+    let ca: ClusterCa = ClusterCa::load(cluster_path, &cluster, ca_key, days)?;
+    if !(1..=3).contains(&party) {
+        return Err(LocksmithError::InvalidInput(format!("party must be 1, 2 or 3, not {}", party)));
+    }
+
+    let now: OffsetDateTime = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap_or(OffsetDateTime::now_utc());
+    let entry: &mut PartyEntry = cluster.parties_mut().into_iter().nth(party - 1)
+        .ok_or_else(|| LocksmithError::InvalidInput(format!("no party {}", party)))?;
+    let (cert, key, serial): (String, String, String) = ca.issue(party, address, days, now)?;
+    entry.address = address.to_string();
+    entry.port = port.unwrap_or(entry.port);
+    entry.certificate = entry.certificate.with_file_name(format!("p{}.{}.pem", party, serial));
+    entry.private_key = entry.private_key.with_file_name(format!("p{}.{}.key", party, serial));
+    entry.previous = None;
+    let files: Vec<(PathBuf, String)> = vec![
+        (relative_to(cluster_path, entry.certificate.clone()), cert),
+        (relative_to(cluster_path, entry.private_key.clone()), key),
+    ];
+
+    for (path, text) in &files {
+        std::fs::write(path, text)?;
+    }
+    write_cluster(cluster_path, &cluster)?;
+    Ok(files.into_iter().map(|(path, _)| path).chain([cluster_path.to_path_buf()]).collect())
+}
+
+/// Checks that `cert` names the CA as issuer, carries a valid CA signature and has not expired
+pub(crate) fn verify_issued_by(cert: &[u8], ca: &[u8], now: OffsetDateTime) -> std::result::Result<(), String> {
+    let cert: CertificateFields = parse_certificate(cert)?;
+    let ca: CertificateFields = parse_certificate(ca)?;
+    if cert.issuer != ca.subject {
+        return Err("issued by another CA".to_string());
+    }
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, ca.public_key)
+        .verify(cert.tbs, cert.signature)
+        .map_err(|_| "the CA signature does not verify".to_string())?;
+    if cert.not_after <= now {
+        return Err(format!("expired at {}", timestamp(cert.not_after)));
+    }
+    Ok(())
+}
+
+// The parts of an X.509 certificate (RFC 5280, section 4.1) the checks above need
+struct CertificateFields<'a> {
+    tbs: &'a [u8],
+    signature: &'a [u8],
+    serial: &'a [u8],
+    issuer: &'a [u8],
+    not_after: OffsetDateTime,
+    subject: &'a [u8],
+    public_key: &'a [u8],
+}
+
+fn parse_certificate(der: &[u8]) -> std::result::Result<CertificateFields<'_>, String> {
+    let (_, certificate, _) = der_next(der, 0x30)?;
+    let (tbs, tbs_fields, rest) = der_next(certificate, 0x30)?;
+    let (_, _, rest) = der_next(rest, 0x30)?;
+    let (_, signature, _) = der_next(rest, 0x03)?;
+
+    // The version is optional
+    let rest: &[u8] = match tbs_fields.first() {
+        Some(0xa0) => der_next(tbs_fields, 0xa0)?.2,
+        _ => tbs_fields,
+    };
+    let (_, serial, rest) = der_next(rest, 0x02)?;
+    let (_, _, rest) = der_next(rest, 0x30)?;
+    let (issuer, _, rest) = der_next(rest, 0x30)?;
+    let (_, times, rest) = der_next(rest, 0x30)?;
+    let (subject, _, rest) = der_next(rest, 0x30)?;
+    let (_, spki, _) = der_next(rest, 0x30)?;
+    let (_, _, spki_rest) = der_next(spki, 0x30)?;
+    let (_, public_key, _) = der_next(spki_rest, 0x03)?;
+
+    let (_, _, times) = der_next(times, times.first().copied().unwrap_or(0))?;
+    let tag: u8 = times.first().copied().unwrap_or(0);
+    let (_, not_after, _) = der_next(times, tag)?;
+
+    Ok(CertificateFields {
+        tbs,
+        // Bit strings start with the number of unused bits
+        signature: signature.get(1..).unwrap_or_default(),
+        serial,
+        issuer,
+        not_after: parse_time(tag, not_after)?,
+        subject,
+        public_key: public_key.get(1..).unwrap_or_default(),
+    })
+}
+
+// A DER element, its contents and the input after it
+type DerElement<'a> = (&'a [u8], &'a [u8], &'a [u8]);
+
+// Splits the first DER element, which must have tag `tag`, off `input`
+fn der_next(input: &[u8], tag: u8) -> std::result::Result<DerElement<'_>, String> {
+    let malformed = || "malformed certificate".to_string();
+    if input.first() != Some(&tag) {
+        return Err(malformed());
+    }
+    let first: usize = *input.get(1).ok_or_else(malformed)? as usize;
+    let (header, len): (usize, usize) = if first < 0x80 {
+        (2, first)
+    } else {
+        let n: usize = first - 0x80;
+        let bytes: &[u8] = input.get(2..2 + n).filter(|_| n <= 4).ok_or_else(malformed)?;
+        (2 + n, bytes.iter().fold(0, |len, b| (len << 8) | *b as usize))
+    };
+    let element: &[u8] = input.get(..header + len).ok_or_else(malformed)?;
+    Ok((element, &element[header..], &input[header + len..]))
+}
+
+// UTCTime (YYMMDDHHMMSSZ) or GeneralizedTime (YYYYMMDDHHMMSSZ)
+fn parse_time(tag: u8, text: &[u8]) -> std::result::Result<OffsetDateTime, String> {
+    let malformed = || format!("malformed certificate time '{}'", String::from_utf8_lossy(text));
+    let digits: &str = std::str::from_utf8(text).ok().and_then(|t| t.strip_suffix('Z')).ok_or_else(malformed)?;
+    let number = |from: usize, len: usize| digits.get(from..from + len).and_then(|d| d.parse::<i32>().ok()).ok_or_else(malformed);
+    let (year, at): (i32, usize) = match tag {
+        0x17 => (number(0, 2).map(|y| if y < 50 { 2000 + y } else { 1900 + y })?, 2),
+        0x18 => (number(0, 4)?, 4),
+        _ => return Err(malformed()),
+    };
+    let month: Month = Month::try_from(number(at, 2)? as u8).map_err(|_| malformed())?;
+    let (day, hour, minute, second): (i32, i32, i32, i32) = (number(at + 2, 2)?, number(at + 4, 2)?, number(at + 6, 2)?, number(at + 8, 2)?);
+    Date::from_calendar_date(year, month, day as u8)
+        .and_then(|date| date.with_hms(hour as u8, minute as u8, second as u8))
+        .map(|time| time.assume_utc())
+        .map_err(|_| malformed())
+}
+
+impl ClusterFile {
+    fn parties_mut(&mut self) -> [&mut PartyEntry; 3] {
+        [&mut self.p1, &mut self.p2, &mut self.p3]
+    }
+}
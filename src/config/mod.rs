@@ -0,0 +1,660 @@
+// Party network configuration.
+//
+// A cluster is described once in a cluster file with the sections [p1], [p2] and [p3]
+// (address, port, certificate, private_key). Every party has a small overlay file that names its
+// `party_index`, the `cluster` file, and optionally overrides fields of any section. Files
+// without `cluster` carry all three sections themselves, like the original p1.toml..p3.toml.
+// Relative certificate and key paths are relative to the file that sets them, except in files
+// without `cluster`, where they stay relative to the working directory as before.
+//
+// Environment variables override both files: LOCKSMITH_PARTY_INDEX, LOCKSMITH_CLUSTER, LOCKSMITH_CA,
+// LOCKSMITH_BACKEND, LOCKSMITH_POLICY, LOCKSMITH_AUDIT_LOG and LOCKSMITH_P<n>_ADDRESS / _PORT / _CERTIFICATE / _PRIVATE_KEY for n = 1..3.
+//
+// Timeouts and reconnects are set in a [network] table (see `NetworkSettings`) of the cluster file,
+// the party file or both; the party file wins field by field. The same table sets the number of
+// `channels` between the parties, which must be the same on all three (see `encrypt::parallel`).
+//
+// A cluster file may name a CA (`ca`, see `certs`); the parties then trust every certificate the
+// CA issued for their peers, and each section may hold the pair it replaced in [p<n>.previous].
+//
+// The MPC backend (`backend`, see `encrypt::backend`) is set in the cluster file, so that all
+// parties run the same one, or in a party file, which wins; it defaults to `lut256-ss-mal`.
+//
+// A party file may name the party's access policy (`policy`, see `policy`) and its audit log
+// (`audit_log`, by default audit/<party file stem>.log), which are never shared through the
+// cluster file.
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Subcommand, ValueEnum};
+use maestro::rep3_core::network::Config;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::encrypt::backend::Backend;
+use crate::error::{LocksmithError, Result};
+
+pub mod certs;
+pub mod policy;
+
+pub const CLUSTER_FILE: &str = "cluster.toml";
+
+/// Most connections between the parties, see `NetworkSettings::channels`
+pub const MAX_CHANNELS: usize = 64;
+
+const CLUSTER_HEADER: &str = "# Shared by all parties; each party's p<n>.toml selects its index\n";
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Write a cluster file, one overlay per party and an access policy that allows everything
+    Init {
+        /// Directory to write cluster.toml, p1.toml..p3.toml and policy.toml into
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+
+        /// Host names or addresses of the three parties
+        #[arg(long, value_delimiter = ',', num_args = 1..=3, default_value = "127.0.0.1")]
+        hosts: Vec<String>,
+
+        /// Port of party 1; parties 2 and 3 use the next ports
+        #[arg(long, default_value_t = 8100)]
+        base_port: u16,
+
+        /// Directory holding p<n>.pem and p<n>.key, relative to DIR unless absolute
+        #[arg(long, value_name = "DIR", default_value = "keys")]
+        keys_dir: PathBuf,
+
+        /// Overwrite existing files
+        #[arg(long)]
+        force: bool,
+    },
+    /// Load and validate party config files; with several files, also check that they describe
+    /// the same cluster with distinct party indices
+    Check {
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+    /// Move a party to a new host with a new certificate, before `--mode reshare`
+    Replace {
+        /// Cluster file to update
+        #[arg(long, value_name = "FILE", default_value = CLUSTER_FILE)]
+        cluster: PathBuf,
+
+        /// Party to replace (1, 2 or 3)
+        #[arg(long, value_name = "N")]
+        party: usize,
+
+        /// Host name or address of the new host
+        #[arg(long)]
+        address: String,
+
+        /// Port of the new host [default: the replaced party's port]
+        #[arg(long)]
+        port: Option<u16>,
+
+        /// CA key [default: ca.key next to the CA certificate]
+        #[arg(long, value_name = "FILE")]
+        ca_key: Option<PathBuf>,
+
+        /// Validity of the new certificate in days
+        #[arg(long, default_value_t = certs::DEFAULT_DAYS)]
+        days: u32,
+    },
+}
+
+/// Connection details of one party
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PartyEntry {
+    pub address: String,
+    pub port: u16,
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    /// The pair replaced by the last `certs rotate`, only in cluster files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<PreviousCertificate>,
+}
+
+/// A certificate and key that stay usable until `until`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PreviousCertificate {
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    #[serde(with = "time::serde::rfc3339")]
+    pub until: OffsetDateTime,
+}
+
+/// Timeouts and reconnect behaviour of the party links
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSettings {
+    /// How long to wait for the other parties when connecting
+    pub connect_timeout_secs: u64,
+    /// Connection attempts before a run gives up; the daemon keeps trying
+    pub connect_attempts: u32,
+    /// First pause between connection attempts, doubled after every failure
+    pub retry_initial_ms: u64,
+    /// Longest pause between connection attempts
+    pub retry_max_ms: u64,
+    /// A daemon session in which the parties exchange nothing for this long has failed
+    pub io_timeout_secs: u64,
+    /// Longest time a daemon session may take to set up or to serve one request
+    pub request_timeout_secs: u64,
+    /// Connections between the parties; large S-box layers are split across them, each served by
+    /// its own thread
+    pub channels: usize,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        NetworkSettings {
+            connect_timeout_secs: 60,
+            connect_attempts: 5,
+            retry_initial_ms: 500,
+            retry_max_ms: 30_000,
+            io_timeout_secs: 30,
+            request_timeout_secs: 600,
+            channels: 1,
+        }
+    }
+}
+
+impl NetworkSettings {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    /// Pause before connection attempt `attempt` + 1 (1-based)
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay: u64 = self.retry_initial_ms.saturating_mul(1u64 << (attempt - 1).min(32));
+        Duration::from_millis(delay.min(self.retry_max_ms))
+    }
+
+    pub fn io_timeout(&self) -> Duration {
+        Duration::from_secs(self.io_timeout_secs)
+    }
+
+    /// The data owner's daemon sends heartbeats this often when idle, well within the IO timeout
+    pub fn heartbeat(&self) -> Duration {
+        self.io_timeout() / 3
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.connect_timeout_secs == 0 || self.io_timeout_secs == 0 || self.request_timeout_secs == 0 {
+            return Err(LocksmithError::Config("[network] timeouts must be at least one second".to_string()));
+        }
+        if self.connect_attempts == 0 {
+            return Err(LocksmithError::Config("[network] connect_attempts must be at least 1".to_string()));
+        }
+        if !(1..=MAX_CHANNELS).contains(&self.channels) {
+            return Err(LocksmithError::Config(format!("[network] channels must be between 1 and {}", MAX_CHANNELS)));
+        }
+        Ok(())
+    }
+}
+
+// A section in an overlay, where every field is optional
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+struct PartialEntry {
+    address: Option<String>,
+    port: Option<u16>,
+    certificate: Option<PathBuf>,
+    private_key: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClusterFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backend: Option<Backend>,
+    // Kept as a table so that party files can override single fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    network: Option<toml::Table>,
+    p1: PartyEntry,
+    p2: PartyEntry,
+    p3: PartyEntry,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PartyFile {
+    party_index: Option<usize>,
+    cluster: Option<PathBuf>,
+    backend: Option<Backend>,
+    policy: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+    network: Option<toml::Table>,
+    p1: Option<PartialEntry>,
+    p2: Option<PartialEntry>,
+    p3: Option<PartialEntry>,
+}
+
+/// The configuration one party runs with, after overlays and environment overrides
+#[derive(Clone, PartialEq, Debug)]
+pub struct PartyConfig {
+    /// 1-based, as in the files
+    pub party_index: usize,
+    pub parties: [PartyEntry; 3],
+    /// CA that issued the party certificates, if any
+    pub ca: Option<PathBuf>,
+    pub network: NetworkSettings,
+    pub backend: Backend,
+    /// This party's access policy; every request is refused without one
+    pub policy: Option<PathBuf>,
+    /// Where this party records the operations it takes part in
+    pub audit_log: PathBuf,
+}
+
+fn config_error(path: &Path, msg: impl std::fmt::Display) -> LocksmithError {
+    LocksmithError::Config(format!("{}: {}", path.display(), msg))
+}
+
+fn read_toml<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T> {
+    let text: String = std::fs::read_to_string(path).map_err(|e| config_error(path, e))?;
+    toml::from_str(&text).map_err(|e| config_error(path, e))
+}
+
+fn read_cluster(path: &Path) -> Result<ClusterFile> {
+    read_toml(path)
+}
+
+fn write_cluster(path: &Path, cluster: &ClusterFile) -> Result<()> {
+    let text: String = toml::to_string(cluster).map_err(|e| LocksmithError::Config(e.to_string()))?;
+    std::fs::write(path, format!("{}{}", CLUSTER_HEADER, text))?;
+    Ok(())
+}
+
+// Relative paths in `file` are taken relative to the directory of `file`
+fn relative_to(file: &Path, path: PathBuf) -> PathBuf {
+    match file.parent() {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path,
+    }
+}
+
+impl PartialEntry {
+    fn apply(self, entry: &mut PartyEntry, file: Option<&Path>) {
+        let resolve = |path: PathBuf| file.map_or(path.clone(), |file| relative_to(file, path));
+        if let Some(address) = self.address {
+            entry.address = address;
+        }
+        if let Some(port) = self.port {
+            entry.port = port;
+        }
+        if let Some(certificate) = self.certificate {
+            entry.certificate = resolve(certificate);
+        }
+        if let Some(private_key) = self.private_key {
+            entry.private_key = resolve(private_key);
+        }
+    }
+
+    fn into_entry(self, path: &Path, party: usize) -> Result<PartyEntry> {
+        let missing = |field: &str| config_error(path, format!("[p{}] has no {}", party, field));
+        Ok(PartyEntry {
+            address: self.address.ok_or_else(|| missing("address"))?,
+            port: self.port.ok_or_else(|| missing("port"))?,
+            certificate: self.certificate.ok_or_else(|| missing("certificate"))?,
+            private_key: self.private_key.ok_or_else(|| missing("private_key"))?,
+            previous: None,
+        })
+    }
+}
+
+/// Loads, overrides from the environment and validates the config of one party
+pub fn load(path: &Path) -> Result<PartyConfig> {
+    let config: PartyConfig = resolve(path, |name| std::env::var(name).ok())?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Merges the overlay at `path` with its cluster file and the variables `env` returns
+pub fn resolve(path: &Path, env: impl Fn(&str) -> Option<String>) -> Result<PartyConfig> {
+    let file: PartyFile = read_toml(path)?;
+    let overrides: [Option<PartialEntry>; 3] = [file.p1, file.p2, file.p3];
+
+    let cluster: Option<PathBuf> = env("LOCKSMITH_CLUSTER").map(PathBuf::from)
+        .or_else(|| file.cluster.map(|cluster| relative_to(path, cluster)));
+    let mut network: toml::Table = toml::Table::new();
+    let (mut parties, ca, backend): ([PartyEntry; 3], Option<PathBuf>, Option<Backend>) = match &cluster {
+        Some(cluster_path) => {
+            let cluster: ClusterFile = read_cluster(cluster_path)?;
+            let mut parties: [PartyEntry; 3] = [cluster.p1, cluster.p2, cluster.p3];
+            for entry in parties.iter_mut() {
+                entry.certificate = relative_to(cluster_path, entry.certificate.clone());
+                entry.private_key = relative_to(cluster_path, entry.private_key.clone());
+                if let Some(previous) = entry.previous.as_mut() {
+                    previous.certificate = relative_to(cluster_path, previous.certificate.clone());
+                    previous.private_key = relative_to(cluster_path, previous.private_key.clone());
+                }
+            }
+            for (entry, overlay) in parties.iter_mut().zip(overrides) {
+                overlay.unwrap_or_default().apply(entry, Some(path));
+            }
+            network.extend(cluster.network.unwrap_or_default());
+            (parties, cluster.ca.map(|ca| relative_to(cluster_path, ca)), cluster.backend)
+        }
+        None => {
+            let [p1, p2, p3] = overrides;
+            ([
+                p1.unwrap_or_default().into_entry(path, 1)?,
+                p2.unwrap_or_default().into_entry(path, 2)?,
+                p3.unwrap_or_default().into_entry(path, 3)?,
+            ], None, None)
+        }
+    };
+    let ca: Option<PathBuf> = env("LOCKSMITH_CA").map(PathBuf::from).or(ca);
+    network.extend(file.network.unwrap_or_default());
+    let network: NetworkSettings = toml::Value::Table(network).try_into()
+        .map_err(|e| config_error(path, format!("[network]: {}", e)))?;
+
+    for (i, entry) in parties.iter_mut().enumerate() {
+        let var = |field: &str| env(&format!("LOCKSMITH_P{}_{}", i + 1, field));
+        if let Some(port) = var("PORT") {
+            entry.port = port.parse().map_err(|_| config_error(path, format!("LOCKSMITH_P{}_PORT is not a port: {}", i + 1, port)))?;
+        }
+        PartialEntry {
+            address: var("ADDRESS"),
+            port: None,
+            certificate: var("CERTIFICATE").map(PathBuf::from),
+            private_key: var("PRIVATE_KEY").map(PathBuf::from),
+        }.apply(entry, None);
+    }
+
+    let party_index: usize = match env("LOCKSMITH_PARTY_INDEX") {
+        Some(index) => index.parse().map_err(|_| config_error(path, format!("LOCKSMITH_PARTY_INDEX is not a number: {}", index)))?,
+        None => file.party_index.ok_or_else(|| config_error(path, "no party_index"))?,
+    };
+    let backend: Backend = match env("LOCKSMITH_BACKEND") {
+        Some(name) => Backend::from_str(&name, false).map_err(|_| config_error(path, format!("LOCKSMITH_BACKEND is not a backend: {}", name)))?,
+        None => file.backend.or(backend).unwrap_or_default(),
+    };
+    let policy: Option<PathBuf> = env("LOCKSMITH_POLICY").map(PathBuf::from)
+        .or_else(|| file.policy.map(|policy| relative_to(path, policy)));
+    let audit_log: PathBuf = env("LOCKSMITH_AUDIT_LOG").map(PathBuf::from)
+        .or_else(|| file.audit_log.map(|audit_log| relative_to(path, audit_log)))
+        .unwrap_or_else(|| Path::new("audit").join(format!("{}.log", path.file_stem().unwrap_or_default().to_string_lossy())));
+    Ok(PartyConfig { party_index, parties, ca, network, backend, policy, audit_log })
+}
+
+impl PartyConfig {
+    pub fn own(&self) -> &PartyEntry {
+        &self.parties[self.party_index - 1]
+    }
+
+    /// Socket address of every party, resolving host names
+    pub fn socket_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.parties.iter().enumerate()
+            .map(|(i, entry)| {
+                let mut addrs: Vec<SocketAddr> = (entry.address.as_str(), entry.port).to_socket_addrs()
+                    .map_err(|e| LocksmithError::Config(format!("party {}: cannot resolve '{}': {}", i + 1, entry.address, e)))?
+                    .collect();
+                // IPv4 first, as the party links are IPv4
+                addrs.sort_by_key(|addr| !addr.is_ipv4());
+                addrs.into_iter().next()
+                    .ok_or_else(|| LocksmithError::Config(format!("party {}: '{}' has no addresses", i + 1, entry.address)))
+            })
+            .collect()
+    }
+
+    /// Checks the party index, ports, host names, the certificates and this party's key
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=3).contains(&self.party_index) {
+            return Err(LocksmithError::Config(format!("party_index must be 1, 2 or 3, not {}", self.party_index)));
+        }
+        self.network.validate()?;
+        let addrs: Vec<SocketAddr> = self.socket_addrs()?;
+        for (i, addr) in addrs.iter().enumerate() {
+            if addr.port() == 0 {
+                return Err(LocksmithError::Config(format!("party {} has port 0", i + 1)));
+            }
+            if let Some(j) = addrs[..i].iter().position(|other| other == addr) {
+                return Err(LocksmithError::Config(format!("parties {} and {} both use {}", j + 1, i + 1, addr)));
+            }
+        }
+
+        let now: OffsetDateTime = OffsetDateTime::now_utc();
+        let ca: Option<Vec<u8>> = self.ca.as_deref().map(read_certificate).transpose().map_err(LocksmithError::Config)?;
+        for (i, entry) in self.parties.iter().enumerate() {
+            let party_error = |e: String| LocksmithError::Config(format!("party {}: {}", i + 1, e));
+            let mut certificates: Vec<&Path> = vec![&entry.certificate];
+            match (&entry.previous, &ca) {
+                (Some(previous), Some(_)) if previous.until > now => certificates.push(&previous.certificate),
+                (Some(_), None) => return Err(party_error("previous certificates need a ca".to_string())),
+                _ => {}
+            }
+            for certificate in certificates {
+                let der: Vec<u8> = read_certificate(certificate).map_err(party_error)?;
+                if let Some(ca) = &ca {
+                    certs::verify_issued_by(&der, ca, now).map_err(|e| party_error(format!("{}: {}", certificate.display(), e)))?;
+                }
+            }
+        }
+        if let Some(policy) = &self.policy {
+            policy::Policy::load(policy)?;
+        }
+        self.identity_at(now)
+            .map(|_| ())
+            .map_err(|e| LocksmithError::Config(format!("party {}: {}", self.party_index, e)))
+    }
+
+    /// SHA-256 fingerprint of party `party`'s (0-based) certificate in hex, its identity in
+    /// access policies
+    pub fn fingerprint(&self, party: usize) -> Result<String> {
+        let der: Vec<u8> = read_certificate(&self.parties[party].certificate)
+            .map_err(|e| LocksmithError::Config(format!("party {}: {}", party + 1, e)))?;
+        Ok(certificate_fingerprint(&der))
+    }
+
+    /// Fingerprints of the certificates party `party` (0-based) may present: its own and, while
+    /// the grace window of a rotation is open, its previous one
+    pub fn accepted_fingerprints(&self, party: usize) -> Result<Vec<String>> {
+        let entry: &PartyEntry = &self.parties[party];
+        let mut certificates: Vec<&Path> = vec![&entry.certificate];
+        if let Some(previous) = entry.previous.as_ref().filter(|previous| previous.until > OffsetDateTime::now_utc()) {
+            certificates.push(&previous.certificate);
+        }
+        certificates.into_iter()
+            .map(|certificate| read_certificate(certificate).map(|der| certificate_fingerprint(&der)))
+            .collect::<std::result::Result<Vec<String>, String>>()
+            .map_err(|e| LocksmithError::Config(format!("party {}: {}", party + 1, e)))
+    }
+
+    /// Fingerprint of the certificate this party presents to the others, which during a
+    /// rotation may be its previous one
+    pub fn presented_fingerprint(&self) -> Result<String> {
+        let party_error = |e: String| LocksmithError::Config(format!("party {}: {}", self.party_index, e));
+        let (certificate, _): (&Path, &Path) = self.identity_at(OffsetDateTime::now_utc()).map_err(party_error)?;
+        Ok(certificate_fingerprint(&read_certificate(certificate).map_err(party_error)?))
+    }
+
+    /// The certificate and key this party presents: its own pair or, while the grace window of a
+    /// rotation is open and the new key is not installed yet, the previous pair
+    fn identity_at(&self, now: OffsetDateTime) -> std::result::Result<(&Path, &Path), String> {
+        let own: &PartyEntry = self.own();
+        match &own.previous {
+            Some(previous) if !own.private_key.exists() => {
+                if previous.until <= now {
+                    return Err(format!(
+                        "{} is not installed and {} was only usable until {}",
+                        own.private_key.display(), previous.certificate.display(), timestamp(previous.until)
+                    ));
+                }
+                check_key_pair(&previous.certificate, &previous.private_key)?;
+                Ok((&previous.certificate, &previous.private_key))
+            }
+            _ => {
+                check_key_pair(&own.certificate, &own.private_key)?;
+                Ok((&own.certificate, &own.private_key))
+            }
+        }
+    }
+
+    /// Builds the maestro config. maestro only reads configs from files, so the resolved
+    /// config is written to a temporary file in the original layout.
+    ///
+    /// maestro checks each peer against the certificate configured for it, so with a CA the
+    /// CA certificate is configured for the peers, which accepts any certificate it issued;
+    /// `connect` then pins each peer to its own certificates, see `accepted_fingerprints`.
+    pub fn to_maestro(&self) -> Result<(usize, Config)> {
+        let addrs: Vec<SocketAddr> = self.socket_addrs()?;
+        let identity: (&Path, &Path) = self.identity_at(OffsetDateTime::now_utc()).map_err(LocksmithError::Config)?;
+        let mut text: String = format!("party_index = {}\n", self.party_index);
+        for (i, (entry, addr)) in self.parties.iter().zip(addrs).enumerate() {
+            let (certificate, private_key): (&Path, &Path) = match &self.ca {
+                _ if i + 1 == self.party_index => identity,
+                Some(ca) => (ca, &entry.private_key),
+                None => (&entry.certificate, &entry.private_key),
+            };
+            let resolved: PartyEntry = PartyEntry {
+                address: addr.ip().to_string(),
+                port: addr.port(),
+                certificate: absolute(certificate)?,
+                private_key: absolute(private_key)?,
+                previous: None,
+            };
+            let section: String = toml::to_string(&resolved).map_err(|e| LocksmithError::Config(e.to_string()))?;
+            text.push_str(&format!("\n[p{}]\n{}", i + 1, section));
+        }
+
+        let path: PathBuf = std::env::temp_dir().join(format!("locksmith-{}-p{}-{:x}.toml", std::process::id(), self.party_index, rand::random::<u32>()));
+        std::fs::write(&path, text)?;
+        let loaded: Result<(usize, Config)> = Config::from_file(&path)
+            .map_err(|e| LocksmithError::Config(format!("resolved config rejected: {}", e)));
+        let _ = std::fs::remove_file(&path);
+        loaded
+    }
+}
+
+/// SHA-256 fingerprint of a DER certificate in hex
+pub fn certificate_fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+// RFC 3339, as in the cluster file
+pub(crate) fn timestamp(time: OffsetDateTime) -> String {
+    time.format(&time::format_description::well_known::Rfc3339).unwrap_or_else(|_| time.to_string())
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    Ok(if path.is_absolute() { path.to_path_buf() } else { std::env::current_dir()?.join(path) })
+}
+
+fn read_certificate(path: &Path) -> std::result::Result<Vec<u8>, String> {
+    let pem: Vec<u8> = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let cert = rustls_pemfile::certs(&mut pem.as_slice()).next()
+        .ok_or_else(|| format!("{}: no certificate", path.display()))?
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(cert.to_vec())
+}
+
+// The certificate must contain the public key that belongs to the private key
+fn check_key_pair(certificate: &Path, private_key: &Path) -> std::result::Result<(), String> {
+    use ring::signature::{self, KeyPair};
+
+    let cert: Vec<u8> = read_certificate(certificate)?;
+    let pem: Vec<u8> = std::fs::read(private_key).map_err(|e| format!("{}: {}", private_key.display(), e))?;
+    let key = rustls_pemfile::private_key(&mut pem.as_slice())
+        .map_err(|e| format!("{}: {}", private_key.display(), e))?
+        .ok_or_else(|| format!("{}: no private key", private_key.display()))?;
+    let der: &[u8] = key.secret_der();
+
+    let rng: ring::rand::SystemRandom = ring::rand::SystemRandom::new();
+    let public_key: Vec<u8> = if let Ok(pair) = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+        pair.public_key().as_ref().to_vec()
+    } else if let Ok(pair) = signature::EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING, der, &rng) {
+        pair.public_key().as_ref().to_vec()
+    } else if let Ok(pair) = signature::EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P384_SHA384_ASN1_SIGNING, der, &rng) {
+        pair.public_key().as_ref().to_vec()
+    } else {
+        return Err(format!("{}: unsupported key type, expected Ed25519 or ECDSA P-256/P-384 in PKCS#8", private_key.display()));
+    };
+
+    if !cert.windows(public_key.len()).any(|window| window == public_key.as_slice()) {
+        return Err(format!("{} does not belong to {}", private_key.display(), certificate.display()));
+    }
+    Ok(())
+}
+
+/// Writes `cluster.toml`, the overlays `p1.toml`..`p3.toml` and the policy `policy.toml` they
+/// name into `dir`
+pub fn init(dir: &Path, hosts: &[String], base_port: u16, keys_dir: &Path, force: bool) -> Result<Vec<PathBuf>> {
+    // Fewer than three hosts: the last one is used for the remaining parties
+    let host = |i: usize| -> String { hosts.get(i).or(hosts.last()).cloned().unwrap_or_else(|| "127.0.0.1".to_string()) };
+    let port = |i: usize| -> Result<u16> {
+        base_port.checked_add(i as u16).ok_or_else(|| LocksmithError::InvalidInput(format!("base port {} leaves no room for three parties", base_port)))
+    };
+    let entry = |i: usize| -> Result<PartyEntry> {
+        Ok(PartyEntry {
+            address: host(i),
+            port: port(i)?,
+            certificate: keys_dir.join(format!("p{}.pem", i + 1)),
+            private_key: keys_dir.join(format!("p{}.key", i + 1)),
+            previous: None,
+        })
+    };
+    let network: toml::Table = toml::Table::try_from(NetworkSettings::default()).map_err(|e| LocksmithError::Config(e.to_string()))?;
+    let cluster: ClusterFile = ClusterFile { ca: None, backend: None, network: Some(network), p1: entry(0)?, p2: entry(1)?, p3: entry(2)? };
+
+    let mut files: Vec<(PathBuf, String)> = vec![(
+        dir.join(CLUSTER_FILE),
+        format!("{}{}", CLUSTER_HEADER,
+            toml::to_string(&cluster).map_err(|e| LocksmithError::Config(e.to_string()))?),
+    )];
+    for party in 1..=3 {
+        files.push((
+            dir.join(format!("p{}.toml", party)),
+            format!("party_index = {}\ncluster = \"{}\"\npolicy = \"{}\"\n", party, CLUSTER_FILE, policy::POLICY_FILE),
+        ));
+    }
+    files.push((dir.join(policy::POLICY_FILE), policy::ALLOW_ALL.to_string()));
+    if !force {
+        if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+            return Err(LocksmithError::InvalidInput(format!("{} exists, use --force to overwrite", path.display())));
+        }
+    }
+
+    std::fs::create_dir_all(dir)?;
+    for (path, text) in &files {
+        std::fs::write(path, text)?;
+    }
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
+
+/// Validates every file and checks that together they form one cluster
+pub fn check(files: &[PathBuf]) -> Result<Vec<PartyConfig>> {
+    let configs: Vec<PartyConfig> = files.iter().map(|path| load(path)).collect::<Result<_>>()?;
+    for (i, config) in configs.iter().enumerate() {
+        if let Some(j) = configs[..i].iter().position(|other| other.party_index == config.party_index) {
+            return Err(LocksmithError::Config(format!(
+                "{} and {} both have party_index {}", files[j].display(), files[i].display(), config.party_index
+            )));
+        }
+        if config.socket_addrs()? != configs[0].socket_addrs()? {
+            return Err(LocksmithError::Config(format!(
+                "{} and {} describe different clusters", files[0].display(), files[i].display()
+            )));
+        }
+        if config.backend != configs[0].backend {
+            return Err(LocksmithError::Config(format!(
+                "{} and {} choose different backends", files[0].display(), files[i].display()
+            )));
+        }
+        if config.network.channels != configs[0].network.channels {
+            return Err(LocksmithError::Config(format!(
+                "{} and {} set different numbers of channels", files[0].display(), files[i].display()
+            )));
+        }
+    }
+    Ok(configs)
+}
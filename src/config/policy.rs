@@ -0,0 +1,87 @@
+// Access policies: which clients may use which keys for which operations.
+//
+// Every party names its own policy file in its party file (`policy`, or LOCKSMITH_POLICY), so the
+// three policies are kept and evaluated independently. Requests come from the data owner, and a
+// client is identified by the SHA-256 fingerprint of the certificate it authenticates its party
+// links with (mutual TLS), as `config check` prints it, or by `*` for any client. A party
+// without a policy refuses every request, so allowing everything takes an explicit rule, as the
+// `policy.toml` that `config init` writes.
+//
+//     [[allow]]
+//     client = "5d41402abc4b2a76b9719d911017c592..."
+//     keys = ["main", "payroll-*"]
+//     operations = ["encrypt", "decrypt"]
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LocksmithError, Result};
+
+/// Policy file `config init` writes next to the party files
+pub const POLICY_FILE: &str = "policy.toml";
+
+// Matches every client in a rule
+const ANY_CLIENT: &str = "*";
+
+/// Written by `config init`: every client may use every key. Narrow it to the fingerprints
+/// `config check` prints.
+pub(crate) const ALLOW_ALL: &str = "\
+# Access policy, see `config check` for the client fingerprints. A party without a policy
+# refuses every request; this one allows every client to use every key.
+[[allow]]
+client = \"*\"
+keys = [\"*\"]
+operations = [\"encrypt\", \"decrypt\", \"reencrypt\", \"import\"]
+";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Encrypt,
+    Decrypt,
+    Reencrypt,
+    Import,
+}
+
+/// The rules of one party's policy file; a request is allowed if any rule allows it
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    allow: Vec<Rule>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// Certificate fingerprint in hex, or `*` for any client
+    client: String,
+    /// Key IDs; a trailing `*` matches any rest
+    keys: Vec<String>,
+    operations: Vec<Operation>,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text: String = std::fs::read_to_string(path)
+            .map_err(|e| LocksmithError::Config(format!("{}: {}", path.display(), e)))?;
+        let policy: Policy = toml::from_str(&text)
+            .map_err(|e| LocksmithError::Config(format!("{}: {}", path.display(), e)))?;
+        if let Some(rule) = policy.allow.iter().find(|rule| rule.client != ANY_CLIENT && !hex::decode(&rule.client).is_ok_and(|client| client.len() == 32)) {
+            return Err(LocksmithError::Config(format!("{}: '{}' is not a SHA-256 fingerprint", path.display(), rule.client)));
+        }
+        Ok(policy)
+    }
+
+    /// Whether the client with certificate fingerprint `client` may use `key_id` for `operation`
+    pub fn allows(&self, client: &str, key_id: &str, operation: Operation) -> bool {
+        self.allow.iter().any(|rule| {
+            (rule.client == ANY_CLIENT || rule.client.eq_ignore_ascii_case(client))
+                && rule.operations.contains(&operation)
+                && rule.keys.iter().any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => key_id.starts_with(prefix),
+                    None => key_id == pattern,
+                })
+        })
+    }
+}
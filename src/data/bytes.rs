@@ -0,0 +1,90 @@
+use std::fmt;
+
+use crate::error::LocksmithError;
+
+// Owned byte strings that should not be mixed up with each other or with plain Vec<u8>
+macro_rules! byte_string {
+    ($(#[$doc:meta])* $name:ident) => {
+        byte_string!(@bytes $(#[$doc])* $name);
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}(0x{})", stringify!($name), self.to_hex())
+            }
+        }
+    };
+    (@bytes $(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, PartialEq, Eq, Default)]
+        pub struct $name(Vec<u8>);
+
+        impl $name {
+            pub fn new(bytes: Vec<u8>) -> Self {
+                $name(bytes)
+            }
+
+            pub fn from_hex(hex: &str) -> Result<Self, LocksmithError> {
+                hex::decode(hex.trim_start_matches("0x"))
+                    .map($name)
+                    .map_err(|e| LocksmithError::Malformed(format!("{} is not valid hex: {}", stringify!($name), e)))
+            }
+
+            pub fn as_bytes(&self) -> &[u8] {
+                &self.0
+            }
+
+            pub fn into_bytes(self) -> Vec<u8> {
+                self.0
+            }
+
+            pub fn to_hex(&self) -> String {
+                hex::encode(&self.0)
+            }
+
+            pub fn len(&self) -> usize {
+                self.0.len()
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.0.is_empty()
+            }
+        }
+
+        impl From<Vec<u8>> for $name {
+            fn from(bytes: Vec<u8>) -> Self {
+                $name(bytes)
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+    };
+    // Secret contents: Debug shows only the length, so that logs and panic messages never hold them
+    ($(#[$doc:meta])* $name:ident, redacted) => {
+        byte_string!(@bytes $(#[$doc])* $name);
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({} bytes)", stringify!($name), self.len())
+            }
+        }
+    };
+}
+
+byte_string!(
+    /// Output of encryption
+    Ciphertext
+);
+
+byte_string!(
+    /// Output of decryption, with any padding removed
+    Plaintext, redacted
+);
+
+byte_string!(
+    /// Authentication tag of an AEAD mode
+    Tag
+);
@@ -1,6 +1,10 @@
+use super::Ciphertext;
+
 pub struct Input {
     input_string: Vec<u8>,
     num_blocks: usize,
+    // length before zero padding
+    data_len: usize,
 }
 
 impl Clone for Input {
@@ -8,6 +12,7 @@ impl Clone for Input {
         Input {
             input_string: self.input_string.clone(),
             num_blocks: self.num_blocks,
+            data_len: self.data_len,
         }
     }
 }
@@ -17,6 +22,17 @@ impl Input {
         Input {
             input_string: input_string.to_vec(),
             num_blocks,
+            data_len: input_string.len(),
+        }
+    }
+
+    /// Zero-pads `data` to whole blocks, remembering the original length
+    pub fn from_data(data: &[u8]) -> Self {
+        let (padded_string, num_blocks) = pad_input_string(data);
+        Input {
+            input_string: padded_string,
+            num_blocks,
+            data_len: data.len(),
         }
     }
 
@@ -28,6 +44,7 @@ impl Input {
         Input {
             input_string: vec![0u8; num_blocks * 16],
             num_blocks,
+            data_len: num_blocks * 16,
         }
     }
 
@@ -38,6 +55,7 @@ impl Input {
                 .flatten()
                 .collect(),
             num_blocks: blocks,
+            data_len: blocks * 16,
         }
     }
 
@@ -48,6 +66,10 @@ impl Input {
     pub fn num_blocks(&self) -> usize {
         self.num_blocks
     }
+
+    pub fn data_len(&self) -> usize {
+        self.data_len
+    }
 }
 
 impl std::fmt::Debug for Input {
@@ -55,13 +77,14 @@ impl std::fmt::Debug for Input {
         f.debug_struct("Input")
             .field("input_string", &String::from_utf8_lossy(&self.input_string))
             .field("num_blocks", &self.num_blocks)
+            .field("data_len", &self.data_len)
             .finish()
     }
 }
 
 pub struct DataLibrary {
     inputs: std::collections::HashMap<String, Input>,
-    ciphertexts: std::collections::HashMap<String, String>,
+    ciphertexts: std::collections::HashMap<String, Ciphertext>,
 }
 
 impl DataLibrary {
@@ -77,11 +100,11 @@ impl DataLibrary {
         self.inputs.get(name)
     }
 
-    pub fn store_ciphertext(&mut self, name: &str, ciphertext: &str) {
-        self.ciphertexts.insert(name.to_string(), ciphertext.to_string());
+    pub fn store_ciphertext(&mut self, name: &str, ciphertext: Ciphertext) {
+        self.ciphertexts.insert(name.to_string(), ciphertext);
     }
 
-    pub fn get_ciphertext(&self, name: &str) -> Option<&String> {
+    pub fn get_ciphertext(&self, name: &str) -> Option<&Ciphertext> {
         self.ciphertexts.get(name)
     }
 }
@@ -96,10 +119,14 @@ pub fn pad_input_string(input_string: &[u8]) -> (Vec<u8>, usize) {
     (input_string_padded, num_blocks)
 }
 
+pub fn load_input(filename: &std::path::Path) -> std::io::Result<Input> {
+    let input_string = std::fs::read(filename)?;
+    Ok(Input::from_data(&input_string))
+}
+
 pub fn write_library_single(library: &mut DataLibrary, name: &str, filename: &str) {
-    let input_string = std::fs::read(filename).expect("Failed to read file");
-    let (padded_string, num_blocks) = pad_input_string(&input_string);
-    library.create(name, Input::from_file(&padded_string, num_blocks));
+    let input: Input = load_input(std::path::Path::new(filename)).expect("Failed to read file");
+    library.create(name, input);
 }
 
 pub fn create_data_library() -> DataLibrary {
@@ -108,8 +135,8 @@ pub fn create_data_library() -> DataLibrary {
     write_library_single(&mut library, "short", "./src/data/sources/input-short.txt");
     write_library_single(&mut library, "long", "./src/data/sources/input-long.txt");
 
-    library.store_ciphertext("short-ecb", "da6340f38337f7f19f2c2a9bf151327e3165b40204a76a91f1f542a560713dc8945358493f31c3d45c967ad1e6404e58");
-    library.store_ciphertext("short-ctr", "c1d466ec6e520f8a8ce1000f6680df7838ba90b15327c2033be52b65cd1ac076964e0e632a4fc3dc5607450c9d4c34fb");
+    library.store_ciphertext("short-ecb", Ciphertext::from_hex("da6340f38337f7f19f2c2a9bf151327e3165b40204a76a91f1f542a560713dc8945358493f31c3d45c967ad1e6404e58").unwrap());
+    library.store_ciphertext("short-ctr", Ciphertext::from_hex("c1d466ec6e520f8a8ce1000f6680df7838ba90b15327c2033be52b65cd1ac076964e0e632a4fc3dc5607450c9d4c34fb").unwrap());
 
     library
 }
@@ -2,10 +2,13 @@ use lazy_static::lazy_static;
 use std::sync::{Mutex,MutexGuard};
 
 mod library;
+mod bytes;
 
 pub use library::Input;
 pub use library::DataLibrary;
 pub use library::create_data_library;
+pub use library::load_input;
+pub use bytes::{Ciphertext, Plaintext, Tag};
 
 lazy_static! {
     static ref LIBRARY: Mutex<DataLibrary> = Mutex::new(create_data_library());
@@ -16,12 +19,12 @@ pub fn get_input(name: &str) -> Input {
     library.get(name).unwrap().clone()
 }
 
-pub fn store_ciphertext(name: &str, ciphertext: &str) {
+pub fn store_ciphertext(name: &str, ciphertext: Ciphertext) {
     let mut library: MutexGuard<'_, DataLibrary> = LIBRARY.lock().unwrap();
     library.store_ciphertext(name, ciphertext);
 }
 
-pub fn get_ciphertext(name: &str) -> String {
+pub fn get_ciphertext(name: &str) -> Ciphertext {
     let library: MutexGuard<'_, DataLibrary> = LIBRARY.lock().unwrap();
     library.get_ciphertext(name).unwrap().clone()
 }
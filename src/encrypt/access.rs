@@ -0,0 +1,92 @@
+// Authorization of requests. Every party checks a request against its own access policy before
+// any key material is used, and the request only goes ahead if all three allow it, so a single
+// compromised party cannot authorize what the other parties' policies refuse. Every party then
+// records the request and its outcome in its audit log, see `audit`.
+//
+// The client is who the data owner authenticated as when the parties connected: each party takes
+// the fingerprint of the certificate the data owner presented in the TLS handshake, which
+// `connect` has checked against the data owner's slot and which during a rotation may be its
+// previous certificate rather than the one its config names.
+use maestro::rep3_core::network::{ConnectedParty, Tls};
+use maestro::rep3_core::party::MainParty;
+use sha2::{Digest, Sha256};
+
+use crate::config::policy::Policy;
+use crate::config::{self, PartyConfig};
+use crate::error::{LocksmithError, Result};
+use super::audit::{AuditLog, Outcome, Record};
+use super::nonce::CounterRange;
+use super::{control, peer_certificate, DATA_OWNER};
+
+pub use crate::config::policy::Operation;
+
+/// What this party checks requests against: its policy, which refuses everything if there is
+/// none, and the identity of the client, the data owner; and where it records them
+#[derive(Clone, Debug)]
+pub struct Access {
+    policy: Option<Policy>,
+    /// Fingerprint of the certificate the data owner presented
+    client: String,
+    audit: AuditLog,
+}
+
+impl Access {
+    /// The policy and audit log of `config`, for the client the data owner authenticated as on
+    /// `conn`
+    pub fn connected(config: &PartyConfig, conn: &ConnectedParty) -> Result<Self> {
+        Ok(Access {
+            policy: config.policy.as_deref().map(Policy::load).transpose()?,
+            client: client_fingerprint(config, conn)?,
+            audit: AuditLog::open(&config.audit_log),
+        })
+    }
+
+    /// Checks that all parties were asked to do the same, and that every party's policy allows it
+    pub(crate) fn authorize(&self, party: &mut MainParty, key_id: &str, operation: Operation) -> Result<()> {
+        let request: String = format!("{:?} {}", operation, key_id);
+        if !control::all_equal(party, &Sha256::digest(request.as_bytes()))? {
+            return Err(LocksmithError::Refused("the parties were asked to use different keys or operations".to_string()));
+        }
+        // Without a policy nothing is allowed
+        let allowed: bool = self.policy.as_ref().is_some_and(|policy| policy.allows(&self.client, key_id, operation));
+        if !control::agree(party, allowed)? {
+            return Err(LocksmithError::Refused(format!(
+                "{:?} with key '{}' is not allowed for client {} by every party's policy", operation, key_id, self.client
+            )));
+        }
+        Ok(())
+    }
+
+    /// Records an operation with `key_id` on `blocks` blocks, and how it ended, in the audit log
+    pub(crate) fn record<T>(
+        &self,
+        key_id: &str,
+        operation: Operation,
+        blocks: u64,
+        nonces: Option<CounterRange>,
+        result: &Result<T>,
+    ) -> Result<()> {
+        self.audit.append(Record {
+            key_id: key_id.to_string(),
+            operation,
+            blocks,
+            requester: self.client.clone(),
+            nonces: nonces.map(Into::into),
+            outcome: Outcome::of(result),
+        })?;
+        Ok(())
+    }
+}
+
+// Fingerprint of the certificate the data owner presented when `conn` was set up; the data owner
+// takes the one it presents itself
+fn client_fingerprint(config: &PartyConfig, conn: &ConnectedParty) -> Result<String> {
+    let link: &Tls = match (DATA_OWNER + 3 - (config.party_index - 1)) % 3 {
+        0 => return config.presented_fingerprint(),
+        1 => &conn.comm_next,
+        _ => &conn.comm_prev,
+    };
+    let certificate: Vec<u8> = peer_certificate(link)
+        .ok_or_else(|| LocksmithError::Network("the data owner presented no certificate".to_string()))?;
+    Ok(config::certificate_fingerprint(&certificate))
+}
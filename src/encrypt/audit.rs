@@ -0,0 +1,258 @@
+// Audit log. Every party appends an entry for each operation it takes part in: key ID,
+// operation, block count, requester, nonce range and outcome, one JSON object per line in the
+// file its party config names (`audit_log`). Each entry carries the hash of the one before it,
+// so an entry that is changed, removed or reordered breaks the chain from there on.
+//
+// The three parties log the same operations in the same order, so a party that rewrote its
+// whole log consistently is still caught by `compare` (`--mode audit`), which checks that the
+// three logs agree. Times and the reasons for failures are local and are not compared.
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use maestro::rep3_core::party::MainParty;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::config::PartyConfig;
+use crate::error::{LocksmithError, Result};
+use super::access::Operation;
+use super::backend::MpcParty;
+use super::nonce::CounterRange;
+use super::{control, setup_party};
+
+const GENESIS: [u8; 32] = [0u8; 32];
+
+/// How an operation ended
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    /// The parties did not agree to carry it out, e.g. by policy or because of the nonce ledger
+    Refused(String),
+    /// It failed or was aborted, e.g. by a failed MPC check or a lost connection
+    Failed(String),
+}
+
+impl Outcome {
+    pub fn of<T>(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => Outcome::Ok,
+            Err(LocksmithError::Refused(reason)) => Outcome::Refused(reason.clone()),
+            Err(e) => Outcome::Failed(e.to_string()),
+        }
+    }
+
+    // Only success is compared: a request one party refuses fails on the others with a
+    // `PeerAborted`, and the reasons are local anyway
+    fn code(&self) -> u8 {
+        match self {
+            Outcome::Ok => 0,
+            Outcome::Refused(_) | Outcome::Failed(_) => 1,
+        }
+    }
+}
+
+/// Counter blocks of a CTR operation, `first` in hex as in the nonce ledger
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Nonces {
+    pub first: String,
+    pub blocks: u64,
+}
+
+impl From<CounterRange> for Nonces {
+    fn from(range: CounterRange) -> Self {
+        Nonces { first: format!("{:032x}", range.first), blocks: range.blocks }
+    }
+}
+
+/// What a party records about one operation
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Record {
+    pub key_id: String,
+    pub operation: Operation,
+    /// Number of 16-byte blocks the operation covered, 0 for imports
+    pub blocks: u64,
+    /// Certificate fingerprint of the client that asked for it
+    pub requester: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonces: Option<Nonces>,
+    pub outcome: Outcome,
+}
+
+impl Record {
+    // Digest of what all three parties record alike
+    fn shared_digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for field in [self.key_id.as_bytes(), self.requester.as_bytes()] {
+            hasher.update((field.len() as u64).to_be_bytes());
+            hasher.update(field);
+        }
+        hasher.update([self.operation as u8, self.outcome.code()]);
+        hasher.update(self.blocks.to_be_bytes());
+        match &self.nonces {
+            Some(nonces) => {
+                hasher.update([1u8]);
+                hasher.update(nonces.first.to_ascii_lowercase().as_bytes());
+                hasher.update(nonces.blocks.to_be_bytes());
+            }
+            None => hasher.update([0u8]),
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// One line of the log
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Entry {
+    /// Position in the log, from 1
+    pub seq: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    #[serde(flatten)]
+    pub record: Record,
+    /// Hash of the previous entry in hex, zeros for the first
+    pub prev: String,
+    /// Hash of this entry in hex, over all other fields
+    pub hash: String,
+}
+
+impl Entry {
+    fn compute_hash(&self) -> Result<String> {
+        let unhashed: Entry = Entry { hash: String::new(), ..self.clone() };
+        let json: Vec<u8> = serde_json::to_vec(&unhashed).map_err(|e| LocksmithError::Malformed(format!("audit entry: {}", e)))?;
+        let mut hasher = Sha256::new();
+        hasher.update(b"locksmith-audit");
+        hasher.update(json);
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+/// One party's audit log file. Clones append to the same chain.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    // Sequence number and hash of the last entry, read from the file on the first append
+    head: Arc<Mutex<Option<(u64, String)>>>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Self {
+        AuditLog { path: path.to_path_buf(), head: Arc::new(Mutex::new(None)) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `record` as the next entry, written to disk before returning
+    pub fn append(&self, record: Record) -> Result<Entry> {
+        let mut head = self.head.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if head.is_none() {
+            *head = Some(match self.entries()?.pop() {
+                Some(last) => (last.seq, last.hash),
+                None => (0, hex::encode(GENESIS)),
+            });
+        }
+        let (seq, prev): (u64, String) = head.clone().unwrap_or_default();
+
+        let time: OffsetDateTime = OffsetDateTime::now_utc();
+        let mut entry: Entry = Entry { seq: seq + 1, time: time.replace_nanosecond(0).unwrap_or(time), record, prev, hash: String::new() };
+        entry.hash = entry.compute_hash()?;
+        let line: String = serde_json::to_string(&entry).map_err(|e| LocksmithError::Malformed(format!("audit entry: {}", e)))?;
+
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file: File = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        file.sync_all()?;
+        *head = Some((entry.seq, entry.hash.clone()));
+        Ok(entry)
+    }
+
+    /// Reads all entries, checking the hash chain; an empty log if the file does not exist yet
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let broken = |seq: usize, what: &str| LocksmithError::Malformed(format!("{}: entry {} {}", self.path.display(), seq, what));
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut prev: String = hex::encode(GENESIS);
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let line: String = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let seq: usize = entries.len() + 1;
+            let entry: Entry = serde_json::from_str(&line).map_err(|e| broken(seq, &format!("cannot be read: {}", e)))?;
+            if entry.seq != seq as u64 {
+                return Err(broken(seq, &format!("has sequence number {}", entry.seq)));
+            }
+            if entry.prev != prev {
+                return Err(broken(seq, "does not follow the entry before it"));
+            }
+            if entry.compute_hash()? != entry.hash {
+                return Err(broken(seq, "was modified"));
+            }
+            prev = entry.hash.clone();
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+/// Checks this party's audit log and compares it with the other two parties' logs.
+///
+/// Every party checks its own hash chain, then the parties search for the longest prefix on
+/// which all three logs agree, comparing digests only. Returns the number of entries if the
+/// logs agree, and is refused on all parties otherwise, naming the first entry that differs.
+pub fn compare(config: &Path) -> Result<u64> {
+    let party_config: PartyConfig = crate::config::load(config)?;
+    let log: AuditLog = AuditLog::open(&party_config.audit_log);
+    let mut mpc: Box<dyn MpcParty> = setup_party(config)?;
+    let party: &mut MainParty = mpc.main_party_mut();
+
+    let entries: Vec<Entry> = control::checkpoint(party, log.entries())?;
+    // prefixes[n] covers the first n entries
+    let mut prefixes: Vec<[u8; 32]> = vec![GENESIS];
+    for entry in &entries {
+        let mut hasher = Sha256::new();
+        hasher.update(prefixes[prefixes.len() - 1]);
+        hasher.update(entry.record.shared_digest());
+        prefixes.push(hasher.finalize().into());
+    }
+
+    let counts: [Vec<u8>; 3] = control::gather(party, &(entries.len() as u64).to_be_bytes())?;
+    if !control::all_equal(party, &counts.concat())? {
+        return Err(LocksmithError::Refused("the parties reported their entry counts inconsistently".to_string()));
+    }
+    let counts: Vec<u64> = counts.iter().map(|count| u64::from_be_bytes(count.as_slice().try_into().unwrap_or_default())).collect();
+    let common: u64 = counts.iter().copied().min().unwrap_or(0);
+
+    // Longest prefix all three agree on; every step is agreed so that all parties search alike
+    let (mut agreed, mut differs): (u64, u64) = (0, common + 1);
+    while differs - agreed > 1 {
+        let mid: u64 = agreed + (differs - agreed) / 2;
+        let equal: bool = control::all_equal(party, &prefixes[mid as usize])?;
+        match control::agree(party, equal)? {
+            true => agreed = mid,
+            false => differs = mid,
+        }
+    }
+
+    let consistent: bool = agreed == common && counts.iter().all(|&count| count == common);
+    party.teardown()?;
+    if consistent {
+        return Ok(common);
+    }
+    let here: String = entries.get(agreed as usize).map_or("none here".to_string(), |entry| {
+        format!("{:?} with key '{}' at {} here", entry.record.operation, entry.record.key_id, crate::config::timestamp(entry.time))
+    });
+    Err(LocksmithError::Refused(format!(
+        "the audit logs agree on their first {} entries only (entry {}: {}); the parties hold {}, {} and {} entries",
+        agreed, agreed + 1, here, counts[0], counts[1], counts[2],
+    )))
+}
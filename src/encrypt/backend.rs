@@ -0,0 +1,235 @@
+// MPC backends: the protocol that evaluates the AES S-boxes. Everything else (input sharing, the
+// linear layers, the key schedules, checks and output) runs on a `dyn MpcParty`, the same way for
+// every backend.
+//
+// `lut256-ss-mal`, the default, and `lut256-ss` are maestro's LUT-256 protocols on (3,3) shares,
+// the first secure with abort against one malicious party, the second semi-honest. `lut256` and
+// `gf4-circuit` invert on replicated shares through `aes::GF8InvBlackBox`, with a LUT-256 protocol
+// or the GF(2^4) tower field circuit, both semi-honest. Semi-honest backends are faster, but a
+// party that deviates from the protocol goes unnoticed: their `finalize` checks nothing.
+//
+// The backend is set in the config (`backend`, see `config`) and must be the same on all three
+// parties; they compare it as soon as the protocol is set up.
+use clap::ValueEnum;
+use maestro::aes::ss::{GF8InvBlackBoxSS, GF8InvBlackBoxSSMal};
+use maestro::gf4_circuit::GF4CircuitSemihonestParty;
+use maestro::lut256::lut256_ss::{Lut256SSMalParty, Lut256SSParty};
+use maestro::lut256::LUT256Party;
+use maestro::rep3_core::network::ConnectedParty;
+use maestro::rep3_core::party::{error::MpcResult, MainParty};
+use maestro::rep3_core::share::RssShare;
+use maestro::share::gf8::GF8;
+use maestro::util::ArithmeticBlackBox;
+use serde::{Deserialize, Serialize};
+
+use crate::aes::{self, ss::{inv_sbox_layer_ss, sbox_layer_ss}, AesVariant};
+use crate::error::{LocksmithError, Result};
+use super::control;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// LUT-256 S-boxes on (3,3) shares, secure with abort against a malicious party
+    #[default]
+    Lut256SsMal,
+    /// LUT-256 S-boxes on (3,3) shares, semi-honest
+    Lut256Ss,
+    /// LUT-256 S-boxes on replicated shares, semi-honest
+    Lut256,
+    /// GF(2^8) inversion through the GF(2^4) tower field, semi-honest
+    Gf4Circuit,
+}
+
+impl Backend {
+    /// Whether `finalize` catches a party that deviates from the protocol
+    pub fn is_malicious_secure(self) -> bool {
+        self == Backend::Lut256SsMal
+    }
+
+    /// Whether `do_preprocessing` prepares anything
+    pub fn has_preprocessing(self) -> bool {
+        matches!(self, Backend::Lut256SsMal | Backend::Lut256Ss)
+    }
+
+    /// Name as in config files and on the command line
+    pub fn name(self) -> String {
+        self.to_possible_value().map_or(String::new(), |value| value.get_name().to_string())
+    }
+}
+
+/// One party of the MPC protocol, whatever the backend
+pub trait MpcParty {
+    fn backend(&self) -> Backend;
+
+    /// Control channel to the other parties, see `control`
+    fn main_party_mut(&mut self) -> &mut MainParty;
+
+    /// Replicated shares of a public value
+    fn constant_rss(&self, value: GF8) -> RssShare<GF8>;
+
+    /// Prepares `n_keys` AES-128 key schedules and `n_blocks` AES-128 blocks, see
+    /// `preprocessing_units`; a no-op for backends without preprocessing
+    fn do_preprocessing(&mut self, n_keys: usize, n_blocks: usize) -> MpcResult<()>;
+
+    /// Applies the AES S-box to every shared byte
+    fn sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()>;
+
+    /// Applies the inverse AES S-box to every shared byte
+    fn inv_sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()>;
+
+    /// Checks everything computed since the last check
+    fn finalize(&mut self) -> MpcResult<()>;
+
+    /// Opens shares to all three parties
+    fn output(&mut self, si: &[GF8], sii: &[GF8]) -> Result<Vec<GF8>>;
+}
+
+impl MpcParty for Lut256SSMalParty {
+    fn backend(&self) -> Backend {
+        Backend::Lut256SsMal
+    }
+
+    fn main_party_mut(&mut self) -> &mut MainParty {
+        GF8InvBlackBoxSS::main_party_mut(self)
+    }
+
+    fn constant_rss(&self, value: GF8) -> RssShare<GF8> {
+        GF8InvBlackBoxSS::constant_rss(self, value)
+    }
+
+    fn do_preprocessing(&mut self, n_keys: usize, n_blocks: usize) -> MpcResult<()> {
+        GF8InvBlackBoxSS::do_preprocessing(self, n_keys, n_blocks)
+    }
+
+    fn sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+        sbox_layer_ss(self, si, sii)
+    }
+
+    fn inv_sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+        inv_sbox_layer_ss(self, si, sii)
+    }
+
+    fn finalize(&mut self) -> MpcResult<()> {
+        GF8InvBlackBoxSSMal::finalize(self)
+    }
+
+    fn output(&mut self, si: &[GF8], sii: &[GF8]) -> Result<Vec<GF8>> {
+        Ok(GF8InvBlackBoxSSMal::output(self, si, sii)?)
+    }
+}
+
+impl MpcParty for Lut256SSParty {
+    fn backend(&self) -> Backend {
+        Backend::Lut256Ss
+    }
+
+    fn main_party_mut(&mut self) -> &mut MainParty {
+        GF8InvBlackBoxSS::main_party_mut(self)
+    }
+
+    fn constant_rss(&self, value: GF8) -> RssShare<GF8> {
+        GF8InvBlackBoxSS::constant_rss(self, value)
+    }
+
+    fn do_preprocessing(&mut self, n_keys: usize, n_blocks: usize) -> MpcResult<()> {
+        GF8InvBlackBoxSS::do_preprocessing(self, n_keys, n_blocks)
+    }
+
+    fn sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+        sbox_layer_ss(self, si, sii)
+    }
+
+    fn inv_sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+        inv_sbox_layer_ss(self, si, sii)
+    }
+
+    fn finalize(&mut self) -> MpcResult<()> {
+        Ok(())
+    }
+
+    fn output(&mut self, si: &[GF8], sii: &[GF8]) -> Result<Vec<GF8>> {
+        // Party i lacks x_i+2, the second share of party i+1
+        let party: &mut MainParty = GF8InvBlackBoxSS::main_party_mut(self);
+        let i: usize = party.i;
+        control::send_bytes(party, (i + 2) % 3, &sii.iter().map(|x| x.0).collect::<Vec<u8>>());
+        let missing: Vec<u8> = control::receive_bytes(party, (i + 1) % 3, si.len())?;
+        Ok(si.iter().zip(sii.iter()).zip(missing.iter()).map(|((si, sii), missing)| GF8(si.0 ^ sii.0 ^ missing)).collect())
+    }
+}
+
+/// A maestro protocol that inverts on replicated shares, as the S-box black box of `aes`
+pub struct RssParty<P> {
+    backend: Backend,
+    party: P,
+}
+
+impl<P: maestro::aes::GF8InvBlackBox> aes::GF8InvBlackBox for RssParty<P> {
+    fn constant(&self, value: GF8) -> RssShare<GF8> {
+        self.party.constant(value)
+    }
+
+    fn gf8_inv(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+        self.party.gf8_inv(si, sii)
+    }
+
+    fn do_preprocessing(&mut self, n_keys: usize, n_blocks: usize, variant: AesVariant) -> MpcResult<()> {
+        let variant: maestro::aes::AesVariant = match variant {
+            AesVariant::Aes128 => maestro::aes::AesVariant::Aes128,
+            AesVariant::Aes256 => maestro::aes::AesVariant::Aes256,
+        };
+        self.party.do_preprocessing(n_keys, n_blocks, variant)
+    }
+
+    fn main_party_mut(&mut self) -> &mut MainParty {
+        self.party.main_party_mut()
+    }
+}
+
+impl<P: maestro::aes::GF8InvBlackBox + ArithmeticBlackBox<GF8>> MpcParty for RssParty<P> {
+    fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    fn main_party_mut(&mut self) -> &mut MainParty {
+        self.party.main_party_mut()
+    }
+
+    fn constant_rss(&self, value: GF8) -> RssShare<GF8> {
+        aes::GF8InvBlackBox::constant(self, value)
+    }
+
+    fn do_preprocessing(&mut self, n_keys: usize, n_blocks: usize) -> MpcResult<()> {
+        aes::GF8InvBlackBox::do_preprocessing(self, n_keys, n_blocks, AesVariant::Aes128)
+    }
+
+    fn sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+        aes::sbox_layer(self, si, sii)
+    }
+
+    fn inv_sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+        aes::inv_sbox_layer(self, si, sii)
+    }
+
+    fn finalize(&mut self) -> MpcResult<()> {
+        ArithmeticBlackBox::finalize(&mut self.party)
+    }
+
+    fn output(&mut self, si: &[GF8], sii: &[GF8]) -> Result<Vec<GF8>> {
+        Ok(ArithmeticBlackBox::output_round(&mut self.party, si, sii)?)
+    }
+}
+
+/// Sets up `backend` on a connection, and refuses unless the other parties chose the same one
+pub fn setup(conn: ConnectedParty, backend: Backend) -> Result<Box<dyn MpcParty>> {
+    let mut party: Box<dyn MpcParty> = match backend {
+        Backend::Lut256SsMal => Box::new(Lut256SSMalParty::setup(conn, true, Some(0), Some("".to_string()))?),
+        Backend::Lut256Ss => Box::new(Lut256SSParty::setup(conn, Some(0), Some("".to_string()))?),
+        Backend::Lut256 => Box::new(RssParty { backend, party: LUT256Party::setup(conn, Some(0), Some("".to_string()))? }),
+        Backend::Gf4Circuit => Box::new(RssParty { backend, party: GF4CircuitSemihonestParty::setup(conn, Some(0), Some("".to_string()))? }),
+    };
+    if !control::all_equal(party.main_party_mut(), &[backend as u8])? {
+        return Err(LocksmithError::Refused(format!("the parties run different backends, this one {}", backend.name())));
+    }
+    tracing::debug!(backend = %backend.name(), "protocol set up");
+    Ok(party)
+}
@@ -0,0 +1,396 @@
+// Backups of one party's key store, for disaster recovery.
+//
+// `export` writes every stored key version of a party into one backup file, encrypted with
+// AES-256-GCM under a fresh data key. The data key is either encrypted to an offline recovery
+// public key (X25519 with an ephemeral key, then HKDF-SHA256) or split into Shamir shares over
+// GF(2^8) for offline custodians, `threshold` of whom are needed to open the backup.
+//
+// `restore` checks the opened keys with the other two parties before writing them: the parties
+// must hold the same key versions at the same epochs, and the restored shares must pass the copy
+// checks of `store_key`. A backup taken before a refresh or a rotation no longer fits and is refused.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::Subcommand;
+use maestro::rep3_core::party::MainParty;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+use crate::error::{LocksmithError, Result};
+use super::backend::MpcParty;
+use super::keystore::{self, KeyFile, KeyStore, StoredKey};
+use super::{control, setup_party};
+
+/// Version of the backup file format
+pub const BACKUP_FORMAT: u32 = 1;
+
+#[derive(Subcommand)]
+pub enum BackupCommand {
+    /// Create a recovery key pair: recovery.key is kept offline, recovery.pub is given to `export`
+    Keygen {
+        /// Directory to write recovery.key and recovery.pub into
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+
+        /// Overwrite existing files
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write an encrypted backup of one party's key store
+    Export {
+        /// The party's config file, which names its index
+        #[arg(long, value_name = "FILE")]
+        config: PathBuf,
+
+        /// The party's key store [default: keystore/<config file stem>]
+        #[arg(long, value_name = "DIR")]
+        keystore: Option<PathBuf>,
+
+        /// Backup file to write
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+
+        /// Recovery public key to encrypt the backup to, see `backup keygen`
+        #[arg(long, value_name = "FILE", conflicts_with = "custodians")]
+        recovery_public_key: Option<PathBuf>,
+
+        /// Split the backup's key across this many custodians, written to <out>.custodian<n>
+        #[arg(long, requires = "threshold")]
+        custodians: Option<u8>,
+
+        /// Custodians needed to open the backup
+        #[arg(long, requires = "custodians")]
+        threshold: Option<u8>,
+    },
+}
+
+/// How the data key of a backup is protected
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protection {
+    /// Encrypted to this X25519 recovery public key
+    Recovery([u8; 32]),
+    /// Split into `count` Shamir shares, any `threshold` of which restore it
+    Custodians { threshold: u8, count: u8 },
+}
+
+/// What opens a backup
+pub enum Unlock {
+    /// The X25519 recovery private key
+    Recovery([u8; 32]),
+    Custodians(Vec<CustodianShare>),
+}
+
+/// A party's key store, encrypted
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Backup {
+    pub format: u32,
+    /// 1-based, as in the config files
+    pub party_index: usize,
+    key: WrappedKey,
+    nonce: String,
+    payload: String,
+}
+
+// The backup's data key, as far as it is in the backup file
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum WrappedKey {
+    Recovery { ephemeral: String, nonce: String, wrapped: String },
+    Custodians { threshold: u8, count: u8 },
+}
+
+/// One custodian's share of a backup's data key
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CustodianShare {
+    /// Hash of the encrypted payload, so that shares of different backups are not mixed up
+    pub backup: String,
+    pub threshold: u8,
+    pub x: u8,
+    pub y: String,
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T> {
+    serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| LocksmithError::Malformed(format!("{}: {}", path.display(), e)))
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let text: String = serde_json::to_string_pretty(value)
+        .map_err(|e| LocksmithError::Malformed(format!("{}: {}", path.display(), e)))?;
+    fs::write(path, text)?;
+    Ok(())
+}
+
+fn decode(field: &str, text: &str) -> Result<Vec<u8>> {
+    hex::decode(text).map_err(|e| LocksmithError::Malformed(format!("backup {}: {}", field, e)))
+}
+
+fn decode_key(field: &str, text: &str) -> Result<[u8; 32]> {
+    decode(field, text)?.try_into().map_err(|_| LocksmithError::Malformed(format!("backup {}: not 32 bytes", field)))
+}
+
+impl CustodianShare {
+    pub fn load(path: &Path) -> Result<Self> {
+        read_json(path)
+    }
+}
+
+/// Writes a new recovery key pair to `dir` as `recovery.key` and `recovery.pub`, in hex.
+/// Returns the written files.
+pub fn keygen(dir: &Path, force: bool) -> Result<Vec<PathBuf>> {
+    let secret: [u8; 32] = rand::random();
+    let public: PublicKey = PublicKey::from(&StaticSecret::from(secret));
+    let files: Vec<(PathBuf, String)> = vec![
+        (dir.join("recovery.key"), hex::encode(secret)),
+        (dir.join("recovery.pub"), hex::encode(public.as_bytes())),
+    ];
+    if !force {
+        if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+            return Err(LocksmithError::InvalidInput(format!("{} exists, use --force to overwrite", path.display())));
+        }
+    }
+
+    fs::create_dir_all(dir)?;
+    for (path, text) in &files {
+        fs::write(path, format!("{}\n", text))?;
+    }
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
+
+/// Reads a recovery key written by `keygen`
+pub fn read_key(path: &Path) -> Result<[u8; 32]> {
+    hex::decode(fs::read_to_string(path)?.trim()).ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| LocksmithError::Malformed(format!("{}: not a 32-byte key in hex", path.display())))
+}
+
+/// Writes a backup of every key in `store` of party `party_index` (1-based) to `out` and, with
+/// custodians, one share file `<out>.custodian<n>` per custodian. Returns the written files.
+pub fn export(store: &KeyStore, party_index: usize, protection: Protection, out: &Path) -> Result<Vec<PathBuf>> {
+    let keys: Vec<KeyFile> = store.load_all()?.iter().map(KeyFile::from_stored).collect::<Result<_>>()?;
+    if keys.is_empty() {
+        return Err(LocksmithError::InvalidInput("there are no stored keys to back up".to_string()));
+    }
+    let mut payload: Vec<u8> = serde_json::to_vec(&keys).map_err(|e| LocksmithError::Malformed(format!("backup: {}", e)))?;
+
+    let data_key: [u8; 32] = rand::random();
+    let nonce: [u8; NONCE_LEN] = seal(&data_key, &payload_aad(party_index), &mut payload)?;
+    let (key, shares): (WrappedKey, Vec<CustodianShare>) = match protection {
+        Protection::Recovery(recipient) => {
+            let ephemeral: StaticSecret = StaticSecret::from(rand::random::<[u8; 32]>());
+            let ephemeral_public: PublicKey = PublicKey::from(&ephemeral);
+            let kek: [u8; 32] = recovery_kek(ephemeral.diffie_hellman(&PublicKey::from(recipient)), ephemeral_public.as_bytes(), &recipient)?;
+            let mut wrapped: Vec<u8> = data_key.to_vec();
+            let wrap_nonce: [u8; NONCE_LEN] = seal(&kek, b"locksmith-backup-key", &mut wrapped)?;
+            let key: WrappedKey = WrappedKey::Recovery {
+                ephemeral: hex::encode(ephemeral_public.as_bytes()),
+                nonce: hex::encode(wrap_nonce),
+                wrapped: hex::encode(wrapped),
+            };
+            (key, Vec::new())
+        }
+        Protection::Custodians { threshold, count } => {
+            if threshold < 2 || threshold > count {
+                return Err(LocksmithError::InvalidInput(format!(
+                    "the threshold must be between 2 and the number of custodians ({}), not {}", count, threshold
+                )));
+            }
+            let backup: String = hex::encode(Sha256::digest(&payload));
+            let shares: Vec<CustodianShare> = shamir_split(&data_key, threshold, count).into_iter()
+                .map(|(x, y)| CustodianShare { backup: backup.clone(), threshold, x, y: hex::encode(y) })
+                .collect();
+            (WrappedKey::Custodians { threshold, count }, shares)
+        }
+    };
+    let backup: Backup = Backup {
+        format: BACKUP_FORMAT,
+        party_index,
+        key,
+        nonce: hex::encode(nonce),
+        payload: hex::encode(payload),
+    };
+
+    write_json(out, &backup)?;
+    let mut written: Vec<PathBuf> = vec![out.to_path_buf()];
+    for share in shares {
+        let path: PathBuf = PathBuf::from(format!("{}.custodian{}", out.display(), share.x));
+        write_json(&path, &share)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+impl Backup {
+    pub fn load(path: &Path) -> Result<Self> {
+        read_json(path)
+    }
+
+    /// Decrypts the stored keys
+    pub fn open(&self, unlock: &Unlock) -> Result<Vec<StoredKey>> {
+        if self.format != BACKUP_FORMAT {
+            return Err(LocksmithError::Malformed(format!("unsupported backup format {}", self.format)));
+        }
+        let mut payload: Vec<u8> = decode("payload", &self.payload)?;
+        let data_key: [u8; 32] = match (&self.key, unlock) {
+            (WrappedKey::Recovery { ephemeral, nonce, wrapped }, Unlock::Recovery(secret)) => {
+                let secret: StaticSecret = StaticSecret::from(*secret);
+                let ephemeral: [u8; 32] = decode_key("ephemeral key", ephemeral)?;
+                let kek: [u8; 32] = recovery_kek(secret.diffie_hellman(&PublicKey::from(ephemeral)), &ephemeral, PublicKey::from(&secret).as_bytes())?;
+                let mut wrapped: Vec<u8> = decode("wrapped key", wrapped)?;
+                unseal(&kek, b"locksmith-backup-key", &decode("key nonce", nonce)?, &mut wrapped)
+                    .map_err(|_| LocksmithError::Refused("the recovery key does not open this backup".to_string()))?
+                    .try_into()
+                    .map_err(|_| LocksmithError::Malformed("backup wrapped key: not 32 bytes".to_string()))?
+            }
+            (WrappedKey::Custodians { threshold, .. }, Unlock::Custodians(shares)) => {
+                let backup: String = hex::encode(Sha256::digest(&payload));
+                if let Some(share) = shares.iter().find(|share| share.backup != backup || share.threshold != *threshold) {
+                    return Err(LocksmithError::InvalidInput(format!("custodian share {} belongs to another backup", share.x)));
+                }
+                let mut points: Vec<(u8, Vec<u8>)> = Vec::new();
+                for share in shares {
+                    if share.x == 0 || points.iter().any(|(x, _)| *x == share.x) {
+                        return Err(LocksmithError::InvalidInput(format!("custodian share {} is invalid or given twice", share.x)));
+                    }
+                    points.push((share.x, decode("custodian share", &share.y)?));
+                }
+                if points.len() < *threshold as usize || points.iter().any(|(_, y)| y.len() != 32) {
+                    return Err(LocksmithError::InvalidInput(format!("{} custodian shares are needed, got {}", threshold, points.len())));
+                }
+                shamir_combine(&points[..*threshold as usize]).try_into()
+                    .map_err(|_| LocksmithError::Malformed("custodian shares".to_string()))?
+            }
+            (WrappedKey::Recovery { .. }, Unlock::Custodians(_)) => {
+                return Err(LocksmithError::InvalidInput("the backup is encrypted to a recovery key".to_string()));
+            }
+            (WrappedKey::Custodians { .. }, Unlock::Recovery(_)) => {
+                return Err(LocksmithError::InvalidInput("the backup is split across custodians".to_string()));
+            }
+        };
+
+        let payload: &[u8] = unseal(&data_key, &payload_aad(self.party_index), &decode("nonce", &self.nonce)?, &mut payload)
+            .map_err(|_| LocksmithError::Refused("the backup cannot be opened with these custodian shares, or is damaged".to_string()))?;
+        let keys: Vec<KeyFile> = serde_json::from_slice(payload).map_err(|e| LocksmithError::Malformed(format!("backup payload: {}", e)))?;
+        keys.into_iter().map(|key| key.into_stored("backup")).collect()
+    }
+}
+
+/// Restores this party's key store from `restored`, the keys of an opened backup, after checking
+/// them with the other two parties, which pass `None`. Exactly one party restores at a time.
+/// Returns the checked keys; only the restoring party writes them.
+pub fn restore(config: &Path, store: &KeyStore, restored: Option<Vec<StoredKey>>) -> Result<Vec<StoredKey>> {
+    let mut mpc: Box<dyn MpcParty> = setup_party(config)?;
+    let party: &mut MainParty = mpc.main_party_mut();
+
+    let restoring: bool = restored.is_some();
+    let flags: Vec<u8> = control::gather(party, &[restoring as u8])?.concat();
+    if !control::all_equal(party, &flags)? || flags.iter().filter(|&&flag| flag == 1).count() != 1 {
+        return Err(LocksmithError::Refused("exactly one party must restore a backup".to_string()));
+    }
+
+    let keys: Result<Vec<StoredKey>> = restored.map_or_else(|| store.load_all(), Ok);
+    let keys: Vec<StoredKey> = control::checkpoint(party, keys)?;
+    if !control::all_equal(party, &keystore::listing_digest(&keys))? {
+        return Err(LocksmithError::Refused("the backup does not hold the key versions and epochs the other parties hold".to_string()));
+    }
+
+    let mut checked: Vec<StoredKey> = Vec::new();
+    for key in keys {
+        let (si, sii): (&[u8], &[u8]) = key.share.replicated_bytes()
+            .ok_or_else(|| LocksmithError::InvalidInput("only stored keys can be restored".to_string()))?;
+        let missing: [u8; 32] = keystore::checked_copies(party, si, sii)?;
+        checked.push(StoredKey { missing: Some(missing), ..key });
+    }
+    if restoring {
+        for key in checked.iter() {
+            store.save(key)?;
+        }
+    }
+    party.teardown()?;
+    Ok(checked)
+}
+
+// Binds the payload to the party it belongs to
+fn payload_aad(party_index: usize) -> Vec<u8> {
+    format!("locksmith-backup-{}-party-{}", BACKUP_FORMAT, party_index).into_bytes()
+}
+
+// Encrypts `data` in place, appending the tag; returns the random nonce
+fn seal(key: &[u8; 32], aad: &[u8], data: &mut Vec<u8>) -> Result<[u8; NONCE_LEN]> {
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let key: LessSafeKey = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key)
+        .map_err(|_| LocksmithError::InvalidInput("backup key".to_string()))?);
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), data)
+        .map_err(|_| LocksmithError::InvalidInput("backup too large".to_string()))?;
+    Ok(nonce)
+}
+
+// Decrypts and authenticates `data` in place; returns the plaintext part
+fn unseal<'a>(key: &[u8; 32], aad: &[u8], nonce: &[u8], data: &'a mut [u8]) -> std::result::Result<&'a mut [u8], ring::error::Unspecified> {
+    let key: LessSafeKey = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key)?);
+    key.open_in_place(Nonce::try_assume_unique_for_key(nonce)?, Aad::from(aad), data)
+}
+
+// Key that wraps the data key, from the X25519 secret shared by the ephemeral and recovery keys
+fn recovery_kek(shared: SharedSecret, ephemeral: &[u8; 32], recipient: &[u8; 32]) -> Result<[u8; 32]> {
+    if !shared.was_contributory() {
+        return Err(LocksmithError::InvalidInput("invalid recovery public key".to_string()));
+    }
+    let salt: Vec<u8> = [ephemeral.as_slice(), recipient.as_slice()].concat();
+    let mut kek: [u8; 32] = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &salt)
+        .extract(shared.as_bytes())
+        .expand(&[b"locksmith-backup-kek"], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut kek))
+        .map_err(|_| LocksmithError::InvalidInput("backup key derivation".to_string()))?;
+    Ok(kek)
+}
+
+// Shamir sharing of every byte of `secret` over GF(2^8): custodian x gets p(x) for a random
+// polynomial p of degree threshold - 1 with p(0) = the byte
+fn shamir_split(secret: &[u8], threshold: u8, count: u8) -> Vec<(u8, Vec<u8>)> {
+    let polynomials: Vec<Vec<u8>> = secret.iter()
+        .map(|&byte| std::iter::once(byte).chain((1..threshold).map(|_| rand::random::<u8>())).collect())
+        .collect();
+    (1..=count)
+        .map(|x| (x, polynomials.iter().map(|p| p.iter().rev().fold(0, |acc, &c| gf_mul(acc, x) ^ c)).collect()))
+        .collect()
+}
+
+// Lagrange interpolation at 0; in GF(2^8) subtraction is XOR
+fn shamir_combine(points: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let basis: Vec<u8> = points.iter().enumerate()
+        .map(|(j, (xj, _))| points.iter().enumerate()
+            .filter(|(m, _)| *m != j)
+            .fold(1, |acc, (_, (xm, _))| gf_mul(acc, gf_mul(*xm, gf_inv(xm ^ xj)))))
+        .collect();
+    (0..points[0].1.len())
+        .map(|b| points.iter().zip(basis.iter()).fold(0, |acc, ((_, y), l)| acc ^ gf_mul(y[b], *l)))
+        .collect()
+}
+
+// Multiplication modulo the AES polynomial x^8 + x^4 + x^3 + x + 1
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product: u8 = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+// a^254 = a^-1 for a != 0
+fn gf_inv(a: u8) -> u8 {
+    (0..7).fold((a, a), |(power, _), _| {
+        let squared: u8 = gf_mul(power, power);
+        (gf_mul(squared, a), squared)
+    }).1
+}
@@ -0,0 +1,223 @@
+// Benchmarks (`--mode bench`), to size clusters and choose a backend. The three parties run the
+// same cases against each other: ECB and CTR encryption of a number of blocks, key schedules
+// alone and preprocessing alone, on every backend asked for (see `backend`). Every case runs its
+// warmup repetitions, which are not measured, then the measured ones.
+//
+// Keys and ECB inputs are random shares (`aes::random_keyschedule`, `aes::random_state`), so no
+// key store is needed and only the protocols are measured. Each party reports its own times and
+// traffic.
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use maestro::aes::VectorAesState;
+use maestro::rep3_core::network::ConnectedParty;
+use maestro::rep3_core::party::Party;
+use maestro::rep3_core::share::RssShare;
+use maestro::share::gf8::GF8;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::aes::{self, ss::aes_no_keyschedule_mal, AesKeyState, AesVariant};
+use crate::data::Input;
+use crate::config::PartyConfig;
+use crate::error::{LocksmithError, Result};
+use crate::metrics::{self, Traffic};
+use super::backend::{Backend, MpcParty};
+use super::parallel;
+use super::rss::key_schedule::aes128_keyschedule_mal;
+use super::{connect, control, preprocessing_units, reveal_to, DATA_OWNER};
+
+/// Largest block count of a case
+pub const MAX_BLOCKS: usize = 1 << 20;
+
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Workload {
+    /// ECB encryption of random shared blocks, opened to all parties
+    Ecb,
+    /// CTR keystream for public counters, opened to the data owner
+    Ctr,
+    /// One AES-128 key schedule of a random shared key, counted as one block
+    KeySchedule,
+    /// Preprocessing for the blocks alone
+    Preprocessing,
+}
+
+/// The cases to run, the same on all three parties
+#[derive(Clone, PartialEq, Debug)]
+pub struct BenchConfig {
+    pub backends: Vec<Backend>,
+    pub workloads: Vec<Workload>,
+    pub blocks: Vec<usize>,
+    pub warmup: usize,
+    pub repetitions: usize,
+}
+
+impl BenchConfig {
+    fn validate(&self) -> Result<()> {
+        if self.backends.is_empty() || self.workloads.is_empty() || self.blocks.is_empty() {
+            return Err(LocksmithError::InvalidInput("nothing to benchmark".to_string()));
+        }
+        if let Some(blocks) = self.blocks.iter().find(|&&blocks| blocks == 0 || blocks > MAX_BLOCKS) {
+            return Err(LocksmithError::InvalidInput(format!("block counts must be between 1 and {}, not {}", MAX_BLOCKS, blocks)));
+        }
+        if self.repetitions == 0 {
+            return Err(LocksmithError::InvalidInput("at least one repetition is needed".to_string()));
+        }
+        Ok(())
+    }
+
+    // What the parties compare to make sure they run the same cases
+    fn digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{:?}", self));
+        hasher.finalize().into()
+    }
+}
+
+/// Measurements of one case on this party
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct BenchResult {
+    pub backend: Backend,
+    pub workload: Workload,
+    pub blocks: usize,
+    pub repetitions: usize,
+    /// Mean, shortest and longest repetition in seconds
+    pub latency: f64,
+    pub latency_min: f64,
+    pub latency_max: f64,
+    /// Blocks per second at the mean latency
+    pub throughput: f64,
+    pub bytes_sent_per_block: f64,
+    pub bytes_received_per_block: f64,
+    /// Communication rounds per repetition
+    pub rounds: f64,
+}
+
+impl BenchResult {
+    fn new(backend: Backend, workload: Workload, blocks: usize, times: &[Duration], traffic: Traffic) -> Self {
+        let seconds: Vec<f64> = times.iter().map(Duration::as_secs_f64).collect();
+        let repetitions: usize = seconds.len();
+        let latency: f64 = seconds.iter().sum::<f64>() / repetitions as f64;
+        let per_block = |bytes: u64| bytes as f64 / (repetitions * blocks) as f64;
+        BenchResult {
+            backend,
+            workload,
+            blocks,
+            repetitions,
+            latency,
+            latency_min: seconds.iter().copied().fold(f64::INFINITY, f64::min),
+            latency_max: seconds.iter().copied().fold(0.0, f64::max),
+            throughput: blocks as f64 / latency,
+            bytes_sent_per_block: per_block(traffic.bytes_sent),
+            bytes_received_per_block: per_block(traffic.bytes_received),
+            rounds: traffic.rounds as f64 / repetitions as f64,
+        }
+    }
+}
+
+// Name of a value as given on the command line
+fn name(value: impl ValueEnum) -> String {
+    value.to_possible_value().map_or(String::new(), |value| value.get_name().to_string())
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<13} {:<13} {:>7} blocks: {:>12.1} blocks/s, latency {:.3} ms ({:.3} to {:.3}), {:.1} bytes sent and {:.1} received per block, {:.0} rounds",
+            self.backend.name(), name(self.workload), self.blocks, self.throughput,
+            self.latency * 1e3, self.latency_min * 1e3, self.latency_max * 1e3,
+            self.bytes_sent_per_block, self.bytes_received_per_block, self.rounds,
+        )
+    }
+}
+
+/// Writes `results` to `path` as JSON
+pub fn save(results: &[BenchResult], path: &Path) -> Result<()> {
+    let json: String = serde_json::to_string_pretty(results).map_err(|e| LocksmithError::Malformed(format!("bench results: {}", e)))?;
+    std::fs::write(path, json + "\n")?;
+    Ok(())
+}
+
+/// Runs the cases of `bench` against the other two parties, which run it with the same config.
+/// Returns this party's results in the order of `bench.backends`, the workloads and the block
+/// counts; key schedules run once per backend, whatever the block counts.
+pub fn run(config: &Path, bench: &BenchConfig) -> Result<Vec<BenchResult>> {
+    let party_config: PartyConfig = crate::config::load(config)?;
+    let mut results: Vec<BenchResult> = Vec::new();
+    for &backend in &bench.backends {
+        let conn: ConnectedParty = connect(&party_config, Some(party_config.network.connect_attempts))?;
+        results.extend(run_cases(parallel::setup(&party_config, backend, conn)?, bench)?);
+    }
+    Ok(results)
+}
+
+fn run_cases(mut party: Box<dyn MpcParty>, bench: &BenchConfig) -> Result<Vec<BenchResult>> {
+    control::checkpoint(party.main_party_mut(), bench.validate())?;
+    if !control::all_equal(party.main_party_mut(), &bench.digest())? {
+        return Err(LocksmithError::Refused("the parties were given different bench settings".to_string()));
+    }
+    let ks: Vec<maestro::aes::AesKeyState> = aes::random_keyschedule(party.main_party_mut(), AesVariant::Aes128).iter()
+        .map(|k: &AesKeyState| maestro::aes::AesKeyState::from_rss_vec(k.to_rss_vec()))
+        .collect();
+
+    let mut results: Vec<BenchResult> = Vec::new();
+    for &workload in &bench.workloads {
+        let counts: &[usize] = match workload {
+            Workload::KeySchedule => &[1],
+            _ => &bench.blocks,
+        };
+        for &blocks in counts {
+            for _ in 0..bench.warmup {
+                run_once(&mut *party, workload, blocks, &ks)?;
+            }
+            let before: Traffic = metrics::traffic(party.main_party_mut());
+            let mut times: Vec<Duration> = Vec::with_capacity(bench.repetitions);
+            for _ in 0..bench.repetitions {
+                let started: Instant = Instant::now();
+                run_once(&mut *party, workload, blocks, &ks)?;
+                times.push(started.elapsed());
+            }
+            let traffic: Traffic = metrics::traffic(party.main_party_mut()) - before;
+            let result: BenchResult = BenchResult::new(party.backend(), workload, blocks, &times, traffic);
+            tracing::info!(backend = ?party.backend(), workload = ?workload, blocks, throughput = result.throughput, "bench case done");
+            results.push(result);
+        }
+    }
+    party.main_party_mut().teardown()?;
+    Ok(results)
+}
+
+// One repetition of a case
+fn run_once(party: &mut dyn MpcParty, workload: Workload, blocks: usize, ks: &[maestro::aes::AesKeyState]) -> Result<()> {
+    let (_, n_blocks): (usize, usize) = preprocessing_units(AesVariant::Aes128, 0, blocks);
+    let input: VectorAesState = match workload {
+        Workload::Preprocessing => return Ok(metrics::measure(party, "preprocessing", |party| party.do_preprocessing(0, n_blocks))?),
+        Workload::KeySchedule => {
+            let (n_keys, _): (usize, usize) = preprocessing_units(AesVariant::Aes128, 1, 0);
+            metrics::measure(party, "preprocessing", |party| party.do_preprocessing(n_keys, 0))?;
+            let key: Vec<RssShare<GF8>> = party.main_party_mut().generate_random(16);
+            metrics::measure(party, "key_schedule", |party| aes128_keyschedule_mal(party, key))?;
+            return Ok(metrics::measure(party, "check", |party| party.finalize())?);
+        }
+        Workload::Ecb => VectorAesState::from_bytes(aes::random_state(party.main_party_mut(), blocks).to_bytes()),
+        Workload::Ctr => VectorAesState::from_bytes(Input::from_nonce(0, blocks).iter().map(|&b| party.constant_rss(GF8(b))).collect()),
+    };
+
+    metrics::measure(party, "preprocessing", |party| party.do_preprocessing(0, n_blocks))?;
+    let output: VectorAesState = metrics::measure(party, "aes", |party| aes_no_keyschedule_mal(party, input, ks))?;
+    metrics::measure(party, "check", |party| party.finalize())?;
+    match workload {
+        Workload::Ctr => {
+            reveal_to(party.main_party_mut(), &output, DATA_OWNER)?;
+        }
+        _ => {
+            let (si, sii): (Vec<GF8>, Vec<GF8>) = output.to_bytes().into_iter().map(|rss: RssShare<GF8>| (rss.si, rss.sii)).unzip();
+            metrics::measure(party, "output", |party| party.output(&si, &sii))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+// Control messages exchanged between the parties outside of the MPC computation itself,
+// e.g. request headers and go/no-go votes. Bytes are sent as GF8 slices over the party links.
+use maestro::rep3_core::{network::task::Direction::{self, Next, Previous}, party::MainParty};
+use maestro::share::gf8::GF8;
+
+use crate::error::{AbortReason, LocksmithError, Result};
+
+/// Upper bound on broadcast message length, so a faulty sender cannot make us allocate arbitrarily
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// Direction in which party `i` reaches party `j` (`i != j`)
+pub fn direction_to(i: usize, j: usize) -> Direction {
+    debug_assert_ne!(i, j);
+    if j == (i + 1) % 3 { Next } else { Previous }
+}
+
+pub fn send_bytes(party: &mut MainParty, to: usize, bytes: &[u8]) {
+    let dir: Direction = direction_to(party.i, to);
+    let field: Vec<GF8> = bytes.iter().map(|&b| GF8(b)).collect();
+    party.io().send_field_slice(dir, field.as_slice());
+}
+
+pub fn receive_bytes(party: &mut MainParty, from: usize, len: usize) -> Result<Vec<u8>> {
+    let dir: Direction = direction_to(party.i, from);
+    let mut field: Vec<GF8> = vec![GF8(0); len];
+    party.io().receive_field_slice(dir, &mut field).rcv()
+        .map_err(|_| LocksmithError::Network(format!("failed to receive from party {}", from)))?;
+    Ok(field.iter().map(|x| x.0).collect())
+}
+
+/// Sends a message from party `from` to both other parties.
+///
+/// `from` passes `Some(message)`, the other two pass `None`. The receivers forward what they got
+/// to each other, so a sender that tells the two parties different things is caught.
+/// Returns the message on every party.
+pub fn broadcast(party: &mut MainParty, from: usize, message: Option<&[u8]>) -> Result<Vec<u8>> {
+    let i: usize = party.i;
+    if i == from {
+        let message: &[u8] = message.ok_or_else(|| LocksmithError::InvalidInput("the broadcasting party must provide the message".to_string()))?;
+        let len: [u8; 4] = (message.len() as u32).to_be_bytes();
+        for to in [(i + 1) % 3, (i + 2) % 3] {
+            send_bytes(party, to, &len);
+            send_bytes(party, to, message);
+        }
+        return Ok(message.to_vec());
+    }
+
+    let other: usize = 3 - i - from;
+    let mut len: [u8; 4] = [0u8; 4];
+    len.copy_from_slice(&receive_bytes(party, from, 4)?);
+    if u32::from_be_bytes(len) as usize > MAX_MESSAGE_LEN {
+        return Err(LocksmithError::Malformed(format!("party {} announced an oversized message", from)));
+    }
+    let received: Vec<u8> = receive_bytes(party, from, u32::from_be_bytes(len) as usize)?;
+
+    // Echo to the other receiver and compare. The length goes first so that both sides
+    // know how much to read even if the sender equivocated.
+    send_bytes(party, other, &len);
+    send_bytes(party, other, &received);
+    let mut echo_len: [u8; 4] = [0u8; 4];
+    echo_len.copy_from_slice(&receive_bytes(party, other, 4)?);
+    if u32::from_be_bytes(echo_len) as usize > MAX_MESSAGE_LEN {
+        return Err(LocksmithError::Malformed(format!("party {} announced an oversized message", other)));
+    }
+    let echo: Vec<u8> = receive_bytes(party, other, u32::from_be_bytes(echo_len) as usize)?;
+
+    if echo != received {
+        return Err(LocksmithError::Abort(AbortReason::Equivocation { party: from }));
+    }
+    Ok(received)
+}
+
+/// Every party states whether it approves; returns `true` on all parties only if all three approve
+pub fn agree(party: &mut MainParty, approve: bool) -> Result<bool> {
+    let i: usize = party.i;
+    let vote: [u8; 1] = [approve as u8];
+    send_bytes(party, (i + 1) % 3, &vote);
+    send_bytes(party, (i + 2) % 3, &vote);
+    let next: Vec<u8> = receive_bytes(party, (i + 1) % 3, 1)?;
+    let prev: Vec<u8> = receive_bytes(party, (i + 2) % 3, 1)?;
+    Ok(approve && next[0] == 1 && prev[0] == 1)
+}
+
+/// Every party contributes `value` (of the same length on all parties); returns `true` only if all three are equal
+pub fn all_equal(party: &mut MainParty, value: &[u8]) -> Result<bool> {
+    let i: usize = party.i;
+    send_bytes(party, (i + 1) % 3, value);
+    send_bytes(party, (i + 2) % 3, value);
+    let next: Vec<u8> = receive_bytes(party, (i + 1) % 3, value.len())?;
+    let prev: Vec<u8> = receive_bytes(party, (i + 2) % 3, value.len())?;
+    Ok(next == value && prev == value)
+}
+
+/// Every party contributes `value` (of the same length on all parties); returns the three values
+/// by party index. A party may tell the other two different things, so follow with `all_equal`
+/// over the result before acting on it.
+pub fn gather(party: &mut MainParty, value: &[u8]) -> Result<[Vec<u8>; 3]> {
+    let i: usize = party.i;
+    send_bytes(party, (i + 1) % 3, value);
+    send_bytes(party, (i + 2) % 3, value);
+    let mut values: [Vec<u8>; 3] = Default::default();
+    values[(i + 1) % 3] = receive_bytes(party, (i + 1) % 3, value.len())?;
+    values[(i + 2) % 3] = receive_bytes(party, (i + 2) % 3, value.len())?;
+    values[i] = value.to_vec();
+    Ok(values)
+}
+
+/// Ends a protocol phase consistently on all parties.
+///
+/// Every party sends a one-byte status (0 for success, otherwise `LocksmithError::code`) to the
+/// other two. A party that failed returns its own error; a party that succeeded but hears of a
+/// failure returns `AbortReason::PeerAborted` naming the peer, so all three stop at the same
+/// point instead of some of them waiting for messages that will never come.
+pub fn checkpoint<T>(party: &mut MainParty, local: Result<T>) -> Result<T> {
+    let i: usize = party.i;
+    let status: [u8; 1] = [local.as_ref().err().map_or(0, |e| e.code())];
+    send_bytes(party, (i + 1) % 3, &status);
+    send_bytes(party, (i + 2) % 3, &status);
+    let next: Vec<u8> = receive_bytes(party, (i + 1) % 3, 1)?;
+    let prev: Vec<u8> = receive_bytes(party, (i + 2) % 3, 1)?;
+
+    let value: T = local?;
+    for (peer, code) in [((i + 1) % 3, next[0]), ((i + 2) % 3, prev[0])] {
+        if code != 0 {
+            return Err(LocksmithError::Abort(AbortReason::PeerAborted { party: peer, kind: LocksmithError::kind_from_code(code) }));
+        }
+    }
+    Ok(value)
+}
@@ -0,0 +1,399 @@
+// Long-running CTR encryption service (`--mode daemon`).
+//
+// The three parties stay connected and compute the key schedule once per session. The data
+// owner (party 0) takes jobs from a channel and the parties serve them one at a time through the
+// keystream service. Between jobs the data owner sends heartbeats, so a dead or silent link is
+// noticed within the IO timeout, and setup and every job have a deadline as well.
+//
+// A session runs on a worker thread and reports its progress to the daemon thread. When the
+// session fails or misses a deadline, every party drops it, reconnects with exponential backoff
+// and serves again once all three parties are back, e.g. after one of them restarted. A worker
+// stuck on a dead link is left behind; it ends when its connection does. The job in flight fails
+// and is not retried, because its counters may already be recorded in the ledgers.
+//
+// A key from the key store can be refreshed periodically: the data owner announces a refresh
+// step, and the parties re-randomise the stored shares and swap in the new key schedule without
+// ending the session. Every session starts by checking a stored key against its key check value.
+//
+// Every session takes the client from the certificate the data owner presents when it connects,
+// and checks that all three parties' access policies allow it to encrypt under the key before
+// the key schedule.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use maestro::rep3_core::network::ConnectedParty;
+use tracing::span::{EnteredSpan, Span};
+
+use crate::aes::AesVariant;
+use crate::config::{NetworkSettings, PartyConfig};
+use crate::data::{Ciphertext, Tag};
+use crate::envelope::{CipherMode, Envelope, Padding};
+use crate::error::{LocksmithError, Result};
+use super::access::Access;
+use super::backend::MpcParty;
+use super::fault::{self, Fault};
+use super::kcv::KCV_LEN;
+use super::keystore::{KeyStore, StoredKey, FIRST_VERSION};
+use super::keystream::{KeystreamRequest, KeystreamService, Nonce, Purpose, Served};
+use super::nonce::NonceLedger;
+use super::parallel;
+use super::{connect, control, KeyShare, DATA_OWNER};
+
+/// Nonce, key version and ciphertext of a job; the version is the one of the session that
+/// served it, which changes when a new session finds the key rotated
+pub type JobResult = Result<(u128, u32, Ciphertext)>;
+
+/// Data to encrypt in CTR mode under a fresh nonce
+pub struct Job {
+    pub data: Vec<u8>,
+    pub reply: Sender<JobResult>,
+}
+
+// What the data owner's daemon tells its worker to do next
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Step {
+    Heartbeat,
+    Job { blocks: u64 },
+    Refresh,
+    Stop,
+}
+
+// The step as the data owner announces it to the other parties; job sizes follow in the
+// keystream request
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Tick {
+    Heartbeat = 0,
+    Job = 1,
+    Stop = 2,
+    Refresh = 3,
+}
+
+impl Tick {
+    fn from_code(code: u8) -> Option<Tick> {
+        match code {
+            0 => Some(Tick::Heartbeat),
+            1 => Some(Tick::Job),
+            2 => Some(Tick::Stop),
+            3 => Some(Tick::Refresh),
+            _ => None,
+        }
+    }
+}
+
+// A key from the key store: where to refresh it, and its key check value
+struct StoreKey {
+    keys: KeyStore,
+    key_id: String,
+    kcv: Option<[u8; KCV_LEN]>,
+}
+
+impl Step {
+    fn tick(self) -> Tick {
+        match self {
+            Step::Heartbeat => Tick::Heartbeat,
+            Step::Job { .. } => Tick::Job,
+            Step::Refresh => Tick::Refresh,
+            Step::Stop => Tick::Stop,
+        }
+    }
+}
+
+// Progress of a session, sent from its worker thread to the daemon
+enum Event {
+    /// Key schedule done, the session can serve jobs
+    Ready,
+    Tick(Tick),
+    /// A job was served or refused; the keystream is only known to the data owner
+    Served(Result<Served>),
+    /// The stored key shares and the key schedule were refreshed
+    Refreshed,
+    /// The session is over: `Ok` after a stop, the error if it failed
+    Ended(Result<()>),
+}
+
+/// Runs the daemon until the data owner's `jobs` channel is closed, which stops all three parties.
+///
+/// The data owner passes `Some(jobs)`, the other parties `None`. Sessions that fail are replaced
+/// by new ones; only errors that a new session cannot fix, such as a bad config or key share,
+/// are returned. The key `key_id` is taken from `keys`, every session loading the latest epoch;
+/// only `DEFAULT_KEY_ID` names the built-in AES-128 key if it is not stored, any other key ID
+/// that is not stored is refused. The data owner refreshes a stored key every `refresh` if given.
+pub fn run(
+    config: &Path,
+    ledger_dir: &Path,
+    keys: &KeyStore,
+    key_id: &str,
+    jobs: Option<Receiver<Job>>,
+    refresh: Option<Duration>,
+) -> Result<()> {
+    let party_config: PartyConfig = crate::config::load(config)?;
+    match (party_config.party_index - 1 == DATA_OWNER, jobs.is_some()) {
+        (true, false) => return Err(LocksmithError::InvalidInput("the data owner (party 0) needs a source of daemon jobs".to_string())),
+        (false, true) => return Err(LocksmithError::InvalidInput("only the data owner (party 0) takes daemon jobs".to_string())),
+        _ => {}
+    }
+    keys.lookup(key_id)?;
+
+    // An injected fault only applies to the first session, so that the daemon can recover
+    let mut fault: Option<Fault> = fault::current();
+    let mut sessions: u64 = 0;
+    loop {
+        sessions += 1;
+        let conn: ConnectedParty = connect(&party_config, None)?;
+        let access: Access = Access::connected(&party_config, &conn)?;
+        let ledger: NonceLedger = NonceLedger::open(ledger_dir, key_id)?;
+        let (events_tx, events): (Sender<Event>, Receiver<Event>) = channel();
+        let (steps_tx, steps): (Option<Sender<Step>>, Option<Receiver<Step>>) = match jobs {
+            Some(_) => {
+                let (tx, rx): (Sender<Step>, Receiver<Step>) = channel();
+                (Some(tx), Some(rx))
+            }
+            None => (None, None),
+        };
+        let stored: Option<StoredKey> = keys.lookup(key_id)?;
+        let key: Option<KeyShare> = stored.as_ref().map(|stored| stored.encryption_share().cloned()).transpose()?;
+        let version: u32 = stored.as_ref().map_or(FIRST_VERSION, |stored| stored.version);
+        let refresh: Option<Duration> = refresh.filter(|_| key.is_some());
+        let store: Option<StoreKey> = stored.map(|stored| StoreKey { keys: keys.clone(), key_id: key_id.to_string(), kcv: stored.kcv });
+        let session_fault: Option<Fault> = fault.take();
+        let session_config: PartyConfig = party_config.clone();
+        let span: Span = tracing::info_span!("daemon_session", party = party_config.party_index, session = sessions, key_id);
+        thread::spawn(move || {
+            let _span: EnteredSpan = span.entered();
+            fault::restore(session_fault);
+            let result: Result<()> = parallel::setup(&session_config, session_config.backend, conn)
+                .and_then(|party| session(party, ledger, key, version, store, &access, steps, &events_tx));
+            let _ = events_tx.send(Event::Ended(result));
+        });
+
+        match supervise(&party_config.network, &events, steps_tx, jobs.as_ref(), refresh, version) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.ends_session() => {
+                tracing::warn!(party = party_config.party_index, session = sessions, "session failed: {}; reconnecting", e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// One session on the worker thread: authorization and key schedule, then one step after the
+// other. `store` is set for a stored key, which can be refreshed.
+#[allow(clippy::too_many_arguments)]
+fn session(
+    party: Box<dyn MpcParty>,
+    ledger: NonceLedger,
+    key: Option<KeyShare>,
+    version: u32,
+    store: Option<StoreKey>,
+    access: &Access,
+    steps: Option<Receiver<Step>>,
+    events: &Sender<Event>,
+) -> Result<()> {
+    let mut service: KeystreamService = KeystreamService::authorized(party, DATA_OWNER, ledger, key.as_ref(), version, access)?;
+    // All parties hold a stored key or none, `start` has checked that
+    if let Some(store) = &store {
+        service.check_key(&store.key_id, store.kcv)?;
+    }
+    tracing::info!("session ready");
+    let _ = events.send(Event::Ready);
+
+    loop {
+        let step: Option<Step> = match &steps {
+            // The daemon gave up on this session
+            Some(steps) => Some(steps.recv().map_err(|_| LocksmithError::Network("session abandoned".to_string()))?),
+            None => None,
+        };
+        let code: Option<[u8; 1]> = step.map(|step| [step.tick() as u8]);
+        let tick: Vec<u8> = control::broadcast(service.main_party_mut(), DATA_OWNER, code.as_ref().map(|code| code.as_slice()))?;
+        let tick: Tick = tick.first().copied().and_then(Tick::from_code)
+            .ok_or_else(|| LocksmithError::Malformed("daemon step".to_string()))?;
+        // Everyone confirms the step to everyone, so each party hears from both others every time
+        control::agree(service.main_party_mut(), true)?;
+        let _ = events.send(Event::Tick(tick));
+
+        match tick {
+            Tick::Heartbeat => {}
+            Tick::Stop => return service.shutdown(),
+            Tick::Refresh => {
+                let store: &StoreKey = store.as_ref()
+                    .ok_or_else(|| LocksmithError::InvalidInput("refresh requested for a key that is not stored".to_string()))?;
+                let epoch: u64 = service.refresh(&store.keys, &store.key_id)?;
+                tracing::info!(key_id = %store.key_id, epoch, "refreshed the key shares");
+                let _ = events.send(Event::Refreshed);
+            }
+            Tick::Job => {
+                let request: Option<KeystreamRequest> = match step {
+                    Some(Step::Job { blocks }) => Some(KeystreamRequest { nonce: Nonce::Auto, start: 0, blocks, purpose: Purpose::Encrypt }),
+                    _ => None,
+                };
+                let served: Result<Served> = tracing::info_span!("job").in_scope(|| {
+                    let served: Result<Served> = service.serve(request);
+                    match &served {
+                        Ok(served) => tracing::info!(nonce = %format!("{:032x}", served.nonce), "job served"),
+                        Err(e) => tracing::warn!("job refused or failed: {}", e),
+                    }
+                    served
+                });
+                let failed: bool = served.as_ref().is_err_and(LocksmithError::ends_session);
+                let _ = events.send(Event::Served(served));
+                if failed {
+                    return Err(LocksmithError::Network("session ended by a failed job".to_string()));
+                }
+            }
+        }
+
+        if fault::trips(Fault::Disconnect) {
+            return Err(LocksmithError::Network("disconnect injected".to_string()));
+        }
+    }
+}
+
+// Drives a session under key version `version` from the daemon thread until it stops (`Ok`) or fails
+fn supervise(
+    network: &NetworkSettings,
+    events: &Receiver<Event>,
+    steps: Option<Sender<Step>>,
+    jobs: Option<&Receiver<Job>>,
+    refresh: Option<Duration>,
+    version: u32,
+) -> Result<()> {
+    let next = |timeout: Duration| -> Result<Event> {
+        match events.recv_timeout(timeout) {
+            Ok(Event::Ended(Ok(()))) => Err(LocksmithError::Network("session ended unexpectedly".to_string())),
+            Ok(Event::Ended(Err(e))) => Err(e),
+            Ok(event) => Ok(event),
+            Err(RecvTimeoutError::Timeout) => Err(LocksmithError::Network(format!("no progress within {:?}", timeout))),
+            Err(RecvTimeoutError::Disconnected) => Err(LocksmithError::Network("session worker stopped".to_string())),
+        }
+    };
+
+    match next(network.request_timeout())? {
+        Event::Ready => {}
+        _ => return Err(LocksmithError::Network("unexpected session event".to_string())),
+    }
+
+    let mut refreshed: Instant = Instant::now();
+    loop {
+        // The data owner picks the next step: a job if one arrives before the next heartbeat is due
+        let mut job: Option<Job> = None;
+        if let (Some(steps), Some(jobs)) = (&steps, jobs) {
+            let refresh_due: bool = refresh.is_some_and(|every| refreshed.elapsed() >= every);
+            let received: Option<std::result::Result<Job, RecvTimeoutError>> = match refresh_due {
+                true => None,
+                false => Some(jobs.recv_timeout(network.heartbeat())),
+            };
+            let step: Step = match received {
+                None => Step::Refresh,
+                Some(Ok(next_job)) if next_job.data.is_empty() => {
+                    let _ = next_job.reply.send(Err(LocksmithError::InvalidInput("nothing to encrypt".to_string())));
+                    continue;
+                }
+                Some(Ok(next_job)) => {
+                    let blocks: u64 = next_job.data.len().div_ceil(16) as u64;
+                    job = Some(next_job);
+                    Step::Job { blocks }
+                }
+                Some(Err(RecvTimeoutError::Timeout)) => Step::Heartbeat,
+                Some(Err(RecvTimeoutError::Disconnected)) => Step::Stop,
+            };
+            if steps.send(step).is_err() {
+                return Err(fail(job, next(Duration::ZERO).err()));
+            }
+        }
+
+        let tick: Tick = match next(network.io_timeout()) {
+            Ok(Event::Tick(tick)) => tick,
+            Ok(_) => return Err(fail(job, None)),
+            Err(e) => return Err(fail(job, Some(e))),
+        };
+        match tick {
+            Tick::Heartbeat => {}
+            Tick::Refresh => match next(network.request_timeout())? {
+                Event::Refreshed => refreshed = Instant::now(),
+                _ => return Err(LocksmithError::Network("unexpected session event".to_string())),
+            },
+            Tick::Stop => {
+                return match events.recv_timeout(network.io_timeout()) {
+                    Ok(Event::Ended(result)) => result,
+                    _ => Err(LocksmithError::Network("session did not stop".to_string())),
+                };
+            }
+            Tick::Job => {
+                let served: Result<Served> = match next(network.request_timeout()) {
+                    Ok(Event::Served(served)) => served,
+                    Ok(_) => return Err(fail(job, None)),
+                    Err(e) => return Err(fail(job, Some(e))),
+                };
+                if let Some(job) = job {
+                    let result: JobResult = served.and_then(|served| {
+                        let keystream: Vec<u8> = served.keystream
+                            .ok_or_else(|| LocksmithError::Network("no keystream for the data owner".to_string()))?;
+                        let ciphertext: Vec<u8> = job.data.iter().zip(keystream.iter()).map(|(data, ks)| data ^ ks).collect();
+                        Ok((served.nonce, version, Ciphertext::new(ciphertext)))
+                    });
+                    let _ = job.reply.send(result);
+                }
+            }
+        }
+    }
+}
+
+// Fails the job in flight, if any, and returns the session's error
+fn fail(job: Option<Job>, error: Option<LocksmithError>) -> LocksmithError {
+    let error: LocksmithError = error.unwrap_or_else(|| LocksmithError::Network("unexpected session event".to_string()));
+    if let Some(job) = job {
+        let _ = job.reply.send(Err(LocksmithError::Network(format!("session failed, submit the job again: {}", error))));
+    }
+    error
+}
+
+/// Takes jobs from the files `*.in` in `dir`, in name order, until the daemon stops. Each file is
+/// replaced by a binary CTR envelope `<name>.lksm`, or by `<name>.err` with the reason it failed.
+/// Each envelope names the key version that served its job.
+pub fn spool(dir: PathBuf, key_id: String, variant: AesVariant) -> Receiver<Job> {
+    let (tx, rx): (Sender<Job>, Receiver<Job>) = channel();
+    thread::spawn(move || loop {
+        let mut inputs: Vec<PathBuf> = fs::read_dir(&dir).into_iter().flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "in"))
+            .collect();
+        inputs.sort();
+
+        for input in inputs {
+            let (reply, result): (Sender<JobResult>, Receiver<JobResult>) = channel();
+            let written: Result<()> = fs::read(&input).map_err(LocksmithError::from).and_then(|data| {
+                let plaintext_len: u64 = data.len() as u64;
+                if tx.send(Job { data, reply }).is_err() {
+                    return Err(LocksmithError::Network("daemon stopped".to_string()));
+                }
+                let (nonce, key_version, ciphertext): (u128, u32, Ciphertext) = result.recv()
+                    .map_err(|_| LocksmithError::Network("daemon stopped".to_string()))??;
+                let envelope: Envelope = Envelope {
+                    key_id: key_id.clone(),
+                    key_version,
+                    variant,
+                    mode: CipherMode::Ctr,
+                    iv: nonce.to_be_bytes().to_vec(),
+                    padding: Padding::None,
+                    plaintext_len,
+                    tag: Tag::default(),
+                    ciphertext,
+                };
+                envelope.save(&input.with_extension("lksm"))
+            });
+            if let Err(e) = written {
+                if fs::write(input.with_extension("err"), format!("{}\n", e)).is_err() {
+                    return;
+                }
+            }
+            if fs::remove_file(&input).is_err() {
+                return;
+            }
+        }
+        thread::sleep(Duration::from_millis(200));
+    });
+    rx
+}
@@ -0,0 +1,186 @@
+use std::path::Path;
+
+use maestro::aes::VectorAesState;
+use maestro::rep3_core::party::MainParty;
+
+use crate::aes::AesVariant;
+use crate::aes::ss::aes_inv_no_keyschedule_mal;
+use crate::data::{Input, Plaintext};
+use crate::error::{LocksmithError, Result};
+use crate::metrics;
+use crate::envelope::{mode_from_u8, mode_to_u8, variant_from_u8, variant_to_u8, CipherMode, Envelope};
+use super::access::{Access, Operation};
+use super::backend::MpcParty;
+use super::keystore::KeySource;
+use super::keystream::{KeystreamRequest, KeystreamService, Nonce, Purpose, Served, MAX_REQUEST_BLOCKS};
+use super::nonce::{CounterRange, NonceLedger};
+use super::{check_owner, control, preprocessing_units, reveal_to, rss, setup_with_access, KeyShare, DATA_OWNER};
+
+// What the helper parties learn about an envelope before decrypting it
+pub(crate) struct DecryptHeader {
+    pub(crate) key_id: String,
+    pub(crate) key_version: u32,
+    pub(crate) variant: AesVariant,
+    pub(crate) mode: CipherMode,
+    pub(crate) nonce: u128,
+    pub(crate) blocks: u64,
+}
+
+impl DecryptHeader {
+    pub(crate) fn from_envelope(envelope: &Envelope) -> Result<Self> {
+        let nonce: u128 = match envelope.mode {
+            CipherMode::Ecb => 0,
+            CipherMode::Ctr => u128::from_be_bytes(envelope.iv.as_slice().try_into()
+                .map_err(|_| LocksmithError::Malformed("CTR envelopes need a 16-byte initial counter block".to_string()))?),
+        };
+        Ok(DecryptHeader {
+            key_id: envelope.key_id.clone(),
+            key_version: envelope.key_version,
+            variant: envelope.variant,
+            mode: envelope.mode,
+            nonce,
+            blocks: envelope.num_blocks() as u64,
+        })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(&self.key_version.to_be_bytes());
+        bytes.push(variant_to_u8(self.variant));
+        bytes.push(mode_to_u8(self.mode));
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes.extend_from_slice(&self.blocks.to_be_bytes());
+        bytes.extend_from_slice(self.key_id.as_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 30 {
+            return None;
+        }
+        Some(DecryptHeader {
+            key_version: u32::from_be_bytes(bytes[0..4].try_into().ok()?),
+            variant: variant_from_u8(bytes[4])?,
+            mode: mode_from_u8(bytes[5])?,
+            nonce: u128::from_be_bytes(bytes[6..22].try_into().ok()?),
+            blocks: u64::from_be_bytes(bytes[22..30].try_into().ok()?),
+            key_id: String::from_utf8(bytes[30..].to_vec()).ok()?,
+        })
+    }
+
+    /// The counter blocks of a CTR envelope
+    pub(crate) fn counters(&self) -> Option<CounterRange> {
+        (self.mode == CipherMode::Ctr).then_some(CounterRange { first: self.nonce, blocks: self.blocks, version: self.key_version })
+    }
+
+    /// Sent by the data owner to the other parties, who return it with `None`
+    pub(crate) fn broadcast(party: &mut MainParty, envelope: Option<&Envelope>) -> Result<Self> {
+        let header: Option<Vec<u8>> = envelope.map(DecryptHeader::from_envelope).transpose()?.map(|h| h.to_bytes());
+        let header: Vec<u8> = control::broadcast(party, DATA_OWNER, header.as_deref())?;
+        DecryptHeader::from_bytes(&header).ok_or_else(|| LocksmithError::Malformed("decryption header".to_string()))
+    }
+
+    /// Checks on every party that the envelope has at most `MAX_REQUEST_BLOCKS` blocks, before
+    /// anything is allocated or preprocessed for it
+    pub(crate) fn check_size(&self, party: &mut MainParty) -> Result<()> {
+        let within_limit: Result<()> = match self.blocks <= MAX_REQUEST_BLOCKS {
+            true => Ok(()),
+            false => Err(LocksmithError::InvalidInput(format!(
+                "the envelope has {} blocks, more than the {} blocks of a single request", self.blocks, MAX_REQUEST_BLOCKS
+            ))),
+        };
+        control::checkpoint(party, within_limit)
+    }
+
+    /// This party's share of the key the envelope names, checked against its variant on all parties
+    pub(crate) fn key(&self, party: &mut MainParty, keys: KeySource) -> Result<KeyShare> {
+        let key: Result<KeyShare> = keys.resolve(party.i, &self.key_id, self.key_version).and_then(|key| match key.variant() == self.variant {
+            true => Ok(key),
+            false => Err(LocksmithError::InvalidInput(format!("no {:?} key shares for key '{}'", self.variant, self.key_id))),
+        });
+        control::checkpoint(party, key)
+    }
+}
+
+/// Decrypts an envelope, choosing key schedule and mode from its header.
+///
+/// The data owner (party 0) passes the envelope and gets the unpadded plaintext back; the other
+/// parties pass `None`, learn only the header and return `None`. `ledger_dir` holds the nonce
+/// ledgers: CTR keystream is only produced for counters recorded there at encryption time.
+/// `keys` gives this party's share of the key and version the envelope names. Refused unless every
+/// party's access policy allows decryption under that key.
+pub fn decrypt(config: &Path, envelope: Option<&Envelope>, ledger_dir: &Path, keys: KeySource) -> Result<Option<Plaintext>> {
+    let (access, mut party): (Access, Box<dyn MpcParty>) = setup_with_access(config)?;
+    check_owner(party.main_party_mut(), envelope.is_some(), "the envelope")?;
+
+    let header: DecryptHeader = DecryptHeader::broadcast(party.main_party_mut(), envelope)?;
+    header.check_size(party.main_party_mut())?;
+    let authorized: Result<()> = access.authorize(party.main_party_mut(), &header.key_id, Operation::Decrypt);
+    let plaintext: Result<Option<Plaintext>> = authorized.and_then(|()| {
+        let key: KeyShare = header.key(party.main_party_mut(), keys)?;
+        match header.mode {
+            CipherMode::Ecb => decrypt_ecb(party, envelope, &header, &key),
+            CipherMode::Ctr => decrypt_ctr(party, envelope, &header, ledger_dir, &key),
+        }
+    });
+    access.record(&header.key_id, Operation::Decrypt, header.blocks, header.counters(), &plaintext)?;
+
+    match (envelope, plaintext?) {
+        (Some(envelope), Some(plaintext)) => Ok(Some(envelope.unpad(plaintext)?)),
+        _ => Ok(None),
+    }
+}
+
+fn decrypt_ecb(
+    mut party: Box<dyn MpcParty>,
+    envelope: Option<&Envelope>,
+    header: &DecryptHeader,
+    key: &KeyShare,
+) -> Result<Option<Plaintext>> {
+    let num_blocks: usize = header.blocks as usize;
+    let ks: Vec<maestro::aes::AesKeyState> = rss::key_schedule::keyshare_keyschedule(&mut *party, key)?;
+
+    // The ciphertext is secret-shared by the data owner like any other input
+    let ciphertext: Input = match envelope {
+        Some(envelope) => Input::from_file(envelope.ciphertext.as_bytes(), num_blocks),
+        None => Input::zero(num_blocks),
+    };
+    let input_ss: VectorAesState = rss::share_input::rss_input(&ciphertext, &mut *party)?;
+
+    let (_, n_blocks): (usize, usize) = preprocessing_units(key.variant(), 0, num_blocks);
+    metrics::measure(&mut *party, "preprocessing", |party| party.do_preprocessing(0, n_blocks))?;
+    let output: VectorAesState = metrics::measure(&mut *party, "aes", |party| aes_inv_no_keyschedule_mal(party, input_ss, &ks))?;
+    let checked: Result<()> = metrics::measure(&mut *party, "check", |party| party.finalize().map_err(LocksmithError::from));
+    control::checkpoint(party.main_party_mut(), checked)?;
+
+    let plaintext: Option<Vec<u8>> = reveal_to(party.main_party_mut(), &output, DATA_OWNER)?;
+    party.main_party_mut().teardown()?;
+    Ok(plaintext.map(Plaintext::new))
+}
+
+fn decrypt_ctr(
+    mut party: Box<dyn MpcParty>,
+    envelope: Option<&Envelope>,
+    header: &DecryptHeader,
+    ledger_dir: &Path,
+    key: &KeyShare,
+) -> Result<Option<Plaintext>> {
+    let ledger: Result<NonceLedger> = NonceLedger::open(ledger_dir, &header.key_id).map_err(LocksmithError::from);
+    let ledger: NonceLedger = control::checkpoint(party.main_party_mut(), ledger)?;
+    let mut service: KeystreamService = KeystreamService::start(party, DATA_OWNER, ledger, Some(key), header.key_version)?;
+
+    let request: Option<KeystreamRequest> = envelope.map(|_| KeystreamRequest {
+        nonce: Nonce::Fixed(header.nonce),
+        start: 0,
+        blocks: header.blocks,
+        purpose: Purpose::Decrypt,
+    });
+    let served: Served = service.serve(request)?;
+    service.shutdown()?;
+
+    Ok(envelope.zip(served.keystream).map(|(envelope, keystream)| {
+        Plaintext::new(envelope.ciphertext.as_bytes().iter().zip(keystream.iter())
+            .map(|(data, ks)| data ^ ks)
+            .collect())
+    }))
+}
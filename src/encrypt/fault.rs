@@ -0,0 +1,66 @@
+// Deliberate deviations from the protocol, to check that a cheating party is caught.
+// Faults are applied to values right before they are sent or stored, and can only be switched on
+// in test builds. The switch is per thread and every simulated party runs on its own thread,
+// so a test makes exactly one party cheat.
+use std::cell::Cell;
+
+use maestro::share::gf8::GF8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fault {
+    /// The data owner sends the next party different input shares than it keeps (`rss_input`,
+    /// `import_key`)
+    InputShare,
+    /// A party sends the next party a different share of its key share than it keeps
+    /// (`keyshare_keyschedule`)
+    KeyShare,
+    /// A party sends the previous party a different share of zero than it keeps (`keystore::refresh`)
+    ZeroShare,
+    /// A remaining party hands the replacement party a different share than it holds (`reshare`)
+    HandoverShare,
+    /// A party changes its copy of S-box outputs after the inversion (key schedule, and the
+    /// AES-256 and inverse ciphers)
+    SboxOutput,
+    /// A daemon party drops its session after serving a step, as if it restarted
+    Disconnect,
+}
+
+thread_local! {
+    static ACTIVE: Cell<Option<Fault>> = const { Cell::new(None) };
+}
+
+/// Makes the party on this thread commit `fault` until `clear` is called
+#[cfg(test)]
+pub fn inject(fault: Fault) {
+    ACTIVE.with(|active| active.set(Some(fault)));
+}
+
+#[cfg(test)]
+pub fn clear() {
+    ACTIVE.with(|active| active.set(None));
+}
+
+/// The fault active on this thread, to pass on to threads it spawns
+pub(crate) fn current() -> Option<Fault> {
+    ACTIVE.with(Cell::get)
+}
+
+pub(crate) fn restore(fault: Option<Fault>) {
+    ACTIVE.with(|active| active.set(fault));
+}
+
+/// Whether the one-off `fault` is active on this thread; it is cleared if so
+pub(crate) fn trips(fault: Fault) -> bool {
+    let tripped: bool = current() == Some(fault);
+    if tripped {
+        restore(None);
+    }
+    tripped
+}
+
+/// Flips the lowest bit of the first value if `fault` is active on this thread
+pub(crate) fn tamper(fault: Fault, values: &mut [GF8]) {
+    if let Some(first) = values.first_mut().filter(|_| current() == Some(fault)) {
+        *first += GF8(1);
+    }
+}
@@ -0,0 +1,99 @@
+// Import of an existing plaintext key, e.g. when moving off a single-host KMS.
+//
+// The client (the data owner) splits the key into three random shares x_0 ^ x_1 ^ x_2 and sends
+// every other party the replicated pair (x_i, x_i+1) it holds, like `rss_input` does for data.
+// The parties check the copies as in `store_key`, then compute the key check value inside MPC;
+// the shares are only stored once it matches the value the client computes from the key.
+use std::path::Path;
+
+use maestro::share::gf8::GF8;
+
+use crate::error::{LocksmithError, Result};
+use super::access::{Access, Operation};
+use super::backend::MpcParty;
+use super::fault::{self, Fault};
+use super::kcv::{self, KCV_LEN};
+use super::keystore::{self, KeyStore, StoredKey, FIRST_VERSION};
+use super::{control, setup_with_access, KeyShare, DATA_OWNER};
+
+/// Imports a plaintext AES-128 or AES-256 key as the first version of `key_id` on all three
+/// parties. The data owner passes the key, the other parties `None`. Refused if any party already
+/// holds a key with that ID or its access policy does not allow the import. Returns the stored
+/// key and its key check value.
+pub fn import_key(config: &Path, store: &KeyStore, key_id: &str, key: Option<&[u8]>) -> Result<(StoredKey, [u8; KCV_LEN])> {
+    let (access, mut mpc): (Access, Box<dyn MpcParty>) = setup_with_access(config)?;
+    let authorized: Result<()> = access.authorize(mpc.main_party_mut(), key_id, Operation::Import);
+    let result: Result<(StoredKey, [u8; KCV_LEN])> = authorized.and_then(|()| import(mpc, store, key_id, key));
+    access.record(key_id, Operation::Import, 0, None, &result)?;
+    result
+}
+
+fn import(mut mpc: Box<dyn MpcParty>, store: &KeyStore, key_id: &str, key: Option<&[u8]>) -> Result<(StoredKey, [u8; KCV_LEN])> {
+    let i: usize = mpc.main_party_mut().i;
+    let valid: Result<()> = match (store.versions(key_id), key) {
+        (Err(e), _) => Err(e),
+        (Ok(_), Some(_)) if i != DATA_OWNER => Err(LocksmithError::InvalidInput("only the data owner (party 0) provides the key".to_string())),
+        (Ok(_), None) if i == DATA_OWNER => Err(LocksmithError::InvalidInput("the data owner must provide the key to import".to_string())),
+        (Ok(_), Some(key)) if key.len() != 16 && key.len() != 32 => {
+            Err(LocksmithError::InvalidInput(format!("keys must be 16 or 32 bytes, not {}", key.len())))
+        }
+        (Ok(_), _) => Ok(()),
+    };
+    control::checkpoint(mpc.main_party_mut(), valid)?;
+    if !control::agree(mpc.main_party_mut(), !store.contains(key_id))? {
+        return Err(LocksmithError::Refused(format!("a party already holds a key '{}'", key_id)));
+    }
+    let len: Option<[u8; 1]> = key.map(|key| [key.len() as u8]);
+    let len: Vec<u8> = control::broadcast(mpc.main_party_mut(), DATA_OWNER, len.as_ref().map(|len| len.as_slice()))?;
+    // Every party checks the announced length before any shares are sent
+    let len: Result<usize> = match len.as_slice() {
+        [len @ (16 | 32)] => Ok(*len as usize),
+        [len] => Err(LocksmithError::InvalidInput(format!("keys must be 16 or 32 bytes, not {}", len))),
+        _ => Err(LocksmithError::Malformed("imported key length".to_string())),
+    };
+    let len: usize = control::checkpoint(mpc.main_party_mut(), len)?;
+
+    let (si, sii): (Vec<u8>, Vec<u8>) = match key {
+        Some(key) => {
+            // x_i for every party i; the third share makes them add up to the key
+            let mut shares: [Vec<u8>; 3] = Default::default();
+            shares[DATA_OWNER] = (0..len).map(|_| rand::random::<u8>()).collect();
+            shares[(DATA_OWNER + 1) % 3] = (0..len).map(|_| rand::random::<u8>()).collect();
+            shares[(DATA_OWNER + 2) % 3] = (0..len).map(|b| key[b] ^ shares[DATA_OWNER][b] ^ shares[(DATA_OWNER + 1) % 3][b]).collect();
+            for to in [(DATA_OWNER + 1) % 3, (DATA_OWNER + 2) % 3] {
+                let mut pair: Vec<GF8> = [shares[to].as_slice(), shares[(to + 1) % 3].as_slice()].concat().into_iter().map(GF8).collect();
+                if to == (DATA_OWNER + 1) % 3 {
+                    fault::tamper(Fault::InputShare, &mut pair);
+                }
+                control::send_bytes(mpc.main_party_mut(), to, &pair.iter().map(|x| x.0).collect::<Vec<u8>>());
+            }
+            (shares[DATA_OWNER].clone(), shares[(DATA_OWNER + 1) % 3].clone())
+        }
+        None => {
+            let pair: Vec<u8> = control::receive_bytes(mpc.main_party_mut(), DATA_OWNER, 2 * len)?;
+            (pair[..len].to_vec(), pair[len..].to_vec())
+        }
+    };
+    let missing: [u8; 32] = keystore::checked_copies(mpc.main_party_mut(), &si, &sii)?;
+    let share: KeyShare = KeyShare::replicated(si, sii)?;
+
+    // The client knows the key, so it can tell whether the parties hold that key
+    let kcv: [u8; KCV_LEN] = kcv::key_check_value(&mut *mpc, &share)?;
+    let matches: bool = key.is_none_or(|key| kcv::local_check_value(key) == kcv);
+    if !control::agree(mpc.main_party_mut(), matches)? {
+        return Err(LocksmithError::Refused(format!("the imported key '{}' does not have the expected key check value", key_id)));
+    }
+
+    let stored: StoredKey = StoredKey {
+        key_id: key_id.to_string(),
+        version: FIRST_VERSION,
+        epoch: 0,
+        decrypt_only: false,
+        share,
+        missing: Some(missing),
+        kcv: Some(kcv),
+    };
+    store.save(&stored)?;
+    mpc.main_party_mut().teardown()?;
+    Ok((stored, kcv))
+}
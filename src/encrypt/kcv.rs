@@ -0,0 +1,88 @@
+// Key check values: the first bytes of the encryption of an all-zero block, the usual way to
+// tell keys apart without showing them. Inside MPC only those bytes are opened.
+//
+// Every stored key version keeps the value computed when it was created. `check_key` and the
+// start of every daemon session compute it again from the current shares, so shares that no
+// longer make up the stored key are noticed before anything is served with them.
+use std::path::Path;
+
+use maestro::aes::VectorAesState;
+use maestro::rep3_core::{party::MainParty, share::RssShare};
+use maestro::share::gf8::GF8;
+
+use crate::aes::AesVariant;
+use crate::aes::ss::aes_no_keyschedule_mal;
+use crate::data::Input;
+use crate::error::{LocksmithError, Result};
+use crate::rust_impl;
+use super::backend::MpcParty;
+use super::keystore::{KeyStore, StoredKey};
+use super::{control, preprocessing_units, rss, setup_party, KeyShare};
+
+/// Bytes of the encrypted zero block that make up a key check value
+pub const KCV_LEN: usize = 3;
+
+/// Encrypts an all-zero block under `key` and opens the first `KCV_LEN` bytes to all parties
+pub fn key_check_value(party: &mut dyn MpcParty, key: &KeyShare) -> Result<[u8; KCV_LEN]> {
+    let ks: Vec<maestro::aes::AesKeyState> = rss::key_schedule::keyshare_keyschedule(party, key)?;
+    schedule_check_value(party, &ks, key.variant())
+}
+
+/// Like `key_check_value`, for a key whose schedule is already computed
+pub(crate) fn schedule_check_value(party: &mut dyn MpcParty, ks: &[maestro::aes::AesKeyState], variant: AesVariant) -> Result<[u8; KCV_LEN]> {
+    let input_bytes: Vec<RssShare<GF8>> = Input::zero(1).iter().map(|&b| party.constant_rss(GF8(b))).collect();
+
+    let (_, n_blocks): (usize, usize) = preprocessing_units(variant, 0, 1);
+    party.do_preprocessing(0, n_blocks)?;
+    let output: VectorAesState = aes_no_keyschedule_mal(party, VectorAesState::from_bytes(input_bytes), ks)?;
+    let checked: Result<()> = party.finalize().map_err(LocksmithError::from);
+    control::checkpoint(party.main_party_mut(), checked)?;
+
+    let (output_i, output_ii): (Vec<GF8>, Vec<GF8>) = output.to_bytes().into_iter()
+        .take(KCV_LEN)
+        .map(|rss: RssShare<GF8>| (rss.si, rss.sii))
+        .unzip();
+    let opened: Vec<GF8> = party.output(&output_i, &output_ii)?;
+    opened.iter().map(|x| x.0).collect::<Vec<u8>>().try_into()
+        .map_err(|_| LocksmithError::Malformed("key check value".to_string()))
+}
+
+/// Checks on all parties that the computed `kcv` of `key_id` is the one each party has stored,
+/// where it has one
+pub(crate) fn verify(party: &mut MainParty, key_id: &str, kcv: [u8; KCV_LEN], stored: Option<[u8; KCV_LEN]>) -> Result<()> {
+    match control::agree(party, stored.is_none_or(|stored| stored == kcv))? {
+        true => Ok(()),
+        false => Err(LocksmithError::Refused(format!(
+            "the shares of key '{}' do not match its stored key check value (computed {})", key_id, hex::encode(kcv)
+        ))),
+    }
+}
+
+/// The key check value of a plaintext key
+pub fn local_check_value(key: &[u8]) -> [u8; KCV_LEN] {
+    rust_impl::aes_ecb(key, &[0u8; 16]).as_bytes()[..KCV_LEN].try_into().unwrap_or_default()
+}
+
+/// Computes the key check value of the newest version of `key_id`, or of the built-in key for
+/// `DEFAULT_KEY_ID` if no key by that ID is stored, on all three parties and checks it against
+/// the stored one. Versions stored without a value get it recorded.
+pub fn check_key(config: &Path, store: &KeyStore, key_id: &str) -> Result<[u8; KCV_LEN]> {
+    let mut mpc: Box<dyn MpcParty> = setup_party(config)?;
+
+    let stored: Result<Option<StoredKey>> = store.lookup(key_id);
+    let stored: Option<StoredKey> = control::checkpoint(mpc.main_party_mut(), stored)?;
+    let version: Option<u32> = stored.as_ref().map(|stored| stored.version);
+    if !control::all_equal(mpc.main_party_mut(), &version.unwrap_or_default().to_be_bytes())? {
+        return Err(LocksmithError::Refused(format!("the parties hold different versions of key '{}'", key_id)));
+    }
+
+    let key: KeyShare = stored.as_ref().map_or_else(|| KeyShare::builtin(mpc.main_party_mut().i), |stored| stored.share.clone());
+    let kcv: [u8; KCV_LEN] = key_check_value(&mut *mpc, &key)?;
+    verify(mpc.main_party_mut(), key_id, kcv, stored.as_ref().and_then(|stored| stored.kcv))?;
+
+    if let Some(stored) = stored.filter(|stored| stored.kcv.is_none()) {
+        store.save(&StoredKey { kcv: Some(kcv), ..stored })?;
+    }
+    mpc.main_party_mut().teardown()?;
+    Ok(kcv)
+}
@@ -0,0 +1,566 @@
+// Key store: a party's replicated shares (x_i, x_i+1) of its keys, one JSON file per key ID and
+// version. `rotate_key` adds a new version of a key, generated inside MPC, and marks the older
+// versions decrypt-only; envelopes name the version they were encrypted under.
+//
+// Stored shares are re-randomised with `refresh`: the parties add a fresh replicated sharing of
+// zero, so the key stays the same but shares taken from a party at different times (epochs)
+// cannot be combined. The zero-sharing comes from pairwise PRG seeds, party i's part being
+// alpha_i = G(seed_i) ^ G(seed_i-1), where seed_i is known to parties i and i+1. Party i sends
+// alpha_i to the previous party, which also holds x_i. A party that sends a wrong alpha_i would
+// change the key, so the previous party checks it against the seeds: alpha_i ^ G(seed_i-1) must
+// be G(seed_i), which the next party knows. The two compare hashes, which tell neither anything
+// new, and every party checks that its new shares match the copies the other parties hold. The
+// new epoch only replaces the stored one once all three parties have written it, see
+// `commit_epochs`.
+//
+// Every party also keeps a hash of x_i+2, the one share it does not hold, which both other
+// parties report whenever the shares change. It lets the parties check shares they hand to a
+// party that replaces another, see `reshare`.
+//
+// The key check value of every version, computed inside MPC when the version is created, is
+// stored with it, so the parties can confirm which key their shares make up, see `kcv`.
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use maestro::rep3_core::party::MainParty;
+use maestro::share::gf8::GF8;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::aes::AesVariant;
+use crate::error::{AbortReason, LocksmithError, Result};
+use super::backend::MpcParty;
+use super::fault::{self, Fault};
+use super::kcv::{self, KCV_LEN};
+use super::nonce::{valid_key_id, DEFAULT_KEY_ID};
+use super::{control, rss, setup_party, KeyShare};
+
+/// The version of the built-in key, and of the first stored version of a key
+pub const FIRST_VERSION: u32 = 1;
+
+/// One version of a key in the store: this party's replicated shares, and how often they have
+/// been refreshed
+#[derive(Clone, PartialEq, Debug)]
+pub struct StoredKey {
+    pub key_id: String,
+    pub version: u32,
+    pub epoch: u64,
+    /// Replaced by a newer version; only used to decrypt and re-encrypt
+    pub decrypt_only: bool,
+    pub share: KeyShare,
+    /// Hash of the share this party does not hold, `None` in files written before it was kept
+    pub missing: Option<[u8; 32]>,
+    /// Key check value, `None` in files written before it was kept
+    pub kcv: Option<[u8; KCV_LEN]>,
+}
+
+impl StoredKey {
+    /// The shares, unless this version may no longer encrypt
+    pub fn encryption_share(&self) -> Result<&KeyShare> {
+        match self.decrypt_only {
+            true => Err(LocksmithError::Refused(format!("version {} of key '{}' is decrypt-only", self.version, self.key_id))),
+            false => Ok(&self.share),
+        }
+    }
+}
+
+/// Where a party takes its share of a key from, for operations that find the key ID and version
+/// in an envelope
+#[derive(Clone, Copy)]
+pub enum KeySource<'a> {
+    /// The built-in AES-128 key, which is the first version of every key ID
+    Builtin,
+    /// This share, whatever the key ID and version
+    Share(&'a KeyShare),
+    /// The key store, with the built-in key for `DEFAULT_KEY_ID` unless it holds a key by that ID
+    Store(&'a KeyStore),
+}
+
+impl KeySource<'_> {
+    /// Party `party`'s share of version `version` of `key_id`
+    pub fn resolve(&self, party: usize, key_id: &str, version: u32) -> Result<KeyShare> {
+        match self {
+            KeySource::Share(share) => Ok((*share).clone()),
+            KeySource::Store(store) if store.contains(key_id) => Ok(store.load_version(key_id, version)?.share),
+            KeySource::Store(_) if key_id != DEFAULT_KEY_ID => {
+                Err(LocksmithError::InvalidInput(format!("no key '{}' in the key store", key_id)))
+            }
+            KeySource::Builtin | KeySource::Store(_) if version == FIRST_VERSION => Ok(KeyShare::builtin(party)),
+            KeySource::Builtin | KeySource::Store(_) => {
+                Err(LocksmithError::InvalidInput(format!("no version {} of key '{}'", version, key_id)))
+            }
+        }
+    }
+}
+
+// On-disk form of a stored key, shares in hex; also the payload of backups
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct KeyFile {
+    key_id: String,
+    version: u32,
+    epoch: u64,
+    decrypt_only: bool,
+    si: String,
+    sii: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    missing: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kcv: Option<String>,
+}
+
+impl KeyFile {
+    pub(crate) fn from_stored(key: &StoredKey) -> Result<Self> {
+        let (si, sii): (&[u8], &[u8]) = key.share.replicated_bytes()
+            .ok_or_else(|| LocksmithError::InvalidInput("only replicated key shares can be stored".to_string()))?;
+        Ok(KeyFile {
+            key_id: key.key_id.clone(),
+            version: key.version,
+            epoch: key.epoch,
+            decrypt_only: key.decrypt_only,
+            si: hex::encode(si),
+            sii: hex::encode(sii),
+            missing: key.missing.map(hex::encode),
+            kcv: key.kcv.map(hex::encode),
+        })
+    }
+
+    /// The stored key; `source` names where the file came from in errors
+    pub(crate) fn into_stored(self, source: &str) -> Result<StoredKey> {
+        if !valid_key_id(&self.key_id) {
+            return Err(LocksmithError::Malformed(format!("{}: invalid key ID '{}'", source, self.key_id)));
+        }
+        let decode = |shares: &str| hex::decode(shares)
+            .map_err(|e| LocksmithError::Malformed(format!("{}: {}", source, e)));
+        let missing: Option<[u8; 32]> = self.missing.as_deref().map(decode).transpose()?
+            .map(|missing| missing.try_into()
+                .map_err(|_| LocksmithError::Malformed(format!("{}: the share hash is not 32 bytes", source))))
+            .transpose()?;
+        let kcv: Option<[u8; KCV_LEN]> = self.kcv.as_deref().map(decode).transpose()?
+            .map(|kcv| kcv.try_into()
+                .map_err(|_| LocksmithError::Malformed(format!("{}: the key check value is not {} bytes", source, KCV_LEN))))
+            .transpose()?;
+        Ok(StoredKey {
+            share: KeyShare::replicated(decode(&self.si)?, decode(&self.sii)?)?,
+            key_id: self.key_id,
+            version: self.version,
+            epoch: self.epoch,
+            decrypt_only: self.decrypt_only,
+            missing,
+            kcv,
+        })
+    }
+}
+
+/// This party's stored keys, as `<key ID>.v<version>.key` files in one directory
+#[derive(Clone, Debug)]
+pub struct KeyStore {
+    dir: PathBuf,
+}
+
+impl KeyStore {
+    /// The key store in `dir`, which is created when the first key is saved
+    pub fn open(dir: &Path) -> Self {
+        KeyStore { dir: dir.to_path_buf() }
+    }
+
+    fn path(&self, key_id: &str, version: u32) -> Result<PathBuf> {
+        if !valid_key_id(key_id) {
+            return Err(LocksmithError::InvalidInput(format!("invalid key ID '{}'", key_id)));
+        }
+        Ok(self.dir.join(format!("{}.v{}.key", key_id, version)))
+    }
+
+    /// The IDs of all stored keys, sorted
+    pub fn key_ids(&self) -> Result<Vec<String>> {
+        let mut key_ids: Vec<String> = fs::read_dir(&self.dir).into_iter().flatten()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter_map(|name| {
+                let (key_id, version): (&str, &str) = name.strip_suffix(".key")?.rsplit_once(".v")?;
+                version.parse::<u32>().ok().filter(|_| valid_key_id(key_id)).map(|_| key_id.to_string())
+            })
+            .collect();
+        key_ids.sort_unstable();
+        key_ids.dedup();
+        Ok(key_ids)
+    }
+
+    pub fn contains(&self, key_id: &str) -> bool {
+        self.versions(key_id).is_ok_and(|versions| !versions.is_empty())
+    }
+
+    /// The stored versions of `key_id`, oldest first
+    pub fn versions(&self, key_id: &str) -> Result<Vec<u32>> {
+        self.path(key_id, FIRST_VERSION)?;
+        let prefix: String = format!("{}.v", key_id);
+        let mut versions: Vec<u32> = fs::read_dir(&self.dir).into_iter().flatten()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter_map(|name| name.strip_prefix(&prefix)?.strip_suffix(".key")?.parse().ok())
+            .collect();
+        versions.sort_unstable();
+        Ok(versions)
+    }
+
+    /// The newest version of `key_id`
+    pub fn load(&self, key_id: &str) -> Result<StoredKey> {
+        let version: u32 = self.versions(key_id)?.last().copied()
+            .ok_or_else(|| LocksmithError::InvalidInput(format!("no key '{}' in {}", key_id, self.dir.display())))?;
+        self.load_version(key_id, version)
+    }
+
+    /// The newest version of `key_id`, or `None` for the built-in key, which only
+    /// `DEFAULT_KEY_ID` names and only while the store holds no key by that ID
+    pub fn lookup(&self, key_id: &str) -> Result<Option<StoredKey>> {
+        match key_id == DEFAULT_KEY_ID && !self.contains(key_id) {
+            true => Ok(None),
+            false => self.load(key_id).map(Some),
+        }
+    }
+
+    pub fn load_version(&self, key_id: &str, version: u32) -> Result<StoredKey> {
+        let path: PathBuf = self.path(key_id, version)?;
+        if !path.exists() {
+            return Err(LocksmithError::InvalidInput(format!("no version {} of key '{}' in {}", version, key_id, self.dir.display())));
+        }
+        let file: KeyFile = serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| LocksmithError::Malformed(format!("key file {}: {}", path.display(), e)))?;
+        if file.key_id != key_id || file.version != version {
+            return Err(LocksmithError::Malformed(format!("key file {} holds version {} of key '{}'", path.display(), file.version, file.key_id)));
+        }
+        file.into_stored(&format!("key file {}", path.display()))
+    }
+
+    /// Every version of every stored key, by key ID and oldest version first
+    pub fn load_all(&self) -> Result<Vec<StoredKey>> {
+        let mut keys: Vec<StoredKey> = Vec::new();
+        for key_id in self.key_ids()? {
+            for version in self.versions(&key_id)? {
+                keys.push(self.load_version(&key_id, version)?);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Writes `key`, replacing any earlier epoch of the same version in one step: the new file
+    /// is synced to disk and then renamed over the old one
+    pub fn save(&self, key: &StoredKey) -> Result<()> {
+        let path: PathBuf = self.path(&key.key_id, key.version)?;
+        let staged: PathBuf = path.with_extension("key.tmp");
+        self.write_synced(key, &staged)?;
+        fs::rename(&staged, &path)?;
+        Ok(())
+    }
+
+    /// Writes a new epoch of `key` next to the stored one, which stays in use until `switch`
+    pub fn stage(&self, key: &StoredKey) -> Result<()> {
+        let path: PathBuf = self.path(&key.key_id, key.version)?;
+        self.write_synced(key, &path.with_extension("key.next"))
+    }
+
+    /// Puts the epoch written by `stage` in use in one step, keeping a copy of the previous one
+    /// until `drop_previous` or `roll_back`
+    pub fn switch(&self, key: &StoredKey) -> Result<()> {
+        let path: PathBuf = self.path(&key.key_id, key.version)?;
+        if path.exists() {
+            let previous: PathBuf = path.with_extension("key.prev");
+            fs::copy(&path, &previous)?;
+            fs::File::open(&previous)?.sync_all()?;
+        }
+        fs::rename(path.with_extension("key.next"), &path)?;
+        Ok(())
+    }
+
+    /// Deletes the previous epoch kept by `switch`
+    pub fn drop_previous(&self, key: &StoredKey) -> Result<()> {
+        let path: PathBuf = self.path(&key.key_id, key.version)?;
+        remove_if_exists(&path.with_extension("key.prev"))
+    }
+
+    /// Goes back to the epoch that was in use before `stage`, whether or not `switch` has run
+    pub fn roll_back(&self, key: &StoredKey) -> Result<()> {
+        let path: PathBuf = self.path(&key.key_id, key.version)?;
+        let (next, previous): (PathBuf, PathBuf) = (path.with_extension("key.next"), path.with_extension("key.prev"));
+        if next.exists() {
+            remove_if_exists(&next)?;
+            remove_if_exists(&previous)
+        } else if previous.exists() {
+            Ok(fs::rename(&previous, &path)?)
+        } else {
+            // The key was new on this party
+            remove_if_exists(&path)
+        }
+    }
+
+    // Writes `key` to `path` and syncs it to disk
+    fn write_synced(&self, key: &StoredKey, path: &Path) -> Result<()> {
+        let file: KeyFile = KeyFile::from_stored(key)?;
+        let text: String = serde_json::to_string_pretty(&file)
+            .map_err(|e| LocksmithError::Malformed(format!("key file: {}", e)))?;
+
+        fs::create_dir_all(&self.dir)?;
+        let mut out: fs::File = fs::File::create(path)?;
+        out.write_all(text.as_bytes())?;
+        out.sync_all()?;
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Replaces the stored epochs of `keys` on all three parties together. Every party first writes
+/// the new epochs next to the ones in use; only once all have written them do the parties switch,
+/// and only once all have switched are the previous epochs deleted. If any party fails, all three
+/// go back to the previous epochs, so no party is left with shares the others do not match.
+pub fn commit_epochs(party: &mut MainParty, store: &KeyStore, keys: &[StoredKey]) -> Result<()> {
+    let staged: Result<()> = keys.iter().try_for_each(|key| store.stage(key));
+    let switched: Result<()> = control::checkpoint(party, staged)
+        .and_then(|()| control::checkpoint(party, keys.iter().try_for_each(|key| store.switch(key))));
+    if let Err(e) = switched {
+        for key in keys {
+            if let Err(undo) = store.roll_back(key) {
+                tracing::warn!(key_id = %key.key_id, version = key.version, "rolling back to the previous epoch failed: {}", undo);
+            }
+        }
+        return Err(e);
+    }
+    keys.iter().try_for_each(|key| store.drop_previous(key))
+}
+
+/// Converts this party's key share (`None` for the built-in key) into replicated shares and
+/// stores them under `key_id`, on all three parties together. Refused if any party already
+/// holds a key with that ID.
+pub fn store_key(config: &Path, store: &KeyStore, key_id: &str, key: Option<&KeyShare>) -> Result<StoredKey> {
+    let mut mpc: Box<dyn MpcParty> = setup_party(config)?;
+    let party: &mut MainParty = mpc.main_party_mut();
+
+    let key: KeyShare = key.cloned().unwrap_or_else(|| KeyShare::builtin(party.i));
+    let valid: Result<()> = match (store.versions(key_id), key.replicated_bytes()) {
+        (Err(e), _) => Err(e),
+        (Ok(_), Some(_)) => Err(LocksmithError::InvalidInput("the key share to store is already replicated".to_string())),
+        (Ok(_), None) => Ok(()),
+    };
+    control::checkpoint(party, valid)?;
+    if !control::agree(party, !store.contains(key_id))? {
+        return Err(LocksmithError::Refused(format!("a party already holds a key '{}'", key_id)));
+    }
+
+    let (share, missing): (KeyShare, [u8; 32]) = replicate(party, &key)?;
+    let kcv: [u8; KCV_LEN] = kcv::key_check_value(&mut *mpc, &share)?;
+    let stored: StoredKey = StoredKey {
+        key_id: key_id.to_string(),
+        version: FIRST_VERSION,
+        epoch: 0,
+        decrypt_only: false,
+        share,
+        missing: Some(missing),
+        kcv: Some(kcv),
+    };
+    store.save(&stored)?;
+    mpc.main_party_mut().teardown()?;
+    Ok(stored)
+}
+
+/// Adds a new version of the stored key `key_id`, of the same length, on all three parties. The
+/// new key is the XOR of a random share from every party, so no party knows it. Older versions
+/// become decrypt-only.
+pub fn rotate_key(config: &Path, store: &KeyStore, key_id: &str) -> Result<StoredKey> {
+    let mut mpc: Box<dyn MpcParty> = setup_party(config)?;
+    let party: &mut MainParty = mpc.main_party_mut();
+
+    let current: StoredKey = control::checkpoint(party, store.load(key_id))?;
+    if !control::all_equal(party, &current.version.to_be_bytes())? {
+        return Err(LocksmithError::Refused(format!("the parties hold different versions of key '{}'", key_id)));
+    }
+
+    let len: usize = match current.share.variant() {
+        AesVariant::Aes128 => 16,
+        AesVariant::Aes256 => 32,
+    };
+    let random: KeyShare = KeyShare::new((0..len).map(|_| rand::random::<u8>()).collect())?;
+    let (share, missing): (KeyShare, [u8; 32]) = replicate(party, &random)?;
+    let kcv: [u8; KCV_LEN] = kcv::key_check_value(&mut *mpc, &share)?;
+    let rotated: StoredKey = StoredKey {
+        version: current.version + 1,
+        epoch: 0,
+        decrypt_only: false,
+        share,
+        missing: Some(missing),
+        kcv: Some(kcv),
+        ..current.clone()
+    };
+
+    // The new version first, so that the key is never left without one that encrypts
+    store.save(&rotated)?;
+    for version in store.versions(key_id)? {
+        let old: StoredKey = store.load_version(key_id, version)?;
+        if version < rotated.version && !old.decrypt_only {
+            store.save(&StoredKey { decrypt_only: true, ..old })?;
+        }
+    }
+    mpc.main_party_mut().teardown()?;
+    Ok(rotated)
+}
+
+/// Refreshes the shares of every version of the stored key `key_id` on all three parties and
+/// stores the new epochs, see `commit_epochs`. Returns the newest version.
+pub fn refresh_key(config: &Path, store: &KeyStore, key_id: &str) -> Result<StoredKey> {
+    let mut mpc: Box<dyn MpcParty> = setup_party(config)?;
+    let party: &mut MainParty = mpc.main_party_mut();
+
+    let mut refreshed: Vec<StoredKey> = refresh_versions(party, store, key_id)?;
+    commit_epochs(party, store, &refreshed)?;
+    party.teardown()?;
+    refreshed.pop().ok_or_else(|| LocksmithError::InvalidInput(format!("no key '{}'", key_id)))
+}
+
+/// Refreshes every stored version of `key_id`, oldest first, once all parties hold the same
+/// versions. Nothing is stored; the caller commits the new epochs with `commit_epochs`.
+pub(crate) fn refresh_versions(party: &mut MainParty, store: &KeyStore, key_id: &str) -> Result<Vec<StoredKey>> {
+    let versions: Vec<u32> = control::checkpoint(party, store.load(key_id).and(store.versions(key_id)))?;
+    let listed: Vec<u8> = versions.iter().flat_map(|version| version.to_be_bytes()).collect();
+    if !control::all_equal(party, &Sha256::digest(&listed))? {
+        return Err(LocksmithError::Refused(format!("the parties hold different versions of key '{}'", key_id)));
+    }
+
+    let mut refreshed: Vec<StoredKey> = Vec::new();
+    for version in versions {
+        let key: StoredKey = control::checkpoint(party, store.load_version(key_id, version))?;
+        refreshed.push(refresh(party, &key)?);
+    }
+    Ok(refreshed)
+}
+
+/// Re-randomises `key` with a fresh replicated sharing of zero and returns the next epoch's
+/// shares; the key itself is unchanged. Aborts on all parties if any check fails.
+pub fn refresh(party: &mut MainParty, key: &StoredKey) -> Result<StoredKey> {
+    let (si, sii): (&[u8], &[u8]) = key.share.replicated_bytes()
+        .ok_or_else(|| LocksmithError::InvalidInput("only stored keys can be refreshed".to_string()))?;
+
+    // All parties must start from the same epoch of the same key
+    let mut header = Sha256::new();
+    header.update(b"locksmith-refresh");
+    header.update((key.key_id.len() as u64).to_be_bytes());
+    header.update(key.key_id.as_bytes());
+    header.update(key.version.to_be_bytes());
+    header.update(key.epoch.to_be_bytes());
+    header.update((si.len() as u64).to_be_bytes());
+    if !control::all_equal(party, &header.finalize())? {
+        return Err(LocksmithError::Refused(format!("the parties hold different epochs of key '{}'", key.key_id)));
+    }
+
+    let i: usize = party.i;
+    let (next, prev): (usize, usize) = ((i + 1) % 3, (i + 2) % 3);
+
+    // Pairwise seeds: our own is shared with the next party, the previous party's with us
+    let mut seed: [u8; 32] = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut seed);
+    control::send_bytes(party, next, &seed);
+    let prev_seed: [u8; 32] = control::receive_bytes(party, prev, 32)?.try_into()
+        .map_err(|_| LocksmithError::Malformed("refresh seed".to_string()))?;
+    let own_stream: Vec<u8> = prg(seed, si.len());
+    let prev_stream: Vec<u8> = prg(prev_seed, si.len());
+
+    // alpha_i, the change to x_i, goes to the previous party, which holds x_i as its second share
+    let alpha: Vec<u8> = own_stream.iter().zip(prev_stream.iter()).map(|(a, b)| a ^ b).collect();
+    let mut to_prev: Vec<GF8> = alpha.iter().map(|&b| GF8(b)).collect();
+    fault::tamper(Fault::ZeroShare, &mut to_prev);
+    control::send_bytes(party, prev, &to_prev.iter().map(|x| x.0).collect::<Vec<u8>>());
+    let alpha_next: Vec<u8> = control::receive_bytes(party, next, si.len())?;
+
+    // alpha_i+1 ^ G(seed_i) must be G(seed_i+1), which the previous party received from the next
+    let expected: Vec<u8> = alpha_next.iter().zip(own_stream.iter()).map(|(a, b)| a ^ b).collect();
+    control::send_bytes(party, prev, &digest(b"locksmith-zero-share", &expected));
+    let claimed: Vec<u8> = control::receive_bytes(party, next, 32)?;
+    let zero_ok: bool = claimed == digest(b"locksmith-zero-share", &prev_stream);
+
+    let new_si: Vec<u8> = si.iter().zip(alpha.iter()).map(|(x, a)| x ^ a).collect();
+    let new_sii: Vec<u8> = sii.iter().zip(alpha_next.iter()).map(|(x, a)| x ^ a).collect();
+    if !control::agree(party, zero_ok)? {
+        return Err(LocksmithError::Abort(AbortReason::CheckFailed));
+    }
+    let missing: [u8; 32] = checked_copies(party, &new_si, &new_sii)?;
+
+    Ok(StoredKey { epoch: key.epoch + 1, share: KeyShare::replicated(new_si, new_sii)?, missing: Some(missing), ..key.clone() })
+}
+
+// Turns an XOR key share into replicated shares and checks the copies
+fn replicate(party: &mut MainParty, key: &KeyShare) -> Result<(KeyShare, [u8; 32])> {
+    let (si, sii): (Vec<u8>, Vec<u8>) = rss::share_key(party, key)?.iter().map(|share| (share.si.0, share.sii.0)).unzip();
+    let missing: [u8; 32] = checked_copies(party, &si, &sii)?;
+    Ok((KeyShare::replicated(si, sii)?, missing))
+}
+
+/// `copies_match` followed by `missing_digest`; aborts on all parties if either check fails
+pub(crate) fn checked_copies(party: &mut MainParty, si: &[u8], sii: &[u8]) -> Result<[u8; 32]> {
+    if !copies_match(party, si, sii)? {
+        return Err(LocksmithError::Abort(AbortReason::CheckFailed));
+    }
+    let missing: Option<[u8; 32]> = missing_digest(party, si, sii)?;
+    match (control::agree(party, missing.is_some())?, missing) {
+        (true, Some(missing)) => Ok(missing),
+        _ => Err(LocksmithError::Abort(AbortReason::CheckFailed)),
+    }
+}
+
+/// Hash over the IDs, versions, epochs and lengths of `keys`, for the parties to check that they
+/// hold the same keys
+pub(crate) fn listing_digest(keys: &[StoredKey]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"locksmith-key-listing");
+    for key in keys {
+        hasher.update((key.key_id.len() as u64).to_be_bytes());
+        hasher.update(key.key_id.as_bytes());
+        hasher.update(key.version.to_be_bytes());
+        hasher.update(key.epoch.to_be_bytes());
+        hasher.update([key.decrypt_only as u8]);
+        hasher.update((key.share.replicated_bytes().map_or(0, |(si, _)| si.len()) as u64).to_be_bytes());
+    }
+    hasher.finalize().into()
+}
+
+/// Checks on all parties that replicated shares are consistent: party i's second share x_i+1 is
+/// the next party's first. Each party sends a hash of its second share to the next party, which
+/// already knows the value. Returns `true` on all parties only if all three copies match.
+pub(crate) fn copies_match(party: &mut MainParty, si: &[u8], sii: &[u8]) -> Result<bool> {
+    let i: usize = party.i;
+    control::send_bytes(party, (i + 1) % 3, &digest(b"locksmith-key-copy", sii));
+    let received: Vec<u8> = control::receive_bytes(party, (i + 2) % 3, 32)?;
+    control::agree(party, received == digest(b"locksmith-key-copy", si))
+}
+
+/// The hash of x_i+2, the share party i does not hold, as reported by both parties that hold it:
+/// the next party as its second share, the previous party as its first. `None` if they differ.
+fn missing_digest(party: &mut MainParty, si: &[u8], sii: &[u8]) -> Result<Option<[u8; 32]>> {
+    let i: usize = party.i;
+    control::send_bytes(party, (i + 1) % 3, &share_digest(si));
+    control::send_bytes(party, (i + 2) % 3, &share_digest(sii));
+    let from_prev: Vec<u8> = control::receive_bytes(party, (i + 2) % 3, 32)?;
+    let from_next: Vec<u8> = control::receive_bytes(party, (i + 1) % 3, 32)?;
+    Ok(from_prev.try_into().ok().filter(|from_prev: &[u8; 32]| from_prev[..] == from_next[..]))
+}
+
+/// The hash a party keeps of a share it does not hold
+pub(crate) fn share_digest(share: &[u8]) -> [u8; 32] {
+    digest(b"locksmith-missing-share", share)
+}
+
+fn digest(label: &[u8], bytes: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(label);
+    hasher.update(bytes);
+    hasher.finalize().into()
+}
+
+// Expands a pairwise seed into `len` bytes
+fn prg(seed: [u8; 32], len: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![0u8; len];
+    StdRng::from_seed(seed).fill_bytes(&mut bytes);
+    bytes
+}
@@ -0,0 +1,324 @@
+use maestro::aes::VectorAesState;
+use maestro::rep3_core::{party::MainParty, share::RssShare};
+use maestro::share::gf8::GF8;
+
+use crate::aes::AesVariant;
+use crate::aes::ss::aes_no_keyschedule_mal;
+use crate::data::Input;
+use crate::error::{LocksmithError, Result};
+use crate::metrics;
+use super::access::{Access, Operation};
+use super::backend::MpcParty;
+use super::kcv::{self, KCV_LEN};
+use super::keystore::{self, KeyStore, StoredKey};
+use super::nonce::{CounterRange, NonceLedger};
+use super::{control, preprocessing_units, reveal_to, rss, KeyShare};
+
+/// Most blocks a single keystream request may ask for; longer data is served in several
+/// requests, as `stream` does
+pub const MAX_REQUEST_BLOCKS: u64 = 1 << 20;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Nonce {
+    /// Chosen by the client
+    Fixed(u128),
+    /// Allocated by the parties from their nonce ledger
+    Auto,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Purpose {
+    /// Counters must be unused and are recorded in the ledger
+    Encrypt,
+    /// Counters must already be recorded in the ledger
+    Decrypt,
+}
+
+/// Request for the keystream blocks AES_k(nonce + counter) for counter in `start..start + blocks`.
+/// Counter blocks are 128-bit big-endian and wrap around, as in `Input::from_nonce`. At most
+/// `MAX_REQUEST_BLOCKS` are served per request.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KeystreamRequest {
+    pub nonce: Nonce,
+    pub start: u128,
+    pub blocks: u64,
+    pub purpose: Purpose,
+}
+
+impl KeystreamRequest {
+    const ENCODED_LEN: usize = 1 + 16 + 16 + 8 + 1;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (tag, nonce): (u8, u128) = match self.nonce {
+            Nonce::Fixed(nonce) => (0, nonce),
+            Nonce::Auto => (1, 0),
+        };
+        let mut bytes: Vec<u8> = Vec::with_capacity(Self::ENCODED_LEN);
+        bytes.push(tag);
+        bytes.extend_from_slice(&nonce.to_be_bytes());
+        bytes.extend_from_slice(&self.start.to_be_bytes());
+        bytes.extend_from_slice(&self.blocks.to_be_bytes());
+        bytes.push(match self.purpose {
+            Purpose::Encrypt => 0,
+            Purpose::Decrypt => 1,
+        });
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::ENCODED_LEN {
+            return None;
+        }
+        let nonce: Nonce = match bytes[0] {
+            0 => Nonce::Fixed(u128::from_be_bytes(bytes[1..17].try_into().ok()?)),
+            1 => Nonce::Auto,
+            _ => return None,
+        };
+        Some(KeystreamRequest {
+            nonce,
+            start: u128::from_be_bytes(bytes[17..33].try_into().ok()?),
+            blocks: u64::from_be_bytes(bytes[33..41].try_into().ok()?),
+            purpose: match bytes[41] {
+                0 => Purpose::Encrypt,
+                1 => Purpose::Decrypt,
+                _ => return None,
+            },
+        })
+    }
+}
+
+/// Result of a served request: the nonce that was used, and the keystream on the client
+pub struct Served {
+    pub nonce: u128,
+    pub keystream: Option<Vec<u8>>,
+}
+
+/// Computes CTR keystream inside MPC and hands it to a single client party.
+///
+/// The counter blocks are public, so they enter the computation as constants and the plaintext
+/// never has to be shared: the client XORs the keystream with its data locally.
+/// Each counter block is served at most once per key for encryption: every party checks the
+/// request against its nonce ledger, the parties confirm that their ledgers are identical, and a
+/// request that overlaps an earlier one is refused by all three before any AES work starts, the
+/// key schedule included.
+/// Keystream for decryption is only served for counters that the ledger has already recorded
+/// under the same key version, so decryption requests cannot be used to encrypt under a fresh or
+/// reused nonce, nor under counters another version encrypted with.
+pub struct KeystreamService {
+    party: Box<dyn MpcParty>,
+    key: KeyShare,
+    // The version of `key`, which the ledger records with every range
+    version: u32,
+    // Computed on the first request the ledger admits
+    ks: Option<Vec<maestro::aes::AesKeyState>>,
+    variant: AesVariant,
+    client: usize,
+    ledger: NonceLedger,
+    // Set by `authorized`: only encryption was authorized, and every request is audited
+    access: Option<Access>,
+}
+
+impl KeystreamService {
+    /// Starts serving with `key`, this party's share of version `version` of the ledger's key,
+    /// `None` for the built-in AES-128 key. The key schedule is computed once, for the first
+    /// request the ledger admits, and kept for all later ones.
+    pub fn start(mut party: Box<dyn MpcParty>, client: usize, ledger: NonceLedger, key: Option<&KeyShare>, version: u32) -> Result<Self> {
+        let valid: Result<()> = match client {
+            0..=2 => Ok(()),
+            _ => Err(LocksmithError::InvalidInput(format!("invalid client party {}", client))),
+        };
+        control::checkpoint(party.main_party_mut(), valid)?;
+        let key: KeyShare = key.cloned().unwrap_or_else(|| KeyShare::builtin(party.main_party_mut().i));
+
+        Ok(KeystreamService { party, variant: key.variant(), key, version, ks: None, client, ledger, access: None })
+    }
+
+    /// Like `start`, once every party's access policy allows the data owner to encrypt under the
+    /// ledger's key ID. The service then only serves encryption requests, and records each of
+    /// them in the audit log.
+    pub fn authorized(
+        mut party: Box<dyn MpcParty>,
+        client: usize,
+        ledger: NonceLedger,
+        key: Option<&KeyShare>,
+        version: u32,
+        access: &Access,
+    ) -> Result<Self> {
+        let authorized: Result<()> = access.authorize(party.main_party_mut(), ledger.key_id(), Operation::Encrypt);
+        if authorized.is_err() {
+            access.record(ledger.key_id(), Operation::Encrypt, 0, None, &authorized)?;
+        }
+        authorized?;
+        let mut service: KeystreamService = Self::start(party, client, ledger, key, version)?;
+        service.access = Some(access.clone());
+        Ok(service)
+    }
+
+    pub fn is_client(&mut self) -> bool {
+        self.party.main_party_mut().i == self.client
+    }
+
+    /// Gives a control channel to the other parties, e.g. to agree on request sizes
+    pub fn main_party_mut(&mut self) -> &mut MainParty {
+        self.party.main_party_mut()
+    }
+
+    /// Serves one request. The client passes `Some(request)` and gets the keystream back;
+    /// the other parties pass `None` and learn only the request header and the nonce used.
+    pub fn serve(&mut self, request: Option<KeystreamRequest>) -> Result<Served> {
+        let (nonce, range): (u128, CounterRange) = self.admit(request, self.version)?;
+        let keystream: Result<Option<Vec<u8>>> = self.shared_keystream(None, &range)
+            .and_then(|output| self.open_to_client(&output));
+        self.record(Some(range), range.blocks, &keystream)?;
+        Ok(Served { nonce, keystream: keystream? })
+    }
+
+    // Records a request in the audit log of an authorized service
+    fn record<T>(&self, range: Option<CounterRange>, blocks: u64, result: &Result<T>) -> Result<()> {
+        match &self.access {
+            Some(access) => access.record(self.ledger.key_id(), Operation::Encrypt, blocks, range, result),
+            None => Ok(()),
+        }
+    }
+
+    /// Agrees on the client's request and checks it against the ledger on every party, recording
+    /// the counters of an encryption request under version `version` of the key. Returns the
+    /// nonce and the counter range.
+    pub(crate) fn admit(&mut self, request: Option<KeystreamRequest>, version: u32) -> Result<(u128, CounterRange)> {
+        let header: Vec<u8> = control::broadcast(
+            self.party.main_party_mut(),
+            self.client,
+            request.map(|r| r.to_bytes()).as_deref(),
+        )?;
+        let request: KeystreamRequest = KeystreamRequest::from_bytes(&header)
+            .ok_or_else(|| LocksmithError::Malformed("keystream request".to_string()))?;
+
+        // Ledgers that have drifted apart cannot be trusted to allocate or to detect reuse
+        let in_sync: bool = control::all_equal(self.party.main_party_mut(), &self.ledger.digest())?;
+        let nonce: Option<u128> = match (request.nonce, request.purpose) {
+            (Nonce::Fixed(nonce), _) => Some(nonce),
+            (Nonce::Auto, Purpose::Encrypt) => self.ledger.allocate(request.start, request.blocks),
+            (Nonce::Auto, Purpose::Decrypt) => None,
+        };
+        let range: Option<CounterRange> = nonce
+            .map(|nonce| CounterRange { first: nonce.wrapping_add(request.start), blocks: request.blocks, version });
+        // Every party bounds the work and memory of a request before it is approved or recorded
+        let within_limit: bool = request.blocks <= MAX_REQUEST_BLOCKS;
+        let allowed: bool = within_limit && match (range, request.purpose) {
+            (Some(range), Purpose::Encrypt) => in_sync && range.blocks > 0 && !self.ledger.overlaps(&range),
+            // An authorized service was only authorized to encrypt
+            (Some(_), Purpose::Decrypt) if self.access.is_some() => false,
+            (Some(range), Purpose::Decrypt) => in_sync && range.blocks > 0 && self.ledger.covers(&range),
+            (None, _) => false,
+        };
+        let approved: bool = control::agree(self.party.main_party_mut(), allowed)?;
+        let (true, Some(nonce), Some(range)) = (approved, nonce, range) else {
+            let refused: Result<(u128, CounterRange)> = Err(LocksmithError::Refused(match (in_sync, range) {
+                _ if !within_limit => format!(
+                    "keystream request for {} blocks exceeds the limit of {} blocks per request",
+                    request.blocks,
+                    MAX_REQUEST_BLOCKS,
+                ),
+                (false, _) => format!("nonce ledgers for key '{}' differ between the parties", self.ledger.key_id()),
+                (true, None) => format!("no nonce available for key '{}'", self.ledger.key_id()),
+                (true, Some(range)) => format!(
+                    "keystream request ({:?}) for {} blocks from counter 0x{:032x} refused by the ledger or by another party",
+                    request.purpose,
+                    range.blocks,
+                    range.first,
+                ),
+            }));
+            self.record(range, request.blocks, &refused)?;
+            return refused;
+        };
+
+        // Recorded before the computation so that a failed run cannot be retried with the same counters
+        if request.purpose == Purpose::Encrypt {
+            self.ledger.record(&range)?;
+        }
+
+        Ok((nonce, range))
+    }
+
+    // Computes the key schedule of the service's key unless it is cached
+    fn schedule(&mut self) -> Result<()> {
+        if self.ks.is_none() {
+            self.ks = Some(rss::key_schedule::keyshare_keyschedule(&mut *self.party, &self.key)?);
+        }
+        Ok(())
+    }
+
+    /// Computes the key schedule of another key of the same variant, for `shared_keystream`
+    pub(crate) fn key_schedule(&mut self, key: &KeyShare) -> Result<Vec<maestro::aes::AesKeyState>> {
+        let matches: Result<()> = match key.variant() == self.variant {
+            true => Ok(()),
+            false => Err(LocksmithError::InvalidInput(format!("expected a {:?} key", self.variant))),
+        };
+        control::checkpoint(self.party.main_party_mut(), matches)?;
+        rss::key_schedule::keyshare_keyschedule(&mut *self.party, key)
+    }
+
+    /// Shared keystream for the counters in `range`, under the service's key or under the key
+    /// schedule `other` from `key_schedule`. Nothing is checked or opened yet.
+    pub(crate) fn shared_keystream(&mut self, other: Option<&[maestro::aes::AesKeyState]>, range: &CounterRange) -> Result<VectorAesState> {
+        if other.is_none() {
+            self.schedule()?;
+        }
+        let num_blocks: usize = range.blocks as usize;
+        let counters: Input = Input::from_nonce(range.first, num_blocks);
+        let input_bytes: Vec<RssShare<GF8>> = counters.iter().map(|&b| self.party.constant_rss(GF8(b))).collect();
+        let input_ss: VectorAesState = VectorAesState::from_bytes(input_bytes);
+
+        let (_, n_blocks): (usize, usize) = preprocessing_units(self.variant, 0, num_blocks);
+        metrics::measure(&mut *self.party, "preprocessing", |party| party.do_preprocessing(0, n_blocks))?;
+        let ks: &[maestro::aes::AesKeyState] = other.or(self.ks.as_deref()).unwrap_or_default();
+        Ok(metrics::measure(&mut *self.party, "aes", |party| aes_no_keyschedule_mal(party, input_ss, ks))?)
+    }
+
+    /// Checks the computation so far and opens `output` to the client
+    pub(crate) fn open_to_client(&mut self, output: &VectorAesState) -> Result<Option<Vec<u8>>> {
+        // Check the computation before anything is opened; if any party's check fails, all abort
+        let checked: Result<()> = metrics::measure(&mut *self.party, "check", |party| party.finalize().map_err(LocksmithError::from));
+        control::checkpoint(self.party.main_party_mut(), checked)?;
+        reveal_to(self.party.main_party_mut(), output, self.client)
+    }
+
+    /// Computes the key check value of the service's key and checks it against the `stored` one
+    /// on all parties, see `kcv::verify`
+    pub fn check_key(&mut self, key_id: &str, stored: Option<[u8; KCV_LEN]>) -> Result<[u8; KCV_LEN]> {
+        self.schedule()?;
+        let kcv: [u8; KCV_LEN] = kcv::schedule_check_value(&mut *self.party, self.ks.as_deref().unwrap_or_default(), self.variant)?;
+        kcv::verify(self.party.main_party_mut(), key_id, kcv, stored)?;
+        Ok(kcv)
+    }
+
+    /// Refreshes the stored shares of every version of the key this service uses, like
+    /// `keystore::refresh_key`, and recomputes the key schedule from the new shares of its own
+    /// version. The stored shares and the cached key schedule are only replaced once all of them
+    /// have passed all checks. Returns the new epoch of the version this service uses.
+    pub fn refresh(&mut self, store: &KeyStore, key_id: &str) -> Result<u64> {
+        let stored: Result<()> = store.load_version(key_id, self.version).and_then(|stored| match stored.share == self.key {
+            true => Ok(()),
+            false => Err(LocksmithError::InvalidInput(format!("key '{}' is not the stored key this session uses", key_id))),
+        });
+        control::checkpoint(self.party.main_party_mut(), stored)?;
+        let refreshed: Vec<StoredKey> = keystore::refresh_versions(self.party.main_party_mut(), store, key_id)?;
+        let own: Result<StoredKey> = refreshed.iter().find(|key| key.version == self.version).cloned()
+            .ok_or_else(|| LocksmithError::InvalidInput(format!("no version {} of key '{}'", self.version, key_id)));
+        let own: StoredKey = control::checkpoint(self.party.main_party_mut(), own)?;
+
+        let ks: Vec<maestro::aes::AesKeyState> = rss::key_schedule::keyshare_keyschedule(&mut *self.party, &own.share)?;
+        let checked: Result<()> = self.party.finalize().map_err(LocksmithError::from);
+        control::checkpoint(self.party.main_party_mut(), checked)?;
+
+        keystore::commit_epochs(self.party.main_party_mut(), store, &refreshed)?;
+        self.key = own.share;
+        self.ks = Some(ks);
+        Ok(own.epoch)
+    }
+
+    pub fn shutdown(mut self) -> Result<()> {
+        self.party.main_party_mut().teardown()?;
+        Ok(())
+    }
+}
@@ -1,138 +1,445 @@
-use crate::data::Input;
+use crate::config::{certs::CertsCommand, ConfigCommand, PartyConfig};
+use crate::data::{Ciphertext, Input};
+use crate::error::{AbortReason, LocksmithError, Result};
 pub mod rss;
-
-use clap::{Parser, ValueEnum};
-use std::path::PathBuf;
+pub mod stream;
+pub mod keystream;
+pub mod nonce;
+pub mod decrypt;
+pub mod fault;
+pub mod daemon;
+pub mod keystore;
+pub mod reencrypt;
+pub mod reshare;
+pub mod backup;
+pub mod kcv;
+pub mod import;
+pub mod access;
+pub mod audit;
+pub mod bench;
+pub mod backend;
+pub mod parallel;
+pub(crate) mod control;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use maestro::aes::VectorAesState;
-use crate::aes::{AesKeyState, ss::aes128_no_keyschedule_mal};
+use crate::aes::{AesVariant, ss::aes_no_keyschedule_mal};
 
-use maestro::{rep3_core::{network::{Config, ConnectedParty}, party::{error::MpcResult, CombinedCommStats}, share::RssShare}, lut256::lut256_ss::{Lut256SSMalParty}};
-use maestro::aes::ss::GF8InvBlackBoxSSMal;
+use maestro::rep3_core::{network::{Config, ConnectedParty, Tls}, party::MainParty, share::RssShare};
 use maestro::share::gf8::GF8;
 
+use crate::logging::LogFormat;
+use crate::metrics::{self, Phase};
+use access::{Access, Operation};
+use backend::{Backend, MpcParty};
+use bench::{BenchConfig, Workload};
+use keystream::{KeystreamRequest, KeystreamService, Nonce, Purpose, Served};
+use nonce::NonceLedger;
+pub use rss::KeyShare;
+
+/// The party that provides plaintext input and receives CTR keystream
+pub const DATA_OWNER: usize = 0;
+
+/// Converts key schedules and blocks of `variant` into the units `do_preprocessing` expects,
+/// which are AES-128 key schedules (40 S-boxes) and AES-128 blocks (160 S-boxes)
+pub(crate) fn preprocessing_units(variant: AesVariant, n_keys: usize, n_blocks: usize) -> (usize, usize) {
+    let aes128: AesVariant = AesVariant::Aes128;
+    (
+        (n_keys * variant.n_ks_sboxes()).div_ceil(aes128.n_ks_sboxes()),
+        (n_blocks * variant.n_rounds()).div_ceil(aes128.n_rounds()),
+    )
+}
 
 #[derive(Parser)]
-struct Cli {
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// This party's config file, see `config init`
+    #[arg(long, value_name = "FILE", required = true)]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = Mode::Ecb)]
+    pub mode: Mode,
+
+    /// Format of the log on stderr; the level is set with LOCKSMITH_LOG [default: info]
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+
+    /// Initial counter block for CTR modes; allocated from the nonce ledger if omitted
+    #[arg(long)]
+    pub nonce: Option<u128>,
+
+    /// Key to use, also selects the nonce ledger; must be in the key store, except that the
+    /// default ID names the built-in key while no key by that ID is stored
+    #[arg(long, default_value = nonce::DEFAULT_KEY_ID)]
+    pub key_id: String,
+
+    /// Directory holding this party's stored key shares [default: keystore/<config file stem>]
+    #[arg(long, value_name = "DIR")]
+    pub keystore: Option<PathBuf>,
+
+    /// Directory holding this party's nonce ledgers [default: ledger/<config file stem>]
+    #[arg(long, value_name = "DIR")]
+    pub ledger_dir: Option<PathBuf>,
+
+    /// File to encrypt in `ctr` and `ctr-stream` mode (data owner only)
     #[arg(long, value_name = "FILE")]
-    config: PathBuf,
-}
+    pub input: Option<PathBuf>,
 
-fn setup_party() -> ConnectedParty {
-    let cli: Cli = Cli::parse();
+    /// Where to write the ciphertext in `ctr-stream` mode, the plaintext in `decrypt` mode, or the
+    /// new envelope in `reencrypt` mode (data owner only), or the results as JSON in `bench` mode
+    #[arg(long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
 
-    let (party_index, config) = Config::from_file(&cli.config)
-        .expect("Failed to load config");
-    
-    let conn: ConnectedParty = ConnectedParty::bind_and_connect(
-        party_index,
-        config.clone(),
-        Some(Duration::from_secs(60)),
-    ).unwrap();
+    /// Envelope written by `ecb` and `ctr` mode and read by `decrypt` mode (data owner only);
+    /// JSON if the file name ends in `.json`, binary otherwise
+    #[arg(long, value_name = "FILE")]
+    pub envelope: Option<PathBuf>,
 
-    conn
-}
+    /// Directory the data owner's daemon takes `*.in` files to encrypt from
+    #[arg(long, value_name = "DIR")]
+    pub spool: Option<PathBuf>,
 
-pub fn print_gf8_bytes(bytes: &Vec<GF8>) {
-    for (i, chunk) in bytes.chunks(16).enumerate() {
-        // raw representation
-        // println!("- Block {} : {:?}", i, chunk);
-        // hex representation
-        println!("- Block {} : 0x{}", i, chunk.iter().map(|x| format!("{:02x}", x.0)).collect::<Vec<_>>().join(""));
-        // binary representation
-        // println!("- Block {} : [{}]", i, chunk.iter().map(|x| format!("{:08b}", x.0)).collect::<Vec<_>>().join(", "));
-    }
-}
+    /// Seconds between share refreshes of a stored key in `daemon` mode (data owner only)
+    #[arg(long, value_name = "SECONDS")]
+    pub refresh_every: Option<u64>,
 
-pub fn encrypt_ecb(data_input: &Input) -> Result<String, Box<dyn std::error::Error>> {
-    let conn: ConnectedParty = setup_party();
+    /// Party (1, 2 or 3) whose host was replaced, in `reshare` mode
+    #[arg(long, value_name = "N")]
+    pub replaced_party: Option<usize>,
 
-    let mut party: Lut256SSMalParty = Lut256SSMalParty::setup(conn, true, Some(0), Some("".to_string())).unwrap();
-    println!("After setup");
+    /// Plaintext key to import in `import-key` mode, 16 or 32 bytes in hex (data owner only)
+    #[arg(long, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
 
-    // Preprocessing
-    // 3 parties need preprocessed triples to generate the key schedule
-    // so 98 blocks of preprocessed OHV
-    println!("Preprocessing with {} blocks", data_input.num_blocks());
-    party.do_preprocessing(3, 95).unwrap(); // 95 or data_input.num_blocks()
+    /// Backup to restore this party's key store from in `restore` mode, see `backup export`
+    #[arg(long, value_name = "FILE")]
+    pub backup: Option<PathBuf>,
 
-    // Print the combined key – no parties know this, only server does
-    // println!("Symmetric key: {:?}", rss::key_schedule::get_combined_key());
+    /// Recovery private key that opens `--backup`
+    #[arg(long, value_name = "FILE", conflicts_with = "custodian_share")]
+    pub recovery_key: Option<PathBuf>,
 
-    let input_ss: VectorAesState = rss::share_input::rss_input(data_input, &mut party).unwrap();
-    
-    println!("Local input bytes as hex: 0x{}", input_ss.to_bytes().iter()
-        .map(|x| format!("{:02x}", x.si.0 ^ x.sii.0))
-        .collect::<Vec<_>>()
-        .join(""));
+    /// Custodian share that opens `--backup`; repeat up to the threshold
+    #[arg(long, value_name = "FILE")]
+    pub custodian_share: Vec<PathBuf>,
 
-    let ks: Vec<maestro::aes::AesKeyState> = rss::key_schedule::keyshare_keyschedule(&mut party);
-    let output: VectorAesState = aes128_no_keyschedule_mal(&mut party, input_ss, &ks).unwrap();
+    /// Number of 16-byte blocks per batch in `ctr-stream` mode
+    #[arg(long, default_value_t = stream::DEFAULT_CHUNK_BLOCKS)]
+    pub chunk_blocks: usize,
 
-    party.finalize().unwrap();
+    /// Where to write the time, traffic and rounds of every protocol phase of the run as JSON
+    #[arg(long, value_name = "FILE")]
+    pub metrics: Option<PathBuf>,
 
-    let output = output.to_bytes();
-    let (output_i, output_ii): (Vec<_>, Vec<_>) = output.into_iter().map(|rss: RssShare<GF8>| (rss.si, rss.sii)).unzip();
-    let ciphertext: Vec<maestro::share::gf8::GF8> = party.output(&output_i, &output_ii).unwrap();
+    /// Address to serve Prometheus metrics on at `/metrics` in `daemon` mode, e.g. 127.0.0.1:9464
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<String>,
+
+    /// Backends to run in `bench` mode, whatever the config names
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Backend::Lut256SsMal, Backend::Lut256Ss, Backend::Lut256, Backend::Gf4Circuit])]
+    pub bench_backends: Vec<Backend>,
 
-    // print_gf8_bytes(&ciphertext);
+    /// Workloads to run in `bench` mode
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Workload::Ecb, Workload::Ctr, Workload::KeySchedule, Workload::Preprocessing])]
+    pub bench_workloads: Vec<Workload>,
 
-    party.main_party_mut().teardown().unwrap();
+    /// Numbers of 16-byte blocks to run every workload on in `bench` mode, up to 1048576
+    #[arg(long, value_delimiter = ',', default_values_t = [1, 16, 256, 4096])]
+    pub bench_blocks: Vec<usize>,
 
-    // Convert to hex string
-    Ok(ciphertext.iter()
-        .map(|x| format!("{:02x}", x.0))
-        .collect::<String>())
+    /// Runs of every case before the measured ones in `bench` mode
+    #[arg(long, default_value_t = 1)]
+    pub warmup: usize,
+
+    /// Measured runs of every case in `bench` mode
+    #[arg(long, default_value_t = 5)]
+    pub repetitions: usize,
 }
 
+#[derive(Subcommand)]
+pub enum Command {
+    /// Generate and check party network configs
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Generate and rotate the TLS certificates of the party links
+    #[command(subcommand)]
+    Certs(CertsCommand),
+    /// Back up a party's key store to an offline recovery key or to custodians
+    #[command(subcommand)]
+    Backup(backup::BackupCommand),
+}
 
-pub fn encrypt_ctr(data_input: &Input, nonce: u128) -> Result<String, Box<dyn std::error::Error>> {
-    let conn: ConnectedParty = setup_party();
+impl Cli {
+    /// The `--config` file; clap requires it unless a subcommand is given
+    pub fn config_path(&self) -> &Path {
+        self.config.as_deref().expect("--config is required")
+    }
 
-    let mut party: Lut256SSMalParty = Lut256SSMalParty::setup(conn, true, Some(0), Some("".to_string())).unwrap();
-    println!("After setup");
+    /// The cases to run in `bench` mode
+    pub fn bench_config(&self) -> BenchConfig {
+        BenchConfig {
+            backends: self.bench_backends.clone(),
+            workloads: self.bench_workloads.clone(),
+            blocks: self.bench_blocks.clone(),
+            warmup: self.warmup,
+            repetitions: self.repetitions,
+        }
+    }
 
-    party.do_preprocessing(3, 95).unwrap(); // 95 or data_input.num_blocks()
-    let nonce_input: Input = Input::from_nonce(nonce, data_input.num_blocks());
-    let input_ss: VectorAesState = rss::share_input::rss_input(&nonce_input, &mut party).unwrap();
+    pub fn ledger_dir(&self) -> PathBuf {
+        self.ledger_dir.clone().unwrap_or_else(|| party_dir("ledger", self.config_path()))
+    }
 
-    println!("Local input bytes as hex: 0x{}", input_ss.to_bytes().iter()
-        .map(|x| format!("{:02x}", x.si.0 ^ x.sii.0))
-        .collect::<Vec<_>>()
-        .join(""));
+    pub fn keystore_dir(&self) -> PathBuf {
+        self.keystore.clone().unwrap_or_else(|| party_dir("keystore", self.config_path()))
+    }
+}
 
-    let ks: Vec<maestro::aes::AesKeyState> = rss::key_schedule::keyshare_keyschedule(&mut party);
-    let output: VectorAesState = aes128_no_keyschedule_mal(&mut party, input_ss, &ks).unwrap();
+/// Default per-party directory under `base`, named after the config file's stem
+pub fn party_dir(base: &str, config: &Path) -> PathBuf {
+    PathBuf::from(base).join(config.file_stem().unwrap_or_default())
+}
 
-    party.finalize().unwrap();
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+pub enum Mode {
+    Ecb,
+    Ctr,
+    /// CTR over a file of any size, read and written in windows
+    CtrStream,
+    /// Decrypt the envelope given with `--envelope`
+    Decrypt,
+    /// Keep serving CTR encryption jobs, reconnecting after failures; the data owner takes jobs
+    /// from `--spool`
+    Daemon,
+    /// Store the built-in key as replicated shares under `--key-id`
+    StoreKey,
+    /// Compute the key check value of `--key-id` and check it against the stored one
+    Kcv,
+    /// Store the plaintext key in `--key-file` as replicated shares under `--key-id`, checked by
+    /// its key check value
+    ImportKey,
+    /// Re-randomise the stored shares of `--key-id`
+    Refresh,
+    /// Add a new version of the stored key `--key-id`; older versions become decrypt-only
+    RotateKey,
+    /// Re-encrypt the envelope given with `--envelope` under the newest version of its key,
+    /// writing the new envelope to `--output`
+    Reencrypt,
+    /// Hand the stored keys and nonce ledgers to the new host of `--replaced-party`, see
+    /// `config replace`
+    Reshare,
+    /// Restore this party's key store from `--backup`, checked by the other two parties, which
+    /// run this mode without `--backup`
+    Restore,
+    /// Check this party's audit log and that the three parties' logs agree
+    Audit,
+    /// Measure throughput, latency and communication of the protocols, see the `--bench-*`
+    /// options; every party prints its own measurements
+    Bench,
+}
 
-    let output = output.to_bytes();
-    let (output_i, output_ii): (Vec<_>, Vec<_>) = output.into_iter().map(|rss: RssShare<GF8>| (rss.si, rss.sii)).unzip();
-    let output_bytes: Vec<maestro::share::gf8::GF8> = party.output(&output_i, &output_ii).unwrap();
-    // print_gf8_bytes(&output_bytes);
+/// Loads and validates this party's config file, connects to the other two parties and sets up
+/// the configured backend on every channel
+pub(crate) fn setup_party(config_path: &Path) -> Result<Box<dyn MpcParty>> {
+    let party_config: PartyConfig = crate::config::load(config_path)?;
+    let conn: ConnectedParty = connect(&party_config, Some(party_config.network.connect_attempts))?;
+    parallel::setup(&party_config, party_config.backend, conn)
+}
 
-    party.main_party_mut().teardown().unwrap();
+/// Like `setup_party`, along with this party's access policy for the client the data owner
+/// authenticated as when the parties connected
+pub(crate) fn setup_with_access(config_path: &Path) -> Result<(Access, Box<dyn MpcParty>)> {
+    let party_config: PartyConfig = crate::config::load(config_path)?;
+    let conn: ConnectedParty = connect(&party_config, Some(party_config.network.connect_attempts))?;
+    let access: Access = Access::connected(&party_config, &conn)?;
+    Ok((access, parallel::setup(&party_config, party_config.backend, conn)?))
+}
 
-    // XOR output_bytes with data_input bytes
-    let input_data_bytes: Vec<u8> = data_input.to_bytes(); //Input::zero(data_input.num_blocks()).to_bytes();
-    println!("Input data bytes as hex: 0x{}", input_data_bytes.iter()
-        .map(|x| format!("{:02x}", x))
-        .collect::<String>());
-    
-    println!("Output bytes as hex: 0x{}", output_bytes.iter()
-        .map(|x| format!("{:02x}", x.0))
-        .collect::<String>());
+/// Connects to the other two parties, retrying with exponential backoff until `attempts`
+/// attempts have failed, or forever if `attempts` is `None`
+pub(crate) fn connect(party_config: &PartyConfig, attempts: Option<u32>) -> Result<ConnectedParty> {
+    let _phase: Phase = metrics::start_timed("connect");
+    let mut attempt: u32 = 1;
+    loop {
+        let (party_index, config): (usize, Config) = party_config.to_maestro()?;
+        match ConnectedParty::bind_and_connect(party_index, config, Some(party_config.network.connect_timeout())) {
+            Ok(conn) => {
+                check_peers(party_config, &conn)?;
+                tracing::debug!(attempt, "connected to both parties");
+                return Ok(conn);
+            }
+            Err(e) if attempts.is_none_or(|attempts| attempt < attempts) => {
+                let delay: Duration = party_config.network.retry_delay(attempt);
+                tracing::warn!(party = party_index, attempt, ?delay, "could not connect: {}; retrying", e);
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(LocksmithError::Network(format!("party {} could not connect after {} attempts: {}", party_index, attempt, e)));
+            }
+        }
+    }
+}
 
-    let ciphertext: Vec<maestro::share::gf8::GF8> = output_bytes.iter().zip(input_data_bytes.iter())
-        .map(|(out, data)| maestro::share::gf8::GF8(out.0 ^ data))
-        .collect();
+// Checks that each peer presented one of the certificates configured for its slot. With a CA,
+// maestro accepts any certificate the CA issued in every slot, so this is what keeps one party
+// from taking another's place, or a replaced host from coming back.
+fn check_peers(party_config: &PartyConfig, conn: &ConnectedParty) -> Result<()> {
+    let i: usize = party_config.party_index - 1;
+    for (peer, link) in [((i + 1) % 3, &conn.comm_next), ((i + 2) % 3, &conn.comm_prev)] {
+        let presented: String = peer_certificate(link)
+            .map(|der| crate::config::certificate_fingerprint(&der))
+            .ok_or_else(|| LocksmithError::Refused(format!("party {} presented no certificate", peer + 1)))?;
+        if !party_config.accepted_fingerprints(peer)?.contains(&presented) {
+            return Err(LocksmithError::Refused(format!(
+                "party {} presented certificate {}, which is not configured for it", peer + 1, presented
+            )));
+        }
+    }
+    Ok(())
+}
 
+/// The end-entity certificate the peer on `link` presented in the TLS handshake
+pub(crate) fn peer_certificate(link: &Tls) -> Option<Vec<u8>> {
+    // The end-entity certificate comes first
+    match link {
+        Tls::Client(stream) => stream.conn.peer_certificates().and_then(|chain| chain.first()).map(|der| der.to_vec()),
+        Tls::Server(stream) => stream.conn.peer_certificates().and_then(|chain| chain.first()).map(|der| der.to_vec()),
+    }
+}
 
-    // Convert to hex string
-    Ok(ciphertext.iter()
-        .map(|x| format!("{:02x}", x.0))
-        .collect::<String>())
+/// Checks on every party that the data owner, and only the data owner, provides `what`; all
+/// three abort together otherwise
+pub(crate) fn check_owner(party: &mut MainParty, provides: bool, what: &str) -> Result<()> {
+    let owner: Result<()> = match (party.i == DATA_OWNER) == provides {
+        true => Ok(()),
+        false => Err(LocksmithError::InvalidInput(format!("only the data owner (party 0) provides {}", what))),
+    };
+    control::checkpoint(party, owner)
 }
 
-    
+/// Opens `state` to party `client` only. The two other parties each send the share the client is
+/// missing, and the client aborts if the two copies disagree. Returns `None` on the other parties.
+pub(crate) fn reveal_to(party: &mut MainParty, state: &VectorAesState, client: usize) -> Result<Option<Vec<u8>>> {
+    let phase: Phase = metrics::start("output", party);
+    let shares: Vec<RssShare<GF8>> = state.to_bytes();
+    let i: usize = party.i;
+
+    if i == client {
+        let from_next: Vec<u8> = control::receive_bytes(party, (i + 1) % 3, shares.len())?;
+        let from_prev: Vec<u8> = control::receive_bytes(party, (i + 2) % 3, shares.len())?;
+        if from_next != from_prev {
+            return Err(LocksmithError::Abort(AbortReason::OutputMismatch));
+        }
+        let opened: Vec<u8> = shares.iter().zip(from_next.iter())
+            .map(|(share, missing)| share.si.0 ^ share.sii.0 ^ missing)
+            .collect();
+        phase.end(party);
+        return Ok(Some(opened));
+    }
+
+    // Party i holds (x_i, x_i+1), so the client's successor sends its second share
+    // and the client's predecessor its first.
+    let missing: Vec<u8> = if i == (client + 1) % 3 {
+        shares.iter().map(|share| share.sii.0).collect()
+    } else {
+        shares.iter().map(|share| share.si.0).collect()
+    };
+    control::send_bytes(party, client, &missing);
+    phase.end(party);
+    Ok(None)
+}
+
+/// Encrypts `data_input` in ECB mode. The ciphertext is opened to all three parties.
+///
+/// All parties pass an input of the same number of blocks; only the data owner's content is used.
+/// `key` is this party's share of the key `key_id`, `None` for the built-in AES-128 key.
+pub fn encrypt_ecb(config: &Path, data_input: &Input, key_id: &str, key: Option<&KeyShare>) -> Result<Ciphertext> {
+    let (access, mut party): (Access, Box<dyn MpcParty>) = setup_with_access(config)?;
+    let authorized: Result<()> = access.authorize(party.main_party_mut(), key_id, Operation::Encrypt);
+    let result: Result<Ciphertext> = authorized.and_then(|()| ecb(party, data_input, key));
+    access.record(key_id, Operation::Encrypt, data_input.num_blocks() as u64, None, &result)?;
+    result
+}
+
+fn ecb(mut party: Box<dyn MpcParty>, data_input: &Input, key: Option<&KeyShare>) -> Result<Ciphertext> {
+    // The data owner's block count is authoritative, a party with a different count cannot
+    // take part in input sharing
+    let is_owner: bool = party.main_party_mut().i == DATA_OWNER;
+    let num_blocks: [u8; 8] = (data_input.num_blocks() as u64).to_be_bytes();
+    let announced: Vec<u8> = control::broadcast(
+        party.main_party_mut(),
+        DATA_OWNER,
+        Some(num_blocks.as_slice()).filter(|_| is_owner),
+    )?;
+    let expected: usize = u64::from_be_bytes(announced.as_slice().try_into()
+        .map_err(|_| LocksmithError::Malformed("block count".to_string()))?) as usize;
+    let length_ok: Result<()> = if data_input.num_blocks() != expected {
+        Err(LocksmithError::InputLengthMismatch { expected, actual: data_input.num_blocks() })
+    } else {
+        Ok(())
+    };
+    control::checkpoint(party.main_party_mut(), length_ok)?;
+
+    let input_ss: VectorAesState = rss::share_input::rss_input(data_input, &mut *party)?;
+
+    let key: KeyShare = key.cloned().unwrap_or_else(|| KeyShare::builtin(party.main_party_mut().i));
+    let ks: Vec<maestro::aes::AesKeyState> = rss::key_schedule::keyshare_keyschedule(&mut *party, &key)?;
+
+    // Preprocessing for the agreed number of blocks
+    let (_, n_blocks): (usize, usize) = preprocessing_units(key.variant(), 0, expected);
+    metrics::measure(&mut *party, "preprocessing", |party| party.do_preprocessing(0, n_blocks))?;
+    let output: VectorAesState = metrics::measure(&mut *party, "aes", |party| aes_no_keyschedule_mal(party, input_ss, &ks))?;
+
+    let checked: Result<()> = metrics::measure(&mut *party, "check", |party| party.finalize().map_err(LocksmithError::from));
+    control::checkpoint(party.main_party_mut(), checked)?;
+
+    let phase: Phase = metrics::start("output", party.main_party_mut());
+    let output = output.to_bytes();
+    let (output_i, output_ii): (Vec<_>, Vec<_>) = output.into_iter().map(|rss: RssShare<GF8>| (rss.si, rss.sii)).unzip();
+    let ciphertext: Vec<maestro::share::gf8::GF8> = party.output(&output_i, &output_ii)?;
+    phase.end(party.main_party_mut());
+
+    party.main_party_mut().teardown()?;
+
+    Ok(Ciphertext::new(ciphertext.iter().map(|x| x.0).collect()))
+}
+
+/// Encrypts in CTR mode through the keystream service.
+///
+/// The data owner (party 0) passes its input and gets the ciphertext back; the other parties
+/// pass `None`, only learn the number of blocks and return `None`. The counter range is checked
+/// against and recorded in `ledger` on every party, under version `version` of the key `key` is a
+/// share of. Returns the nonce that was used.
+pub fn encrypt_ctr(
+    config: &Path,
+    data_input: Option<&Input>,
+    nonce: Nonce,
+    ledger: NonceLedger,
+    key: Option<&KeyShare>,
+    version: u32,
+) -> Result<(u128, Option<Ciphertext>)> {
+    let (access, party): (Access, Box<dyn MpcParty>) = setup_with_access(config)?;
+
+    let mut service: KeystreamService = KeystreamService::authorized(party, DATA_OWNER, ledger, key, version, &access)?;
+    check_owner(service.main_party_mut(), data_input.is_some(), "CTR input")?;
+
+    let request: Option<KeystreamRequest> = data_input
+        .map(|input| KeystreamRequest { nonce, start: 0, blocks: input.num_blocks() as u64, purpose: Purpose::Encrypt });
+    let served: Served = service.serve(request)?;
+    service.shutdown()?;
+
+    // XOR keystream with data_input bytes, locally on the data owner
+    let ciphertext: Option<Ciphertext> = data_input.zip(served.keystream).map(|(input, keystream)| {
+        Ciphertext::new(input.iter().zip(keystream.iter())
+            .map(|(data, ks)| data ^ ks)
+            .collect())
+    });
+    Ok((served.nonce, ciphertext))
+}
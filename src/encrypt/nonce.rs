@@ -0,0 +1,219 @@
+// Nonce ledger: the counter blocks that have been used with a key, so that no CTR keystream
+// block is ever produced twice. Every party keeps its own copy and the copies are compared
+// before each request, see `KeystreamService::serve`.
+//
+// One ledger covers all versions of a key. Each range records the version it was used under:
+// counters are never allocated twice for a key ID, whatever the version, but only the version
+// that encrypted under a range may produce its keystream again for decryption.
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use super::keystore::FIRST_VERSION;
+
+pub const DEFAULT_KEY_ID: &str = "default";
+
+/// `blocks` consecutive counter blocks starting at `first`, wrapping around 2^128, used under
+/// version `version` of the key
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CounterRange {
+    pub first: u128,
+    pub blocks: u64,
+    pub version: u32,
+}
+
+impl CounterRange {
+    // Inclusive intervals of counter values, split in two if the range wraps around
+    fn intervals(&self) -> Vec<(u128, u128)> {
+        if self.blocks == 0 {
+            return Vec::new();
+        }
+        let last: u128 = self.first.wrapping_add(self.blocks as u128 - 1);
+        if self.first <= last {
+            vec![(self.first, last)]
+        } else {
+            vec![(self.first, u128::MAX), (0, last)]
+        }
+    }
+}
+
+/// Counter blocks for which keystream has already been produced
+#[derive(Default, Debug)]
+pub struct ServedRanges {
+    ranges: Vec<(u128, u128)>,
+}
+
+impl ServedRanges {
+    pub fn overlaps(&self, range: &CounterRange) -> bool {
+        range.intervals().iter().any(|&(first, last)| {
+            self.ranges.iter().any(|&(lo, hi)| first <= hi && lo <= last)
+        })
+    }
+
+    pub fn record(&mut self, range: &CounterRange) {
+        self.ranges.extend(range.intervals());
+    }
+
+    /// Whether every counter in `range` has been served
+    pub fn covers(&self, range: &CounterRange) -> bool {
+        let mut sorted: Vec<(u128, u128)> = self.ranges.clone();
+        sorted.sort_unstable();
+        range.intervals().iter().all(|&(first, last)| {
+            // Walk the sorted intervals, extending the covered prefix of [first, last]
+            let mut next: u128 = first;
+            for &(lo, hi) in sorted.iter() {
+                if lo > next {
+                    break;
+                }
+                if hi >= next {
+                    if hi >= last {
+                        return true;
+                    }
+                    next = hi + 1;
+                }
+            }
+            false
+        })
+    }
+
+    /// First counter value above everything served so far, `None` once the top of the space is used
+    pub fn next_free(&self) -> Option<u128> {
+        match self.ranges.iter().map(|&(_, hi)| hi).max() {
+            None => Some(0),
+            Some(hi) => hi.checked_add(1),
+        }
+    }
+}
+
+/// The used counter ranges for one key, persisted as one `first blocks version` line per request;
+/// lines without a version were written before versions were kept and belong to the first
+pub struct NonceLedger {
+    key_id: String,
+    path: Option<PathBuf>,
+    served: ServedRanges,
+    entries: Vec<CounterRange>,
+}
+
+impl NonceLedger {
+    /// A ledger that is not persisted, for tests and one-off runs
+    pub fn in_memory(key_id: &str) -> Self {
+        NonceLedger { key_id: key_id.to_string(), path: None, served: ServedRanges::default(), entries: Vec::new() }
+    }
+
+    /// Opens (or creates) the ledger for `key_id` in `dir`
+    pub fn open(dir: &Path, key_id: &str) -> std::io::Result<Self> {
+        if !valid_key_id(key_id) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid key ID '{}'", key_id)));
+        }
+        std::fs::create_dir_all(dir)?;
+        let path: PathBuf = dir.join(format!("{}.ledger", key_id));
+
+        let mut ledger: NonceLedger = NonceLedger::in_memory(key_id);
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line: String = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let range: CounterRange = parse_entry(&line).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("corrupt ledger entry '{}' in {}", line, path.display()))
+                })?;
+                ledger.served.record(&range);
+                ledger.entries.push(range);
+            }
+        }
+        ledger.path = Some(path);
+        Ok(ledger)
+    }
+
+    /// The key IDs of the ledgers in `dir`, sorted
+    pub fn key_ids(dir: &Path) -> Vec<String> {
+        let mut key_ids: Vec<String> = std::fs::read_dir(dir).into_iter().flatten()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter_map(|name| name.strip_suffix(".ledger").filter(|key_id| valid_key_id(key_id)).map(str::to_string))
+            .collect();
+        key_ids.sort_unstable();
+        key_ids
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The recorded ranges, in the order they were recorded
+    pub fn entries(&self) -> &[CounterRange] {
+        &self.entries
+    }
+
+    pub fn overlaps(&self, range: &CounterRange) -> bool {
+        self.served.overlaps(range)
+    }
+
+    /// Whether every counter in `range` has been used under the version of `range`
+    pub fn covers(&self, range: &CounterRange) -> bool {
+        let mut served: ServedRanges = ServedRanges::default();
+        for entry in self.entries.iter().filter(|entry| entry.version == range.version) {
+            served.record(entry);
+        }
+        served.covers(range)
+    }
+
+    /// Picks a nonce such that counters `nonce + start ..` are all unused, or `None` if the counter space is exhausted
+    pub fn allocate(&self, start: u128, blocks: u64) -> Option<u128> {
+        let first: u128 = self.served.next_free()?;
+        // The allocated range must not wrap around into used counters
+        first.checked_add(blocks as u128)?;
+        Some(first.wrapping_sub(start))
+    }
+
+    /// Records a range as used, writing it to disk before returning
+    pub fn record(&mut self, range: &CounterRange) -> std::io::Result<()> {
+        if let Some(path) = &self.path {
+            let mut file: File = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{:032x} {} {}", range.first, range.blocks, range.version)?;
+            file.sync_all()?;
+        }
+        self.served.record(range);
+        self.entries.push(*range);
+        Ok(())
+    }
+
+    /// Digest over the key ID and all recorded ranges, used by the parties to check that their
+    /// ledgers are identical before trusting them
+    pub fn digest(&self) -> [u8; 32] {
+        let mut entries: Vec<(u128, u64, u32)> = self.entries.iter().map(|r| (r.first, r.blocks, r.version)).collect();
+        entries.sort_unstable();
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"locksmith-nonce-ledger");
+        hasher.update((self.key_id.len() as u64).to_be_bytes());
+        hasher.update(self.key_id.as_bytes());
+        for (first, blocks, version) in entries {
+            hasher.update(first.to_be_bytes());
+            hasher.update(blocks.to_be_bytes());
+            hasher.update(version.to_be_bytes());
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// Key IDs name files, so they are restricted to `[A-Za-z0-9._-]` and may not start with a dot
+pub(crate) fn valid_key_id(key_id: &str) -> bool {
+    !key_id.is_empty() && key_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') && !key_id.starts_with('.')
+}
+
+fn parse_entry(line: &str) -> Option<CounterRange> {
+    let mut parts = line.split_whitespace();
+    let first: u128 = u128::from_str_radix(parts.next()?, 16).ok()?;
+    let blocks: u64 = parts.next()?.parse().ok()?;
+    let version: u32 = match parts.next() {
+        Some(version) => version.parse().ok()?,
+        None => FIRST_VERSION,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(CounterRange { first, blocks, version })
+}
@@ -0,0 +1,257 @@
+// Parallel S-box layers over several channels.
+//
+// With `channels` above one in [network] (see `config`), the parties connect that many times and
+// set up the backend once per connection. The first channel stays with the calling thread and
+// does everything but the S-box layers alone; every further channel gets a thread of its own.
+// A large layer of whole blocks is split into one run of blocks per channel, the channels
+// evaluate their runs at the same time and the results are copied back in place, so encrypting
+// many blocks scales with cores as well as with bandwidth. `finalize` checks every channel.
+//
+// Small layers, such as those of the key schedules, stay on the first channel. With a backend
+// that preprocesses, `do_preprocessing` decides for the blocks that follow it: it splits them
+// across the channels if there are enough, and each channel prepares its share only.
+//
+// All parties split the same way, as the split only depends on the layer sizes, the
+// preprocessing and the number of channels, which they compare when they set up.
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use maestro::rep3_core::network::ConnectedParty;
+use maestro::rep3_core::party::{error::{MpcError, MpcResult}, MainParty};
+use maestro::rep3_core::share::RssShare;
+use maestro::share::gf8::GF8;
+use tracing::span::Span;
+
+use crate::config::PartyConfig;
+use crate::error::{LocksmithError, Result};
+use crate::metrics::{self, Traffic};
+use super::backend::{self, Backend, MpcParty};
+use super::{connect, control};
+
+/// Fewest blocks per channel for which a layer is split
+pub const MIN_BLOCKS_PER_CHANNEL: usize = 32;
+
+const BLOCK: usize = 16;
+
+// Work for the thread of a further channel
+type Job = Box<dyn FnOnce(&mut dyn MpcParty) + Send>;
+
+// Where the result of a job arrives, with the channel's traffic
+type Reply<T> = Receiver<MpcResult<(T, Traffic)>>;
+
+// Both shares of a run of S-boxes sent to a further channel
+type Run = (Vec<GF8>, Vec<GF8>);
+
+// A further channel, served by its own thread until the job sender is dropped
+struct Channel {
+    jobs: Sender<Job>,
+}
+
+/// The first channel and the threads serving the others, as one `MpcParty`
+pub struct ParallelParty {
+    first: Box<dyn MpcParty>,
+    channels: Vec<Channel>,
+    // Whether block layers are split, as decided by the last preprocessing for blocks
+    split_prepared: bool,
+}
+
+/// Sets up `backend` on `conn` and, with `[network] channels` above one, on as many further
+/// connections; refuses unless the other parties use the same number of channels
+pub fn setup(party_config: &PartyConfig, backend: Backend, conn: ConnectedParty) -> Result<Box<dyn MpcParty>> {
+    let channels: usize = party_config.network.channels;
+    let mut first: Box<dyn MpcParty> = backend::setup(conn, backend)?;
+    if !control::all_equal(first.main_party_mut(), &(channels as u64).to_be_bytes())? {
+        return Err(LocksmithError::Refused(format!("the parties use different numbers of channels, this one {}", channels)));
+    }
+    if channels == 1 {
+        return Ok(first);
+    }
+
+    // The connections are made one after the other, in the same order on every party
+    let mut ready: Vec<Receiver<Result<()>>> = Vec::new();
+    let mut further: Vec<Channel> = Vec::new();
+    for index in 1..channels {
+        let conn: ConnectedParty = connect(party_config, Some(party_config.network.connect_attempts))?;
+        let (ready_tx, ready_rx): (Sender<Result<()>>, Receiver<Result<()>>) = channel();
+        let (jobs, jobs_rx): (Sender<Job>, Receiver<Job>) = channel();
+        let span: Span = tracing::debug_span!("channel", index);
+        thread::spawn(move || {
+            let _span = span.entered();
+            match backend::setup(conn, backend) {
+                Ok(party) => {
+                    let _ = ready_tx.send(Ok(()));
+                    serve(party, jobs_rx);
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                }
+            }
+        });
+        ready.push(ready_rx);
+        further.push(Channel { jobs });
+    }
+    for (index, ready) in ready.iter().enumerate() {
+        ready.recv().map_err(|_| LocksmithError::Network(format!("channel {} ended during setup", index + 1)))??;
+    }
+    tracing::debug!(channels, "channels set up");
+    Ok(Box::new(ParallelParty { first, channels: further, split_prepared: false }))
+}
+
+// Runs the jobs of a further channel, then closes it
+fn serve(mut party: Box<dyn MpcParty>, jobs: Receiver<Job>) {
+    for job in jobs {
+        job(&mut *party);
+    }
+    if let Err(e) = party.main_party_mut().teardown() {
+        tracing::debug!("channel teardown failed: {}", e);
+    }
+}
+
+impl Channel {
+    // Starts `f` on this channel; the result arrives with the channel's traffic
+    fn start<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut dyn MpcParty) -> MpcResult<T> + Send + 'static,
+    ) -> Reply<T> {
+        let (reply, result): (Sender<MpcResult<(T, Traffic)>>, Reply<T>) = channel();
+        let job: Job = Box::new(move |party: &mut dyn MpcParty| {
+            let outcome: MpcResult<T> = f(party);
+            let traffic: Traffic = metrics::take(party.main_party_mut());
+            let _ = reply.send(outcome.map(|value| (value, traffic)));
+        });
+        // A channel whose thread has ended shows up as a missing result
+        let _ = self.jobs.send(job);
+        result
+    }
+}
+
+// Waits for a result of `Channel::start` and counts its traffic on this thread. The rounds are
+// left out, as they overlap with those of the first channel.
+fn finish<T>(result: Reply<T>) -> MpcResult<T> {
+    let (value, traffic): (T, Traffic) = result.recv().unwrap_or(Err(MpcError::Receive))?;
+    metrics::add(Traffic { rounds: 0, ..traffic });
+    Ok(value)
+}
+
+/// Splits `total` into `parts` runs as even as possible, the longer ones first
+pub fn split(total: usize, parts: usize) -> Vec<usize> {
+    (0..parts).map(|part| total / parts + usize::from(part < total % parts)).collect()
+}
+
+/// AES-128 blocks each channel prepares when `n_blocks` (see `preprocessing_units`) are split
+/// across `channels`. Layers split by blocks, so the blocks of AES-256 may round up by up to two
+/// units more per channel than in total.
+pub fn channel_units(n_blocks: usize, channels: usize) -> usize {
+    n_blocks.div_ceil(channels) + 2
+}
+
+impl ParallelParty {
+    fn n_channels(&self) -> usize {
+        self.channels.len() + 1
+    }
+
+    // Whether a layer of `len` S-boxes is split across the channels
+    fn splits(&self, len: usize) -> bool {
+        if !len.is_multiple_of(BLOCK) {
+            return false;
+        }
+        match self.first.backend().has_preprocessing() {
+            true => self.split_prepared,
+            false => len / BLOCK >= self.n_channels() * MIN_BLOCKS_PER_CHANNEL,
+        }
+    }
+
+    fn layer(&mut self, si: &mut [GF8], sii: &mut [GF8], inverse: bool) -> MpcResult<()> {
+        let apply = move |party: &mut dyn MpcParty, si: &mut [GF8], sii: &mut [GF8]| match inverse {
+            true => party.inv_sbox_layer(si, sii),
+            false => party.sbox_layer(si, sii),
+        };
+        if !self.splits(si.len()) {
+            return apply(&mut *self.first, si, sii);
+        }
+
+        let runs: Vec<usize> = split(si.len() / BLOCK, self.n_channels()).into_iter().map(|blocks| blocks * BLOCK).collect();
+        let (first_i, rest_i): (&mut [GF8], &mut [GF8]) = si.split_at_mut(runs[0]);
+        let (first_ii, rest_ii): (&mut [GF8], &mut [GF8]) = sii.split_at_mut(runs[0]);
+        let mut pending: Vec<(usize, usize, Reply<Run>)> = Vec::new();
+        let mut start: usize = 0;
+        for (channel, &len) in self.channels.iter().zip(&runs[1..]).filter(|(_, len)| **len > 0) {
+            let mut run_i: Vec<GF8> = rest_i[start..start + len].to_vec();
+            let mut run_ii: Vec<GF8> = rest_ii[start..start + len].to_vec();
+            pending.push((start, len, channel.start(move |party| {
+                apply(party, &mut run_i, &mut run_ii)?;
+                Ok((run_i, run_ii))
+            })));
+            start += len;
+        }
+
+        // Every channel is waited for, also when one fails
+        let mut result: MpcResult<()> = apply(&mut *self.first, first_i, first_ii);
+        for (start, len, reply) in pending {
+            match finish(reply) {
+                Ok((run_i, run_ii)) => {
+                    rest_i[start..start + len].copy_from_slice(&run_i);
+                    rest_ii[start..start + len].copy_from_slice(&run_ii);
+                }
+                Err(e) => result = result.and(Err(e)),
+            }
+        }
+        result
+    }
+}
+
+impl MpcParty for ParallelParty {
+    fn backend(&self) -> Backend {
+        self.first.backend()
+    }
+
+    fn main_party_mut(&mut self) -> &mut MainParty {
+        self.first.main_party_mut()
+    }
+
+    fn constant_rss(&self, value: GF8) -> RssShare<GF8> {
+        self.first.constant_rss(value)
+    }
+
+    fn do_preprocessing(&mut self, n_keys: usize, n_blocks: usize) -> MpcResult<()> {
+        let channels: usize = self.n_channels();
+        if n_blocks > 0 {
+            self.split_prepared = n_blocks >= channels * MIN_BLOCKS_PER_CHANNEL;
+        }
+        if n_blocks == 0 || !self.split_prepared {
+            return self.first.do_preprocessing(n_keys, n_blocks);
+        }
+        let units: usize = channel_units(n_blocks, channels);
+        let pending: Vec<Reply<()>> = self.channels.iter()
+            .map(|channel| channel.start(move |party| party.do_preprocessing(0, units)))
+            .collect();
+        let mut result: MpcResult<()> = self.first.do_preprocessing(n_keys, units);
+        for reply in pending {
+            result = result.and(finish(reply));
+        }
+        result
+    }
+
+    fn sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+        self.layer(si, sii, false)
+    }
+
+    fn inv_sbox_layer(&mut self, si: &mut [GF8], sii: &mut [GF8]) -> MpcResult<()> {
+        self.layer(si, sii, true)
+    }
+
+    fn finalize(&mut self) -> MpcResult<()> {
+        let pending: Vec<Reply<()>> = self.channels.iter()
+            .map(|channel| channel.start(|party| party.finalize()))
+            .collect();
+        let mut result: MpcResult<()> = self.first.finalize();
+        for reply in pending {
+            result = result.and(finish(reply));
+        }
+        result
+    }
+
+    fn output(&mut self, si: &[GF8], sii: &[GF8]) -> Result<Vec<GF8>> {
+        self.first.output(si, sii)
+    }
+}
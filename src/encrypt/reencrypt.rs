@@ -0,0 +1,157 @@
+// Re-encryption of envelopes under the newest version of their key, inside MPC.
+//
+// ECB: the data owner secret-shares the old ciphertext, the parties decrypt it under the old key
+// and encrypt the shared plaintext under the new one, and only the new ciphertext is opened.
+// CTR: the ciphertext never enters the computation. The parties compute both keystreams and
+// open their XOR to the data owner, who adds it to the old ciphertext; that difference is the
+// XOR of the two ciphertexts, so the data owner learns nothing it does not learn from the result.
+// In both modes no party sees the plaintext.
+use std::path::Path;
+
+use maestro::aes::VectorAesState;
+use maestro::rep3_core::share::RssShare;
+use maestro::share::gf8::GF8;
+
+use crate::aes::ss::{aes_inv_no_keyschedule_mal, aes_no_keyschedule_mal};
+use crate::data::{Ciphertext, Input};
+use crate::error::{LocksmithError, Result};
+use crate::metrics;
+use crate::envelope::{CipherMode, Envelope};
+use super::access::{Access, Operation};
+use super::backend::MpcParty;
+use super::decrypt::DecryptHeader;
+use super::keystore::{KeySource, KeyStore, StoredKey};
+use super::keystream::{KeystreamRequest, KeystreamService, Nonce, Purpose};
+use super::nonce::{CounterRange, NonceLedger};
+use super::{check_owner, control, preprocessing_units, reveal_to, rss, KeyShare, DATA_OWNER};
+
+/// Re-encrypts an envelope under the newest version of its key, which must be newer than the
+/// version the envelope names.
+///
+/// The data owner (party 0) passes the envelope and gets the new envelope back; the other
+/// parties pass `None`, learn only the header and return `None`. CTR envelopes get a fresh
+/// initial counter block from the nonce ledger of the key in `ledger_dir`. Refused unless every
+/// party's access policy allows re-encryption under that key.
+pub fn reencrypt(config: &Path, envelope: Option<&Envelope>, ledger_dir: &Path, keys: &KeyStore) -> Result<Option<Envelope>> {
+    let (access, mut party): (Access, Box<dyn MpcParty>) = super::setup_with_access(config)?;
+    check_owner(party.main_party_mut(), envelope.is_some(), "the envelope")?;
+
+    let header: DecryptHeader = DecryptHeader::broadcast(party.main_party_mut(), envelope)?;
+    header.check_size(party.main_party_mut())?;
+    let authorized: Result<()> = access.authorize(party.main_party_mut(), &header.key_id, Operation::Reencrypt);
+    let result: Result<(Option<CounterRange>, Option<Envelope>)> = authorized.and_then(|()| reencrypt_with(party, envelope, &header, ledger_dir, keys));
+    let counters: Option<CounterRange> = result.as_ref().ok().and_then(|(counters, _)| *counters);
+    access.record(&header.key_id, Operation::Reencrypt, header.blocks, counters, &result)?;
+    Ok(result?.1)
+}
+
+// Re-encrypts once authorized; returns the new counter blocks of a CTR envelope and, on the data
+// owner, the new envelope
+fn reencrypt_with(
+    mut party: Box<dyn MpcParty>,
+    envelope: Option<&Envelope>,
+    header: &DecryptHeader,
+    ledger_dir: &Path,
+    keys: &KeyStore,
+) -> Result<(Option<CounterRange>, Option<Envelope>)> {
+    let old: KeyShare = header.key(party.main_party_mut(), KeySource::Store(keys))?;
+    let new: Result<StoredKey> = keys.load(&header.key_id).and_then(|new| {
+        if new.version <= header.key_version {
+            return Err(LocksmithError::Refused(format!(
+                "the envelope is already under version {} of key '{}', the newest is {}",
+                header.key_version,
+                header.key_id,
+                new.version,
+            )));
+        }
+        match new.encryption_share()?.variant() == header.variant {
+            true => Ok(new),
+            false => Err(LocksmithError::InvalidInput(format!("version {} of key '{}' is not a {:?} key", new.version, header.key_id, header.variant))),
+        }
+    });
+    let new: StoredKey = control::checkpoint(party.main_party_mut(), new)?;
+    if !control::all_equal(party.main_party_mut(), &new.version.to_be_bytes())? {
+        return Err(LocksmithError::Refused(format!("the parties hold different versions of key '{}'", header.key_id)));
+    }
+
+    let (counters, iv, ciphertext): (Option<CounterRange>, Vec<u8>, Option<Ciphertext>) = match header.mode {
+        CipherMode::Ecb => (None, Vec::new(), reencrypt_ecb(party, envelope, header, &old, &new.share)?),
+        CipherMode::Ctr => {
+            let (nonce, ciphertext): (u128, Option<Ciphertext>) = reencrypt_ctr(party, envelope, header, ledger_dir, &old, &new)?;
+            (Some(CounterRange { first: nonce, blocks: header.blocks, version: new.version }), nonce.to_be_bytes().to_vec(), ciphertext)
+        }
+    };
+
+    Ok((counters, envelope.zip(ciphertext).map(|(envelope, ciphertext)| Envelope {
+        key_version: new.version,
+        iv,
+        ciphertext,
+        ..envelope.clone()
+    })))
+}
+
+fn reencrypt_ecb(
+    mut party: Box<dyn MpcParty>,
+    envelope: Option<&Envelope>,
+    header: &DecryptHeader,
+    old: &KeyShare,
+    new: &KeyShare,
+) -> Result<Option<Ciphertext>> {
+    let num_blocks: usize = header.blocks as usize;
+    let ks_old: Vec<maestro::aes::AesKeyState> = rss::key_schedule::keyshare_keyschedule(&mut *party, old)?;
+    let ks_new: Vec<maestro::aes::AesKeyState> = rss::key_schedule::keyshare_keyschedule(&mut *party, new)?;
+
+    let ciphertext: Input = match envelope {
+        Some(envelope) => Input::from_file(envelope.ciphertext.as_bytes(), num_blocks),
+        None => Input::zero(num_blocks),
+    };
+    let input_ss: VectorAesState = rss::share_input::rss_input(&ciphertext, &mut *party)?;
+
+    // Each block goes through the inverse cipher and the cipher
+    let (_, n_blocks): (usize, usize) = preprocessing_units(header.variant, 0, 2 * num_blocks);
+    metrics::measure(&mut *party, "preprocessing", |party| party.do_preprocessing(0, n_blocks))?;
+    let output: VectorAesState = metrics::measure(&mut *party, "aes", |party| {
+        let plaintext_ss: VectorAesState = aes_inv_no_keyschedule_mal(party, input_ss, &ks_old)?;
+        aes_no_keyschedule_mal(party, plaintext_ss, &ks_new)
+    })?;
+    let checked: Result<()> = metrics::measure(&mut *party, "check", |party| party.finalize().map_err(LocksmithError::from));
+    control::checkpoint(party.main_party_mut(), checked)?;
+
+    let ciphertext: Option<Vec<u8>> = reveal_to(party.main_party_mut(), &output, DATA_OWNER)?;
+    party.main_party_mut().teardown()?;
+    Ok(ciphertext.map(Ciphertext::new))
+}
+
+// Returns the new initial counter block and, on the data owner, the new ciphertext
+fn reencrypt_ctr(
+    mut party: Box<dyn MpcParty>,
+    envelope: Option<&Envelope>,
+    header: &DecryptHeader,
+    ledger_dir: &Path,
+    old: &KeyShare,
+    new: &StoredKey,
+) -> Result<(u128, Option<Ciphertext>)> {
+    // One ledger per key ID covers all its versions, so counters used under the old version are
+    // never allocated again; each range is recorded with the version it was used under
+    let ledger: Result<NonceLedger> = NonceLedger::open(ledger_dir, &header.key_id).map_err(LocksmithError::from);
+    let ledger: NonceLedger = control::checkpoint(party.main_party_mut(), ledger)?;
+    let mut service: KeystreamService = KeystreamService::start(party, DATA_OWNER, ledger, Some(old), header.key_version)?;
+
+    let request = |nonce: Nonce, purpose: Purpose| envelope.map(|_| KeystreamRequest { nonce, start: 0, blocks: header.blocks, purpose });
+    let (_, old_range): (u128, CounterRange) = service.admit(request(Nonce::Fixed(header.nonce), Purpose::Decrypt), header.key_version)?;
+    let (nonce, new_range): (u128, CounterRange) = service.admit(request(Nonce::Auto, Purpose::Encrypt), new.version)?;
+
+    let ks_new: Vec<maestro::aes::AesKeyState> = service.key_schedule(&new.share)?;
+    let old_stream: Vec<RssShare<GF8>> = service.shared_keystream(None, &old_range)?.to_bytes();
+    let new_stream: Vec<RssShare<GF8>> = service.shared_keystream(Some(&ks_new), &new_range)?.to_bytes();
+    let delta: VectorAesState = VectorAesState::from_bytes(old_stream.into_iter().zip(new_stream).map(|(a, b)| a + b).collect());
+
+    let delta: Option<Vec<u8>> = service.open_to_client(&delta)?;
+    service.shutdown()?;
+
+    Ok((nonce, envelope.zip(delta).map(|(envelope, delta)| {
+        Ciphertext::new(envelope.ciphertext.as_bytes().iter().zip(delta.iter())
+            .map(|(data, d)| data ^ d)
+            .collect())
+    })))
+}
@@ -0,0 +1,216 @@
+// Resharing: moves the stored keys and nonce ledgers to a new host that takes the place of a
+// decommissioned party, without reconstructing any key.
+//
+// The new host runs with the replaced party's index d and config (see `config replace`) and an
+// empty key store. Party d held (x_d, x_d+1): the previous party d+2 holds x_d as its second share
+// and the next party d+1 holds x_d+1 as its first, so each hands one share to the new party. Each
+// share is checked against the hash the other remaining party keeps of it as the one share it does
+// not hold, so a remaining party that hands over a wrong share is caught. All three parties then
+// refresh the key, which leaves the shares the old host may still have in a past epoch.
+//
+// Ledgers are public; the next party sends them and all three compare digests afterwards.
+use std::path::Path;
+
+use maestro::rep3_core::party::MainParty;
+use maestro::share::gf8::GF8;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AbortReason, LocksmithError, Result};
+use super::backend::MpcParty;
+use super::fault::{self, Fault};
+use super::kcv::KCV_LEN;
+use super::keystore::{self, KeyStore, StoredKey};
+use super::nonce::{CounterRange, NonceLedger};
+use super::{control, setup_party, KeyShare};
+
+// Bytes of a ledger entry: a 16-byte first counter, an 8-byte block count and a 4-byte version
+const LEDGER_ENTRY: usize = 28;
+
+// Ledger entries per broadcast
+const LEDGER_CHUNK: usize = control::MAX_MESSAGE_LEN / LEDGER_ENTRY;
+
+// What the remaining parties hold, announced by the next party and checked by the previous one
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Holdings {
+    keys: Vec<KeyEntry>,
+    ledgers: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct KeyEntry {
+    key_id: String,
+    version: u32,
+    epoch: u64,
+    decrypt_only: bool,
+    len: usize,
+    kcv: Option<[u8; KCV_LEN]>,
+}
+
+/// Moves every stored key version and nonce ledger to a new party that replaces party `replaced`
+/// (0-based), on the new committee. The new party passes its empty key store and ledger directory.
+/// Returns the keys as stored afterwards, all one epoch on.
+pub fn reshare(config: &Path, store: &KeyStore, ledger_dir: &Path, replaced: usize) -> Result<Vec<StoredKey>> {
+    let mut mpc: Box<dyn MpcParty> = setup_party(config)?;
+    let party: &mut MainParty = mpc.main_party_mut();
+
+    let i: usize = party.i;
+    let valid: Result<()> = match replaced {
+        0..=2 => Ok(()),
+        _ => Err(LocksmithError::InvalidInput(format!("invalid party {}", replaced))),
+    };
+    control::checkpoint(party, valid)?;
+    if !control::all_equal(party, &[replaced as u8])? {
+        return Err(LocksmithError::Refused("the parties name different replaced parties".to_string()));
+    }
+    let next: usize = (replaced + 1) % 3;
+
+    // The new party must not hold anything yet, so that nothing is mixed with the handed-over state
+    let local: Result<Option<(Holdings, Vec<StoredKey>)>> = match i == replaced {
+        true => store.key_ids().and_then(|key_ids| match key_ids.is_empty() && NonceLedger::key_ids(ledger_dir).is_empty() {
+            true => Ok(None),
+            false => Err(LocksmithError::InvalidInput("the new party's key store and ledger directory must be empty".to_string())),
+        }),
+        false => holdings(store, ledger_dir).map(Some),
+    };
+    let local: Option<(Holdings, Vec<StoredKey>)> = control::checkpoint(party, local)?;
+    let encoded: Option<Vec<u8>> = local.as_ref()
+        .map(|(holdings, _)| serde_json::to_vec(holdings))
+        .transpose()
+        .map_err(|e| LocksmithError::Malformed(format!("key listing: {}", e)))?;
+    let announced: Vec<u8> = control::broadcast(party, next, encoded.as_deref().filter(|_| i == next))?;
+    let holdings: Option<Holdings> = serde_json::from_slice(&announced).ok();
+    let same: bool = holdings.is_some() && encoded.is_none_or(|own| own == announced);
+    let (true, Some(holdings)) = (control::agree(party, same)?, holdings) else {
+        return Err(LocksmithError::Refused("the remaining parties hold different keys or nonce ledgers".to_string()));
+    };
+    let held: Vec<StoredKey> = local.map(|(_, keys)| keys).unwrap_or_default();
+
+    let mut reshared: Vec<StoredKey> = Vec::new();
+    for (n, entry) in holdings.keys.iter().enumerate() {
+        let key: Option<StoredKey> = match held.get(n) {
+            Some(key) => {
+                hand_over(party, key, replaced)?;
+                Some(key.clone())
+            }
+            None => take_over(party, entry, replaced)?,
+        };
+        let (true, Some(key)) = (control::agree(party, key.is_some())?, key) else {
+            return Err(LocksmithError::Abort(AbortReason::CheckFailed));
+        };
+        reshared.push(keystore::refresh(party, &key)?);
+    }
+
+    let mut ledgers: Vec<NonceLedger> = Vec::new();
+    for key_id in holdings.ledgers.iter() {
+        let ledger: NonceLedger = match i == replaced {
+            true => NonceLedger::in_memory(key_id),
+            false => NonceLedger::open(ledger_dir, key_id)?,
+        };
+        let ledger: NonceLedger = transfer_ledger(party, ledger, next, i == replaced)?;
+        if !control::all_equal(party, &ledger.digest())? {
+            return Err(LocksmithError::Refused(format!("nonce ledgers for key '{}' differ between the parties", key_id)));
+        }
+        ledgers.push(ledger);
+    }
+
+    keystore::commit_epochs(party, store, &reshared)?;
+    if i == replaced {
+        for received in ledgers {
+            let mut ledger: NonceLedger = NonceLedger::open(ledger_dir, received.key_id())?;
+            for range in received.entries() {
+                ledger.record(range)?;
+            }
+        }
+    }
+    party.teardown()?;
+    Ok(reshared)
+}
+
+// A remaining party's key versions, oldest first per key ID, and its ledger key IDs
+fn holdings(store: &KeyStore, ledger_dir: &Path) -> Result<(Holdings, Vec<StoredKey>)> {
+    let mut entries: Vec<KeyEntry> = Vec::new();
+    let mut keys: Vec<StoredKey> = Vec::new();
+    for key_id in store.key_ids()? {
+        for version in store.versions(&key_id)? {
+            let key: StoredKey = store.load_version(&key_id, version)?;
+            let (Some(_), Some((si, _))) = (key.missing, key.share.replicated_bytes()) else {
+                return Err(LocksmithError::Refused(format!(
+                    "version {} of key '{}' was stored without share hashes, refresh it with all three parties first", version, key_id
+                )));
+            };
+            entries.push(KeyEntry { key_id: key_id.clone(), version, epoch: key.epoch, decrypt_only: key.decrypt_only, len: si.len(), kcv: key.kcv });
+            keys.push(key);
+        }
+    }
+    Ok((Holdings { keys: entries, ledgers: NonceLedger::key_ids(ledger_dir) }, keys))
+}
+
+// Sends the new party the share it needs from us and our hash of the share it gets from the other
+fn hand_over(party: &mut MainParty, key: &StoredKey, replaced: usize) -> Result<()> {
+    let (si, sii): (&[u8], &[u8]) = key.share.replicated_bytes()
+        .ok_or_else(|| LocksmithError::InvalidInput("only stored keys can be handed over".to_string()))?;
+    let missing: [u8; 32] = key.missing
+        .ok_or_else(|| LocksmithError::InvalidInput(format!("key '{}' has no share hash", key.key_id)))?;
+
+    // The previous party of the new one holds x_d as its second share, the next x_d+1 as its first
+    let share: &[u8] = if party.i == (replaced + 2) % 3 { sii } else { si };
+    let mut share: Vec<GF8> = share.iter().map(|&b| GF8(b)).collect();
+    fault::tamper(Fault::HandoverShare, &mut share);
+    control::send_bytes(party, replaced, &share.iter().map(|x| x.0).collect::<Vec<u8>>());
+    control::send_bytes(party, replaced, &missing);
+    Ok(())
+}
+
+// The new party's side of `hand_over`: `None` if a share does not match the other party's hash
+fn take_over(party: &mut MainParty, entry: &KeyEntry, replaced: usize) -> Result<Option<StoredKey>> {
+    let (next, prev): (usize, usize) = ((replaced + 1) % 3, (replaced + 2) % 3);
+    let si: Vec<u8> = control::receive_bytes(party, prev, entry.len)?;
+    let hash_of_sii: Vec<u8> = control::receive_bytes(party, prev, 32)?;
+    let sii: Vec<u8> = control::receive_bytes(party, next, entry.len)?;
+    let hash_of_si: Vec<u8> = control::receive_bytes(party, next, 32)?;
+
+    if keystore::share_digest(&si)[..] != hash_of_si[..] || keystore::share_digest(&sii)[..] != hash_of_sii[..] {
+        return Ok(None);
+    }
+    Ok(Some(StoredKey {
+        key_id: entry.key_id.clone(),
+        version: entry.version,
+        epoch: entry.epoch,
+        decrypt_only: entry.decrypt_only,
+        share: KeyShare::replicated(si, sii)?,
+        missing: None,
+        kcv: entry.kcv,
+    }))
+}
+
+// Broadcasts the next party's ledger entries; the new party records them in `ledger`
+fn transfer_ledger(party: &mut MainParty, mut ledger: NonceLedger, from: usize, receive: bool) -> Result<NonceLedger> {
+    let count: Option<[u8; 8]> = (party.i == from).then(|| (ledger.entries().len() as u64).to_be_bytes());
+    let count: Vec<u8> = control::broadcast(party, from, count.as_ref().map(|count| count.as_slice()))?;
+    let count: u64 = u64::from_be_bytes(count.as_slice().try_into()
+        .map_err(|_| LocksmithError::Malformed("ledger size".to_string()))?);
+
+    for chunk in 0..(count as usize).div_ceil(LEDGER_CHUNK) {
+        let encoded: Option<Vec<u8>> = (party.i == from).then(|| {
+            ledger.entries().iter().skip(chunk * LEDGER_CHUNK).take(LEDGER_CHUNK)
+                .flat_map(|range| [range.first.to_be_bytes().as_slice(), range.blocks.to_be_bytes().as_slice(), range.version.to_be_bytes().as_slice()].concat())
+                .collect()
+        });
+        let received: Vec<u8> = control::broadcast(party, from, encoded.as_deref())?;
+        if !receive {
+            continue;
+        }
+        for entry in received.chunks(LEDGER_ENTRY) {
+            let range: CounterRange = match entry.len() {
+                LEDGER_ENTRY => CounterRange {
+                    first: u128::from_be_bytes(entry[..16].try_into().unwrap_or_default()),
+                    blocks: u64::from_be_bytes(entry[16..24].try_into().unwrap_or_default()),
+                    version: u32::from_be_bytes(entry[24..].try_into().unwrap_or_default()),
+                },
+                _ => return Err(LocksmithError::Malformed("ledger entry".to_string())),
+            };
+            ledger.record(&range)?;
+        }
+    }
+    Ok(ledger)
+}
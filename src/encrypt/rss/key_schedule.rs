@@ -1,7 +1,15 @@
 use maestro::{aes::{GF8InvBlackBox}, rep3_core::{network::task::Direction::{Next, Previous}, share::RssShare, party::{error::MpcResult, MainParty, Party}}, share::gf8::GF8};
-use maestro::{aes::{ss::{GF8InvBlackBoxSS, GF8InvBlackBoxSSMal}}, lut256::{lut256_ss::{Lut256SSMalParty, Lut256SSParty}, LUT256Party}};
 
-use crate::aes::AesKeyState;
+use std::fmt;
+
+use crate::aes::{AesKeyState, AesVariant};
+use crate::aes::ss::sub_bytes;
+use crate::encrypt::backend::MpcParty;
+use crate::encrypt::fault::{self, Fault};
+use crate::encrypt::{control, keystore, preprocessing_units};
+use crate::envelope::variant_to_u8;
+use crate::error::{AbortReason, LocksmithError, Result};
+use crate::metrics::{self, Phase};
 
 const AES_KEYSHARE: [[u8; 16]; 3] = [
     // Party 0's share
@@ -23,8 +31,77 @@ pub fn get_combined_key() -> [u8; 16] {
     combined_key
 }
 
-fn aes128_keyschedule_round_mal<Protocol: GF8InvBlackBoxSSMal>(
-    party: &mut Protocol,
+/// This party's share of an AES-128 or AES-256 key: either an XOR share, where the key is the XOR
+/// of the three parties' shares, or replicated shares (x_i, x_i+1) of a key held in a key store
+#[derive(Clone, PartialEq)]
+pub struct KeyShare(Shares);
+
+#[derive(Clone, PartialEq)]
+enum Shares {
+    Xor(Vec<u8>),
+    Replicated { si: Vec<u8>, sii: Vec<u8> },
+}
+
+impl KeyShare {
+    pub fn new(bytes: Vec<u8>) -> Result<Self> {
+        match bytes.len() {
+            16 | 32 => Ok(KeyShare(Shares::Xor(bytes))),
+            len => Err(LocksmithError::InvalidInput(format!("key shares must be 16 or 32 bytes, not {}", len))),
+        }
+    }
+
+    /// Replicated shares of the key bytes, as `share_key` computes them
+    pub fn replicated(si: Vec<u8>, sii: Vec<u8>) -> Result<Self> {
+        match (si.len(), sii.len()) {
+            (16, 16) | (32, 32) => Ok(KeyShare(Shares::Replicated { si, sii })),
+            (si, sii) => Err(LocksmithError::InvalidInput(format!("replicated key shares must be 16 or 32 bytes each, not {} and {}", si, sii))),
+        }
+    }
+
+    /// Party `party`'s share of the built-in AES-128 key
+    pub fn builtin(party: usize) -> Self {
+        KeyShare(Shares::Xor(AES_KEYSHARE[party].to_vec()))
+    }
+
+    /// Splits `key` into three random shares, one per party
+    pub fn split(key: &[u8]) -> Result<[KeyShare; 3]> {
+        let first: Vec<u8> = (0..key.len()).map(|_| rand::random::<u8>()).collect();
+        let second: Vec<u8> = (0..key.len()).map(|_| rand::random::<u8>()).collect();
+        let third: Vec<u8> = key.iter().zip(first.iter().zip(second.iter()))
+            .map(|(k, (a, b))| k ^ a ^ b)
+            .collect();
+        Ok([KeyShare::new(first)?, KeyShare::new(second)?, KeyShare::new(third)?])
+    }
+
+    pub fn variant(&self) -> AesVariant {
+        if self.len() == 32 { AesVariant::Aes256 } else { AesVariant::Aes128 }
+    }
+
+    /// `(si, sii)` for replicated shares, `None` for an XOR share
+    pub fn replicated_bytes(&self) -> Option<(&[u8], &[u8])> {
+        match &self.0 {
+            Shares::Xor(_) => None,
+            Shares::Replicated { si, sii } => Some((si, sii)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match &self.0 {
+            Shares::Xor(bytes) => bytes.len(),
+            Shares::Replicated { si, .. } => si.len(),
+        }
+    }
+}
+
+// Shares are secret, only the variant is printed
+impl fmt::Debug for KeyShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyShare({:?})", self.variant())
+    }
+}
+
+fn aes128_keyschedule_round_mal(
+    party: &mut dyn MpcParty,
     rk: &AesKeyState,
     rcon: GF8,
 ) -> MpcResult<AesKeyState> {
@@ -33,23 +110,13 @@ fn aes128_keyschedule_round_mal<Protocol: GF8InvBlackBoxSSMal>(
     let mut rot_ii: [GF8; 4] = [rk.sii[7], rk.sii[11], rk.sii[15], rk.sii[3]];
     
     // Apply S-box to rotated word
-    let mut state_ss: Vec<GF8> = vec![GF8(0); 4];
-    party.gf8_inv_rss_to_ss(&mut state_ss, &rot_i, &rot_ii)?;
-    
-    // Apply affine transform
-    let c = party.constant(GF8(0x63));
-    state_ss.iter_mut().for_each(|dst| *dst = dst.aes_sbox_affine_transform() + c);
-    
-    // Convert back to RSS
-    let mut new_rot_i: Vec<GF8> = vec![GF8(0); 4];
-    let mut new_rot_ii: Vec<GF8> = vec![GF8(0); 4];
-    party.gf8_inv_and_rss_output(&mut state_ss, &mut new_rot_i, &mut new_rot_ii)?;
+    sub_bytes(party, &mut rot_i, &mut rot_ii)?;
     
     let mut output: AesKeyState = rk.clone();
     // XOR with first word of previous round key
     for i in 0..4 {
-        output.si[4 * i] += new_rot_i[i];
-        output.sii[4 * i] += new_rot_ii[i];
+        output.si[4 * i] += rot_i[i];
+        output.sii[4 * i] += rot_ii[i];
     }
     
     // Add round constant to first byte
@@ -68,8 +135,8 @@ fn aes128_keyschedule_round_mal<Protocol: GF8InvBlackBoxSSMal>(
     Ok(output)
 }
 
-pub fn aes128_keyschedule_mal<Protocol: GF8InvBlackBoxSSMal>(
-    party: &mut Protocol,
+pub fn aes128_keyschedule_mal(
+    party: &mut dyn MpcParty,
     key: Vec<RssShare<GF8>>,
 ) -> MpcResult<Vec<AesKeyState>> {
     debug_assert_eq!(key.len(), 16);
@@ -97,47 +164,133 @@ pub fn aes128_keyschedule_mal<Protocol: GF8InvBlackBoxSSMal>(
     Ok(ks)
 }
 
-pub fn keyshare_keyschedule(
-    party: &mut Lut256SSMalParty,
-) -> Vec<maestro::aes::AesKeyState> {
+
+pub fn aes256_keyschedule_mal(
+    party: &mut dyn MpcParty,
+    mut key: Vec<RssShare<GF8>>,
+) -> MpcResult<Vec<AesKeyState>> {
+    debug_assert_eq!(key.len(), 32);
+    const ROUND_CONSTANTS: [GF8; 7] = [GF8(0x01), GF8(0x02), GF8(0x04), GF8(0x08), GF8(0x10), GF8(0x20), GF8(0x40)];
+
+    let mut ks: Vec<AesKeyState> = Vec::with_capacity(15);
+    let key2: Vec<RssShare<GF8>> = key.split_off(16);
+    ks.push(AesKeyState::from_bytes(key)); // rk0
+    ks.push(AesKeyState::from_bytes(key2)); // rk1
+
+    for i in 1..=7 {
+        // RotWord and SubWord of the last column, then Rcon
+        let mut rot_i: [GF8; 4] = [ks[2*i-1].si[7], ks[2*i-1].si[11], ks[2*i-1].si[15], ks[2*i-1].si[3]];
+        let mut rot_ii: [GF8; 4] = [ks[2*i-1].sii[7], ks[2*i-1].sii[11], ks[2*i-1].sii[15], ks[2*i-1].sii[3]];
+        sub_bytes(party, &mut rot_i, &mut rot_ii)?;
+        let rcon: RssShare<GF8> = party.constant_rss(ROUND_CONSTANTS[i - 1]);
+        rot_i[0] += rcon.si;
+        rot_ii[0] += rcon.sii;
+        ks.push(next_round_key(&ks[2*i-2], &rot_i, &rot_ii));
+
+        if i < 7 {
+            // SubWord of the last column, without RotWord and Rcon
+            let mut sub_i: [GF8; 4] = [ks[2*i].si[3], ks[2*i].si[7], ks[2*i].si[11], ks[2*i].si[15]];
+            let mut sub_ii: [GF8; 4] = [ks[2*i].sii[3], ks[2*i].sii[7], ks[2*i].sii[11], ks[2*i].sii[15]];
+            sub_bytes(party, &mut sub_i, &mut sub_ii)?;
+            ks.push(next_round_key(&ks[2*i-1], &sub_i, &sub_ii));
+        }
+    }
+
+    Ok(ks)
+}
+
+// Round key from the one two rounds back and the transformed word
+fn next_round_key(prev: &AesKeyState, temp_i: &[GF8; 4], temp_ii: &[GF8; 4]) -> AesKeyState {
+    let mut rk: AesKeyState = prev.clone();
+    for i in 0..4 {
+        rk.si[4 * i] += temp_i[i];
+        rk.sii[4 * i] += temp_ii[i];
+    }
+    for j in 1..4 {
+        for i in 0..4 {
+            rk.si[4 * i + j] += rk.si[4 * i + j - 1];
+            rk.sii[4 * i + j] += rk.sii[4 * i + j - 1];
+        }
+    }
+    rk
+}
+
+/// Turns this party's key share into replicated shares of the key bytes. XOR shares are
+/// secret-shared by every party, replicated shares are used as they are.
+pub fn share_key(party: &mut MainParty, key: &KeyShare) -> MpcResult<Vec<RssShare<GF8>>> {
+    let bytes: &[u8] = match &key.0 {
+        Shares::Xor(bytes) => bytes,
+        Shares::Replicated { si, sii } => {
+            return Ok(si.iter().zip(sii.iter()).map(|(&si, &sii)| RssShare::from(GF8(si), GF8(sii))).collect());
+        }
+    };
+
     // Generate random shares for each byte of our key share
-    let shared_key: Vec<RssShare<GF8>> = AES_KEYSHARE[party.main_party_mut().i].iter()
+    bytes.iter()
         .map(|&b| {
             // Generate random shares that sum to our part of the key
             let si = GF8(rand::random::<u8>());
             let sii = GF8(b) - si;
             
             // First round: all parties send their sii shares
-            party.main_party_mut().io().send_field_slice(Next, vec![sii].as_slice());
+            let mut to_next: Vec<GF8> = vec![sii];
+            fault::tamper(Fault::KeyShare, &mut to_next);
+            party.io().send_field_slice(Next, to_next.as_slice());
             
             // First round: all parties receive from previous
             let mut received_si = vec![GF8(0); 1];
-            party.main_party_mut().io().receive_field_slice(Previous, &mut received_si).rcv().unwrap();
+            party.io().receive_field_slice(Previous, &mut received_si).rcv()?;
             
             // Second round: all parties send their si shares
-            party.main_party_mut().io().send_field_slice(Previous, vec![si].as_slice());
+            party.io().send_field_slice(Previous, vec![si].as_slice());
             
             // Second round: all parties receive from next
             let mut received_sii = vec![GF8(0); 1];
-            party.main_party_mut().io().receive_field_slice(Next, &mut received_sii).rcv().unwrap();
+            party.io().receive_field_slice(Next, &mut received_sii).rcv()?;
             
             // Combine all shares
-            RssShare {
+            Ok(RssShare {
                 si: si + received_si[0],
                 sii: sii + received_sii[0],
-            }
+            })
         })
-        .collect();
+        .collect::<MpcResult<Vec<_>>>()
+}
 
-    let ks: Vec<AesKeyState> = aes128_keyschedule_mal(party, shared_key).unwrap();
-    
-    // println!("Keyshare key schedule:");
-    // for (i, key) in ks.iter().enumerate() {
-    //     println!("Round {}: si: {:?}, sii: {:?}", i, key.si.iter().map(|x| x.0).collect::<Vec<_>>(), key.sii.iter().map(|x| x.0).collect::<Vec<_>>());
-    // }
+/// Secret-shares every party's key share and computes the AES-128 or AES-256 key schedule of
+/// the combined key. Runs its own preprocessing for the key schedule S-boxes.
+pub fn keyshare_keyschedule(
+    party: &mut dyn MpcParty,
+    key: &KeyShare,
+) -> Result<Vec<maestro::aes::AesKeyState>> {
+    let phase: Phase = metrics::start("key_schedule", party.main_party_mut());
+    // A party with a key of another length or form would run a different protocol
+    let variant: AesVariant = key.variant();
+    let form: u8 = key.replicated_bytes().is_some() as u8;
+    if !control::all_equal(party.main_party_mut(), &[variant_to_u8(variant), form])? {
+        return Err(LocksmithError::InvalidInput("the parties hold key shares of different lengths or forms".to_string()));
+    }
+
+    // 3 parties need preprocessed triples to generate the key schedule
+    let (n_keys, _): (usize, usize) = preprocessing_units(variant, 3, 0);
+    party.do_preprocessing(n_keys, 0)?;
+
+    // Stored shares from different epochs, or damaged ones, would silently make another key
+    if let Some((si, sii)) = key.replicated_bytes() {
+        if !keystore::copies_match(party.main_party_mut(), si, sii)? {
+            return Err(LocksmithError::Abort(AbortReason::CheckFailed));
+        }
+    }
+    let shared_key: Vec<RssShare<GF8>> = share_key(party.main_party_mut(), key)?;
+
+    let ks: Vec<AesKeyState> = match variant {
+        AesVariant::Aes128 => aes128_keyschedule_mal(party, shared_key)?,
+        AesVariant::Aes256 => aes256_keyschedule_mal(party, shared_key)?,
+    };
+    phase.end(party.main_party_mut());
 
     // cast back from custom AesKeyState (pub si, sii) to maestro AesKeyState for composability with other functions
-    ks.iter()
+    Ok(ks.iter()
         .map(|k| maestro::aes::AesKeyState::from_rss_vec(k.to_rss_vec()))
-        .collect()
+        .collect())
 }
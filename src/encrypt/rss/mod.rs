@@ -2,4 +2,4 @@ pub(crate) mod share_input;
 pub(crate) mod key_schedule;
 
 pub use share_input::rss_input;
-pub use key_schedule::keyshare_keyschedule;
\ No newline at end of file
+pub use key_schedule::{keyshare_keyschedule, share_key, KeyShare};
\ No newline at end of file
@@ -1,6 +1,8 @@
 use crate::data::Input;
-use maestro::{aes::VectorAesState, rep3_core::{network::{ConnectedParty,task::Direction::{Next, Previous}}, share::RssShare}, share::gf8::GF8};
-use maestro::{aes::{ss::{GF8InvBlackBoxSS, GF8InvBlackBoxSSMal}, GF8InvBlackBox}, lut256::{lut256_ss::{Lut256SSMalParty, Lut256SSParty}, LUT256Party}};
+use crate::encrypt::backend::MpcParty;
+use crate::encrypt::fault::{self, Fault};
+use crate::metrics::{self, Phase};
+use maestro::{aes::VectorAesState, rep3_core::{network::{ConnectedParty,task::Direction::{Next, Previous}}, party::error::MpcResult, share::RssShare}, share::gf8::GF8};
 
 
 // fn hex_to_bytes(hex: &str) -> Vec<u8> {
@@ -14,24 +16,13 @@ use maestro::{aes::{ss::{GF8InvBlackBoxSS, GF8InvBlackBoxSSMal}, GF8InvBlackBox}
 //         .collect()
 // }
 
-pub fn rss_input(library_input: &Input, party: &mut Lut256SSMalParty) -> Option<VectorAesState> {
+pub fn rss_input(library_input: &Input, party: &mut dyn MpcParty) -> MpcResult<VectorAesState> {
+    let phase: Phase = metrics::start("input", party.main_party_mut());
     let input_bytes: Vec<GF8> = library_input.iter().map(|&b| GF8(b)).collect::<Vec<_>>();
-    
-    println!("Full input bytes as hex: 0x{}", input_bytes.iter()
-        .map(|x| format!("{:02x}", x.0))
-        .collect::<Vec<_>>()
-        .join(""));
 
     let input: VectorAesState = if party.main_party_mut().i == 0 {
         // Party 0 provides the secret input and creates shares
-        println!("– Input: {}", String::from_utf8_lossy(&input_bytes.iter().map(|x| x.0).collect::<Vec<_>>()));
-        println!("Number of 16-byte blocks: {}", library_input.num_blocks());
-        // println!("Party bytes input: {:?}", input_bytes.iter().map(|x| x.0).collect::<Vec<_>>());
-
-        // println!("Input bytes as hex: 0x{}", input_bytes.iter()
-        //     .map(|&x| format!("{:02x}", x.0))
-        //     .collect::<Vec<_>>()
-        //     .join(""));     
+        crate::trace_secret!(input = %hex::encode(library_input.to_bytes()), "plaintext input");
 
         // Convert bytes to shares and distribute them
         let shared_bytes: Vec<RssShare<GF8>> = input_bytes.iter()
@@ -40,11 +31,14 @@ pub fn rss_input(library_input: &Input, party: &mut Lut256SSMalParty) -> Option<
                 let si: GF8 = GF8(rand::random::<u8>());
                 let sii: GF8 = b - si;
                 // Send sii to party 1 and si to party 2
-                party.main_party_mut().io().send_field_slice(Next, vec![sii].as_slice());
+                let mut to_next: Vec<GF8> = vec![sii];
+                fault::tamper(Fault::InputShare, &mut to_next);
+                party.main_party_mut().io().send_field_slice(Next, to_next.as_slice());
                 party.main_party_mut().io().send_field_slice(Previous, vec![si].as_slice());
                 RssShare { si, sii }
             })
             .collect();
+        crate::trace_secret!(shares = ?shared_bytes.iter().map(|share| (share.si.0, share.sii.0)).collect::<Vec<_>>(), "input shares");
         
         VectorAesState::from_bytes(shared_bytes)
     } else {
@@ -52,10 +46,10 @@ pub fn rss_input(library_input: &Input, party: &mut Lut256SSMalParty) -> Option<
         // let num_bytes = library_input.iter().len();
         let mut shares: Vec<GF8> = vec![GF8(0); 16*library_input.num_blocks()];
         if party.main_party_mut().i == 1 {
-            party.main_party_mut().io().receive_field_slice(Previous, &mut shares).rcv().unwrap();
+            party.main_party_mut().io().receive_field_slice(Previous, &mut shares).rcv()?;
         } else {
             
-            party.main_party_mut().io().receive_field_slice(Next, &mut shares).rcv().unwrap();
+            party.main_party_mut().io().receive_field_slice(Next, &mut shares).rcv()?;
         }
         
         let shared_bytes: Vec<RssShare<GF8>> = shares.into_iter()
@@ -67,9 +61,11 @@ pub fn rss_input(library_input: &Input, party: &mut Lut256SSMalParty) -> Option<
                 }
             })
             .collect();
+        crate::trace_secret!(shares = ?shared_bytes.iter().map(|share| (share.si.0, share.sii.0)).collect::<Vec<_>>(), "input shares");
 
         VectorAesState::from_bytes(shared_bytes)
     };
 
-    Some(input)
+    phase.end(party.main_party_mut());
+    Ok(input)
 }
\ No newline at end of file
@@ -0,0 +1,236 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+
+use crate::error::{AbortReason, LocksmithError, Result};
+use super::access::Access;
+use super::backend::MpcParty;
+use super::keystream::{KeystreamRequest, KeystreamService, Nonce, Purpose, Served, MAX_REQUEST_BLOCKS};
+use super::nonce::NonceLedger;
+use super::{check_owner, control, setup_with_access, KeyShare, DATA_OWNER};
+
+/// Number of 16-byte blocks handled per MPC batch unless configured otherwise
+pub const DEFAULT_CHUNK_BLOCKS: usize = 1024;
+
+/// Plaintext source and ciphertext sink, only held by the data owner (party 0)
+pub struct StreamIo {
+    pub reader: Box<dyn Read + Send>,
+    pub writer: Box<dyn Write + Send>,
+    /// Length of the input if known up front, only used for progress reporting
+    pub total_bytes: Option<u64>,
+}
+
+impl StreamIo {
+    pub fn from_paths(input: &Path, output: &Path) -> std::io::Result<Self> {
+        let file: File = File::open(input)?;
+        let total_bytes: u64 = file.metadata()?.len();
+        Ok(StreamIo {
+            reader: Box::new(BufReader::new(file)),
+            writer: Box::new(BufWriter::new(File::create(output)?)),
+            total_bytes: Some(total_bytes),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub bytes_done: u64,
+    pub blocks_done: u64,
+    pub total_bytes: Option<u64>,
+}
+
+/// Outcome of a streamed encryption, known to all three parties
+#[derive(Clone, Copy, Debug)]
+pub struct StreamSummary {
+    /// Nonce of the first window, `None` if the input was empty and no nonce was allocated
+    pub nonce: Option<u128>,
+    pub bytes: u64,
+}
+
+impl Progress {
+    pub fn percent(&self) -> Option<f64> {
+        self.total_bytes
+            .filter(|&total| total > 0)
+            .map(|total| 100.0 * self.bytes_done as f64 / total as f64)
+    }
+}
+
+// Reads the input in windows of `window` bytes on a separate thread so that the next
+// window is already in memory while the parties work on the current one.
+// The channel holds at most one window, which bounds memory use independent of the file size.
+fn spawn_reader(mut reader: Box<dyn Read + Send>, window: usize) -> (Receiver<std::io::Result<Vec<u8>>>, JoinHandle<()>) {
+    let (tx, rx) = sync_channel::<std::io::Result<Vec<u8>>>(1);
+    let handle: JoinHandle<()> = thread::spawn(move || loop {
+        let mut buf: Vec<u8> = vec![0u8; window];
+        let mut filled: usize = 0;
+        while filled < window {
+            match reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            }
+        }
+        buf.truncate(filled);
+        // An empty window marks the end of the input
+        let done: bool = filled == 0;
+        if tx.send(Ok(buf)).is_err() || done {
+            return;
+        }
+    });
+    (rx, handle)
+}
+
+// Ciphertext sink on its own thread, which ends with the result of all writes. It takes each
+// window with its keystream and XORs them itself, so that the parties already compute the
+// keystream of the next window meanwhile; the channel holds at most one more window.
+type Writer = (SyncSender<(Vec<u8>, Vec<u8>)>, JoinHandle<std::io::Result<()>>);
+
+fn spawn_writer(mut writer: Box<dyn Write + Send>) -> Writer {
+    let (tx, rx) = sync_channel::<(Vec<u8>, Vec<u8>)>(1);
+    let handle: JoinHandle<std::io::Result<()>> = thread::spawn(move || {
+        for (window, keystream) in rx {
+            let ciphertext: Vec<u8> = window.iter().zip(keystream.iter())
+                .map(|(data, ks)| data ^ ks)
+                .collect();
+            writer.write_all(&ciphertext)?;
+        }
+        writer.flush()
+    });
+    (tx, handle)
+}
+
+/// Encrypts a stream in CTR mode with bounded memory.
+///
+/// The data owner (party 0) passes `Some(StreamIo)`, the other two parties pass `None`.
+/// The input is processed in windows of `chunk_blocks` blocks: for every window the parties
+/// compute the keystream for the matching counter range while the next window is being read
+/// and the previous one is XORed with its keystream and written out.
+/// The keystream is only revealed to the data owner, so the other parties never see plaintext
+/// or ciphertext; they only learn the length of each window.
+/// With `Nonce::Auto` the nonce is allocated for the first window and the following windows
+/// continue its counter range. `key` is this party's share of version `version` of the key,
+/// `None` for the built-in key.
+/// A read or write error on the data owner aborts all three parties.
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_ctr_stream(
+    config: &Path,
+    data: Option<StreamIo>,
+    nonce: Nonce,
+    chunk_blocks: usize,
+    ledger: NonceLedger,
+    key: Option<&KeyShare>,
+    version: u32,
+    progress: impl FnMut(&Progress),
+) -> Result<StreamSummary> {
+    if chunk_blocks == 0 || chunk_blocks as u64 > MAX_REQUEST_BLOCKS {
+        return Err(LocksmithError::InvalidInput(format!("chunk_blocks must be between 1 and {}", MAX_REQUEST_BLOCKS)));
+    }
+
+    let (access, party): (Access, Box<dyn MpcParty>) = setup_with_access(config)?;
+    let service: KeystreamService = KeystreamService::authorized(party, DATA_OWNER, ledger, key, version, &access)?;
+    encrypt_ctr_stream_with(service, data, nonce, chunk_blocks, progress)
+}
+
+fn encrypt_ctr_stream_with(
+    mut service: KeystreamService,
+    data: Option<StreamIo>,
+    mut nonce: Nonce,
+    chunk_blocks: usize,
+    mut progress: impl FnMut(&Progress),
+) -> Result<StreamSummary> {
+    check_owner(service.main_party_mut(), data.is_some(), "the stream input and output")?;
+
+    let total_bytes: Option<u64> = data.as_ref().and_then(|io| io.total_bytes);
+    let (reader, mut writer) = match data {
+        Some(io) => (Some(spawn_reader(io.reader, 16 * chunk_blocks)), Some(spawn_writer(io.writer))),
+        None => (None, None),
+    };
+
+    let mut counter: u128 = 0;
+    let mut done: Progress = Progress { bytes_done: 0, blocks_done: 0, total_bytes };
+    // A read or write error of the data owner, announced to the others instead of the next window
+    let mut failure: Option<LocksmithError> = None;
+
+    loop {
+        let window: Option<Vec<u8>> = reader.as_ref().map(|(rx, _)| match rx.recv() {
+            Ok(Ok(window)) => window,
+            Ok(Err(e)) => {
+                failure = Some(e.into());
+                Vec::new()
+            }
+            Err(_) => Vec::new(),
+        });
+        // At the end of the input the output is flushed, so that a failed write still aborts
+        if window.as_ref().is_some_and(Vec::is_empty) && failure.is_none() {
+            failure = finish_writer(writer.take()).err();
+        }
+
+        // The data owner announces the length of the next window, zero ends the stream, and a
+        // single byte, the code of its error, aborts it
+        let announcement: Option<Vec<u8>> = window.as_ref().map(|window| match &failure {
+            Some(e) => vec![e.code()],
+            None => (window.len() as u64).to_be_bytes().to_vec(),
+        });
+        let announced: Vec<u8> = control::broadcast(service.main_party_mut(), DATA_OWNER, announcement.as_deref())?;
+        let len: usize = match announced.as_slice() {
+            [code] => {
+                return Err(failure.take().unwrap_or(LocksmithError::Abort(AbortReason::PeerAborted {
+                    party: DATA_OWNER,
+                    kind: LocksmithError::kind_from_code(*code),
+                })));
+            }
+            len => u64::from_be_bytes(len.try_into().map_err(|_| LocksmithError::Malformed("window length".to_string()))?) as usize,
+        };
+        if len == 0 {
+            break;
+        }
+
+        let num_blocks: usize = len.div_ceil(16);
+        let request: Option<KeystreamRequest> = window.as_ref()
+            .map(|_| KeystreamRequest { nonce, start: counter, blocks: num_blocks as u64, purpose: Purpose::Encrypt });
+        let served: Served = service.serve(request)?;
+        nonce = Nonce::Fixed(served.nonce);
+
+        if let (Some((tx, _)), Some(window), Some(keystream)) = (&writer, window, served.keystream) {
+            // The writer only stops on an error, which the next announcement reports
+            if tx.send((window, keystream)).is_err() {
+                failure = Some(finish_writer(writer.take()).err()
+                    .unwrap_or_else(|| std::io::Error::other("output writer stopped").into()));
+            }
+        }
+
+        counter = counter.wrapping_add(num_blocks as u128);
+        done.bytes_done += len as u64;
+        done.blocks_done += num_blocks as u64;
+        progress(&done);
+    }
+
+    service.shutdown()?;
+
+    if let Some((rx, handle)) = reader {
+        drop(rx);
+        let _ = handle.join();
+    }
+
+    let nonce: Option<u128> = match nonce {
+        Nonce::Fixed(nonce) => Some(nonce),
+        Nonce::Auto => None,
+    };
+    Ok(StreamSummary { nonce, bytes: done.bytes_done })
+}
+
+// Closes the output and waits until everything is written
+fn finish_writer(writer: Option<Writer>) -> Result<()> {
+    if let Some((tx, handle)) = writer {
+        drop(tx);
+        handle.join().map_err(|_| std::io::Error::other("output writer panicked"))??;
+    }
+    Ok(())
+}
@@ -0,0 +1,344 @@
+// Self-describing container for ciphertexts, so that a ciphertext can be decrypted without
+// knowing out of band which key, AES variant and mode produced it.
+//
+// Binary layout (version 2), all integers big-endian:
+//   magic "LKSM" | version u8 | key ID (u8 len + bytes) | key version u32 | variant u8 | mode u8 |
+//   IV/nonce (u8 len + bytes) | padding u8 | plaintext length u64 | tag (u8 len + bytes) |
+//   ciphertext (u64 len + bytes)
+// Version 1 has no key version and is read as the first version of the key.
+// The JSON form carries the same fields with byte strings in hex.
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::aes::AesVariant;
+use crate::data::{Ciphertext, Plaintext, Tag};
+use crate::encrypt::keystore::FIRST_VERSION;
+use crate::error::{LocksmithError, Result};
+
+pub const MAGIC: [u8; 4] = *b"LKSM";
+pub const VERSION: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CipherMode {
+    Ecb,
+    Ctr,
+}
+
+/// How the plaintext was extended to whole blocks before encryption
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Padding {
+    None,
+    /// Zero bytes up to the block size, as done by `data::pad_input_string`
+    Zero,
+    Pkcs7,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Envelope {
+    pub key_id: String,
+    /// Version of the key, see `keystore::rotate_key`
+    pub key_version: u32,
+    pub variant: AesVariant,
+    pub mode: CipherMode,
+    /// IV for block modes, initial counter block for CTR, empty for ECB
+    pub iv: Vec<u8>,
+    pub padding: Padding,
+    pub plaintext_len: u64,
+    /// Authentication tag, empty for unauthenticated modes
+    pub tag: Tag,
+    pub ciphertext: Ciphertext,
+}
+
+pub(crate) fn variant_to_u8(variant: AesVariant) -> u8 {
+    match variant {
+        AesVariant::Aes128 => 1,
+        AesVariant::Aes256 => 2,
+    }
+}
+
+pub(crate) fn variant_from_u8(value: u8) -> Option<AesVariant> {
+    match value {
+        1 => Some(AesVariant::Aes128),
+        2 => Some(AesVariant::Aes256),
+        _ => None,
+    }
+}
+
+pub(crate) fn mode_to_u8(mode: CipherMode) -> u8 {
+    match mode {
+        CipherMode::Ecb => 1,
+        CipherMode::Ctr => 2,
+    }
+}
+
+pub(crate) fn mode_from_u8(value: u8) -> Option<CipherMode> {
+    match value {
+        1 => Some(CipherMode::Ecb),
+        2 => Some(CipherMode::Ctr),
+        _ => None,
+    }
+}
+
+fn padding_to_u8(padding: Padding) -> u8 {
+    match padding {
+        Padding::None => 0,
+        Padding::Zero => 1,
+        Padding::Pkcs7 => 2,
+    }
+}
+
+fn padding_from_u8(value: u8) -> Option<Padding> {
+    match value {
+        0 => Some(Padding::None),
+        1 => Some(Padding::Zero),
+        2 => Some(Padding::Pkcs7),
+        _ => None,
+    }
+}
+
+fn malformed(msg: impl Into<String>) -> LocksmithError {
+    LocksmithError::Malformed(msg.into())
+}
+
+// Cursor over the binary encoding
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(malformed("truncated envelope"));
+        }
+        let out: &'a [u8] = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes: [u8; 8] = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn short_bytes(&mut self) -> Result<&'a [u8]> {
+        let len: usize = self.u8()? as usize;
+        self.take(len)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonEnvelope {
+    version: u8,
+    key_id: String,
+    #[serde(default = "first_version")]
+    key_version: u32,
+    variant: String,
+    mode: String,
+    iv: String,
+    padding: String,
+    plaintext_len: u64,
+    tag: String,
+    ciphertext: String,
+}
+
+fn first_version() -> u32 {
+    FIRST_VERSION
+}
+
+impl Envelope {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        for (name, len) in [("key ID", self.key_id.len()), ("IV", self.iv.len()), ("tag", self.tag.len())] {
+            if len > u8::MAX as usize {
+                return Err(LocksmithError::InvalidInput(format!("{} too long for envelope", name)));
+            }
+        }
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(64 + self.ciphertext.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.key_id.len() as u8);
+        bytes.extend_from_slice(self.key_id.as_bytes());
+        bytes.extend_from_slice(&self.key_version.to_be_bytes());
+        bytes.push(variant_to_u8(self.variant));
+        bytes.push(mode_to_u8(self.mode));
+        bytes.push(self.iv.len() as u8);
+        bytes.extend_from_slice(&self.iv);
+        bytes.push(padding_to_u8(self.padding));
+        bytes.extend_from_slice(&self.plaintext_len.to_be_bytes());
+        bytes.push(self.tag.len() as u8);
+        bytes.extend_from_slice(self.tag.as_bytes());
+        bytes.extend_from_slice(&(self.ciphertext.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(self.ciphertext.as_bytes());
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader: Reader = Reader { bytes, pos: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err(malformed("not a locksmith envelope"));
+        }
+        let version: u8 = reader.u8()?;
+        if version != VERSION && version != 1 {
+            return Err(malformed(format!("unsupported envelope version {}", version)));
+        }
+
+        let key_id: String = String::from_utf8(reader.short_bytes()?.to_vec()).map_err(|_| malformed("key ID is not UTF-8"))?;
+        let key_version: u32 = match version {
+            1 => FIRST_VERSION,
+            _ => u32::from_be_bytes(reader.take(4)?.try_into().map_err(|_| malformed("truncated envelope"))?),
+        };
+        let variant: AesVariant = variant_from_u8(reader.u8()?).ok_or_else(|| malformed("unknown AES variant"))?;
+        let mode: CipherMode = mode_from_u8(reader.u8()?).ok_or_else(|| malformed("unknown cipher mode"))?;
+        let iv: Vec<u8> = reader.short_bytes()?.to_vec();
+        let padding: Padding = padding_from_u8(reader.u8()?).ok_or_else(|| malformed("unknown padding scheme"))?;
+        let plaintext_len: u64 = reader.u64()?;
+        let tag: Tag = Tag::new(reader.short_bytes()?.to_vec());
+        let ciphertext_len: usize = usize::try_from(reader.u64()?).map_err(|_| malformed("ciphertext too long"))?;
+        let ciphertext: Ciphertext = Ciphertext::new(reader.take(ciphertext_len)?.to_vec());
+        if reader.pos != bytes.len() {
+            return Err(malformed("trailing bytes after envelope"));
+        }
+
+        let envelope: Envelope = Envelope { key_id, key_version, variant, mode, iv, padding, plaintext_len, tag, ciphertext };
+        envelope.validate()?;
+        Ok(envelope)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        let json: JsonEnvelope = JsonEnvelope {
+            version: VERSION,
+            key_id: self.key_id.clone(),
+            key_version: self.key_version,
+            variant: match self.variant {
+                AesVariant::Aes128 => "aes-128",
+                AesVariant::Aes256 => "aes-256",
+            }.to_string(),
+            mode: match self.mode {
+                CipherMode::Ecb => "ecb",
+                CipherMode::Ctr => "ctr",
+            }.to_string(),
+            iv: hex::encode(&self.iv),
+            padding: match self.padding {
+                Padding::None => "none",
+                Padding::Zero => "zero",
+                Padding::Pkcs7 => "pkcs7",
+            }.to_string(),
+            plaintext_len: self.plaintext_len,
+            tag: self.tag.to_hex(),
+            ciphertext: self.ciphertext.to_hex(),
+        };
+        serde_json::to_string_pretty(&json).map_err(|e| LocksmithError::InvalidInput(e.to_string()))
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        let json: JsonEnvelope = serde_json::from_str(text).map_err(|e| malformed(e.to_string()))?;
+        if json.version != VERSION && json.version != 1 {
+            return Err(malformed(format!("unsupported envelope version {}", json.version)));
+        }
+
+        let envelope: Envelope = Envelope {
+            key_id: json.key_id,
+            key_version: json.key_version,
+            variant: match json.variant.as_str() {
+                "aes-128" => AesVariant::Aes128,
+                "aes-256" => AesVariant::Aes256,
+                other => return Err(malformed(format!("unknown AES variant '{}'", other))),
+            },
+            mode: match json.mode.as_str() {
+                "ecb" => CipherMode::Ecb,
+                "ctr" => CipherMode::Ctr,
+                other => return Err(malformed(format!("unknown cipher mode '{}'", other))),
+            },
+            iv: hex::decode(&json.iv).map_err(|_| malformed("IV is not valid hex"))?,
+            padding: match json.padding.as_str() {
+                "none" => Padding::None,
+                "zero" => Padding::Zero,
+                "pkcs7" => Padding::Pkcs7,
+                other => return Err(malformed(format!("unknown padding scheme '{}'", other))),
+            },
+            plaintext_len: json.plaintext_len,
+            tag: Tag::from_hex(&json.tag)?,
+            ciphertext: Ciphertext::from_hex(&json.ciphertext)?,
+        };
+        envelope.validate()?;
+        Ok(envelope)
+    }
+
+    /// Parses either encoding, telling them apart by the leading magic bytes
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.starts_with(&MAGIC) {
+            Self::from_bytes(bytes)
+        } else {
+            Self::from_json(std::str::from_utf8(bytes).map_err(|_| malformed("neither a binary nor a JSON envelope"))?)
+        }
+    }
+
+    /// Reads an envelope in either encoding
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// Writes the envelope as JSON if the file name ends in `.json`, in the binary encoding otherwise
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let bytes: Vec<u8> = match path.extension() {
+            Some(ext) if ext == "json" => self.to_json()?.into_bytes(),
+            _ => self.to_bytes()?,
+        };
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Number of 16-byte blocks of ciphertext
+    pub fn num_blocks(&self) -> usize {
+        self.ciphertext.len().div_ceil(16)
+    }
+
+    /// Checks the fields against each other
+    pub fn validate(&self) -> Result<()> {
+        match self.mode {
+            CipherMode::Ecb => {
+                if !self.iv.is_empty() {
+                    return Err(malformed("ECB envelopes carry no IV"));
+                }
+                if !self.ciphertext.len().is_multiple_of(16) {
+                    return Err(malformed("ECB ciphertext is not a whole number of blocks"));
+                }
+                if self.padding == Padding::None && self.plaintext_len != self.ciphertext.len() as u64 {
+                    return Err(malformed("unpadded ECB ciphertext does not match the plaintext length"));
+                }
+            }
+            CipherMode::Ctr => {
+                if self.iv.len() != 16 {
+                    return Err(malformed("CTR envelopes need a 16-byte initial counter block"));
+                }
+                if self.padding == Padding::Pkcs7 {
+                    return Err(malformed("CTR ciphertexts are not PKCS#7 padded"));
+                }
+            }
+        }
+        if self.plaintext_len > self.ciphertext.len() as u64 {
+            return Err(malformed("plaintext length exceeds ciphertext length"));
+        }
+        Ok(())
+    }
+
+    /// Removes the padding from a decrypted plaintext
+    pub fn unpad(&self, plaintext: Plaintext) -> Result<Plaintext> {
+        let mut plaintext: Vec<u8> = plaintext.into_bytes();
+        if self.padding == Padding::Pkcs7 {
+            let pad: usize = *plaintext.last().ok_or_else(|| malformed("empty PKCS#7 plaintext"))? as usize;
+            if pad == 0 || pad > 16 || pad > plaintext.len() || plaintext[plaintext.len() - pad..].iter().any(|&b| b as usize != pad) {
+                return Err(malformed("invalid PKCS#7 padding"));
+            }
+        }
+        plaintext.truncate(self.plaintext_len as usize);
+        Ok(Plaintext::new(plaintext))
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use maestro::rep3_core::party::error::MpcError;
+
+/// Errors returned by the locksmith library
+#[derive(Debug)]
+pub enum LocksmithError {
+    /// The party configuration could not be loaded or is inconsistent
+    Config(String),
+    /// Connecting to or exchanging messages with another party failed
+    Network(String),
+    /// The computation was aborted because a party deviated from the protocol, or because
+    /// another party aborted. No output is revealed after an abort.
+    Abort(AbortReason),
+    /// More AES blocks were requested than were preprocessed for this session
+    PreprocessingExhausted { available: usize, requested: usize },
+    /// The parties' inputs do not have the length the data owner announced
+    InputLengthMismatch { expected: usize, actual: usize },
+    /// Any other failure inside the MPC protocol
+    Mpc(MpcError),
+    Io(std::io::Error),
+    /// The caller's request or input is not valid on this party
+    InvalidInput(String),
+    /// Data read from a file or received from another party could not be decoded
+    Malformed(String),
+    /// The parties did not agree to carry out the request
+    Refused(String),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum AbortReason {
+    /// A malicious-security check (MAC or multiplication check) failed
+    CheckFailed,
+    /// A party sent different values to the two other parties
+    Equivocation { party: usize },
+    /// The two copies of an output share sent to the client differ
+    OutputMismatch,
+    /// Another party aborted, with the kind of error it reported
+    PeerAborted { party: usize, kind: &'static str },
+}
+
+pub type Result<T> = std::result::Result<T, LocksmithError>;
+
+// One-byte codes for the error kinds, exchanged in `control::checkpoint`
+const KINDS: [&str; 11] = [
+    "config",
+    "network",
+    "abort",
+    "preprocessing exhausted",
+    "input length mismatch",
+    "mpc",
+    "io",
+    "invalid input",
+    "malformed",
+    "refused",
+    "unknown",
+];
+
+impl LocksmithError {
+    /// Non-zero code for the kind of this error, see `kind_from_code`
+    pub fn code(&self) -> u8 {
+        let index: usize = match self {
+            LocksmithError::Config(_) => 0,
+            LocksmithError::Network(_) => 1,
+            LocksmithError::Abort(_) => 2,
+            LocksmithError::PreprocessingExhausted { .. } => 3,
+            LocksmithError::InputLengthMismatch { .. } => 4,
+            LocksmithError::Mpc(_) => 5,
+            LocksmithError::Io(_) => 6,
+            LocksmithError::InvalidInput(_) => 7,
+            LocksmithError::Malformed(_) => 8,
+            LocksmithError::Refused(_) => 9,
+        };
+        index as u8 + 1
+    }
+
+    /// Whether the parties can no longer rely on the session after this error, because a link
+    /// broke, a party cheated or the parties may have fallen out of step
+    pub fn ends_session(&self) -> bool {
+        matches!(
+            self,
+            LocksmithError::Network(_) | LocksmithError::Abort(_) | LocksmithError::Mpc(_) | LocksmithError::Io(_) | LocksmithError::Malformed(_)
+        )
+    }
+
+    pub fn kind_from_code(code: u8) -> &'static str {
+        KINDS[(code as usize).saturating_sub(1).min(KINDS.len() - 1)]
+    }
+}
+
+impl fmt::Display for AbortReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbortReason::CheckFailed => write!(f, "malicious-security check failed"),
+            AbortReason::Equivocation { party } => write!(f, "party {} sent inconsistent messages", party),
+            AbortReason::OutputMismatch => write!(f, "output shares from the other parties disagree"),
+            AbortReason::PeerAborted { party, kind } => write!(f, "party {} aborted ({} error)", party, kind),
+        }
+    }
+}
+
+impl fmt::Display for LocksmithError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocksmithError::Config(msg) => write!(f, "configuration error: {}", msg),
+            LocksmithError::Network(msg) => write!(f, "network error: {}", msg),
+            LocksmithError::Abort(reason) => write!(f, "aborted: {}", reason),
+            LocksmithError::PreprocessingExhausted { available, requested } => write!(
+                f,
+                "preprocessing exhausted: {} blocks requested, {} available",
+                requested, available
+            ),
+            LocksmithError::InputLengthMismatch { expected, actual } => write!(
+                f,
+                "input length mismatch: expected {} blocks, got {}",
+                expected, actual
+            ),
+            LocksmithError::Mpc(e) => write!(f, "MPC protocol error: {:?}", e),
+            LocksmithError::Io(e) => write!(f, "I/O error: {}", e),
+            LocksmithError::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            LocksmithError::Malformed(msg) => write!(f, "malformed data: {}", msg),
+            LocksmithError::Refused(msg) => write!(f, "request refused: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LocksmithError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LocksmithError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<MpcError> for LocksmithError {
+    fn from(e: MpcError) -> Self {
+        match e {
+            // maestro reports failed MAC and multiplication checks as a failed sacrifice
+            MpcError::Sacrifice => LocksmithError::Abort(AbortReason::CheckFailed),
+            MpcError::Receive => LocksmithError::Network("failed to receive from another party".to_string()),
+            MpcError::Io(e) => LocksmithError::Network(e.to_string()),
+            e => LocksmithError::Mpc(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for LocksmithError {
+    fn from(e: std::io::Error) -> Self {
+        LocksmithError::Io(e)
+    }
+}
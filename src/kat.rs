@@ -0,0 +1,92 @@
+// Reader for the NIST CAVP response files (.rsp) in src/data/sources/kat, in the layout used by
+// AESAVS (ECBVarTxt128.rsp, CBCVarKey256.rsp, CBCMCT128.rsp, ...) and GCMVS (gcmEncrypt.rsp,
+// which holds the McGrew-Viega test cases rather than NIST's).
+//
+// A file is a sequence of bracketed headers and records separated by blank lines. A header
+// without `=` starts a section, e.g. `[ENCRYPT]`; a header with `=` sets a parameter of the
+// following records, e.g. `[Keylen = 128]`. Records are `NAME = hex` lines starting at COUNT.
+use std::collections::HashMap;
+
+const KAT_DIR: &str = "./src/data/sources/kat";
+
+pub struct Vector {
+    pub file: String,
+    pub section: String,
+    pub count: usize,
+    params: HashMap<String, String>,
+    fields: HashMap<String, Vec<u8>>,
+}
+
+impl Vector {
+    /// Field `name` in AESAVS spelling; GCMVS abbreviations (PT, CT) are mapped on load
+    pub fn get(&self, name: &str) -> &[u8] {
+        self.fields.get(name).unwrap_or_else(|| panic!("{} has no {}", self, name))
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.fields.contains_key(name)
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    pub fn is_decrypt(&self) -> bool {
+        self.section == "DECRYPT"
+    }
+}
+
+impl std::fmt::Display for Vector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} [{}] COUNT = {}", self.file, self.section, self.count)
+    }
+}
+
+/// All records of `file`, in file order
+pub fn load(file: &str) -> Vec<Vector> {
+    let path: String = format!("{}/{}", KAT_DIR, file);
+    let text: String = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+
+    let mut vectors: Vec<Vector> = Vec::new();
+    let mut section: String = String::new();
+    let mut params: HashMap<String, String> = HashMap::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            match header.split_once('=') {
+                Some((name, value)) => {
+                    params.insert(name.trim().to_uppercase(), value.trim().to_string());
+                }
+                None => {
+                    section = header.trim().to_string();
+                    params.clear();
+                }
+            }
+            continue;
+        }
+
+        let (name, value) = line.split_once('=').unwrap_or_else(|| panic!("{}: unexpected line '{}'", path, line));
+        let (name, value): (String, &str) = (name.trim().to_uppercase(), value.trim());
+        if name == "COUNT" {
+            vectors.push(Vector {
+                file: file.to_string(),
+                section: section.clone(),
+                count: value.parse().unwrap_or_else(|_| panic!("{}: bad COUNT '{}'", path, value)),
+                params: params.clone(),
+                fields: HashMap::new(),
+            });
+            continue;
+        }
+        let name: String = match name.as_str() {
+            "PT" => "PLAINTEXT".to_string(),
+            "CT" => "CIPHERTEXT".to_string(),
+            _ => name,
+        };
+        let vector: &mut Vector = vectors.last_mut().unwrap_or_else(|| panic!("{}: {} before the first COUNT", path, name));
+        let bytes: Vec<u8> = hex::decode(value).unwrap_or_else(|e| panic!("{}: {} is not hex: {}", vector, name, e));
+        vector.fields.insert(name, bytes);
+    }
+    vectors
+}
@@ -0,0 +1,43 @@
+// Levelled, structured logging through `tracing`, written to stderr so that stdout keeps the
+// results. Every run is a `session` span naming the party, mode and key; the protocol phases in
+// it (connecting, input sharing, key schedule, preprocessing, AES, checks, output) are child
+// spans, so every line says where in a session it comes from.
+//
+// The level is set with LOCKSMITH_LOG in `EnvFilter` syntax, e.g. `debug` or
+// `locksmith::encrypt=trace`, and defaults to `info`. Plaintext, keys and shares are never
+// logged, except through `trace_secret!` in builds with the `debug-secrets` feature.
+use clap::ValueEnum;
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Installs the global subscriber; does nothing if one is installed already
+pub fn init(format: LogFormat) {
+    let filter: EnvFilter = EnvFilter::try_from_env("LOCKSMITH_LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+}
+
+/// Span of a protocol phase, to enter or to run a closure `in_scope`
+pub fn phase(name: &'static str) -> Span {
+    tracing::debug_span!("phase", name)
+}
+
+/// Logs plaintext or share material at trace level. Compiled in only with the `debug-secrets`
+/// feature; otherwise the arguments are not even evaluated.
+#[macro_export]
+macro_rules! trace_secret {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "debug-secrets")]
+        tracing::trace!(secret = true, $($arg)*);
+    };
+}
@@ -1,38 +1,327 @@
+use clap::{Parser, ValueEnum};
 use maestro::rep3_core::share::{HasZero, RssShare};
 use maestro::share::gf8::GF8;
 
 use mpz_fields::p256::P256;
 
+pub mod config;
 pub mod data;
 pub mod encrypt;
 pub mod rust_impl;
 pub mod aes;
+pub mod envelope;
+pub mod error;
+pub mod logging;
+pub mod metrics;
+#[cfg(test)]
+mod sim;
+#[cfg(test)]
+mod kat;
 
-use data::Input;
+use aes::AesVariant;
+use data::{Ciphertext, Input, Plaintext, Tag};
+use config::{certs::CertsCommand, ConfigCommand};
+use encrypt::{Cli, Command, KeyShare, Mode};
+use encrypt::backup::{Backup, BackupCommand, CustodianShare, Protection, Unlock};
+use encrypt::keystore::{KeySource, KeyStore, StoredKey, FIRST_VERSION};
+use encrypt::keystream::Nonce;
+use encrypt::nonce::NonceLedger;
+use encrypt::stream::{Progress, StreamIo, StreamSummary};
+use envelope::{CipherMode, Envelope, Padding};
 
 fn main() {
-    println!("This is Locksmith!");
+    let cli: Cli = Cli::parse();
+    logging::init(cli.log_format);
+    tracing::info!("This is Locksmith!");
 
     // Create a new replicated secret share (using Araki optimisation)
     let rss_share: RssShare<GF8> = RssShare::<GF8>::from(GF8::ZERO, GF8::ZERO);
-    println!("{:?}", rss_share);
+    tracing::debug!(?rss_share);
 
     // Create element of P256 field for multi-party ECDH
     let p256_element: P256 = P256::new(0).unwrap();  
-    println!("{:?}", p256_element);
+    tracing::debug!(?p256_element);
 
-    let input: &Input = &data::get_input("short");
-    let ciphertext: String = encrypt::encrypt_ecb(input).unwrap();
-    // let ciphertext: String = encrypt::encrypt_ctr(input, 0).unwrap();
-    println!("Ciphertext: 0x{}", ciphertext);
+    match &cli.command {
+        Some(Command::Config(command)) => return run_config_command(command),
+        Some(Command::Certs(command)) => return run_certs_command(command),
+        Some(Command::Backup(command)) => return run_backup_command(command),
+        None => {}
+    }
+    // One session per run; the daemon opens one for every MPC session in it
+    let party: Option<usize> = config::load(cli.config_path()).ok().map(|config| config.party_index);
+    let _session: tracing::span::EnteredSpan = tracing::info_span!("session", party, mode = ?cli.mode, key_id = %cli.key_id).entered();
+    let nonce: Nonce = cli.nonce.map_or(Nonce::Auto, Nonce::Fixed);
+    let keys: KeyStore = KeyStore::open(&cli.keystore_dir());
+    // The newest version of the stored key --key-id names encrypts; only the default ID falls back
+    // to the built-in key. Modes that create or manage keys look the key up themselves.
+    let stored: Option<StoredKey> = match cli.mode {
+        Mode::Ecb | Mode::Ctr | Mode::CtrStream | Mode::Daemon => or_abort(keys.lookup(&cli.key_id)),
+        _ => None,
+    };
+    let key_version: u32 = stored.as_ref().map_or(FIRST_VERSION, |stored| stored.version);
+    let key: Option<KeyShare> = stored.as_ref().map(|stored| or_abort(stored.encryption_share()).clone());
+    let variant: AesVariant = key.as_ref().map_or(AesVariant::Aes128, KeyShare::variant);
+    match cli.mode {
+        Mode::Ecb => {
+            let input: &Input = &data::get_input("short");
+            let ciphertext: Ciphertext = or_abort(encrypt::encrypt_ecb(cli.config_path(), input, &cli.key_id, key.as_ref()));
+            println!("Ciphertext: 0x{}", ciphertext.to_hex());
+            if let Some(path) = &cli.envelope {
+                let envelope: Envelope = Envelope {
+                    key_id: cli.key_id.clone(),
+                    key_version,
+                    variant,
+                    mode: CipherMode::Ecb,
+                    iv: Vec::new(),
+                    padding: Padding::Zero,
+                    plaintext_len: input.data_len() as u64,
+                    tag: Tag::default(),
+                    ciphertext,
+                };
+                or_abort(envelope.save(path));
+            }
+        }
+        Mode::Ctr => {
+            // Only the data owner holds the plaintext, the other parties just compute keystream
+            let input: Option<Input> = cli.input.as_ref().map(|path| or_abort(data::load_input(path).map_err(Into::into)));
+            let ledger: NonceLedger = or_abort(NonceLedger::open(&cli.ledger_dir(), &cli.key_id).map_err(Into::into));
+            let (nonce, ciphertext) = or_abort(encrypt::encrypt_ctr(cli.config_path(), input.as_ref(), nonce, ledger, key.as_ref(), key_version));
+            println!("Nonce: 0x{:032x}", nonce);
+            match (ciphertext, input) {
+                (Some(ciphertext), Some(input)) => {
+                    println!("Ciphertext: 0x{}", ciphertext.to_hex());
+                    if let Some(path) = &cli.envelope {
+                        let envelope: Envelope = Envelope {
+                            key_id: cli.key_id.clone(),
+                            key_version,
+                            variant,
+                            mode: CipherMode::Ctr,
+                            iv: nonce.to_be_bytes().to_vec(),
+                            padding: Padding::Zero,
+                            plaintext_len: input.data_len() as u64,
+                            tag: Tag::default(),
+                            ciphertext,
+                        };
+                        or_abort(envelope.save(path));
+                    }
+                }
+                _ => println!("Keystream delivered to the data owner"),
+            }
+        }
+        Mode::CtrStream => {
+            let data: Option<StreamIo> = match (&cli.input, &cli.output) {
+                (Some(input), Some(output)) => Some(or_abort(StreamIo::from_paths(input, output).map_err(Into::into))),
+                (None, None) => None,
+                _ => or_abort(Err(error::LocksmithError::InvalidInput(
+                    "--input and --output must be given together in ctr-stream mode".to_string(),
+                ))),
+            };
+            let ledger: NonceLedger = or_abort(NonceLedger::open(&cli.ledger_dir(), &cli.key_id).map_err(Into::into));
+            let summary: StreamSummary = or_abort(encrypt::stream::encrypt_ctr_stream(cli.config_path(), data, nonce, cli.chunk_blocks, ledger, key.as_ref(), key_version, print_progress));
+            if let Some(nonce) = summary.nonce {
+                println!("Nonce: 0x{:032x}", nonce);
+            }
+            println!("Encrypted {} bytes", summary.bytes);
+        }
+        Mode::Daemon => {
+            let jobs: Option<std::sync::mpsc::Receiver<encrypt::daemon::Job>> = cli.spool.as_ref()
+                .map(|dir| encrypt::daemon::spool(dir.clone(), cli.key_id.clone(), variant));
+            let refresh: Option<std::time::Duration> = cli.refresh_every.map(std::time::Duration::from_secs);
+            if let Some(addr) = &cli.metrics_addr {
+                let local: std::net::SocketAddr = or_abort(metrics::serve(addr));
+                println!("Metrics at http://{}/metrics", local);
+            }
+            or_abort(encrypt::daemon::run(cli.config_path(), &cli.ledger_dir(), &keys, &cli.key_id, jobs, refresh));
+            println!("Daemon stopped");
+        }
+        Mode::StoreKey => {
+            let stored: StoredKey = or_abort(encrypt::keystore::store_key(cli.config_path(), &keys, &cli.key_id, None));
+            println!("Stored key '{}' in {}", stored.key_id, cli.keystore_dir().display());
+        }
+        Mode::Kcv => {
+            let kcv: [u8; encrypt::kcv::KCV_LEN] = or_abort(encrypt::kcv::check_key(cli.config_path(), &keys, &cli.key_id));
+            println!("Key '{}' has key check value {}", cli.key_id, hex::encode(kcv));
+        }
+        Mode::ImportKey => {
+            // Only the data owner reads the key; the others receive their shares of it
+            let plaintext: Option<Vec<u8>> = cli.key_file.as_ref().map(|path| {
+                let text: String = or_abort(std::fs::read_to_string(path).map_err(Into::into));
+                or_abort(hex::decode(text.trim())
+                    .map_err(|_| error::LocksmithError::InvalidInput("the key file must hold the key in hex".to_string())))
+            });
+            let (stored, kcv) = or_abort(encrypt::import::import_key(cli.config_path(), &keys, &cli.key_id, plaintext.as_deref()));
+            println!("Imported key '{}' with key check value {}", stored.key_id, hex::encode(kcv));
+        }
+        Mode::Refresh => {
+            let stored: StoredKey = or_abort(encrypt::keystore::refresh_key(cli.config_path(), &keys, &cli.key_id));
+            println!("Refreshed the shares of key '{}' to epoch {}", stored.key_id, stored.epoch);
+        }
+        Mode::RotateKey => {
+            let stored: StoredKey = or_abort(encrypt::keystore::rotate_key(cli.config_path(), &keys, &cli.key_id));
+            println!("Key '{}' is now at version {}", stored.key_id, stored.version);
+        }
+        Mode::Reshare => {
+            // Run by the two remaining parties and the new host, which uses the replaced party's config
+            let replaced: usize = or_abort(cli.replaced_party
+                .and_then(|party| party.checked_sub(1))
+                .filter(|&index| index < 3)
+                .ok_or_else(|| error::LocksmithError::InvalidInput("reshare mode needs --replaced-party 1, 2 or 3".to_string())));
+            let reshared: Vec<StoredKey> = or_abort(encrypt::reshare::reshare(cli.config_path(), &keys, &cli.ledger_dir(), replaced));
+            println!("Moved {} key versions to the new party {}", reshared.len(), replaced + 1);
+        }
+        Mode::Restore => {
+            // Only the restoring party opens a backup; the other two check it against their keys
+            let restored: Option<Vec<StoredKey>> = cli.backup.as_ref().map(|path| {
+                let unlock: Unlock = match &cli.recovery_key {
+                    Some(key) => Unlock::Recovery(or_abort(encrypt::backup::read_key(key))),
+                    None => Unlock::Custodians(cli.custodian_share.iter().map(|share| or_abort(CustodianShare::load(share))).collect()),
+                };
+                or_abort(or_abort(Backup::load(path)).open(&unlock))
+            });
+            let restoring: bool = restored.is_some();
+            let checked: Vec<StoredKey> = or_abort(encrypt::backup::restore(cli.config_path(), &keys, restored));
+            match restoring {
+                true => println!("Restored {} key versions to {}", checked.len(), cli.keystore_dir().display()),
+                false => println!("Checked {} restored key versions", checked.len()),
+            }
+        }
+        Mode::Bench => {
+            let results: Vec<encrypt::bench::BenchResult> = or_abort(encrypt::bench::run(cli.config_path(), &cli.bench_config()));
+            for result in &results {
+                println!("{}", result);
+            }
+            if let Some(path) = &cli.output {
+                or_abort(encrypt::bench::save(&results, path));
+            }
+        }
+        Mode::Audit => {
+            let entries: u64 = or_abort(encrypt::audit::compare(cli.config_path()));
+            println!("The audit logs of all three parties agree on {} entries", entries);
+        }
+        Mode::Reencrypt => {
+            // Reads --envelope and writes the envelope under the newest key version to --output
+            // The data owner checks for --output before the parties do any work
+            let output: Option<&std::path::PathBuf> = match (&cli.envelope, &cli.output) {
+                (Some(_), None) => or_abort(Err(error::LocksmithError::InvalidInput(
+                    "--output is required for the data owner in reencrypt mode".to_string(),
+                ))),
+                (_, output) => output.as_ref(),
+            };
+            let envelope: Option<Envelope> = cli.envelope.as_ref().map(|path| or_abort(Envelope::load(path)));
+            let reencrypted: Option<Envelope> = or_abort(encrypt::reencrypt::reencrypt(cli.config_path(), envelope.as_ref(), &cli.ledger_dir(), &keys));
+            match reencrypted.zip(output) {
+                Some((reencrypted, output)) => {
+                    or_abort(reencrypted.save(output));
+                    println!("Re-encrypted under version {} of key '{}'", reencrypted.key_version, reencrypted.key_id);
+                }
+                None => println!("Envelope re-encrypted for the data owner"),
+            }
+        }
+        Mode::Decrypt => {
+            // Key, variant and mode all come from the envelope header
+            let envelope: Option<Envelope> = cli.envelope.as_ref().map(|path| or_abort(Envelope::load(path)));
+            let plaintext: Option<Plaintext> = or_abort(encrypt::decrypt::decrypt(cli.config_path(), envelope.as_ref(), &cli.ledger_dir(), KeySource::Store(&keys)));
+            match (plaintext, &cli.output) {
+                (Some(plaintext), Some(output)) => {
+                    or_abort(std::fs::write(output, plaintext.as_bytes()).map_err(Into::into));
+                    println!("Decrypted {} bytes", plaintext.len());
+                }
+                (Some(plaintext), None) => println!("Plaintext: {}", String::from_utf8_lossy(plaintext.as_bytes())),
+                (None, _) => println!("Plaintext delivered to the data owner"),
+            }
+        }
+    }
+
+    if let Some(path) = &cli.metrics {
+        let mode: String = cli.mode.to_possible_value().map_or(String::new(), |value| value.get_name().to_string());
+        let report: metrics::Report = metrics::Report { party, mode, key_id: cli.key_id.clone(), phases: metrics::snapshot() };
+        or_abort(report.save(path));
+    }
+}
+
+fn run_config_command(command: &ConfigCommand) {
+    match command {
+        ConfigCommand::Init { dir, hosts, base_port, keys_dir, force } => {
+            for path in or_abort(config::init(dir, hosts, *base_port, keys_dir, *force)) {
+                println!("Wrote {}", path.display());
+            }
+        }
+        ConfigCommand::Check { files } => {
+            for party in or_abort(config::check(files)) {
+                let own: &config::PartyEntry = party.own();
+                // The fingerprint is what access policies name the party by
+                let fingerprint: String = or_abort(party.fingerprint(party.party_index - 1));
+                println!("Party {}: {}:{} OK, certificate SHA-256 {}", party.party_index, own.address, own.port, fingerprint);
+                println!("  backend {}, {} channel(s)", party.backend.name(), party.network.channels);
+                match &party.policy {
+                    Some(policy) => println!("  access policy {}", policy.display()),
+                    None => println!("  no access policy, every request is refused"),
+                }
+            }
+        }
+        ConfigCommand::Replace { cluster, party, address, port, ca_key, days } => {
+            for path in or_abort(config::certs::replace(cluster, *party, address, *port, ca_key.as_deref(), *days)) {
+                println!("Wrote {}", path.display());
+            }
+        }
+    }
+}
+
+fn run_certs_command(command: &CertsCommand) {
+    let written: Vec<std::path::PathBuf> = match command {
+        CertsCommand::Init { cluster, keys_dir, days, force } => or_abort(config::certs::init(cluster, keys_dir, *days, *force)),
+        CertsCommand::Rotate { cluster, parties, grace_hours, ca_key, days, force } => or_abort(config::certs::rotate(
+            cluster, parties, time::Duration::hours((*grace_hours).into()), ca_key.as_deref(), *days, *force,
+        )),
+    };
+    for path in written {
+        println!("Wrote {}", path.display());
+    }
+}
+
+fn run_backup_command(command: &BackupCommand) {
+    let written: Vec<std::path::PathBuf> = match command {
+        BackupCommand::Keygen { dir, force } => or_abort(encrypt::backup::keygen(dir, *force)),
+        BackupCommand::Export { config, keystore, out, recovery_public_key, custodians, threshold } => {
+            let protection: Protection = match (recovery_public_key, custodians, threshold) {
+                (Some(key), None, None) => Protection::Recovery(or_abort(encrypt::backup::read_key(key))),
+                (None, Some(count), Some(threshold)) => Protection::Custodians { threshold: *threshold, count: *count },
+                _ => or_abort(Err(error::LocksmithError::InvalidInput(
+                    "give either --recovery-public-key or --custodians with --threshold".to_string(),
+                ))),
+            };
+            let party_index: usize = or_abort(config::load(config)).party_index;
+            let store: KeyStore = KeyStore::open(&keystore.clone().unwrap_or_else(|| encrypt::party_dir("keystore", config)));
+            or_abort(encrypt::backup::export(&store, party_index, protection, out))
+        }
+    };
+    for path in written {
+        println!("Wrote {}", path.display());
+    }
+}
+
+// Stops this party with the reason for the failure instead of a panic
+fn or_abort<T>(result: error::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Aborted: {}", e);
+        std::process::exit(1);
+    })
+}
+
+fn print_progress(progress: &Progress) {
+    match progress.percent() {
+        Some(percent) => println!("- {} blocks, {} bytes ({:.1}%)", progress.blocks_done, progress.bytes_done, percent),
+        None => println!("- {} blocks, {} bytes", progress.blocks_done, progress.bytes_done),
+    }
 }
 
 #[test]
 fn test_rust_aes_ecb() {
     let input: &Input = &data::get_input("short");
 
-    let ecb_rust_ciphertext: String = rust_impl::rust_aes_ecb(input);
-    let ecb_locksmith_ciphertext: String = data::get_ciphertext("short-ecb");
+    let ecb_rust_ciphertext: Ciphertext = rust_impl::rust_aes_ecb(input);
+    let ecb_locksmith_ciphertext: Ciphertext = data::get_ciphertext("short-ecb");
     assert_eq!(ecb_rust_ciphertext, ecb_locksmith_ciphertext);
 }
 
@@ -40,8 +329,1895 @@ fn test_rust_aes_ecb() {
 fn test_rust_aes_ctr() {
     let input: &Input = &data::get_input("short");
 
-    let ctr_rust_ciphertext: String = rust_impl::rust_aes_ctr(input,0);
-    let ctr_locksmith_ciphertext: String = data::get_ciphertext("short-ctr");
+    let ctr_rust_ciphertext: Ciphertext = rust_impl::rust_aes_ctr(input,0);
+    let ctr_locksmith_ciphertext: Ciphertext = data::get_ciphertext("short-ctr");
 
     assert_eq!(ctr_rust_ciphertext, ctr_locksmith_ciphertext);
-}
\ No newline at end of file
+}
+#[test]
+fn test_nonce_ledger_ranges() {
+    use encrypt::keystream::{KeystreamRequest, Purpose};
+    use encrypt::nonce::CounterRange;
+
+    let mut ledger: NonceLedger = NonceLedger::in_memory("test");
+    let first: CounterRange = CounterRange { first: 100, blocks: 10, version: FIRST_VERSION };
+    assert!(!ledger.overlaps(&first));
+    ledger.record(&first).unwrap();
+
+    // Same counters reached through a different nonce/offset split
+    assert!(ledger.overlaps(&CounterRange { first: 109, blocks: 1, version: FIRST_VERSION }));
+    assert!(!ledger.overlaps(&CounterRange { first: 110, blocks: 5, version: FIRST_VERSION }));
+
+    // Auto allocation continues above everything used so far
+    assert_eq!(ledger.allocate(0, 4), Some(110));
+    assert_eq!(ledger.allocate(10, 4), Some(100));
+
+    // Ranges that wrap around 2^128
+    let wrapping: CounterRange = CounterRange { first: u128::MAX - 1, blocks: 4, version: FIRST_VERSION };
+    assert!(!ledger.overlaps(&wrapping));
+    ledger.record(&wrapping).unwrap();
+    assert!(ledger.overlaps(&CounterRange { first: 1, blocks: 1, version: FIRST_VERSION }));
+
+    // Ledgers that recorded the same ranges agree, regardless of order
+    let mut other: NonceLedger = NonceLedger::in_memory("test");
+    other.record(&wrapping).unwrap();
+    assert_ne!(ledger.digest(), other.digest());
+    other.record(&first).unwrap();
+    assert_eq!(ledger.digest(), other.digest());
+    assert_ne!(ledger.digest(), NonceLedger::in_memory("other").digest());
+
+    // Decryption is only allowed for recorded counters
+    assert!(ledger.covers(&CounterRange { first: 102, blocks: 8, version: FIRST_VERSION }));
+    assert!(!ledger.covers(&CounterRange { first: 105, blocks: 10, version: FIRST_VERSION }));
+    assert!(ledger.covers(&CounterRange { first: u128::MAX, blocks: 3, version: FIRST_VERSION }));
+
+    // Counters are allocated once across all versions, but only decrypt under their own version
+    let second: CounterRange = CounterRange { first: 200, blocks: 10, version: 2 };
+    assert!(ledger.overlaps(&CounterRange { version: 2, ..first }));
+    ledger.record(&second).unwrap();
+    assert!(ledger.covers(&second));
+    assert!(!ledger.covers(&CounterRange { version: FIRST_VERSION, ..second }));
+    assert!(!ledger.covers(&CounterRange { version: 2, ..first }));
+    other.record(&CounterRange { version: 3, ..second }).unwrap();
+    assert_ne!(ledger.digest(), other.digest());
+
+    // Ledger files keep the version; lines written without one belong to the first version
+    let dir: std::path::PathBuf = std::env::temp_dir().join(format!("locksmith-ledger-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("test.ledger"), format!("{:032x} 10\n", 100)).unwrap();
+    let mut stored: NonceLedger = NonceLedger::open(&dir, "test").unwrap();
+    assert_eq!(stored.entries(), &[first]);
+    stored.record(&second).unwrap();
+    assert_eq!(NonceLedger::open(&dir, "test").unwrap().entries(), &[first, second]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let request: KeystreamRequest = KeystreamRequest { nonce: Nonce::Auto, start: 3, blocks: 7, purpose: Purpose::Decrypt };
+    assert_eq!(KeystreamRequest::from_bytes(&request.to_bytes()), Some(request));
+}
+
+#[test]
+fn test_envelope_roundtrip() {
+    let envelope: Envelope = Envelope {
+        key_id: "test".to_string(),
+        key_version: 3,
+        variant: AesVariant::Aes128,
+        mode: CipherMode::Ctr,
+        iv: 7u128.to_be_bytes().to_vec(),
+        padding: Padding::Zero,
+        plaintext_len: 20,
+        tag: Tag::default(),
+        ciphertext: Ciphertext::new(vec![0xab; 32]),
+    };
+    assert_eq!(Envelope::parse(&envelope.to_bytes().unwrap()).unwrap(), envelope);
+    assert_eq!(Envelope::parse(envelope.to_json().unwrap().as_bytes()).unwrap(), envelope);
+    assert_eq!(envelope.unpad(Plaintext::new(vec![1; 32])).unwrap().len(), 20);
+
+    // Debug output never shows plaintext, only ciphertext
+    assert_eq!(format!("{:?}", Plaintext::new(vec![0x41; 20])), "Plaintext(20 bytes)");
+    assert_eq!(format!("{:?}", Ciphertext::new(vec![0xab; 2])), "Ciphertext(0xabab)");
+
+    // Truncated or inconsistent envelopes are rejected
+    let bytes: Vec<u8> = envelope.to_bytes().unwrap();
+    assert!(Envelope::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    assert!(Envelope::from_bytes(&Envelope { mode: CipherMode::Ecb, ..envelope.clone() }.to_bytes().unwrap()).is_err());
+
+    // Version 1 envelopes have no key version and name the first one
+    let mut v1: Vec<u8> = bytes[..4].to_vec();
+    v1.push(1);
+    v1.extend_from_slice(&bytes[5..10]);
+    v1.extend_from_slice(&bytes[14..]);
+    assert_eq!(Envelope::from_bytes(&v1).unwrap(), Envelope { key_version: FIRST_VERSION, ..envelope.clone() });
+}
+
+#[test]
+fn test_error_kinds() {
+    use error::{AbortReason, LocksmithError};
+    use maestro::rep3_core::party::error::MpcError;
+
+    // Failed malicious-security checks surface as aborts, not as generic protocol errors
+    assert!(matches!(LocksmithError::from(MpcError::Sacrifice), LocksmithError::Abort(AbortReason::CheckFailed)));
+    assert!(matches!(LocksmithError::from(MpcError::Receive), LocksmithError::Network(_)));
+
+    let exhausted: LocksmithError = LocksmithError::PreprocessingExhausted { available: 95, requested: 96 };
+    assert_ne!(exhausted.code(), 0);
+    assert_eq!(LocksmithError::kind_from_code(exhausted.code()), "preprocessing exhausted");
+    assert_eq!(LocksmithError::kind_from_code(200), "unknown");
+}
+
+#[test]
+fn test_sim_input_sharing() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let input: Input = data::get_input("short");
+    let opened: Vec<Vec<u8>> = cluster.run(|party, config| {
+        let mut mpc: Box<dyn encrypt::backend::MpcParty> = encrypt::setup_party(config).unwrap();
+        // Only the data owner's bytes may end up in the sharing
+        let own: Input = if party == encrypt::DATA_OWNER { input.clone() } else { Input::zero(input.num_blocks()) };
+        let shared: maestro::aes::VectorAesState = encrypt::rss::rss_input(&own, &mut *mpc).unwrap();
+        mpc.finalize().unwrap();
+        let (si, sii): (Vec<GF8>, Vec<GF8>) = shared.to_bytes().into_iter().map(|rss| (rss.si, rss.sii)).unzip();
+        let output: Vec<GF8> = mpc.output(&si, &sii).unwrap();
+        mpc.main_party_mut().teardown().unwrap();
+        output.iter().map(|x| x.0).collect()
+    });
+    for bytes in opened {
+        assert_eq!(bytes, input.to_bytes());
+    }
+}
+
+#[test]
+fn test_sim_ecb_matches_rust_impl() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let input: Input = data::get_input("short");
+    let ciphertexts: Vec<Ciphertext> = cluster.run(|_, config| encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None).unwrap());
+    // The ciphertext is opened to every party; this also checks the MPC key schedule
+    for ciphertext in ciphertexts {
+        assert_eq!(ciphertext, rust_impl::rust_aes_ecb(&input));
+    }
+}
+
+#[test]
+fn test_sim_ctr_roundtrip() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let input: Input = data::get_input("short");
+    let encrypt = |party: usize, config: &std::path::Path| {
+        let ledger: NonceLedger = NonceLedger::open(&cluster.party_dir(party), "sim").unwrap();
+        let data: Option<&Input> = (party == encrypt::DATA_OWNER).then_some(&input);
+        encrypt::encrypt_ctr(config, data, Nonce::Fixed(0), ledger, None, FIRST_VERSION)
+    };
+
+    let results: Vec<(u128, Option<Ciphertext>)> = cluster.run(|party, config| encrypt(party, config).unwrap());
+    assert_eq!(results[0], (0, Some(rust_impl::rust_aes_ctr(&input, 0))));
+    assert_eq!(results[1], (0, None));
+    assert_eq!(results[2], (0, None));
+
+    // The same counters are refused by every party the second time
+    assert!(cluster.run(|party, config| encrypt(party, config).is_err()).iter().all(|&refused| refused));
+
+    let envelope: Envelope = Envelope {
+        key_id: "sim".to_string(),
+        key_version: FIRST_VERSION,
+        variant: AesVariant::Aes128,
+        mode: CipherMode::Ctr,
+        iv: 0u128.to_be_bytes().to_vec(),
+        padding: Padding::Zero,
+        plaintext_len: input.data_len() as u64,
+        tag: Tag::default(),
+        ciphertext: results[0].1.clone().unwrap(),
+    };
+    let plaintexts: Vec<Option<Plaintext>> = cluster.run(|party, config| {
+        let envelope: Option<&Envelope> = (party == encrypt::DATA_OWNER).then_some(&envelope);
+        encrypt::decrypt::decrypt(config, envelope, &cluster.party_dir(party), KeySource::Builtin).unwrap()
+    });
+    assert_eq!(plaintexts[0].as_ref().map(Plaintext::as_bytes), Some(&input.to_bytes()[..input.data_len()]));
+    assert!(plaintexts[1].is_none() && plaintexts[2].is_none());
+}
+
+#[test]
+fn test_sim_keystream_request_limit() {
+    use encrypt::keystream::{KeystreamRequest, KeystreamService, Purpose, MAX_REQUEST_BLOCKS};
+
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let request = |blocks: u64| KeystreamRequest { nonce: Nonce::Auto, start: 0, blocks, purpose: Purpose::Encrypt };
+    let results: Vec<(bool, u128)> = cluster.run(|_, config| {
+        let mpc: Box<dyn encrypt::backend::MpcParty> = encrypt::setup_party(config).unwrap();
+        let mut service: KeystreamService = KeystreamService::start(mpc, encrypt::DATA_OWNER, NonceLedger::in_memory("limit"), None, FIRST_VERSION).unwrap();
+        let is_owner: bool = service.is_client();
+
+        // Refused by every party before any counters are recorded, so the next request starts at 0
+        let refused: bool = matches!(service.serve(is_owner.then(|| request(MAX_REQUEST_BLOCKS + 1))), Err(error::LocksmithError::Refused(_)));
+        let nonce: u128 = service.serve(is_owner.then(|| request(1))).unwrap().nonce;
+        service.shutdown().unwrap();
+        (refused, nonce)
+    });
+    assert!(results.iter().all(|&result| result == (true, 0)));
+}
+
+#[test]
+fn test_sim_keystream_refused_before_key_schedule() {
+    use encrypt::keystream::{KeystreamRequest, KeystreamService, Purpose};
+
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let request = |purpose: Purpose| KeystreamRequest { nonce: Nonce::Fixed(7), start: 0, blocks: 1, purpose };
+    let traffic: Vec<(metrics::Traffic, metrics::Traffic)> = cluster.run(|_, config| {
+        let mut mpc: Box<dyn encrypt::backend::MpcParty> = encrypt::setup_party(config).unwrap();
+        let before: metrics::Traffic = metrics::traffic(mpc.main_party_mut());
+        let mut service: KeystreamService = KeystreamService::start(mpc, encrypt::DATA_OWNER, NonceLedger::in_memory("lazy"), None, FIRST_VERSION).unwrap();
+        let is_owner: bool = service.is_client();
+
+        // Decrypting counters the ledger never recorded is refused with only the control rounds
+        assert!(matches!(service.serve(is_owner.then(|| request(Purpose::Decrypt))), Err(error::LocksmithError::Refused(_))));
+        let refused: metrics::Traffic = metrics::traffic(service.main_party_mut());
+        service.serve(is_owner.then(|| request(Purpose::Encrypt))).unwrap();
+        let served: metrics::Traffic = metrics::traffic(service.main_party_mut());
+        service.shutdown().unwrap();
+        (refused - before, served - refused)
+    });
+    // The first admitted request pays for the key schedule and the AES rounds
+    for (refused, served) in traffic {
+        assert!(refused.rounds * 4 < served.rounds, "{:?} vs {:?}", refused, served);
+        assert!(refused.bytes_sent * 4 < served.bytes_sent, "{:?} vs {:?}", refused, served);
+    }
+}
+
+// NIST files with single-block ECB records
+#[cfg(test)]
+const KAT_ECB_FILES: [&str; 4] = ["ECBVarTxt128.rsp", "ECBVarTxt256.rsp", "ECBVarKey128.rsp", "ECBVarKey256.rsp"];
+
+// NIST files with single-block CBC records, which are the ECB values under an all-zero IV
+#[cfg(test)]
+const KAT_CBC_FILES: [&str; 4] = ["CBCVarTxt128.rsp", "CBCVarTxt256.rsp", "CBCVarKey128.rsp", "CBCVarKey256.rsp"];
+
+#[cfg(test)]
+const KAT_MCT_FILES: [&str; 4] = ["ECBMCT128.rsp", "ECBMCT256.rsp", "CBCMCT128.rsp", "CBCMCT256.rsp"];
+
+// AESAVS Monte Carlo test: 1000 chained encryptions per record, see AESAVS section 6.4.
+// `encrypt` is the block cipher under the record's key.
+#[cfg(test)]
+fn mct_ciphertext(vector: &kat::Vector, mut encrypt: impl FnMut(&[u8]) -> Vec<u8>) -> Vec<u8> {
+    let mut plaintext: Vec<u8> = vector.get("PLAINTEXT").to_vec();
+    if !vector.has("IV") {
+        for _ in 0..1000 {
+            plaintext = encrypt(&plaintext);
+        }
+        return plaintext;
+    }
+    // CBC: each plaintext is the ciphertext before last, starting from the IV
+    let mut chain: Vec<u8> = vector.get("IV").to_vec();
+    for _ in 0..1000 {
+        let chained: Vec<u8> = plaintext.iter().zip(&chain).map(|(p, c)| p ^ c).collect();
+        let ciphertext: Vec<u8> = encrypt(&chained);
+        plaintext = std::mem::replace(&mut chain, ciphertext);
+    }
+    chain
+}
+
+// Encrypts `data` in ECB mode on the simulator under `key`, split into fresh shares
+#[cfg(test)]
+fn sim_ecb(cluster: &sim::Cluster, key: &[u8], data: &[u8]) -> Ciphertext {
+    let shares: [encrypt::KeyShare; 3] = encrypt::KeyShare::split(key).unwrap();
+    let input: Input = Input::from_file(data, data.len() / 16);
+    let ciphertexts: Vec<Ciphertext> = cluster.run(|party, config| {
+        let own: Input = if party == encrypt::DATA_OWNER { input.clone() } else { Input::zero(input.num_blocks()) };
+        encrypt::encrypt_ecb(config, &own, encrypt::nonce::DEFAULT_KEY_ID, Some(&shares[party])).unwrap()
+    });
+    assert!(ciphertexts.iter().all(|c| c == &ciphertexts[0]));
+    ciphertexts[0].clone()
+}
+
+#[cfg(test)]
+fn sim_ecb_decrypt(cluster: &sim::Cluster, key: &[u8], ciphertext: &[u8]) -> Plaintext {
+    let shares: [encrypt::KeyShare; 3] = encrypt::KeyShare::split(key).unwrap();
+    let envelope: Envelope = Envelope {
+        key_id: "kat".to_string(),
+        key_version: FIRST_VERSION,
+        variant: shares[0].variant(),
+        mode: CipherMode::Ecb,
+        iv: Vec::new(),
+        padding: Padding::None,
+        plaintext_len: ciphertext.len() as u64,
+        tag: Tag::default(),
+        ciphertext: Ciphertext::new(ciphertext.to_vec()),
+    };
+    let plaintexts: Vec<Option<Plaintext>> = cluster.run(|party, config| {
+        let envelope: Option<&Envelope> = (party == encrypt::DATA_OWNER).then_some(&envelope);
+        encrypt::decrypt::decrypt(config, envelope, &cluster.party_dir(party), KeySource::Share(&shares[party])).unwrap()
+    });
+    plaintexts[encrypt::DATA_OWNER].clone().unwrap()
+}
+
+#[test]
+fn test_sim_envelope_size_limit() {
+    use encrypt::keystream::MAX_REQUEST_BLOCKS;
+
+    // Refused on every party before the key schedule or any shares of the ciphertext
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let envelope: Envelope = Envelope {
+        key_id: "kat".to_string(),
+        key_version: FIRST_VERSION,
+        variant: AesVariant::Aes128,
+        mode: CipherMode::Ecb,
+        iv: Vec::new(),
+        padding: Padding::None,
+        plaintext_len: (MAX_REQUEST_BLOCKS + 1) * 16,
+        tag: Tag::default(),
+        ciphertext: Ciphertext::new(vec![0u8; (MAX_REQUEST_BLOCKS as usize + 1) * 16]),
+    };
+    let outcomes: Vec<bool> = cluster.run(|party, config| {
+        let envelope: Option<&Envelope> = (party == encrypt::DATA_OWNER).then_some(&envelope);
+        matches!(
+            encrypt::decrypt::decrypt(config, envelope, &cluster.party_dir(party), KeySource::Builtin),
+            Err(error::LocksmithError::InvalidInput(e)) if e.contains("blocks")
+        )
+    });
+    assert!(outcomes.iter().all(|&refused| refused));
+    let outcomes: Vec<bool> = cluster.run(|party, config| {
+        let envelope: Option<&Envelope> = (party == encrypt::DATA_OWNER).then_some(&envelope);
+        let keys: KeyStore = KeyStore::open(&cluster.party_dir(party).join("keys"));
+        matches!(
+            encrypt::reencrypt::reencrypt(config, envelope, &cluster.party_dir(party), &keys),
+            Err(error::LocksmithError::InvalidInput(e)) if e.contains("blocks")
+        )
+    });
+    assert!(outcomes.iter().all(|&refused| refused));
+}
+
+#[cfg(test)]
+fn sim_ctr(cluster: &sim::Cluster, key: &[u8], counter: u128, data: &[u8]) -> Ciphertext {
+    let shares: [encrypt::KeyShare; 3] = encrypt::KeyShare::split(key).unwrap();
+    let input: Input = Input::from_data(data);
+    let results: Vec<(u128, Option<Ciphertext>)> = cluster.run(|party, config| {
+        let data: Option<&Input> = (party == encrypt::DATA_OWNER).then_some(&input);
+        let ledger: NonceLedger = NonceLedger::in_memory("kat");
+        encrypt::encrypt_ctr(config, data, Nonce::Fixed(counter), ledger, Some(&shares[party]), FIRST_VERSION).unwrap()
+    });
+    let ciphertext: Vec<u8> = results[encrypt::DATA_OWNER].1.clone().unwrap().into_bytes();
+    Ciphertext::new(ciphertext[..data.len()].to_vec())
+}
+
+// GCM on the simulator: the hash subkey and the counter blocks come from the MPC keystream
+// service, GHASH is computed by the data owner
+#[cfg(test)]
+fn sim_gcm(cluster: &sim::Cluster, key: &[u8], iv: &[u8], aad: &[u8], data: &[u8]) -> (Ciphertext, Tag) {
+    use encrypt::keystream::{KeystreamRequest, KeystreamService, Purpose};
+
+    let shares: [encrypt::KeyShare; 3] = encrypt::KeyShare::split(key).unwrap();
+    let blocks: u64 = data.len().div_ceil(16) as u64;
+    let results: Vec<Option<(Ciphertext, Tag)>> = cluster.run(|party, config| {
+        let mpc: Box<dyn encrypt::backend::MpcParty> = encrypt::setup_party(config).unwrap();
+        let ledger: NonceLedger = NonceLedger::in_memory("kat");
+        let mut service: KeystreamService = KeystreamService::start(mpc, encrypt::DATA_OWNER, ledger, Some(&shares[party]), FIRST_VERSION).unwrap();
+        let request = |nonce: u128, blocks: u64| KeystreamRequest { nonce: Nonce::Fixed(nonce), start: 0, blocks, purpose: Purpose::Encrypt };
+        let is_owner: bool = service.is_client();
+
+        // H = E_K(0^128)
+        let h: Option<u128> = service.serve(is_owner.then(|| request(0, 1))).unwrap().keystream
+            .map(|keystream| u128::from_be_bytes(keystream.try_into().unwrap()));
+        // E_K(J0) masks the tag, the data is encrypted from J0 + 1 on. The service counts in
+        // 128 bits, which is inc32 as long as the low word does not wrap.
+        let j0: Option<u128> = h.map(|h| rust_impl::gcm_j0(h, iv));
+        assert!(j0.is_none_or(|j0| (j0 as u32).checked_add(blocks as u32).is_some()));
+        let keystream: Option<Vec<u8>> = service.serve(j0.map(|j0| request(j0, blocks + 1))).unwrap().keystream;
+        service.shutdown().unwrap();
+
+        h.zip(keystream).map(|(h, keystream)| {
+            let ciphertext: Vec<u8> = data.iter().zip(&keystream[16..]).map(|(d, k)| d ^ k).collect();
+            let tag: u128 = rust_impl::ghash(h, aad, &ciphertext) ^ u128::from_be_bytes(keystream[..16].try_into().unwrap());
+            (Ciphertext::new(ciphertext), Tag::new(tag.to_be_bytes().to_vec()))
+        })
+    });
+    results[encrypt::DATA_OWNER].clone().unwrap()
+}
+
+// Runs single-block records, each a key and a block, through the cipher or with `decrypt` the
+// inverse cipher on the simulator, all in one session. Consecutive records under the same key
+// share an input and a key schedule. Returns the output blocks of the data owner in order.
+#[cfg(test)]
+fn sim_ecb_records(cluster: &sim::Cluster, records: &[(&[u8], Vec<u8>)], decrypt: bool) -> Vec<u8> {
+    use maestro::aes::VectorAesState;
+
+    let mut runs: Vec<(&[u8], Vec<u8>)> = Vec::new();
+    for (key, block) in records {
+        match runs.last_mut() {
+            Some((run_key, blocks)) if run_key == key => blocks.extend_from_slice(block),
+            _ => runs.push((key, block.clone())),
+        }
+    }
+    let shares: Vec<[encrypt::KeyShare; 3]> = runs.iter().map(|(key, _)| encrypt::KeyShare::split(key).unwrap()).collect();
+    let outputs: Vec<Option<Vec<u8>>> = cluster.run(|party, config| {
+        let mut mpc: Box<dyn encrypt::backend::MpcParty> = encrypt::setup_party(config).unwrap();
+        let mut states: Vec<VectorAesState> = Vec::new();
+        for ((_, blocks), shares) in runs.iter().zip(&shares) {
+            let num_blocks: usize = blocks.len() / 16;
+            let input: Input = if party == encrypt::DATA_OWNER { Input::from_file(blocks, num_blocks) } else { Input::zero(num_blocks) };
+            let input_ss: VectorAesState = encrypt::rss::share_input::rss_input(&input, &mut *mpc).unwrap();
+            let ks: Vec<maestro::aes::AesKeyState> = encrypt::rss::key_schedule::keyshare_keyschedule(&mut *mpc, &shares[party]).unwrap();
+            let (_, n_blocks): (usize, usize) = encrypt::preprocessing_units(shares[party].variant(), 0, num_blocks);
+            mpc.do_preprocessing(0, n_blocks).unwrap();
+            states.push(match decrypt {
+                true => aes::ss::aes_inv_no_keyschedule_mal(&mut *mpc, input_ss, &ks).unwrap(),
+                false => aes::ss::aes_no_keyschedule_mal(&mut *mpc, input_ss, &ks).unwrap(),
+            });
+        }
+        mpc.finalize().unwrap();
+        let output: Option<Vec<u8>> = states.iter()
+            .map(|state| encrypt::reveal_to(mpc.main_party_mut(), state, encrypt::DATA_OWNER).unwrap())
+            .collect::<Option<Vec<Vec<u8>>>>()
+            .map(|blocks| blocks.concat());
+        mpc.main_party_mut().teardown().unwrap();
+        output
+    });
+    outputs[encrypt::DATA_OWNER].clone().unwrap()
+}
+
+// The first record of an AESAVS Monte Carlo file on the simulator. The chain is sequential, so
+// every step is a one-block keystream request whose counter block is the block to encrypt,
+// E_K(counter), served in one session under the record's key.
+#[cfg(test)]
+fn sim_mct_ciphertext(cluster: &sim::Cluster, vector: &kat::Vector) -> Vec<u8> {
+    use encrypt::keystream::{KeystreamRequest, KeystreamService, Purpose};
+
+    let shares: [encrypt::KeyShare; 3] = encrypt::KeyShare::split(vector.get("KEY")).unwrap();
+    let results: Vec<Option<Vec<u8>>> = cluster.run(|party, config| {
+        let mpc: Box<dyn encrypt::backend::MpcParty> = encrypt::setup_party(config).unwrap();
+        let ledger: NonceLedger = NonceLedger::in_memory("mct");
+        let mut service: KeystreamService = KeystreamService::start(mpc, encrypt::DATA_OWNER, ledger, Some(&shares[party]), FIRST_VERSION).unwrap();
+        let ciphertext: Option<Vec<u8>> = match service.is_client() {
+            true => Some(mct_ciphertext(vector, |block| {
+                let counter: u128 = u128::from_be_bytes(block.try_into().unwrap());
+                let request: KeystreamRequest = KeystreamRequest { nonce: Nonce::Fixed(counter), start: 0, blocks: 1, purpose: Purpose::Encrypt };
+                service.serve(Some(request)).unwrap().keystream.unwrap()
+            })),
+            false => {
+                for _ in 0..1000 {
+                    service.serve(None).unwrap();
+                }
+                None
+            }
+        };
+        service.shutdown().unwrap();
+        ciphertext
+    });
+    results[encrypt::DATA_OWNER].clone().unwrap()
+}
+
+// Decrypts a CTR ciphertext on the simulator through an envelope. Keystream for decryption is
+// only served for counters the ledger recorded, so the counters are first used to encrypt the
+// same number of blocks under `key_id`.
+#[cfg(test)]
+fn sim_ctr_decrypt(cluster: &sim::Cluster, key_id: &str, key: &[u8], counter: u128, ciphertext: &[u8]) -> Plaintext {
+    let shares: [encrypt::KeyShare; 3] = encrypt::KeyShare::split(key).unwrap();
+    let input: Input = Input::from_data(ciphertext);
+    cluster.run(|party, config| {
+        let data: Option<&Input> = (party == encrypt::DATA_OWNER).then_some(&input);
+        let ledger: NonceLedger = NonceLedger::open(&cluster.party_dir(party), key_id).unwrap();
+        encrypt::encrypt_ctr(config, data, Nonce::Fixed(counter), ledger, Some(&shares[party]), FIRST_VERSION).unwrap()
+    });
+    let envelope: Envelope = Envelope {
+        key_id: key_id.to_string(),
+        key_version: FIRST_VERSION,
+        variant: shares[0].variant(),
+        mode: CipherMode::Ctr,
+        iv: counter.to_be_bytes().to_vec(),
+        padding: Padding::None,
+        plaintext_len: ciphertext.len() as u64,
+        tag: Tag::default(),
+        ciphertext: Ciphertext::new(ciphertext.to_vec()),
+    };
+    let plaintexts: Vec<Option<Plaintext>> = cluster.run(|party, config| {
+        let envelope: Option<&Envelope> = (party == encrypt::DATA_OWNER).then_some(&envelope);
+        encrypt::decrypt::decrypt(config, envelope, &cluster.party_dir(party), KeySource::Share(&shares[party])).unwrap()
+    });
+    plaintexts[encrypt::DATA_OWNER].clone().unwrap()
+}
+
+#[test]
+fn test_kat_rust_impl() {
+    for file in KAT_ECB_FILES {
+        for vector in kat::load(file) {
+            let (key, plaintext, ciphertext) = (vector.get("KEY"), vector.get("PLAINTEXT"), vector.get("CIPHERTEXT"));
+            if vector.is_decrypt() {
+                assert_eq!(rust_impl::aes_ecb_decrypt(key, ciphertext).as_bytes(), plaintext, "{}", vector);
+            } else {
+                assert_eq!(rust_impl::aes_ecb(key, plaintext).as_bytes(), ciphertext, "{}", vector);
+            }
+        }
+    }
+
+    for file in KAT_CBC_FILES {
+        for vector in kat::load(file) {
+            let (key, iv, plaintext, ciphertext) = (vector.get("KEY"), vector.get("IV"), vector.get("PLAINTEXT"), vector.get("CIPHERTEXT"));
+            if vector.is_decrypt() {
+                assert_eq!(rust_impl::aes_cbc_decrypt(key, iv, ciphertext).as_bytes(), plaintext, "{}", vector);
+            } else {
+                assert_eq!(rust_impl::aes_cbc(key, iv, plaintext).as_bytes(), ciphertext, "{}", vector);
+            }
+        }
+    }
+
+    for vector in kat::load("SP800-38A.rsp") {
+        let (key, plaintext, ciphertext) = (vector.get("KEY"), vector.get("PLAINTEXT"), vector.get("CIPHERTEXT"));
+        let (encrypted, decrypted): (Ciphertext, Plaintext) = match &vector.section[..3] {
+            "ECB" => (rust_impl::aes_ecb(key, plaintext), rust_impl::aes_ecb_decrypt(key, ciphertext)),
+            "CBC" => (rust_impl::aes_cbc(key, vector.get("IV"), plaintext), rust_impl::aes_cbc_decrypt(key, vector.get("IV"), ciphertext)),
+            _ => {
+                let counter: u128 = u128::from_be_bytes(vector.get("IV").try_into().unwrap());
+                (rust_impl::aes_ctr(key, counter, plaintext), Plaintext::new(rust_impl::aes_ctr(key, counter, ciphertext).into_bytes()))
+            }
+        };
+        assert_eq!(encrypted.as_bytes(), ciphertext, "{}", vector);
+        assert_eq!(decrypted.as_bytes(), plaintext, "{}", vector);
+    }
+
+    for vector in kat::load("gcmEncrypt.rsp") {
+        // Only full-length tags are produced
+        assert_eq!(vector.param("TAGLEN"), Some("128"), "{}", vector);
+        let (ciphertext, tag) = rust_impl::aes_gcm(vector.get("KEY"), vector.get("IV"), vector.get("AAD"), vector.get("PLAINTEXT"));
+        assert_eq!(ciphertext.as_bytes(), vector.get("CIPHERTEXT"), "{}", vector);
+        assert_eq!(tag.as_bytes(), vector.get("TAG"), "{}", vector);
+    }
+}
+
+#[test]
+fn test_kat_rust_impl_mct() {
+    for file in KAT_MCT_FILES {
+        let vectors: Vec<kat::Vector> = kat::load(file);
+        assert_eq!(vectors.len(), 100);
+        for vector in vectors {
+            let key: &[u8] = vector.get("KEY");
+            assert_eq!(mct_ciphertext(&vector, |block| rust_impl::aes_ecb(key, block).into_bytes()), vector.get("CIPHERTEXT"), "{}", vector);
+        }
+    }
+}
+
+#[test]
+fn test_sim_kat_ecb() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    for file in KAT_ECB_FILES {
+        let vectors: Vec<kat::Vector> = kat::load(file);
+        for decrypt in [false, true] {
+            let vectors: Vec<&kat::Vector> = vectors.iter().filter(|v| v.is_decrypt() == decrypt).collect();
+            let (from, to): (&str, &str) = if decrypt { ("CIPHERTEXT", "PLAINTEXT") } else { ("PLAINTEXT", "CIPHERTEXT") };
+            let records: Vec<(&[u8], Vec<u8>)> = vectors.iter().map(|v| (v.get("KEY"), v.get(from).to_vec())).collect();
+            let expected: Vec<u8> = vectors.iter().flat_map(|v| v.get(to).to_vec()).collect();
+            assert_eq!(sim_ecb_records(&cluster, &records, decrypt), expected, "{}", vectors[0]);
+        }
+    }
+}
+
+#[test]
+fn test_sim_kat_cbc() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let xor = |a: &[u8], b: &[u8]| -> Vec<u8> { a.iter().zip(b).map(|(a, b)| a ^ b).collect() };
+    for file in KAT_CBC_FILES {
+        let vectors: Vec<kat::Vector> = kat::load(file);
+        // A single block is chained with the IV only: C = E_K(P ^ IV) and P = D_K(C) ^ IV
+        let encrypt: Vec<&kat::Vector> = vectors.iter().filter(|v| !v.is_decrypt()).collect();
+        let records: Vec<(&[u8], Vec<u8>)> = encrypt.iter().map(|v| (v.get("KEY"), xor(v.get("PLAINTEXT"), v.get("IV")))).collect();
+        let expected: Vec<u8> = encrypt.iter().flat_map(|v| v.get("CIPHERTEXT").to_vec()).collect();
+        assert_eq!(sim_ecb_records(&cluster, &records, false), expected, "{}", encrypt[0]);
+
+        let decrypt: Vec<&kat::Vector> = vectors.iter().filter(|v| v.is_decrypt()).collect();
+        let records: Vec<(&[u8], Vec<u8>)> = decrypt.iter().map(|v| (v.get("KEY"), v.get("CIPHERTEXT").to_vec())).collect();
+        let plaintext: Vec<u8> = sim_ecb_records(&cluster, &records, true).chunks(16).zip(&decrypt)
+            .flat_map(|(block, v)| xor(block, v.get("IV")))
+            .collect();
+        let expected: Vec<u8> = decrypt.iter().flat_map(|v| v.get("PLAINTEXT").to_vec()).collect();
+        assert_eq!(plaintext, expected, "{}", decrypt[0]);
+    }
+}
+
+// Only the first record of each Monte Carlo file runs on the simulator. Its chain is 1000
+// sequential requests, and every later record starts from the end of the one before, so a file
+// is 100000 requests; the other 99 records are checked against rust_impl only, in
+// test_kat_rust_impl_mct. NIST publishes no Monte Carlo test for CTR.
+#[test]
+fn test_sim_kat_mct() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    for file in KAT_MCT_FILES {
+        let vector: kat::Vector = kat::load(file).remove(0);
+        assert_eq!(sim_mct_ciphertext(&cluster, &vector), vector.get("CIPHERTEXT"), "{}", vector);
+    }
+}
+
+// SP 800-38A has the only NIST CTR examples; its decryption examples use the same values as the
+// encryption ones, so every example runs in both directions
+#[test]
+fn test_sim_kat_sp800_38a() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    for vector in kat::load("SP800-38A.rsp") {
+        let (key, plaintext, ciphertext) = (vector.get("KEY"), vector.get("PLAINTEXT"), vector.get("CIPHERTEXT"));
+        let (encrypted, decrypted): (Vec<u8>, Vec<u8>) = match &vector.section[..3] {
+            "ECB" => (sim_ecb(&cluster, key, plaintext).into_bytes(), sim_ecb_decrypt(&cluster, key, ciphertext).into_bytes()),
+            "CBC" => {
+                // One block per session, the data owner chains the opened ciphertext into the next input
+                let encrypted: Vec<u8> = plaintext.chunks(16).fold(Vec::new(), |mut encrypted: Vec<u8>, block| {
+                    let previous: &[u8] = if encrypted.is_empty() { vector.get("IV") } else { &encrypted[encrypted.len() - 16..] };
+                    let chained: Vec<u8> = block.iter().zip(previous).map(|(p, c)| p ^ c).collect();
+                    encrypted.extend_from_slice(sim_ecb(&cluster, key, &chained).as_bytes());
+                    encrypted
+                });
+                // Decryption is parallel: P_i = D_K(C_i) ^ C_(i-1), with C_0 the IV
+                let previous: Vec<u8> = [vector.get("IV"), &ciphertext[..ciphertext.len() - 16]].concat();
+                let decrypted: Vec<u8> = sim_ecb_decrypt(&cluster, key, ciphertext).as_bytes().iter().zip(&previous)
+                    .map(|(p, c)| p ^ c)
+                    .collect();
+                (encrypted, decrypted)
+            }
+            _ => {
+                let counter: u128 = u128::from_be_bytes(vector.get("IV").try_into().unwrap());
+                let key_id: String = format!("kat-{}", vector.section.to_lowercase());
+                (sim_ctr(&cluster, key, counter, plaintext).into_bytes(), sim_ctr_decrypt(&cluster, &key_id, key, counter, ciphertext).into_bytes())
+            }
+        };
+        assert_eq!(encrypted, ciphertext, "{}", vector);
+        assert_eq!(decrypted, plaintext, "{}", vector);
+    }
+}
+
+// gcmEncrypt.rsp holds the McGrew-Viega test cases in the GCMVS layout, not NIST's GCMVS files
+#[test]
+fn test_sim_kat_gcm() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    for vector in kat::load("gcmEncrypt.rsp") {
+        let (ciphertext, tag) = sim_gcm(&cluster, vector.get("KEY"), vector.get("IV"), vector.get("AAD"), vector.get("PLAINTEXT"));
+        assert_eq!(ciphertext.as_bytes(), vector.get("CIPHERTEXT"), "{}", vector);
+        assert_eq!(tag.as_bytes(), vector.get("TAG"), "{}", vector);
+    }
+}
+
+// Runs ECB on the malicious-secure backend with party `cheater` committing `fault` and checks that
+// every party aborts instead of returning a ciphertext
+#[cfg(test)]
+fn assert_fault_detected(fault: encrypt::fault::Fault, cheater: usize, key: Option<&[u8]>) {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    // Only the malicious-secure backend detects anything, whichever backend the suite runs on
+    for party in 0..3 {
+        cluster.set_backend(party, encrypt::backend::Backend::Lut256SsMal);
+    }
+    let input: Input = data::get_input("short");
+    let shares: Option<[encrypt::KeyShare; 3]> = key.map(|key| encrypt::KeyShare::split(key).unwrap());
+    let results: Vec<error::Result<Ciphertext>> = cluster.run(|party, config| {
+        if party == cheater {
+            encrypt::fault::inject(fault);
+        }
+        let result: error::Result<Ciphertext> = encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, shares.as_ref().map(|shares| &shares[party]));
+        encrypt::fault::clear();
+        result
+    });
+    for (party, result) in results.iter().enumerate() {
+        assert!(result.is_err(), "party {} output {:?} although party {} committed {:?}", party, result, cheater, fault);
+    }
+}
+
+#[test]
+fn test_sim_fault_input_share() {
+    // Only the data owner sends messages during input sharing
+    assert_fault_detected(encrypt::fault::Fault::InputShare, encrypt::DATA_OWNER, None);
+}
+
+#[test]
+fn test_sim_fault_key_share() {
+    for cheater in 0..3 {
+        assert_fault_detected(encrypt::fault::Fault::KeyShare, cheater, None);
+    }
+}
+
+#[test]
+fn test_sim_fault_sbox_output() {
+    // The AES-128 key schedule, and with an AES-256 key also every round of the cipher
+    for cheater in 0..3 {
+        assert_fault_detected(encrypt::fault::Fault::SboxOutput, cheater, None);
+    }
+    assert_fault_detected(encrypt::fault::Fault::SboxOutput, 1, Some(&[0x5a; 32]));
+}
+
+#[test]
+fn test_config_init_and_check() {
+    let dir: std::path::PathBuf = std::env::temp_dir().join(format!("locksmith-config-{}", std::process::id()));
+    let keys: std::path::PathBuf = std::env::current_dir().unwrap().join("keys");
+    let hosts: Vec<String> = vec!["localhost".to_string()];
+    let files: Vec<std::path::PathBuf> = config::init(&dir, &hosts, 8100, &keys, false).unwrap();
+    let overlays: Vec<std::path::PathBuf> = files[1..4].to_vec();
+    assert_eq!(config::check(&overlays).unwrap().len(), 3);
+    // Every party starts with the allow-all policy written next to the overlays
+    assert_eq!(files[4], dir.join(config::policy::POLICY_FILE));
+    assert!(config::check(&overlays).unwrap().iter().all(|party| party.policy.as_deref() == Some(files[4].as_path())));
+
+    // Existing files are only replaced with force
+    assert!(config::init(&dir, &hosts, 8100, &keys, false).is_err());
+
+    // Environment variables win over the cluster file and the overlay
+    let env = |vars: &'static [(&'static str, &'static str)]| move |name: &str| {
+        vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.to_string())
+    };
+    let party: config::PartyConfig = config::resolve(&overlays[0], env(&[("LOCKSMITH_PARTY_INDEX", "3"), ("LOCKSMITH_P2_PORT", "9000")])).unwrap();
+    assert_eq!(party.party_index, 3);
+    assert_eq!(party.parties[1].port, 9000);
+    assert_eq!(party.own().certificate, keys.join("p3.pem"));
+    party.validate().unwrap();
+
+    // Rejected before connecting: clashing ports, a key of another party, a bad index
+    let clash: config::PartyConfig = config::resolve(&overlays[0], env(&[("LOCKSMITH_P3_PORT", "8100")])).unwrap();
+    assert!(clash.validate().is_err());
+    let wrong_key: config::PartyConfig = config::resolve(&overlays[0], env(&[("LOCKSMITH_P1_PRIVATE_KEY", "keys/p2.key")])).unwrap();
+    assert!(wrong_key.validate().is_err());
+    let bad_index: config::PartyConfig = config::resolve(&overlays[0], env(&[("LOCKSMITH_PARTY_INDEX", "4")])).unwrap();
+    assert!(bad_index.validate().is_err());
+    assert!(config::check(&[overlays[0].clone(), overlays[0].clone()]).is_err());
+
+    // The backend defaults to the malicious one; a party file or the environment picks another,
+    // and parties that pick different ones are not a cluster
+    assert_eq!(party.backend, encrypt::backend::Backend::Lut256SsMal);
+    let gf4: config::PartyConfig = config::resolve(&overlays[0], env(&[("LOCKSMITH_BACKEND", "gf4-circuit")])).unwrap();
+    assert_eq!(gf4.backend, encrypt::backend::Backend::Gf4Circuit);
+    assert!(config::resolve(&overlays[0], env(&[("LOCKSMITH_BACKEND", "rot13")])).is_err());
+    let text: String = std::fs::read_to_string(&overlays[1]).unwrap();
+    std::fs::write(&overlays[1], format!("backend = \"lut256\"\n{}", text)).unwrap();
+    assert!(config::check(&overlays[1..]).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_certs_init_and_rotate() {
+    let dir: std::path::PathBuf = std::env::temp_dir().join(format!("locksmith-certs-{}", std::process::id()));
+    let hosts: Vec<String> = vec!["127.0.0.1".to_string()];
+    let overlays: Vec<std::path::PathBuf> = config::init(&dir, &hosts, 8100, std::path::Path::new("keys"), false).unwrap()[1..4].to_vec();
+    let cluster: std::path::PathBuf = dir.join(config::CLUSTER_FILE);
+    config::certs::init(&cluster, std::path::Path::new("keys"), 1, false).unwrap();
+    assert_eq!(config::check(&overlays).unwrap().len(), 3);
+    assert!(config::certs::init(&cluster, std::path::Path::new("keys"), 1, false).is_err());
+
+    // A certificate from outside the cluster CA is refused
+    let repo_keys: std::path::PathBuf = std::env::current_dir().unwrap().join("keys");
+    let foreign: config::PartyConfig = config::resolve(&overlays[0], |name| match name {
+        "LOCKSMITH_P1_CERTIFICATE" => Some(repo_keys.join("p1.pem").display().to_string()),
+        "LOCKSMITH_P1_PRIVATE_KEY" => Some(repo_keys.join("p1.key").display().to_string()),
+        _ => None,
+    }).unwrap();
+    assert!(foreign.validate().is_err());
+
+    // Within the grace window a party without its new key starts with the old pair
+    let written: Vec<std::path::PathBuf> = config::certs::rotate(&cluster, &[2], time::Duration::hours(1), None, 1, false).unwrap();
+    let new_key: &std::path::PathBuf = written.iter().find(|path| path.extension() == Some("key".as_ref())).unwrap();
+    assert_eq!(config::check(&overlays).unwrap().len(), 3);
+    std::fs::remove_file(new_key).unwrap();
+    let party: config::PartyConfig = config::load(&overlays[1]).unwrap();
+    assert!(party.own().previous.is_some());
+    assert!(config::certs::rotate(&cluster, &[2], time::Duration::hours(1), None, 1, false).is_err());
+
+    // After the window the old pair is refused
+    let written: Vec<std::path::PathBuf> = config::certs::rotate(&cluster, &[3], time::Duration::ZERO, None, 1, false).unwrap();
+    let new_key: &std::path::PathBuf = written.iter().find(|path| path.extension() == Some("key".as_ref())).unwrap();
+    std::fs::remove_file(new_key).unwrap();
+    assert!(config::load(&overlays[2]).is_err());
+    assert!(config::load(&overlays[0]).is_ok());
+
+    // A replaced party moves to the new host with a new certificate and without the old pair
+    let old_host: Vec<String> = config::load(&overlays[0]).unwrap().accepted_fingerprints(1).unwrap();
+    assert_eq!(old_host.len(), 2);
+    config::certs::replace(&cluster, 2, "127.0.0.2", Some(8200), None, 1).unwrap();
+    let party: config::PartyConfig = config::load(&overlays[1]).unwrap();
+    assert_eq!((party.own().address.as_str(), party.own().port), ("127.0.0.2", 8200));
+    assert!(party.own().previous.is_none());
+    // Its peers no longer accept any certificate of the old host
+    let accepted: Vec<String> = config::load(&overlays[0]).unwrap().accepted_fingerprints(1).unwrap();
+    assert_eq!(accepted, vec![party.fingerprint(1).unwrap()]);
+    assert!(old_host.iter().all(|fingerprint| !accepted.contains(fingerprint)));
+    assert!(config::certs::replace(&cluster, 4, "127.0.0.2", None, None, 1).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sim_certs_rotation() {
+    let cluster: sim::Cluster = sim::Cluster::with_ca();
+    let written: Vec<std::path::PathBuf> = config::certs::rotate(
+        &cluster.dir().join(config::CLUSTER_FILE), &[], time::Duration::hours(1), None, 1, false,
+    ).unwrap();
+    // Party 2 has not installed its new key and presents its old certificate to the rotated parties
+    let new_key: &std::path::PathBuf = written.iter()
+        .find(|path| path.file_name().unwrap().to_string_lossy().starts_with("p2.") && path.extension() == Some("key".as_ref()))
+        .unwrap();
+    std::fs::remove_file(new_key).unwrap();
+
+    let input: Input = data::get_input("short");
+    let ciphertexts: Vec<Ciphertext> = cluster.run(|_, config| encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None).unwrap());
+    for ciphertext in ciphertexts {
+        assert_eq!(ciphertext, rust_impl::rust_aes_ecb(&input));
+    }
+}
+
+#[test]
+fn test_sim_certs_pinned_per_party() {
+    let cluster: sim::Cluster = sim::Cluster::with_ca();
+    // Party 2 presents party 3's certificate, which the CA issued as well
+    let third: config::PartyEntry = config::load(cluster.config(2)).unwrap().parties[2].clone();
+    let text: String = std::fs::read_to_string(cluster.config(1)).unwrap();
+    std::fs::write(cluster.config(1), format!(
+        "{}\n[p2]\ncertificate = \"{}\"\nprivate_key = \"{}\"\n",
+        text, third.certificate.display(), third.private_key.display(),
+    )).unwrap();
+
+    let outcomes: Vec<error::Result<()>> = cluster.run(|_, path| {
+        let party_config: config::PartyConfig = config::load(path)?;
+        encrypt::connect(&party_config, Some(1)).map(|_| ())
+    });
+    // Its peers refuse it; party 2 itself sees the certificates it expects
+    for party in [0, 2] {
+        assert!(matches!(&outcomes[party], Err(error::LocksmithError::Refused(e)) if e.contains("party 2")), "{:?}", outcomes[party]);
+    }
+}
+
+#[test]
+fn test_sim_certs_replaced_host_refused() {
+    let cluster: sim::Cluster = sim::Cluster::with_ca();
+    let old_host: config::PartyEntry = config::load(cluster.config(1)).unwrap().parties[1].clone();
+    config::certs::replace(&cluster.dir().join(config::CLUSTER_FILE), 2, "127.0.0.1", None, None, 1).unwrap();
+    // The old host of party 2 still has its CA-issued pair
+    let text: String = std::fs::read_to_string(cluster.config(1)).unwrap();
+    std::fs::write(cluster.config(1), format!(
+        "{}\n[p2]\ncertificate = \"{}\"\nprivate_key = \"{}\"\n",
+        text, old_host.certificate.display(), old_host.private_key.display(),
+    )).unwrap();
+
+    let outcomes: Vec<error::Result<()>> = cluster.run(|_, path| {
+        let party_config: config::PartyConfig = config::load(path)?;
+        encrypt::connect(&party_config, Some(1)).map(|_| ())
+    });
+    for party in [0, 2] {
+        assert!(matches!(&outcomes[party], Err(error::LocksmithError::Refused(e)) if e.contains("party 2")), "{:?}", outcomes[party]);
+    }
+}
+
+#[test]
+fn test_sim_access_presented_certificate() {
+    let cluster: sim::Cluster = sim::Cluster::with_ca();
+    let written: Vec<std::path::PathBuf> = config::certs::rotate(
+        &cluster.dir().join(config::CLUSTER_FILE), &[], time::Duration::hours(1), None, 1, false,
+    ).unwrap();
+    // The data owner has not installed its new key and presents its previous certificate
+    let new_key: &std::path::PathBuf = written.iter()
+        .find(|path| path.file_name().unwrap().to_string_lossy().starts_with("p1.") && path.extension() == Some("key".as_ref()))
+        .unwrap();
+    std::fs::remove_file(new_key).unwrap();
+    let owner: config::PartyConfig = config::load(cluster.config(encrypt::DATA_OWNER)).unwrap();
+    let presented: String = owner.presented_fingerprint().unwrap();
+    let configured: String = owner.fingerprint(encrypt::DATA_OWNER).unwrap();
+    assert_ne!(presented, configured);
+
+    // Policies are evaluated for the certificate the data owner presented, not the configured one
+    let policy = |client: &str| format!("[[allow]]\nclient = \"{client}\"\nkeys = [\"default\"]\noperations = [\"encrypt\"]\n");
+    cluster.set_policy(2, &policy(&configured));
+    let input: Input = data::get_input("short");
+    let encrypt = |config: &std::path::Path| encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None);
+    assert!(cluster.run(|_, config| matches!(encrypt(config), Err(error::LocksmithError::Refused(_)))).iter().all(|&refused| refused));
+    std::fs::write(cluster.dir().join("p3-policy.toml"), policy(&presented)).unwrap();
+    for ciphertext in cluster.run(|_, config| encrypt(config).unwrap()) {
+        assert_eq!(ciphertext, rust_impl::rust_aes_ecb(&input));
+    }
+}
+
+#[test]
+fn test_config_network_settings() {
+    let dir: std::path::PathBuf = std::env::temp_dir().join(format!("locksmith-network-{}", std::process::id()));
+    let keys: std::path::PathBuf = std::env::current_dir().unwrap().join("keys");
+    let hosts: Vec<String> = vec!["127.0.0.1".to_string()];
+    let overlays: Vec<std::path::PathBuf> = config::init(&dir, &hosts, 8100, &keys, false).unwrap();
+    let overlay: std::path::PathBuf = overlays[1].clone();
+    assert_eq!(config::load(&overlay).unwrap().network, config::NetworkSettings::default());
+
+    // The party file overrides single fields of the cluster file's table
+    let cluster: String = std::fs::read_to_string(dir.join(config::CLUSTER_FILE)).unwrap();
+    std::fs::write(dir.join(config::CLUSTER_FILE), cluster.replace("io_timeout_secs = 30", "io_timeout_secs = 9")).unwrap();
+    std::fs::write(&overlay, "party_index = 1\ncluster = \"cluster.toml\"\n\n[network]\nconnect_attempts = 2\n").unwrap();
+    let network: config::NetworkSettings = config::load(&overlay).unwrap().network;
+    assert_eq!((network.io_timeout_secs, network.connect_attempts), (9, 2));
+    assert_eq!(network.heartbeat(), std::time::Duration::from_secs(3));
+
+    // Backoff doubles up to the maximum
+    let delays: Vec<u64> = (1..=8).map(|attempt| network.retry_delay(attempt).as_millis() as u64).collect();
+    assert_eq!(delays, vec![500, 1000, 2000, 4000, 8000, 16000, 30000, 30000]);
+
+    std::fs::write(&overlay, "party_index = 1\ncluster = \"cluster.toml\"\n\n[network]\nio_timeout_secs = 0\n").unwrap();
+    assert!(config::load(&overlay).is_err());
+    std::fs::write(&overlay, "party_index = 1\ncluster = \"cluster.toml\"\n\n[network]\nio_timeout = 5\n").unwrap();
+    assert!(config::load(&overlay).is_err());
+
+    // Channels are between 1 and the maximum, and the same on every party
+    for channels in [0, config::MAX_CHANNELS + 1] {
+        std::fs::write(&overlay, format!("party_index = 1\ncluster = \"cluster.toml\"\n\n[network]\nchannels = {}\n", channels)).unwrap();
+        assert!(config::load(&overlay).is_err());
+    }
+    std::fs::write(&overlay, "party_index = 1\ncluster = \"cluster.toml\"\n\n[network]\nchannels = 4\n").unwrap();
+    assert_eq!(config::load(&overlay).unwrap().network.channels, 4);
+    assert!(config::check(&overlays[1..4]).is_err());
+    std::fs::write(dir.join(config::CLUSTER_FILE), cluster.replace("channels = 1", "channels = 4")).unwrap();
+    config::check(&overlays[1..4]).unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sim_daemon_peer_restart() {
+    use encrypt::daemon::{Job, JobResult};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    let cluster: sim::Cluster = sim::Cluster::new();
+    cluster.set_network("io_timeout_secs = 3\nconnect_timeout_secs = 20\nretry_initial_ms = 100\nretry_max_ms = 1000\n");
+    let (jobs_tx, jobs_rx): (Sender<Job>, Receiver<Job>) = channel();
+    let jobs_rx: std::sync::Mutex<Option<Receiver<Job>>> = std::sync::Mutex::new(Some(jobs_rx));
+
+    std::thread::scope(|scope| {
+        // The client keeps submitting until two jobs went through after party 1 dropped its first session
+        let client = scope.spawn(move || {
+            let mut served: Vec<(Vec<u8>, u128, Ciphertext)> = Vec::new();
+            for i in 0..10u8 {
+                let data: Vec<u8> = vec![i; 40];
+                let (reply, result): (Sender<JobResult>, Receiver<JobResult>) = channel();
+                jobs_tx.send(Job { data: data.clone(), reply }).unwrap();
+                if let Ok((nonce, version, ciphertext)) = result.recv().unwrap() {
+                    assert_eq!(version, FIRST_VERSION);
+                    served.push((data, nonce, ciphertext));
+                }
+                if served.len() == 2 {
+                    break;
+                }
+            }
+            served
+        });
+
+        let stopped: Vec<error::Result<()>> = cluster.run(|party, config| {
+            // Party 1 drops its session after the first step, as if it restarted
+            if party == 1 {
+                encrypt::fault::inject(encrypt::fault::Fault::Disconnect);
+            }
+            let jobs: Option<Receiver<Job>> = if party == encrypt::DATA_OWNER { jobs_rx.lock().unwrap().take() } else { None };
+            let keys: KeyStore = KeyStore::open(&cluster.party_dir(party).join("keys"));
+            let result: error::Result<()> = encrypt::daemon::run(config, &cluster.party_dir(party), &keys, encrypt::nonce::DEFAULT_KEY_ID, jobs, None);
+            encrypt::fault::clear();
+            result
+        });
+        assert!(stopped.iter().all(|result| result.is_ok()));
+
+        let served: Vec<(Vec<u8>, u128, Ciphertext)> = client.join().unwrap();
+        assert_eq!(served.len(), 2);
+        assert_ne!(served[0].1, served[1].1);
+        let key: [u8; 16] = encrypt::rss::key_schedule::get_combined_key();
+        for (data, nonce, ciphertext) in served {
+            assert_eq!(ciphertext, rust_impl::aes_ctr(&key, nonce, &data));
+        }
+    });
+}
+
+#[test]
+fn test_keystore_files() {
+    let dir: std::path::PathBuf = std::env::temp_dir().join(format!("locksmith-keystore-{}", std::process::id()));
+    let keys: KeyStore = KeyStore::open(&dir);
+    assert!(!keys.contains("main"));
+    assert!(keys.load("main").is_err());
+
+    let stored: StoredKey = StoredKey {
+        key_id: "main".to_string(),
+        version: FIRST_VERSION,
+        epoch: 3,
+        decrypt_only: false,
+        share: KeyShare::replicated(vec![1; 16], vec![2; 16]).unwrap(),
+        missing: Some([5; 32]),
+        kcv: Some([6; 3]),
+    };
+    keys.save(&stored).unwrap();
+    assert!(keys.contains("main"));
+    assert_eq!(keys.load("main").unwrap(), stored);
+
+    // A later epoch replaces the file in one step
+    let next: StoredKey = StoredKey { epoch: 4, share: KeyShare::replicated(vec![3; 32], vec![4; 32]).unwrap(), ..stored.clone() };
+    keys.save(&next).unwrap();
+    assert_eq!(keys.load("main").unwrap(), next);
+    assert!(!dir.join("main.v1.key.tmp").exists());
+
+    // A staged epoch is only used once switched to, and the previous one is kept until dropped
+    let staged: StoredKey = StoredKey { epoch: 5, ..next.clone() };
+    keys.stage(&staged).unwrap();
+    assert_eq!(keys.load("main").unwrap(), next);
+    keys.roll_back(&staged).unwrap();
+    assert!(!dir.join("main.v1.key.next").exists());
+    keys.stage(&staged).unwrap();
+    keys.switch(&staged).unwrap();
+    assert_eq!(keys.load("main").unwrap(), staged);
+    keys.roll_back(&staged).unwrap();
+    assert_eq!(keys.load("main").unwrap(), next);
+    keys.stage(&staged).unwrap();
+    keys.switch(&staged).unwrap();
+    keys.drop_previous(&staged).unwrap();
+    assert!(!dir.join("main.v1.key.prev").exists());
+    assert_eq!(keys.load("main").unwrap(), staged);
+    assert_eq!(keys.versions("main").unwrap(), vec![1]);
+
+    // Rolling back a key that was new on this party removes it
+    let fresh: StoredKey = StoredKey { key_id: "fresh".to_string(), ..stored.clone() };
+    keys.stage(&fresh).unwrap();
+    keys.switch(&fresh).unwrap();
+    keys.roll_back(&fresh).unwrap();
+    assert!(!keys.contains("fresh"));
+
+    // The newest version is loaded by default; older ones stay available for decryption
+    let rotated: StoredKey = StoredKey { version: 2, epoch: 0, ..stored.clone() };
+    keys.save(&rotated).unwrap();
+    keys.save(&StoredKey { decrypt_only: true, ..next.clone() }).unwrap();
+    assert_eq!(keys.versions("main").unwrap(), vec![1, 2]);
+    keys.save(&StoredKey { key_id: "other".to_string(), missing: None, kcv: None, ..stored.clone() }).unwrap();
+    assert_eq!(keys.key_ids().unwrap(), vec!["main".to_string(), "other".to_string()]);
+    assert_eq!(keys.load("other").unwrap().missing, None);
+    assert_eq!(keys.load("other").unwrap().kcv, None);
+    assert_eq!(keys.load("main").unwrap(), rotated);
+    let old: StoredKey = keys.load_version("main", 1).unwrap();
+    assert!(old.decrypt_only && old.encryption_share().is_err());
+    assert!(keys.load_version("main", 3).is_err());
+    assert_eq!(KeySource::Store(&keys).resolve(0, "main", 1).unwrap(), next.share);
+    assert!(matches!(KeySource::Store(&keys).resolve(0, "unknown", FIRST_VERSION), Err(error::LocksmithError::InvalidInput(_))));
+    assert_eq!(KeySource::Store(&keys).resolve(0, encrypt::nonce::DEFAULT_KEY_ID, FIRST_VERSION).unwrap(), KeyShare::builtin(0));
+    assert!(KeySource::Store(&keys).resolve(0, encrypt::nonce::DEFAULT_KEY_ID, 2).is_err());
+    assert!(matches!(keys.lookup("unknown"), Err(error::LocksmithError::InvalidInput(_))));
+    assert_eq!(keys.lookup(encrypt::nonce::DEFAULT_KEY_ID).unwrap(), None);
+    assert_eq!(keys.lookup("main").unwrap(), Some(rotated.clone()));
+
+    // Only replicated shares of a valid key ID are stored
+    assert!(KeyShare::replicated(vec![1; 16], vec![2; 32]).is_err());
+    assert!(keys.save(&StoredKey { share: KeyShare::builtin(0), ..stored.clone() }).is_err());
+    assert!(keys.save(&StoredKey { key_id: "../main".to_string(), ..stored.clone() }).is_err());
+
+    std::fs::write(dir.join("broken.v1.key"), "{}").unwrap();
+    assert!(matches!(keys.load("broken"), Err(error::LocksmithError::Malformed(_))));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// The key the three parties' stored shares add up to, checking that the copies match
+#[cfg(test)]
+fn stored_key_value(stored: &[StoredKey]) -> Vec<u8> {
+    let shares: Vec<(&[u8], &[u8])> = stored.iter().map(|key| key.share.replicated_bytes().unwrap()).collect();
+    for i in 0..3 {
+        assert_eq!(shares[i].1, shares[(i + 1) % 3].0, "party {}'s second share is not party {}'s first", i, (i + 1) % 3);
+    }
+    (0..shares[0].0.len()).map(|b| shares[0].0[b] ^ shares[1].0[b] ^ shares[2].0[b]).collect()
+}
+
+#[test]
+fn test_sim_key_refresh() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let key: [u8; 16] = encrypt::rss::key_schedule::get_combined_key();
+    let keys = |party: usize| KeyStore::open(&cluster.party_dir(party).join("keys"));
+
+    let stored: Vec<StoredKey> = cluster.run(|party, config| encrypt::keystore::store_key(config, &keys(party), "main", None).unwrap());
+    assert_eq!(stored_key_value(&stored), key);
+    // A second key under the same ID is refused
+    assert!(cluster.run(|party, config| encrypt::keystore::store_key(config, &keys(party), "main", None).is_err()).iter().all(|&refused| refused));
+
+    let refreshed: Vec<StoredKey> = cluster.run(|party, config| encrypt::keystore::refresh_key(config, &keys(party), "main").unwrap());
+    assert!(refreshed.iter().all(|key| key.epoch == 1));
+    assert_eq!(stored_key_value(&refreshed), key);
+    for (party, key) in refreshed.iter().enumerate() {
+        assert_ne!(key.share, stored[party].share);
+        assert_eq!(&keys(party).load("main").unwrap(), key);
+    }
+
+    // The refreshed shares still encrypt under the same key
+    let input: Input = data::get_input("short");
+    let ciphertexts: Vec<Ciphertext> = cluster.run(|party, config| {
+        encrypt::encrypt_ecb(config, &input, "main", Some(&keys(party).load("main").unwrap().share)).unwrap()
+    });
+    assert!(ciphertexts.iter().all(|ciphertext| ciphertext == &rust_impl::rust_aes_ecb(&input)));
+
+    // A party that sends a wrong share of zero is caught, and no store is changed
+    for cheater in 0..3 {
+        let results: Vec<error::Result<StoredKey>> = cluster.run(|party, config| {
+            if party == cheater {
+                encrypt::fault::inject(encrypt::fault::Fault::ZeroShare);
+            }
+            let result: error::Result<StoredKey> = encrypt::keystore::refresh_key(config, &keys(party), "main");
+            encrypt::fault::clear();
+            result
+        });
+        assert!(results.iter().all(|result| result.is_err()), "party {} cheated undetected", cheater);
+        for (party, key) in refreshed.iter().enumerate() {
+            assert_eq!(&keys(party).load("main").unwrap(), key);
+        }
+    }
+}
+
+#[test]
+fn test_sim_keystream_refresh_every_version() {
+    use encrypt::keystream::KeystreamService;
+
+    // A running service refreshes the older versions of its key too, so that none of them keeps
+    // shares from before the refresh
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let keys = |party: usize| KeyStore::open(&cluster.party_dir(party).join("keys"));
+    cluster.run(|party, config| encrypt::keystore::store_key(config, &keys(party), "main", None).unwrap());
+    let rotated: Vec<StoredKey> = cluster.run(|party, config| encrypt::keystore::rotate_key(config, &keys(party), "main").unwrap());
+    let old: Vec<StoredKey> = (0..3).map(|party| keys(party).load_version("main", FIRST_VERSION).unwrap()).collect();
+
+    let epochs: Vec<u64> = cluster.run(|party, config| {
+        let mpc: Box<dyn encrypt::backend::MpcParty> = encrypt::setup_party(config).unwrap();
+        let ledger: NonceLedger = NonceLedger::in_memory("main");
+        let mut service: KeystreamService = KeystreamService::start(mpc, encrypt::DATA_OWNER, ledger, Some(&rotated[party].share), rotated[party].version).unwrap();
+        let epoch: u64 = service.refresh(&keys(party), "main").unwrap();
+        service.shutdown().unwrap();
+        epoch
+    });
+    assert!(epochs.iter().all(|&epoch| epoch == 1));
+    let first: Vec<StoredKey> = (0..3).map(|party| keys(party).load_version("main", FIRST_VERSION).unwrap()).collect();
+    for (party, (first, old)) in first.iter().zip(old.iter()).enumerate() {
+        assert_eq!(first.epoch, 1);
+        assert_ne!(first.share, old.share);
+        assert_eq!(keys(party).load("main").unwrap().epoch, 1);
+    }
+    assert_eq!(stored_key_value(&first), stored_key_value(&old));
+}
+
+#[test]
+fn test_sim_key_rotation_reencrypt() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let keys = |party: usize| KeyStore::open(&cluster.party_dir(party).join("keys"));
+    let input: Input = data::get_input("short");
+    cluster.run(|party, config| encrypt::keystore::store_key(config, &keys(party), "main", None).unwrap());
+
+    let results: Vec<(u128, Option<Ciphertext>)> = cluster.run(|party, config| {
+        let ledger: NonceLedger = NonceLedger::open(&cluster.party_dir(party), "main").unwrap();
+        let data: Option<&Input> = (party == encrypt::DATA_OWNER).then_some(&input);
+        encrypt::encrypt_ctr(config, data, Nonce::Auto, ledger, Some(&keys(party).load("main").unwrap().share), FIRST_VERSION).unwrap()
+    });
+    let ctr: Envelope = Envelope {
+        key_id: "main".to_string(),
+        key_version: FIRST_VERSION,
+        variant: AesVariant::Aes128,
+        mode: CipherMode::Ctr,
+        iv: results[0].0.to_be_bytes().to_vec(),
+        padding: Padding::Zero,
+        plaintext_len: input.data_len() as u64,
+        tag: Tag::default(),
+        ciphertext: results[0].1.clone().unwrap(),
+    };
+    let ecb: Envelope = Envelope {
+        mode: CipherMode::Ecb,
+        iv: Vec::new(),
+        ciphertext: rust_impl::rust_aes_ecb(&input),
+        ..ctr.clone()
+    };
+
+    let rotated: Vec<StoredKey> = cluster.run(|party, config| encrypt::keystore::rotate_key(config, &keys(party), "main").unwrap());
+    assert!(rotated.iter().all(|key| key.version == 2 && !key.decrypt_only));
+    assert_ne!(stored_key_value(&rotated), encrypt::rss::key_schedule::get_combined_key());
+    for party in 0..3 {
+        assert!(keys(party).load_version("main", FIRST_VERSION).unwrap().encryption_share().is_err());
+    }
+
+    for envelope in [&ctr, &ecb] {
+        let reencrypted: Vec<Option<Envelope>> = cluster.run(|party, config| {
+            let envelope: Option<&Envelope> = (party == encrypt::DATA_OWNER).then_some(envelope);
+            encrypt::reencrypt::reencrypt(config, envelope, &cluster.party_dir(party), &keys(party)).unwrap()
+        });
+        assert!(reencrypted[1].is_none() && reencrypted[2].is_none());
+        let reencrypted: Envelope = reencrypted[0].clone().unwrap();
+        assert_eq!(reencrypted.key_version, 2);
+        assert_ne!(reencrypted.ciphertext, envelope.ciphertext);
+
+        // The new envelope decrypts under the new version, and cannot be re-encrypted again
+        let plaintexts: Vec<Option<Plaintext>> = cluster.run(|party, config| {
+            let envelope: Option<&Envelope> = (party == encrypt::DATA_OWNER).then_some(&reencrypted);
+            encrypt::decrypt::decrypt(config, envelope, &cluster.party_dir(party), KeySource::Store(&keys(party))).unwrap()
+        });
+        assert_eq!(plaintexts[0].as_ref().map(Plaintext::as_bytes), Some(&input.to_bytes()[..input.data_len()]));
+        assert!(cluster.run(|party, config| {
+            let envelope: Option<&Envelope> = (party == encrypt::DATA_OWNER).then_some(&reencrypted);
+            encrypt::reencrypt::reencrypt(config, envelope, &cluster.party_dir(party), &keys(party)).is_err()
+        }).iter().all(|&refused| refused));
+
+        // Counters allocated under the new version give no keystream under the old one
+        if envelope.mode == CipherMode::Ctr {
+            let downgraded: Envelope = Envelope { key_version: FIRST_VERSION, ..reencrypted.clone() };
+            assert!(cluster.run(|party, config| {
+                let envelope: Option<&Envelope> = (party == encrypt::DATA_OWNER).then_some(&downgraded);
+                encrypt::decrypt::decrypt(config, envelope, &cluster.party_dir(party), KeySource::Store(&keys(party))).is_err()
+            }).iter().all(|&refused| refused));
+        }
+    }
+}
+
+#[test]
+fn test_sim_reshare() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let key: [u8; 16] = encrypt::rss::key_schedule::get_combined_key();
+    let input: Input = data::get_input("short");
+    // Every host has its own key store and ledger directory; party 2 moves to new hosts
+    let host_dir = |party: usize, host: usize| cluster.party_dir(party).join(format!("host{}", host));
+    let keys = |dir: &std::path::Path| KeyStore::open(&dir.join("keys"));
+    let encrypt = |party: usize, config: &std::path::Path, dir: &std::path::Path| {
+        let ledger: NonceLedger = NonceLedger::open(dir, "main").unwrap();
+        let data: Option<&Input> = (party == encrypt::DATA_OWNER).then_some(&input);
+        encrypt::encrypt_ctr(config, data, Nonce::Auto, ledger, Some(&keys(dir).load("main").unwrap().share), FIRST_VERSION).unwrap()
+    };
+
+    let stored: Vec<StoredKey> = cluster.run(|party, config| {
+        encrypt::keystore::store_key(config, &keys(&host_dir(party, 0)), "main", None).unwrap()
+    });
+    cluster.run(|party, config| encrypt(party, config, &host_dir(party, 0)));
+
+    let host = |party: usize| if party == 2 { 1 } else { 0 };
+    let reshared: Vec<Vec<StoredKey>> = cluster.run(|party, config| {
+        let dir: std::path::PathBuf = host_dir(party, host(party));
+        encrypt::reshare::reshare(config, &keys(&dir), &dir, 2).unwrap()
+    });
+    let reshared: Vec<StoredKey> = reshared.into_iter().map(|keys| keys.into_iter().next().unwrap()).collect();
+    assert!(reshared.iter().all(|key| key.epoch == 1 && key.missing.is_some()));
+    assert_eq!(stored_key_value(&reshared), key);
+    assert_ne!(reshared[2].share, stored[2].share);
+
+    // The new committee encrypts under the same key, with the ledger carried over
+    let results: Vec<(u128, Option<Ciphertext>)> = cluster.run(|party, config| encrypt(party, config, &host_dir(party, host(party))));
+    assert_ne!(results[0].0, 0);
+    assert_eq!(results[0].1, Some(rust_impl::rust_aes_ctr(&input, results[0].0)));
+
+    // A remaining party that hands over a wrong share is caught, and the new host gets nothing
+    for cheater in [0, 1] {
+        let outcomes: Vec<bool> = cluster.run(|party, config| {
+            if party == cheater {
+                encrypt::fault::inject(encrypt::fault::Fault::HandoverShare);
+            }
+            let dir: std::path::PathBuf = host_dir(party, if party == 2 { 2 + cheater } else { 0 });
+            let result: error::Result<Vec<StoredKey>> = encrypt::reshare::reshare(config, &keys(&dir), &dir, 2);
+            encrypt::fault::clear();
+            result.is_err()
+        });
+        assert!(outcomes.iter().all(|&failed| failed), "party {} cheated undetected", cheater);
+        assert!(keys(&host_dir(2, 2 + cheater)).key_ids().unwrap().is_empty());
+    }
+}
+
+#[test]
+fn test_backup_files() {
+    let dir: std::path::PathBuf = std::env::temp_dir().join(format!("locksmith-backup-{}", std::process::id()));
+    let keys: KeyStore = KeyStore::open(&dir.join("keys"));
+    let out: std::path::PathBuf = dir.join("party1.backup");
+    let protection: Protection = Protection::Custodians { threshold: 2, count: 3 };
+    assert!(encrypt::backup::export(&keys, 1, protection, &out).is_err());
+
+    let stored: StoredKey = StoredKey {
+        key_id: "main".to_string(),
+        version: FIRST_VERSION,
+        epoch: 2,
+        decrypt_only: false,
+        share: KeyShare::replicated(vec![1; 16], vec![2; 16]).unwrap(),
+        missing: Some([5; 32]),
+        kcv: Some([6; 3]),
+    };
+    keys.save(&stored).unwrap();
+    keys.save(&StoredKey { version: 2, share: KeyShare::replicated(vec![3; 32], vec![4; 32]).unwrap(), ..stored.clone() }).unwrap();
+    let expected: Vec<StoredKey> = keys.load_all().unwrap();
+
+    // Encrypted to a recovery key; only its private key opens the backup
+    encrypt::backup::keygen(&dir, false).unwrap();
+    assert!(encrypt::backup::keygen(&dir, false).is_err());
+    let public: [u8; 32] = encrypt::backup::read_key(&dir.join("recovery.pub")).unwrap();
+    let secret: [u8; 32] = encrypt::backup::read_key(&dir.join("recovery.key")).unwrap();
+    assert_eq!(encrypt::backup::export(&keys, 1, Protection::Recovery(public), &out).unwrap(), vec![out.clone()]);
+    let backup: Backup = Backup::load(&out).unwrap();
+    assert_eq!(backup.party_index, 1);
+    assert_eq!(backup.open(&Unlock::Recovery(secret)).unwrap(), expected);
+    assert!(backup.open(&Unlock::Recovery([7; 32])).is_err());
+    // The payload is bound to the party it was taken from
+    let mut moved: Backup = backup.clone();
+    moved.party_index = 2;
+    assert!(moved.open(&Unlock::Recovery(secret)).is_err());
+
+    // Split across three custodians, any two of whom open it
+    let written: Vec<std::path::PathBuf> = encrypt::backup::export(&keys, 1, protection, &out).unwrap();
+    let shares: Vec<CustodianShare> = written[1..].iter().map(|path| CustodianShare::load(path).unwrap()).collect();
+    assert_eq!(shares.len(), 3);
+    let backup: Backup = Backup::load(&out).unwrap();
+    for pair in [[0, 1], [0, 2], [2, 1]] {
+        let unlock: Unlock = Unlock::Custodians(pair.iter().map(|&n| shares[n].clone()).collect());
+        assert_eq!(backup.open(&unlock).unwrap(), expected);
+    }
+    assert!(backup.open(&Unlock::Custodians(vec![shares[0].clone()])).is_err());
+    assert!(backup.open(&Unlock::Custodians(vec![shares[0].clone(), shares[0].clone()])).is_err());
+    assert!(backup.open(&Unlock::Recovery(secret)).is_err());
+    let wrong: CustodianShare = CustodianShare { y: "00".repeat(32), ..shares[1].clone() };
+    assert!(backup.open(&Unlock::Custodians(vec![shares[0].clone(), wrong])).is_err());
+    assert!(encrypt::backup::export(&keys, 1, Protection::Custodians { threshold: 4, count: 3 }, &out).is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_sim_backup_restore() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let keys = |party: usize| KeyStore::open(&cluster.party_dir(party).join("keys"));
+    let out: std::path::PathBuf = cluster.dir().join("party2.backup");
+    let recovery: [u8; 32] = [9; 32];
+    let public: [u8; 32] = x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::from(recovery)).to_bytes();
+
+    let stored: Vec<StoredKey> = cluster.run(|party, config| encrypt::keystore::store_key(config, &keys(party), "main", None).unwrap());
+    encrypt::backup::export(&keys(1), 2, Protection::Recovery(public), &out).unwrap();
+
+    // Party 2 loses its store and restores it from the backup
+    std::fs::remove_dir_all(cluster.party_dir(1).join("keys")).unwrap();
+    let restore = |party: usize, config: &std::path::Path, restored: Option<Vec<StoredKey>>| {
+        encrypt::backup::restore(config, &keys(party), restored.filter(|_| party == 1))
+    };
+    let backup: Vec<StoredKey> = Backup::load(&out).unwrap().open(&Unlock::Recovery(recovery)).unwrap();
+    let restored: Vec<Vec<StoredKey>> = cluster.run(|party, config| restore(party, config, Some(backup.clone())).unwrap());
+    assert!(restored.iter().all(|keys| keys.len() == 1));
+    assert_eq!(keys(1).load("main").unwrap(), stored[1]);
+
+    // Exactly one party restores
+    let outcomes: Vec<bool> = cluster.run(|party, config| {
+        encrypt::backup::restore(config, &keys(party), None).is_err()
+    });
+    assert!(outcomes.iter().all(|&refused| refused));
+
+    // Shares that no longer fit the other parties' copies are caught and not written
+    let tampered: Vec<StoredKey> = backup.iter().map(|key| {
+        let (si, sii): (&[u8], &[u8]) = key.share.replicated_bytes().unwrap();
+        let mut si: Vec<u8> = si.to_vec();
+        si[0] ^= 1;
+        StoredKey { share: KeyShare::replicated(si, sii.to_vec()).unwrap(), ..key.clone() }
+    }).collect();
+    assert!(cluster.run(|party, config| restore(party, config, Some(tampered.clone())).is_err()).iter().all(|&refused| refused));
+
+    // After a refresh the old backup is refused, and the store keeps the new epoch
+    cluster.run(|party, config| encrypt::keystore::refresh_key(config, &keys(party), "main").unwrap());
+    assert!(cluster.run(|party, config| restore(party, config, Some(backup.clone())).is_err()).iter().all(|&refused| refused));
+    assert_eq!(keys(1).load("main").unwrap().epoch, 1);
+}
+
+#[test]
+fn test_sim_import_key() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let keys = |party: usize| KeyStore::open(&cluster.party_dir(party).join("keys"));
+    let key: Vec<u8> = (0..32).map(|_| rand::random::<u8>()).collect();
+    let import = |party: usize, config: &std::path::Path, key_id: &str| {
+        encrypt::import::import_key(config, &keys(party), key_id, Some(key.as_slice()).filter(|_| party == encrypt::DATA_OWNER))
+    };
+
+    let imported: Vec<(StoredKey, [u8; 3])> = cluster.run(|party, config| import(party, config, "imported").unwrap());
+    let stored: Vec<StoredKey> = imported.iter().map(|(stored, _)| stored.clone()).collect();
+    assert_eq!(stored_key_value(&stored), key);
+    assert!(imported.iter().all(|(_, kcv)| kcv[..] == rust_impl::aes_ecb(&key, &[0u8; 16]).as_bytes()[..3]));
+
+    // The imported shares encrypt under the key
+    let input: Input = data::get_input("short");
+    let ciphertexts: Vec<Ciphertext> = cluster.run(|party, config| {
+        encrypt::encrypt_ecb(config, &input, "imported", Some(&keys(party).load("imported").unwrap().share)).unwrap()
+    });
+    assert!(ciphertexts.iter().all(|ciphertext| ciphertext == &rust_impl::aes_ecb(&key, &input.to_bytes())));
+
+    // An existing ID is refused, and a client that sends inconsistent shares is caught
+    assert!(cluster.run(|party, config| import(party, config, "imported").is_err()).iter().all(|&refused| refused));
+    let outcomes: Vec<bool> = cluster.run(|party, config| {
+        if party == encrypt::DATA_OWNER {
+            encrypt::fault::inject(encrypt::fault::Fault::InputShare);
+        }
+        let result: error::Result<(StoredKey, [u8; 3])> = import(party, config, "tampered");
+        encrypt::fault::clear();
+        result.is_err()
+    });
+    assert!(outcomes.iter().all(|&failed| failed));
+    assert!((0..3).all(|party| !keys(party).contains("tampered")));
+}
+
+#[test]
+fn test_sim_key_check_value() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let keys = |party: usize| KeyStore::open(&cluster.party_dir(party).join("keys"));
+    let builtin: [u8; 3] = encrypt::kcv::local_check_value(&encrypt::rss::key_schedule::get_combined_key());
+
+    // Stored with the key, unchanged by a refresh, and computed again on demand
+    let stored: Vec<StoredKey> = cluster.run(|party, config| encrypt::keystore::store_key(config, &keys(party), "main", None).unwrap());
+    assert!(stored.iter().all(|key| key.kcv == Some(builtin)));
+    cluster.run(|party, config| encrypt::keystore::refresh_key(config, &keys(party), "main").unwrap());
+    let checked: Vec<[u8; 3]> = cluster.run(|party, config| encrypt::kcv::check_key(config, &keys(party), "main").unwrap());
+    assert!(checked.iter().all(|&kcv| kcv == builtin));
+    assert!(cluster.run(|party, config| encrypt::kcv::check_key(config, &keys(party), encrypt::nonce::DEFAULT_KEY_ID).unwrap()).iter().all(|&kcv| kcv == builtin));
+    assert!(cluster.run(|party, config| encrypt::kcv::check_key(config, &keys(party), "unknown").is_err()).iter().all(|&refused| refused));
+
+    // A rotated version has its own value, the same on all parties
+    let rotated: Vec<StoredKey> = cluster.run(|party, config| encrypt::keystore::rotate_key(config, &keys(party), "main").unwrap());
+    assert!(rotated.iter().all(|key| key.kcv.is_some() && key.kcv == rotated[0].kcv && key.kcv != Some(builtin)));
+
+    // A value missing from a file is recorded, a wrong one is caught by every party
+    let current: StoredKey = keys(1).load("main").unwrap();
+    keys(1).save(&StoredKey { kcv: None, ..current.clone() }).unwrap();
+    cluster.run(|party, config| encrypt::kcv::check_key(config, &keys(party), "main").unwrap());
+    assert_eq!(keys(1).load("main").unwrap().kcv, rotated[1].kcv);
+    keys(2).save(&StoredKey { kcv: Some(builtin), ..keys(2).load("main").unwrap() }).unwrap();
+    assert!(cluster.run(|party, config| encrypt::kcv::check_key(config, &keys(party), "main").is_err()).iter().all(|&refused| refused));
+
+    // A daemon does not start serving with shares that do not match their stored value
+    // (with no jobs, a daemon that passes the check stops right away)
+    let outcomes: Vec<bool> = cluster.run(|party, config| {
+        let jobs: Option<std::sync::mpsc::Receiver<encrypt::daemon::Job>> = (party == encrypt::DATA_OWNER).then(|| std::sync::mpsc::channel().1);
+        let result: error::Result<()> = encrypt::daemon::run(config, &cluster.party_dir(party), &keys(party), "main", jobs, None);
+        matches!(result, Err(error::LocksmithError::Refused(_)))
+    });
+    assert!(outcomes.iter().all(|&refused| refused));
+}
+
+#[test]
+fn test_access_policy() {
+    use config::policy::{Operation, Policy};
+
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let client: String = config::load(cluster.config(0)).unwrap().fingerprint(encrypt::DATA_OWNER).unwrap();
+    assert_eq!(client.len(), 64);
+    cluster.set_policy(1, &format!(
+        "[[allow]]\nclient = \"{}\"\nkeys = [\"main\", \"payroll-*\"]\noperations = [\"encrypt\", \"decrypt\"]\n",
+        client.to_uppercase(),
+    ));
+    let party: config::PartyConfig = config::load(cluster.config(1)).unwrap();
+    let policy: Policy = Policy::load(party.policy.as_deref().unwrap()).unwrap();
+    assert!(policy.allows(&client, "main", Operation::Encrypt));
+    assert!(policy.allows(&client, "payroll-2026", Operation::Decrypt));
+    assert!(!policy.allows(&client, "payroll", Operation::Decrypt));
+    assert!(!policy.allows(&client, "main", Operation::Import));
+    assert!(!policy.allows(&"0".repeat(64), "main", Operation::Encrypt));
+    // `*` matches every client
+    let party: config::PartyConfig = config::load(cluster.config(0)).unwrap();
+    let policy: Policy = Policy::load(party.policy.as_deref().unwrap()).unwrap();
+    assert!(policy.allows(&"0".repeat(64), "main", Operation::Import));
+
+    // A policy that does not parse or names no fingerprint makes the config invalid
+    cluster.set_policy(2, "[[allow]]\nclient = \"party 0\"\nkeys = [\"main\"]\noperations = [\"encrypt\"]\n");
+    assert!(config::load(cluster.config(2)).is_err());
+    cluster.set_policy(0, "[[allow]]\nclient = \"00\"\nkeys = [\"main\"]\noperations = [\"sign\"]\n");
+    assert!(config::load(cluster.config(0)).is_err());
+}
+
+#[test]
+fn test_sim_access_policy() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let keys = |party: usize| KeyStore::open(&cluster.party_dir(party).join("keys"));
+    let client: String = config::load(cluster.config(0)).unwrap().fingerprint(encrypt::DATA_OWNER).unwrap();
+    // Only party 2 restricts the client, to encrypting under "default" and importing "main*"
+    cluster.set_policy(2, &format!(
+        "[[allow]]\nclient = \"{client}\"\nkeys = [\"default\"]\noperations = [\"encrypt\"]\n\n\
+         [[allow]]\nclient = \"{client}\"\nkeys = [\"main*\"]\noperations = [\"import\"]\n",
+    ));
+
+    let input: Input = data::get_input("short");
+    let expected: Ciphertext = rust_impl::aes_ecb(&encrypt::rss::key_schedule::get_combined_key(), &input.to_bytes());
+    let ciphertexts: Vec<Ciphertext> = cluster.run(|_, config| encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None).unwrap());
+    assert!(ciphertexts.iter().all(|ciphertext| ciphertext == &expected));
+
+    // One party's policy is enough to refuse a request on all three
+    let refused = |result: error::Result<Ciphertext>| matches!(result, Err(error::LocksmithError::Refused(_)));
+    assert!(cluster.run(|_, config| refused(encrypt::encrypt_ecb(config, &input, "other", None))).iter().all(|&refused| refused));
+    let key: [u8; 16] = [7u8; 16];
+    let outcomes: Vec<bool> = cluster.run(|party, config| {
+        let key: Option<&[u8]> = Some(key.as_slice()).filter(|_| party == encrypt::DATA_OWNER);
+        encrypt::import::import_key(config, &keys(party), "other", key).is_err()
+    });
+    assert!(outcomes.iter().all(|&refused| refused));
+    assert!((0..3).all(|party| !keys(party).contains("other")));
+    cluster.run(|party, config| {
+        let key: Option<&[u8]> = Some(key.as_slice()).filter(|_| party == encrypt::DATA_OWNER);
+        encrypt::import::import_key(config, &keys(party), "main", key).unwrap()
+    });
+
+    // The parties must all be asked for the same key
+    let outcomes: Vec<bool> = cluster.run(|party, config| {
+        let key_id: &str = if party == 1 { "main" } else { encrypt::nonce::DEFAULT_KEY_ID };
+        refused(encrypt::encrypt_ecb(config, &input, key_id, None))
+    });
+    assert!(outcomes.iter().all(|&refused| refused));
+
+    // A party without a policy refuses everything
+    let text: String = std::fs::read_to_string(cluster.config(1)).unwrap();
+    let without: String = text.lines().filter(|line| !line.starts_with("policy")).map(|line| format!("{}\n", line)).collect();
+    std::fs::write(cluster.config(1), without).unwrap();
+    assert!(config::load(cluster.config(1)).unwrap().policy.is_none());
+    assert!(cluster.run(|_, config| refused(encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None))).iter().all(|&refused| refused));
+}
+
+#[test]
+fn test_audit_log() {
+    use encrypt::access::Operation;
+    use encrypt::audit::{AuditLog, Entry, Outcome, Record};
+
+    let dir: std::path::PathBuf = std::env::temp_dir().join(format!("locksmith-audit-{}", std::process::id()));
+    let path: std::path::PathBuf = dir.join("p1.log");
+    let record = |blocks: u64, outcome: Outcome| Record {
+        key_id: "main".to_string(),
+        operation: Operation::Encrypt,
+        blocks,
+        requester: "00".repeat(32),
+        nonces: Some(encrypt::nonce::CounterRange { first: 16, blocks, version: FIRST_VERSION }.into()),
+        outcome,
+    };
+
+    // The chain continues across reopening
+    let log: AuditLog = AuditLog::open(&path);
+    assert!(log.entries().unwrap().is_empty());
+    log.append(record(2, Outcome::Ok)).unwrap();
+    log.append(record(3, Outcome::Refused("by policy".to_string()))).unwrap();
+    AuditLog::open(&path).append(record(4, Outcome::Ok)).unwrap();
+    let entries: Vec<Entry> = log.entries().unwrap();
+    assert_eq!(entries.iter().map(|entry| (entry.seq, entry.record.blocks)).collect::<Vec<_>>(), vec![(1, 2), (2, 3), (3, 4)]);
+    assert_eq!(entries[1].prev, entries[0].hash);
+    assert_eq!(entries[1].record, record(3, Outcome::Refused("by policy".to_string())));
+
+    // An entry that is changed or removed breaks the chain
+    let text: String = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, text.replacen("\"blocks\":3", "\"blocks\":5", 1)).unwrap();
+    assert!(log.entries().is_err());
+    let lines: Vec<&str> = text.lines().collect();
+    std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+    assert!(log.entries().is_err());
+    std::fs::write(&path, &text).unwrap();
+    assert_eq!(log.entries().unwrap(), entries);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sim_audit_log() {
+    use encrypt::access::Operation;
+    use encrypt::audit::{AuditLog, Entry, Outcome};
+
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let client: String = config::load(cluster.config(0)).unwrap().fingerprint(encrypt::DATA_OWNER).unwrap();
+    let input: Input = data::get_input("short");
+    let blocks: u64 = input.num_blocks() as u64;
+    cluster.run(|_, config| encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None).unwrap());
+    let encrypt_ctr = |party: usize, config: &std::path::Path| {
+        let ledger: NonceLedger = NonceLedger::open(&cluster.party_dir(party), "sim").unwrap();
+        let data: Option<&Input> = (party == encrypt::DATA_OWNER).then_some(&input);
+        encrypt::encrypt_ctr(config, data, Nonce::Fixed(7), ledger, None, FIRST_VERSION)
+    };
+    cluster.run(|party, config| encrypt_ctr(party, config).unwrap());
+    // Reusing the counters is refused, and recorded as such
+    cluster.run(|party, config| encrypt_ctr(party, config).unwrap_err());
+
+    // Every party recorded the same three operations
+    for party in 0..3 {
+        let entries: Vec<Entry> = AuditLog::open(&cluster.audit_log(party)).entries().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|entry| entry.record.operation == Operation::Encrypt && entry.record.requester == client));
+        assert!(entries.iter().all(|entry| entry.record.blocks == blocks));
+        assert_eq!((entries[0].record.key_id.as_str(), &entries[0].record.nonces), (encrypt::nonce::DEFAULT_KEY_ID, &None));
+        assert_eq!(entries[1].record.key_id, "sim");
+        assert_eq!(entries[1].record.nonces, Some(encrypt::nonce::CounterRange { first: 7, blocks, version: FIRST_VERSION }.into()));
+        assert_eq!(entries[1].record.outcome, Outcome::Ok);
+        assert!(matches!(entries[2].record.outcome, Outcome::Refused(_)));
+    }
+    assert!(cluster.run(|_, config| encrypt::audit::compare(config).unwrap()).iter().all(|&entries| entries == 3));
+
+    // A party whose log lost an entry is caught by all, and so is one whose log was altered
+    let text: String = std::fs::read_to_string(cluster.audit_log(1)).unwrap();
+    let shortened: Vec<&str> = text.lines().take(2).collect();
+    std::fs::write(cluster.audit_log(1), format!("{}\n", shortened.join("\n"))).unwrap();
+    let outcomes: Vec<bool> = cluster.run(|_, config| matches!(encrypt::audit::compare(config), Err(error::LocksmithError::Refused(_))));
+    assert!(outcomes.iter().all(|&refused| refused));
+    std::fs::write(cluster.audit_log(1), text.replacen("\"blocks\":", "\"blocks\":1", 1)).unwrap();
+    assert!(cluster.run(|_, config| encrypt::audit::compare(config).is_err()).iter().all(|&failed| failed));
+}
+
+#[test]
+fn test_sim_logs_no_secrets() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let input: Input = data::get_input("short");
+    let runs: Vec<(Ciphertext, String)> = cluster.run(|_, config| {
+        sim::capture_logs(|| encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None).unwrap())
+    });
+
+    // Even at trace level, the plaintext and the key only show up in builds for debugging
+    let plaintext: String = hex::encode(input.to_bytes());
+    let key: String = hex::encode(encrypt::rss::key_schedule::get_combined_key());
+    for (party, (_, logs)) in runs.iter().enumerate() {
+        assert!(logs.contains("phase") && logs.contains("connected"), "party {} logged no phases", party);
+        assert!(!logs.contains(&key));
+        assert_eq!(logs.contains(&plaintext), party == encrypt::DATA_OWNER && cfg!(feature = "debug-secrets"));
+    }
+}
+
+#[test]
+fn test_phase_metrics() {
+    use std::io::{Read, Write};
+    use std::collections::BTreeMap;
+
+    // Times include nested phases
+    {
+        let _outer: metrics::Phase = metrics::start_timed("test_outer");
+        let _inner: metrics::Phase = metrics::start_timed("test_inner");
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    let phases: BTreeMap<String, metrics::PhaseMetrics> = metrics::snapshot();
+    let (outer, inner): (metrics::PhaseMetrics, metrics::PhaseMetrics) = (phases["test_outer"], phases["test_inner"]);
+    assert_eq!((outer.count, inner.count), (1, 1));
+    assert!(outer.seconds >= inner.seconds && inner.seconds >= 0.005);
+
+    let report: metrics::Report = metrics::Report { party: Some(1), mode: "ecb".to_string(), key_id: "main".to_string(), phases };
+    let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["phases"]["test_inner"]["count"], 1);
+    assert_eq!(json["phases"]["test_inner"]["bytes_sent"], 0);
+
+    // Prometheus scrapes the totals over HTTP
+    let addr: std::net::SocketAddr = metrics::serve("127.0.0.1:0").unwrap();
+    let scrape = |path: &str| -> String {
+        let mut stream: std::net::TcpStream = std::net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response: String = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response: String = scrape("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("# TYPE locksmith_phase_seconds_total counter"));
+    assert!(response.contains("locksmith_phase_runs_total{phase=\"test_inner\"} 1\n"));
+    assert!(scrape("/").starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn test_sim_phase_metrics() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let input: Input = data::get_input("short");
+    cluster.run(|_, config| encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None).unwrap());
+
+    // Every phase ran and the S-box layers carry the traffic of the AES rounds
+    let phases: std::collections::BTreeMap<String, metrics::PhaseMetrics> = metrics::snapshot();
+    for name in ["connect", "input", "key_schedule", "preprocessing", "aes", "aes_sbox", "check", "output"] {
+        assert!(phases.get(name).is_some_and(|phase| phase.count > 0), "no {} phase", name);
+    }
+    let sbox: metrics::PhaseMetrics = phases["aes_sbox"];
+    assert!(sbox.bytes_sent > 0 && sbox.bytes_received > 0 && sbox.rounds > 0);
+    assert!(sbox.count >= 3 * 10);
+}
+
+#[test]
+fn test_bench_options() {
+    use encrypt::backend::Backend;
+    use encrypt::bench::{BenchConfig, Workload};
+
+    let cli: Cli = Cli::parse_from(["locksmith", "--config", "p1.toml", "--mode", "bench"]);
+    let defaults: BenchConfig = cli.bench_config();
+    assert_eq!(defaults.backends, Backend::value_variants());
+    assert_eq!(defaults.workloads.len(), 4);
+    assert!(defaults.blocks.iter().all(|&blocks| (1..=encrypt::bench::MAX_BLOCKS).contains(&blocks)));
+
+    let cli: Cli = Cli::parse_from([
+        "locksmith", "--config", "p1.toml", "--mode", "bench", "--bench-backends", "gf4-circuit",
+        "--bench-workloads", "ctr,key-schedule", "--bench-blocks", "1,1048576", "--warmup", "0", "--repetitions", "3",
+    ]);
+    assert_eq!(cli.bench_config(), BenchConfig {
+        backends: vec![Backend::Gf4Circuit],
+        workloads: vec![Workload::Ctr, Workload::KeySchedule],
+        blocks: vec![1, 1 << 20],
+        warmup: 0,
+        repetitions: 3,
+    });
+}
+
+#[test]
+fn test_sim_bench() {
+    use encrypt::backend::Backend;
+    use encrypt::bench::{BenchConfig, BenchResult, Workload};
+
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let bench: BenchConfig = BenchConfig {
+        backends: vec![Backend::Lut256SsMal, Backend::Gf4Circuit],
+        workloads: vec![Workload::Ecb, Workload::Ctr, Workload::KeySchedule, Workload::Preprocessing],
+        blocks: vec![1, 20],
+        warmup: 1,
+        repetitions: 2,
+    };
+    let runs: Vec<Vec<BenchResult>> = cluster.run(|_, config| encrypt::bench::run(config, &bench).unwrap());
+
+    // Two block counts for three workloads and one key schedule, for both backends
+    for results in &runs {
+        assert_eq!(results.len(), 2 * (3 * 2 + 1));
+        for result in results {
+            assert_eq!(result.repetitions, 2);
+            assert!(result.throughput > 0.0 && result.latency_min <= result.latency && result.latency <= result.latency_max);
+        }
+        let ecb: Vec<&BenchResult> = results.iter().filter(|result| result.workload == Workload::Ecb).collect();
+        assert!(ecb.iter().all(|result| result.bytes_sent_per_block > 0.0 && result.rounds > 0.0));
+    }
+
+    // Parties with different settings refuse to run
+    let outcomes: Vec<bool> = cluster.run(|party, config| {
+        let bench: BenchConfig = BenchConfig { repetitions: 1 + party, ..bench.clone() };
+        matches!(encrypt::bench::run(config, &bench), Err(error::LocksmithError::Refused(_)))
+    });
+    assert!(outcomes.iter().all(|&refused| refused));
+}
+
+#[test]
+fn test_sim_backends() {
+    use encrypt::backend::Backend;
+
+    // The same operations agree with the reference on every backend: the cipher and its inverse
+    // with both key lengths, CTR, and key check values of stored keys
+    let data: Vec<u8> = (0..48).collect();
+    let builtin: [u8; 3] = encrypt::kcv::local_check_value(&encrypt::rss::key_schedule::get_combined_key());
+    for &backend in Backend::value_variants() {
+        let cluster: sim::Cluster = sim::Cluster::new();
+        for party in 0..3 {
+            cluster.set_backend(party, backend);
+        }
+        for key in [&[0x2b; 16][..], &[0x5a; 32][..]] {
+            let ciphertext: Ciphertext = sim_ecb(&cluster, key, &data);
+            assert_eq!(ciphertext, rust_impl::aes_ecb(key, &data), "{:?}", backend);
+            assert_eq!(sim_ecb_decrypt(&cluster, key, ciphertext.as_bytes()).as_bytes(), &data[..], "{:?}", backend);
+            assert_eq!(sim_ctr(&cluster, key, 7, &data), rust_impl::aes_ctr(key, 7, &data), "{:?}", backend);
+        }
+        let keys = |party: usize| KeyStore::open(&cluster.party_dir(party).join("keys"));
+        let stored: Vec<StoredKey> = cluster.run(|party, config| encrypt::keystore::store_key(config, &keys(party), "main", None).unwrap());
+        assert!(stored.iter().all(|key| key.kcv == Some(builtin)), "{:?}", backend);
+    }
+
+    // Parties on different backends refuse to run
+    let cluster: sim::Cluster = sim::Cluster::new();
+    cluster.set_backend(0, Backend::Lut256Ss);
+    cluster.set_backend(1, Backend::Lut256Ss);
+    cluster.set_backend(2, Backend::Gf4Circuit);
+    let input: Input = data::get_input("short");
+    let outcomes: Vec<bool> = cluster.run(|_, config| encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None).is_err());
+    assert!(outcomes.iter().all(|&refused| refused));
+}
+
+#[test]
+fn test_sim_every_backend() {
+    use encrypt::backend::Backend;
+
+    // Clusters start on the default backend, so the other backends rerun the whole simulator
+    // suite in a child process; the children skip the tests that pick their backends themselves
+    if std::env::var(sim::BACKEND_VAR).is_ok() {
+        return;
+    }
+    for &backend in Backend::value_variants().iter().filter(|&&backend| backend != Backend::default()) {
+        let output: std::process::Output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["test_sim_", "--skip", "test_sim_every_backend", "--skip", "test_sim_backends", "--skip", "test_sim_parallel_sbox"])
+            .env(sim::BACKEND_VAR, backend.name())
+            .env_remove("LOCKSMITH_BACKEND")
+            .output()
+            .expect("Failed to rerun the simulator tests");
+        assert!(
+            output.status.success(),
+            "simulator tests failed on {:?}:\n{}{}",
+            backend, String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr),
+        );
+    }
+}
+
+#[test]
+fn test_parallel_split() {
+    use encrypt::parallel::{channel_units, split};
+
+    assert_eq!(split(10, 4), vec![3, 3, 2, 2]);
+    assert_eq!(split(2, 3), vec![1, 1, 0]);
+    assert_eq!(split(96, 3), vec![32, 32, 32]);
+
+    // Every channel prepares enough for the longest run of a split layer, with either key length
+    for variant in [AesVariant::Aes128, AesVariant::Aes256] {
+        for channels in 2..=8 {
+            for blocks in 1..500 {
+                let (_, total): (usize, usize) = encrypt::preprocessing_units(variant, 0, blocks);
+                let (_, longest): (usize, usize) = encrypt::preprocessing_units(variant, 0, split(blocks, channels)[0]);
+                assert!(channel_units(total, channels) >= longest, "{:?} {} {}", variant, channels, blocks);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sim_parallel_sbox() {
+    use encrypt::backend::Backend;
+
+    // Enough blocks that every backend splits the layers across two channels
+    let data: Vec<u8> = (0..80 * 16).map(|i| i as u8).collect();
+    for &backend in Backend::value_variants() {
+        let cluster: sim::Cluster = sim::Cluster::new();
+        cluster.set_network("channels = 2\n");
+        for party in 0..3 {
+            cluster.set_backend(party, backend);
+        }
+        for key in [&[0x2b; 16][..], &[0x5a; 32][..]] {
+            let ciphertext: Ciphertext = sim_ecb(&cluster, key, &data);
+            assert_eq!(ciphertext, rust_impl::aes_ecb(key, &data), "{:?}", backend);
+            assert_eq!(sim_ecb_decrypt(&cluster, key, ciphertext.as_bytes()).as_bytes(), &data[..], "{:?}", backend);
+            assert_eq!(sim_ctr(&cluster, key, 7, &data), rust_impl::aes_ctr(key, 7, &data), "{:?}", backend);
+        }
+    }
+
+    // Parties with different numbers of channels refuse to run
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let text: String = std::fs::read_to_string(cluster.config(2)).unwrap();
+    std::fs::write(cluster.config(2), format!("{}\n[network]\nchannels = 2\n", text)).unwrap();
+    let input: Input = data::get_input("short");
+    let outcomes: Vec<bool> = cluster.run(|_, config| encrypt::encrypt_ecb(config, &input, encrypt::nonce::DEFAULT_KEY_ID, None).is_err());
+    assert!(outcomes.iter().all(|&refused| refused));
+}
+
+#[test]
+fn test_sim_ctr_stream() {
+    let cluster: sim::Cluster = sim::Cluster::new();
+    let key: [u8; 32] = [0x5a; 32];
+    let shares: [KeyShare; 3] = KeyShare::split(&key).unwrap();
+
+    // Five windows of four blocks, the last one partial
+    let data: Vec<u8> = (0..4 * 4 * 16 + 23).map(|i| (i * 7) as u8).collect();
+    let input: std::path::PathBuf = cluster.dir().join("stream.in");
+    let output: std::path::PathBuf = cluster.dir().join("stream.out");
+    std::fs::write(&input, &data).unwrap();
+    let results: Vec<(StreamSummary, Vec<Progress>)> = cluster.run(|party, config| {
+        let io: Option<StreamIo> = (party == encrypt::DATA_OWNER).then(|| StreamIo::from_paths(&input, &output).unwrap());
+        let mut progress: Vec<Progress> = Vec::new();
+        let ledger: NonceLedger = NonceLedger::in_memory("stream");
+        let summary: StreamSummary = encrypt::stream::encrypt_ctr_stream(config, io, Nonce::Fixed(7), 4, ledger, Some(&shares[party]), FIRST_VERSION, |done| progress.push(*done)).unwrap();
+        (summary, progress)
+    });
+    assert_eq!(std::fs::read(&output).unwrap(), rust_impl::aes_ctr(&key, 7, &data).as_bytes());
+
+    // Every party counts the same windows; only the data owner knows the total
+    for (party, (summary, progress)) in results.iter().enumerate() {
+        assert_eq!((summary.nonce, summary.bytes), (Some(7), data.len() as u64));
+        let blocks: Vec<u64> = progress.iter().map(|done| done.blocks_done).collect();
+        assert_eq!(blocks, vec![4, 8, 12, 16, 18]);
+        assert_eq!(progress.last().unwrap().bytes_done, data.len() as u64);
+        assert_eq!(progress[0].percent().is_some(), party == encrypt::DATA_OWNER);
+    }
+    assert_eq!(results[encrypt::DATA_OWNER].1.last().unwrap().percent(), Some(100.0));
+}
+
+#[test]
+fn test_sim_ctr_stream_failure() {
+    use error::{AbortReason, LocksmithError};
+    use std::io::{Read, Write};
+
+    // Fails once `left` bytes have gone through
+    struct Failing {
+        left: usize,
+    }
+    impl Failing {
+        fn pass(&mut self, len: usize) -> std::io::Result<usize> {
+            if self.left == 0 {
+                return Err(std::io::Error::other("disk gone"));
+            }
+            let n: usize = len.min(self.left);
+            self.left -= n;
+            Ok(n)
+        }
+    }
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.pass(buf.len())
+        }
+    }
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.pass(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A read error, then a write error of the data owner after the first window aborts all three
+    let cluster: sim::Cluster = sim::Cluster::new();
+    for read_fails in [true, false] {
+        let outcomes: Vec<error::Result<StreamSummary>> = cluster.run(|party, config| {
+            let io: Option<StreamIo> = (party == encrypt::DATA_OWNER).then(|| {
+                let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match read_fails {
+                    true => (Box::new(Failing { left: 64 }), Box::new(std::io::sink())),
+                    false => (Box::new(std::io::repeat(1).take(256)), Box::new(Failing { left: 64 })),
+                };
+                StreamIo { reader, writer, total_bytes: None }
+            });
+            let ledger: NonceLedger = NonceLedger::in_memory("stream");
+            encrypt::stream::encrypt_ctr_stream(config, io, Nonce::Auto, 4, ledger, None, FIRST_VERSION, |_| {})
+        });
+        assert!(matches!(outcomes[encrypt::DATA_OWNER], Err(LocksmithError::Io(_))), "{:?}", outcomes[0]);
+        for outcome in &outcomes[1..] {
+            assert!(matches!(outcome, Err(LocksmithError::Abort(AbortReason::PeerAborted { party: 0, kind: "io" }))), "{:?}", outcome);
+        }
+    }
+}
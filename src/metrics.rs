@@ -0,0 +1,248 @@
+// Per-phase metrics: wall time, traffic and communication rounds of the protocol phases
+// (connecting, input sharing, key schedule, preprocessing, AES and its S-box layers, checks,
+// output), summed over the whole process. A run writes them as JSON (`--metrics`), a daemon also
+// serves them to Prometheus (`--metrics-addr`).
+//
+// Phases nest, e.g. the S-box layers run inside `aes`, and times include the nested phases.
+// Traffic is taken from maestro's comm stats at every phase boundary and counted once, for the
+// innermost phase open at the time; traffic outside all phases, such as the agreement rounds
+// between them, is counted as `other`. The traffic of further channels (`encrypt::parallel`)
+// counts for the phase they served, but not their rounds, which overlap those of the first.
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use maestro::rep3_core::party::{CombinedCommStats, MainParty};
+use serde::Serialize;
+use tracing::span::EnteredSpan;
+
+use crate::encrypt::backend::MpcParty;
+use crate::error::{LocksmithError, Result};
+use crate::logging;
+
+/// Phase that traffic outside of all phases is counted for
+pub const OTHER: &str = "other";
+
+/// Totals of one phase
+#[derive(Serialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct PhaseMetrics {
+    /// Number of times the phase ran
+    pub count: u64,
+    pub seconds: f64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Communication rounds, the larger count of the links to the two other parties
+    pub rounds: u64,
+}
+
+/// Traffic of one party
+#[derive(Serialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct Traffic {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub rounds: u64,
+}
+
+impl std::ops::Add for Traffic {
+    type Output = Traffic;
+
+    fn add(self, other: Traffic) -> Traffic {
+        Traffic {
+            bytes_sent: self.bytes_sent + other.bytes_sent,
+            bytes_received: self.bytes_received + other.bytes_received,
+            rounds: self.rounds + other.rounds,
+        }
+    }
+}
+
+impl std::ops::Sub for Traffic {
+    type Output = Traffic;
+
+    fn sub(self, other: Traffic) -> Traffic {
+        Traffic {
+            bytes_sent: self.bytes_sent - other.bytes_sent,
+            bytes_received: self.bytes_received - other.bytes_received,
+            rounds: self.rounds - other.rounds,
+        }
+    }
+}
+
+static TOTALS: Mutex<BTreeMap<&'static str, PhaseMetrics>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    // Phases open on this thread, innermost last
+    static OPEN: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+    // All traffic counted on this thread
+    static SEEN: Cell<Traffic> = const { Cell::new(Traffic { bytes_sent: 0, bytes_received: 0, rounds: 0 }) };
+}
+
+fn update(name: &'static str, f: impl FnOnce(&mut PhaseMetrics)) {
+    let mut totals = TOTALS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    f(totals.entry(name).or_default());
+}
+
+/// Traffic of `party` since it was last counted, left uncounted. A thread serving a further
+/// channel (see `encrypt::parallel`) takes it, and the thread it works for passes it to `add`.
+pub fn take(party: &MainParty) -> Traffic {
+    let stats: CombinedCommStats = party.io().reset_comm_stats();
+    Traffic {
+        bytes_sent: stats.prev.bytes_sent + stats.next.bytes_sent,
+        bytes_received: stats.prev.bytes_received + stats.next.bytes_received,
+        rounds: stats.prev.rounds.max(stats.next.rounds) as u64,
+    }
+}
+
+// Counts the traffic since the last phase boundary for the innermost open phase
+fn count(party: &MainParty) {
+    add(take(party));
+}
+
+/// Counts `traffic` for the innermost phase open on this thread
+pub fn add(traffic: Traffic) {
+    SEEN.with(|seen| seen.set(seen.get() + traffic));
+    let name: &'static str = OPEN.with(|open| open.borrow().last().copied()).unwrap_or(OTHER);
+    update(name, |phase| {
+        phase.bytes_sent += traffic.bytes_sent;
+        phase.bytes_received += traffic.bytes_received;
+        phase.rounds += traffic.rounds;
+    });
+}
+
+/// All traffic of `party` on this thread so far; the difference of two calls is the traffic in
+/// between, whatever phases it was counted for
+pub fn traffic(party: &MainParty) -> Traffic {
+    count(party);
+    SEEN.with(Cell::get)
+}
+
+/// A running phase, in its own tracing span. Ends when dropped, see `end`.
+pub struct Phase {
+    name: &'static str,
+    started: Instant,
+    _span: EnteredSpan,
+}
+
+/// Starts phase `name` of `party` on this thread
+pub fn start(name: &'static str, party: &MainParty) -> Phase {
+    count(party);
+    start_timed(name)
+}
+
+/// Starts phase `name` before there is a party to count the traffic of, i.e. when connecting
+pub fn start_timed(name: &'static str) -> Phase {
+    OPEN.with(|open| open.borrow_mut().push(name));
+    Phase { name, started: Instant::now(), _span: logging::phase(name).entered() }
+}
+
+impl Phase {
+    /// Ends the phase and counts its traffic. A phase that is only dropped, e.g. when it fails,
+    /// leaves its traffic to the enclosing phase.
+    pub fn end(self, party: &MainParty) {
+        count(party);
+    }
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        OPEN.with(|open| {
+            let mut open = open.borrow_mut();
+            if let Some(at) = open.iter().rposition(|&name| name == self.name) {
+                open.remove(at);
+            }
+        });
+        let elapsed: Duration = self.started.elapsed();
+        update(self.name, |phase| {
+            phase.count += 1;
+            phase.seconds += elapsed.as_secs_f64();
+        });
+    }
+}
+
+/// Runs `f` as phase `name` of `party`
+pub fn measure<T>(party: &mut dyn MpcParty, name: &'static str, f: impl FnOnce(&mut dyn MpcParty) -> T) -> T {
+    let phase: Phase = start(name, party.main_party_mut());
+    let result: T = f(party);
+    phase.end(party.main_party_mut());
+    result
+}
+
+/// Totals of all phases in this process so far
+pub fn snapshot() -> BTreeMap<String, PhaseMetrics> {
+    let totals = TOTALS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    totals.iter().map(|(&name, &phase)| (name.to_string(), phase)).collect()
+}
+
+/// Metrics of one run, as written by `--metrics`
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Report {
+    pub party: Option<usize>,
+    pub mode: String,
+    pub key_id: String,
+    pub phases: BTreeMap<String, PhaseMetrics>,
+}
+
+impl Report {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json: String = serde_json::to_string_pretty(self).map_err(|e| LocksmithError::Malformed(format!("metrics: {}", e)))?;
+        std::fs::write(path, json + "\n")?;
+        Ok(())
+    }
+}
+
+// Name, help text and value of an exported metric
+type Exported = (&'static str, &'static str, fn(&PhaseMetrics) -> f64);
+
+const EXPORTED: [Exported; 5] = [
+    ("locksmith_phase_runs_total", "Number of times a protocol phase ran", |phase| phase.count as f64),
+    ("locksmith_phase_seconds_total", "Wall time spent in a protocol phase, including nested phases", |phase| phase.seconds),
+    ("locksmith_phase_sent_bytes_total", "Bytes sent to the other parties during a protocol phase", |phase| phase.bytes_sent as f64),
+    ("locksmith_phase_received_bytes_total", "Bytes received from the other parties during a protocol phase", |phase| phase.bytes_received as f64),
+    ("locksmith_phase_rounds_total", "Communication rounds of a protocol phase", |phase| phase.rounds as f64),
+];
+
+/// Renders `phases` in the Prometheus text format
+pub fn prometheus(phases: &BTreeMap<String, PhaseMetrics>) -> String {
+    let mut text: String = String::new();
+    for (metric, help, value) in EXPORTED {
+        text += &format!("# HELP {} {}\n# TYPE {} counter\n", metric, help, metric);
+        for (name, phase) in phases {
+            text += &format!("{}{{phase=\"{}\"}} {}\n", metric, name, value(phase));
+        }
+    }
+    text
+}
+
+/// Serves the totals to Prometheus at `http://<addr>/metrics` from a background thread, for
+/// as long as the process runs. Returns the address it listens on.
+pub fn serve(addr: &str) -> Result<SocketAddr> {
+    let listener: TcpListener = TcpListener::bind(addr)?;
+    let local: SocketAddr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = respond(stream) {
+                tracing::debug!("metrics request failed: {}", e);
+            }
+        }
+    });
+    tracing::info!(%local, "serving metrics");
+    Ok(local)
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request: String = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let (status, body): (&str, String) = match request.split_whitespace().take(2).collect::<Vec<&str>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", prometheus(&snapshot())),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body,
+    )
+}
@@ -1,62 +1,152 @@
-use aes::cipher::inout::InOut;
-use aes::cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray};
-use aes::Aes128;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit, generic_array::GenericArray};
+use aes::{Aes128, Aes256};
 
-pub fn rust_aes_ecb(input: &crate::Input) -> String {
-    // Create AES key from our test key
-    let key_bytes: [u8; 16] = crate::encrypt::rss::key_schedule::get_combined_key();
-    let key: &GenericArray<u8, aes::cipher::typenum::UInt<aes::cipher::typenum::UInt<aes::cipher::typenum::UInt<aes::cipher::typenum::UInt<aes::cipher::typenum::UInt<aes::cipher::typenum::UTerm, aes::cipher::consts::B1>, aes::cipher::consts::B0>, aes::cipher::consts::B0>, aes::cipher::consts::B0>, aes::cipher::consts::B0>> = GenericArray::from_slice(&key_bytes);
-    let cipher: Aes128 = Aes128::new(key);
-
-    // Get input blocks
-    let input_bytes: Vec<u8> = input.to_bytes();
-    let num_blocks: usize = input.num_blocks();
-
-    // Encrypt each block (ECB mode)
-    let mut ciphertext: Vec<u8> = Vec::with_capacity(num_blocks * 16);
-    for chunk in input_bytes.chunks(16) {
-        let mut block: GenericArray<u8, aes::cipher::typenum::UInt<aes::cipher::typenum::UInt<aes::cipher::typenum::UInt<aes::cipher::typenum::UInt<aes::cipher::typenum::UInt<aes::cipher::typenum::UTerm, aes::cipher::consts::B1>, aes::cipher::consts::B0>, aes::cipher::consts::B0>, aes::cipher::consts::B0>, aes::cipher::consts::B0>> = GenericArray::clone_from_slice(chunk);
-        cipher.encrypt_block(&mut block);
-        ciphertext.extend_from_slice(&block);
+use crate::data::{Ciphertext, Plaintext, Tag};
+
+// Plain (non-MPC) AES used as the reference for the MPC implementation.
+// Keys are 16 or 32 bytes and select AES-128 or AES-256; data must be whole blocks except in CTR and GCM.
+
+enum Cipher {
+    Aes128(Box<Aes128>),
+    Aes256(Box<Aes256>),
+}
+
+impl Cipher {
+    fn new(key: &[u8]) -> Self {
+        match key.len() {
+            16 => Cipher::Aes128(Box::new(Aes128::new(GenericArray::from_slice(key)))),
+            32 => Cipher::Aes256(Box::new(Aes256::new(GenericArray::from_slice(key)))),
+            len => panic!("AES keys are 16 or 32 bytes, not {}", len),
+        }
     }
 
-    println!("ECB ciphertext: 0x{}", hex::encode(&ciphertext));
+    fn encrypt(&self, block: &[u8]) -> [u8; 16] {
+        let mut block = GenericArray::clone_from_slice(block);
+        match self {
+            Cipher::Aes128(cipher) => cipher.encrypt_block(&mut block),
+            Cipher::Aes256(cipher) => cipher.encrypt_block(&mut block),
+        }
+        block.into()
+    }
+
+    fn decrypt(&self, block: &[u8]) -> [u8; 16] {
+        let mut block = GenericArray::clone_from_slice(block);
+        match self {
+            Cipher::Aes128(cipher) => cipher.decrypt_block(&mut block),
+            Cipher::Aes256(cipher) => cipher.decrypt_block(&mut block),
+        }
+        block.into()
+    }
+}
 
-    return hex::encode(&ciphertext);
+pub fn rust_aes_ecb(input: &crate::Input) -> Ciphertext {
+    let key_bytes: [u8; 16] = crate::encrypt::rss::key_schedule::get_combined_key();
+    aes_ecb(&key_bytes, &input.to_bytes())
 }
 
-pub fn rust_aes_ctr(input: &crate::Input, nonce: u128) -> String {
-    // Create AES key from our test key
+pub fn rust_aes_ctr(input: &crate::Input, nonce: u128) -> Ciphertext {
     let key_bytes: [u8; 16] = crate::encrypt::rss::key_schedule::get_combined_key();
-    let key = GenericArray::from_slice(&key_bytes);
-    let cipher: Aes128 = Aes128::new(key);
-
-    // Get input bytes
-    let input_bytes: Vec<u8> = input.to_bytes();
-    
-    // Initialize counter (nonce + counter)
-    let mut counter = [0u8; 16];
-    counter[..16].copy_from_slice(&nonce.to_be_bytes());
-    let mut ctr_value = 0u128;
-
-    // Encrypt in CTR mode
-    let mut ciphertext: Vec<u8> = Vec::with_capacity(input_bytes.len());
-    for chunk in input_bytes.chunks(16) {
-        // Update counter block
-        counter[..16].copy_from_slice(&(nonce.wrapping_add(ctr_value)).to_be_bytes());
-        let mut counter_block = GenericArray::clone_from_slice(&counter);
-        
-        // Encrypt counter
-        cipher.encrypt_block(&mut counter_block);
-        
-        // XOR with plaintext
-        for (i, &byte) in chunk.iter().enumerate() {
-            ciphertext.push(byte ^ counter_block[i]);
+    aes_ctr(&key_bytes, nonce, &input.to_bytes())
+}
+
+pub fn aes_ecb(key: &[u8], data: &[u8]) -> Ciphertext {
+    let cipher: Cipher = Cipher::new(key);
+    Ciphertext::new(data.chunks(16).flat_map(|block| cipher.encrypt(block)).collect())
+}
+
+pub fn aes_ecb_decrypt(key: &[u8], data: &[u8]) -> Plaintext {
+    let cipher: Cipher = Cipher::new(key);
+    Plaintext::new(data.chunks(16).flat_map(|block| cipher.decrypt(block)).collect())
+}
+
+pub fn aes_cbc(key: &[u8], iv: &[u8], data: &[u8]) -> Ciphertext {
+    let cipher: Cipher = Cipher::new(key);
+    let mut previous: [u8; 16] = iv.try_into().expect("CBC IVs are 16 bytes");
+    let mut ciphertext: Vec<u8> = Vec::with_capacity(data.len());
+    for block in data.chunks(16) {
+        let chained: Vec<u8> = block.iter().zip(previous.iter()).map(|(p, c)| p ^ c).collect();
+        previous = cipher.encrypt(&chained);
+        ciphertext.extend_from_slice(&previous);
+    }
+    Ciphertext::new(ciphertext)
+}
+
+pub fn aes_cbc_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Plaintext {
+    let cipher: Cipher = Cipher::new(key);
+    let previous: Vec<&[u8]> = std::iter::once(iv).chain(data.chunks(16)).collect();
+    Plaintext::new(data.chunks(16).zip(previous)
+        .flat_map(|(block, previous)| cipher.decrypt(block).into_iter().zip(previous).map(|(p, c)| p ^ c))
+        .collect())
+}
+
+/// CTR with 128-bit big-endian counter blocks starting at `counter`, wrapping around
+pub fn aes_ctr(key: &[u8], counter: u128, data: &[u8]) -> Ciphertext {
+    let cipher: Cipher = Cipher::new(key);
+    Ciphertext::new(data.chunks(16).enumerate()
+        .flat_map(|(i, chunk)| {
+            let keystream: [u8; 16] = cipher.encrypt(&counter.wrapping_add(i as u128).to_be_bytes());
+            chunk.iter().zip(keystream).map(|(d, k)| d ^ k).collect::<Vec<u8>>()
+        })
+        .collect())
+}
+
+/// GCM (NIST SP 800-38D) with a 128-bit tag
+pub fn aes_gcm(key: &[u8], iv: &[u8], aad: &[u8], data: &[u8]) -> (Ciphertext, Tag) {
+    let cipher: Cipher = Cipher::new(key);
+    let h: u128 = u128::from_be_bytes(cipher.encrypt(&[0u8; 16]));
+    let j0: u128 = gcm_j0(h, iv);
+
+    let keystream: Vec<u8> = (0..data.len().div_ceil(16))
+        .flat_map(|i| cipher.encrypt(&gcm_inc32(j0, 1 + i as u32).to_be_bytes()))
+        .collect();
+    let ciphertext: Vec<u8> = data.iter().zip(keystream).map(|(d, k)| d ^ k).collect();
+
+    let mask: [u8; 16] = cipher.encrypt(&j0.to_be_bytes());
+    let tag: u128 = ghash(h, aad, &ciphertext) ^ u128::from_be_bytes(mask);
+    (Ciphertext::new(ciphertext), Tag::new(tag.to_be_bytes().to_vec()))
+}
+
+/// Pre-counter block J0 for the hash subkey `h`
+pub fn gcm_j0(h: u128, iv: &[u8]) -> u128 {
+    if iv.len() == 12 {
+        let mut block: [u8; 16] = [0u8; 16];
+        block[..12].copy_from_slice(iv);
+        block[15] = 1;
+        return u128::from_be_bytes(block);
+    }
+    ghash(h, &[], iv)
+}
+
+/// Adds `n` to the low 32 bits of a counter block, modulo 2^32
+pub fn gcm_inc32(block: u128, n: u32) -> u128 {
+    let low: u32 = (block as u32).wrapping_add(n);
+    (block & !(u32::MAX as u128)) | low as u128
+}
+
+/// GHASH_H over the zero-padded `aad` and `ciphertext` followed by their bit lengths
+pub fn ghash(h: u128, aad: &[u8], ciphertext: &[u8]) -> u128 {
+    let mut y: u128 = 0;
+    for data in [aad, ciphertext] {
+        for chunk in data.chunks(16) {
+            let mut block: [u8; 16] = [0u8; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            y = gf128_mul(y ^ u128::from_be_bytes(block), h);
         }
-        
-        ctr_value = ctr_value.wrapping_add(1);
     }
+    let lengths: u128 = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+    gf128_mul(y ^ lengths, h)
+}
 
-    println!("CTR ciphertext: 0x{}", hex::encode(&ciphertext));
-    hex::encode(&ciphertext)
-}
\ No newline at end of file
+// Multiplication in GF(2^128) with the bit order of SP 800-38D (the first bit is the MSB)
+fn gf128_mul(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xe1 << 120;
+    let mut z: u128 = 0;
+    let mut v: u128 = y;
+    for i in 0..128 {
+        if (x >> (127 - i)) & 1 == 1 {
+            z ^= v;
+        }
+        v = if v & 1 == 1 { (v >> 1) ^ R } else { v >> 1 };
+    }
+    z
+}
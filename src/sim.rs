@@ -0,0 +1,211 @@
+// In-process three-party cluster for tests. Every party runs on its own thread and the parties
+// connect over loopback TLS, with certificates and config files generated for each cluster,
+// so the MPC code paths are exercised exactly as in a deployment but without `run-all.sh`.
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::config::{self, PartyEntry, CLUSTER_FILE};
+use crate::encrypt::backend::Backend;
+use clap::ValueEnum;
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, SanType};
+
+static NEXT_CLUSTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Environment variable naming the backend that clusters start with, so that the simulator tests
+/// can be run once per backend; `Backend::default()` if unset
+pub const BACKEND_VAR: &str = "LOCKSMITH_SIM_BACKEND";
+
+// Backend every party of a new cluster runs until `set_backend` changes it
+fn initial_backend() -> Backend {
+    match std::env::var(BACKEND_VAR) {
+        Ok(name) => Backend::from_str(&name, false).unwrap_or_else(|_| panic!("{} is not a backend: {}", BACKEND_VAR, name)),
+        Err(_) => Backend::default(),
+    }
+}
+
+/// Config files and TLS material for three parties on 127.0.0.1, removed again on drop
+pub struct Cluster {
+    dir: PathBuf,
+    configs: Vec<PathBuf>,
+}
+
+impl Cluster {
+    pub fn new() -> Self {
+        let dir: PathBuf = std::env::temp_dir().join(format!(
+            "locksmith-sim-{}-{}",
+            std::process::id(),
+            NEXT_CLUSTER.fetch_add(1, Ordering::SeqCst),
+        ));
+        std::fs::create_dir_all(&dir).expect("Failed to create cluster directory");
+
+        // Self-signed Ed25519 certificates for 127.0.0.1, like the ones in keys/
+        for party in 1..=3 {
+            let mut params: CertificateParams = CertificateParams::new(Vec::<String>::new());
+            params.alg = &rcgen::PKCS_ED25519;
+            params.subject_alt_names = vec![SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST))];
+            let mut name: DistinguishedName = DistinguishedName::new();
+            name.push(DnType::CommonName, format!("Party {}", party));
+            name.push(DnType::OrganizationName, "Locksmith simulator");
+            params.distinguished_name = name;
+
+            let cert: Certificate = Certificate::from_params(params).expect("Failed to generate certificate");
+            std::fs::write(dir.join(format!("p{}.pem", party)), cert.serialize_pem().unwrap()).unwrap();
+            std::fs::write(dir.join(format!("p{}.key", party)), cert.serialize_private_key_pem()).unwrap();
+        }
+
+        // Listeners are held until all three ports are picked so that the ports are distinct
+        let listeners: Vec<TcpListener> = (0..3)
+            .map(|_| TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("Failed to find a free port"))
+            .collect();
+        let ports: Vec<u16> = listeners.iter().map(|l| l.local_addr().unwrap().port()).collect();
+        drop(listeners);
+
+        let backend: Backend = initial_backend();
+        let mut peers: String = String::new();
+        for party in 1..=3 {
+            peers.push_str(&format!(
+                "\n[p{party}]\naddress = \"127.0.0.1\"\nport = {}\ncertificate = \"{}\"\nprivate_key = \"{}\"\n",
+                ports[party - 1],
+                dir.join(format!("p{}.pem", party)).display(),
+                dir.join(format!("p{}.key", party)).display(),
+            ));
+        }
+        let configs: Vec<PathBuf> = (1..=3)
+            .map(|party| {
+                let path: PathBuf = dir.join(format!("p{}.toml", party));
+                let audit_log: PathBuf = dir.join(format!("audit-p{}.log", party));
+                std::fs::write(&path, format!(
+                    "party_index = {}\nbackend = \"{}\"\naudit_log = \"{}\"\npolicy = \"p{}-policy.toml\"\n{}",
+                    party, backend.name(), audit_log.display(), party, peers,
+                )).unwrap();
+                // Parties refuse everything without a policy, so each starts with one that allows everything
+                std::fs::write(dir.join(format!("p{}-policy.toml", party)), config::policy::ALLOW_ALL).unwrap();
+                path
+            })
+            .collect();
+
+        Cluster { dir, configs }
+    }
+
+    /// Like `new`, but with a cluster file and per-party overlays, and certificates issued by a
+    /// cluster CA as `certs init` writes them
+    pub fn with_ca() -> Self {
+        let cluster: Cluster = Cluster::new();
+        let parties: [PartyEntry; 3] = config::resolve(&cluster.configs[0], |_| None).expect("Failed to read config").parties;
+        let mut text: String = String::new();
+        for (i, entry) in parties.iter().enumerate() {
+            text.push_str(&format!(
+                "[p{}]\naddress = \"{}\"\nport = {}\ncertificate = \"\"\nprivate_key = \"\"\n\n",
+                i + 1, entry.address, entry.port,
+            ));
+        }
+        std::fs::write(cluster.dir.join(CLUSTER_FILE), text).unwrap();
+        for (i, path) in cluster.configs.iter().enumerate() {
+            std::fs::write(path, format!(
+                "party_index = {}\nbackend = \"{}\"\ncluster = \"{}\"\naudit_log = \"audit-p{}.log\"\npolicy = \"p{}-policy.toml\"\n",
+                i + 1, initial_backend().name(), CLUSTER_FILE, i + 1, i + 1,
+            )).unwrap();
+        }
+        config::certs::init(&cluster.dir.join(CLUSTER_FILE), Path::new("ca"), 1, false).expect("Failed to issue certificates");
+        cluster
+    }
+
+    /// Adds a `[network]` table with `settings` to every party's config file
+    pub fn set_network(&self, settings: &str) {
+        for path in &self.configs {
+            let text: String = std::fs::read_to_string(path).unwrap();
+            std::fs::write(path, format!("{}\n[network]\n{}", text, settings)).unwrap();
+        }
+    }
+
+    /// Replaces the access policy of party `party` (0-based), which allows everything at first,
+    /// with `policy`
+    pub fn set_policy(&self, party: usize, policy: &str) {
+        std::fs::write(self.dir.join(format!("p{}-policy.toml", party + 1)), policy).unwrap();
+    }
+
+    /// Replaces the backend of party `party` (0-based), `LOCKSMITH_SIM_BACKEND` at first, with `backend`
+    pub fn set_backend(&self, party: usize, backend: Backend) {
+        let text: String = std::fs::read_to_string(&self.configs[party]).unwrap();
+        let text: String = text.lines()
+            .map(|line| if line.starts_with("backend = ") { format!("backend = \"{}\"", backend.name()) } else { line.to_string() })
+            .collect::<Vec<String>>()
+            .join("\n");
+        std::fs::write(&self.configs[party], text + "\n").unwrap();
+    }
+
+    /// Directory of the config files
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Config file of party `party` (0-based)
+    pub fn config(&self, party: usize) -> &Path {
+        &self.configs[party]
+    }
+
+    /// Audit log of party `party` (0-based)
+    pub fn audit_log(&self, party: usize) -> PathBuf {
+        self.dir.join(format!("audit-p{}.log", party + 1))
+    }
+
+    /// Scratch directory of party `party`, e.g. for nonce ledgers
+    pub fn party_dir(&self, party: usize) -> PathBuf {
+        self.dir.join(format!("party{}", party))
+    }
+
+    /// Runs `f(party, config)` for all three parties concurrently and returns the results in party order
+    pub fn run<T, F>(&self, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(usize, &Path) -> T + Sync,
+    {
+        thread::scope(|scope| {
+            let handles: Vec<thread::ScopedJoinHandle<'_, T>> = (0..3)
+                .map(|party| {
+                    let f: &F = &f;
+                    let config: &Path = self.config(party);
+                    scope.spawn(move || f(party, config))
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().expect("party thread panicked")).collect()
+        })
+    }
+}
+
+/// Runs `f` with the log lines of this thread captured down to trace level, and returns them
+/// along with its result
+pub fn capture_logs<T>(f: impl FnOnce() -> T) -> (T, String) {
+    let lines: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(Vec::new()));
+    let writer: Arc<Mutex<Vec<u8>>> = lines.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(move || CapturedLines(writer.clone()))
+        .finish();
+    let result: T = tracing::subscriber::with_default(subscriber, f);
+    let text: String = String::from_utf8_lossy(&lines.lock().unwrap()).into_owned();
+    (result, text)
+}
+
+struct CapturedLines(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLines {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}